@@ -0,0 +1,726 @@
+//! Parsers for Android diagnostic command output (getprop, dumpsys, df, du, screencap,
+//! storage access, wake locks, ps, Termux:API)
+
+use serde::Serialize;
+use std::collections::HashMap;
+
+/// Commands android_info runs, each parsed on its own so one failing doesn't hide the rest
+pub const GETPROP_COMMAND: &str = "getprop";
+pub const BATTERY_COMMAND: &str = "dumpsys battery";
+pub const WIFI_COMMAND: &str = "dumpsys wifi";
+pub const STORAGE_COMMAND: &str = "df -h /data";
+
+/// Writes the screen as a PNG to stdout
+pub const SCREENCAP_COMMAND: &str = "screencap -p";
+
+const PNG_SIGNATURE: &[u8] = b"\x89PNG\r\n\x1a\n";
+
+/// Prints a line for each storage check that passes, for parse_storage_status
+/// Listing /sdcard is what fails with "Permission denied" until Termux has the permission
+pub const STORAGE_STATUS_COMMAND: &str = "[ -d ~/storage ] && echo storage_dir; \
+     [ -d ~/storage/shared/ ] && echo shared_link; \
+     ls /sdcard/ >/dev/null 2>&1 && echo sdcard; :";
+
+/// Take or release the Termux wake lock, printing `missing_tool` if this Termux lacks the
+/// script and `wake_lock:<status>` otherwise, for parse_wake_lock
+/// termux-wake-lock comes with Termux itself (termux-tools), not the Termux:API add-on
+pub fn wake_lock_command(acquire: bool) -> String {
+    let script = if acquire {
+        "termux-wake-lock"
+    } else {
+        "termux-wake-unlock"
+    };
+    format!(
+        "command -v {0} >/dev/null 2>&1 || {{ echo missing_tool; exit 0; }}; \
+         {0}; echo \"wake_lock:$?\"",
+        script
+    )
+}
+
+/// How a wake_lock_command went
+#[derive(Debug, PartialEq)]
+pub enum WakeLockOutcome {
+    Done,
+    MissingTool,
+    /// The script ran but failed, with its exit status
+    Failed(Option<i32>),
+}
+
+/// Parse the output of `wake_lock_command`
+pub fn parse_wake_lock(output: &str) -> WakeLockOutcome {
+    match output.lines().map(str::trim).rfind(|l| !l.is_empty()) {
+        Some("missing_tool") => WakeLockOutcome::MissingTool,
+        Some("wake_lock:0") => WakeLockOutcome::Done,
+        Some(line) => WakeLockOutcome::Failed(
+            line.strip_prefix("wake_lock:")
+                .and_then(|status| status.parse().ok()),
+        ),
+        None => WakeLockOutcome::Failed(None),
+    }
+}
+
+/// Advice when termux-wake-lock isn't on the device
+pub const WAKE_LOCK_MISSING: &str = "termux-wake-lock is not installed on this device. \
+     It ships with Termux in the termux-tools package: run pkg install termux-tools, \
+     or update Termux if it is very old.";
+
+/// Printed to stderr by `termux_api_command` when the script isn't installed
+pub const TERMUX_API_MISSING_MARKER: &str = "mcp-android-ssh:no_termux_api";
+
+/// Run a Termux:API script such as termux-clipboard-get with `args` (already quoted), or
+/// print TERMUX_API_MISSING_MARKER to stderr if the termux-api package isn't installed
+pub fn termux_api_command(script: &str, args: &str) -> String {
+    format!(
+        "command -v {0} >/dev/null 2>&1 || {{ echo {1} >&2; exit 0; }}; {0}{2}",
+        script, TERMUX_API_MISSING_MARKER, args
+    )
+}
+
+/// Whether a termux_api_command found its script missing
+pub fn termux_api_missing(stderr: &str) -> bool {
+    stderr
+        .lines()
+        .any(|l| l.trim() == TERMUX_API_MISSING_MARKER)
+}
+
+/// Advice when a Termux:API script isn't on the device
+/// The scripts only talk to the Termux:API app, and hang if it isn't installed
+pub fn termux_api_guidance(script: &str) -> String {
+    format!(
+        "{} is not installed on this device. It needs the termux-api package \
+         (pkg install termux-api) and the Termux:API app, installed from the same place as Termux \
+         (F-Droid or GitHub). If the command then hangs until the timeout, the app is missing.",
+        script
+    )
+}
+
+/// Lists processes with the columns process_list reports, falling back for ps
+/// implementations that don't know them: procps and toybox take the first form,
+/// busybox the second, and anything left prints its default columns
+pub const PS_COMMAND: &str = "ps -A -o pid,user,%cpu,%mem,args 2>/dev/null \
+     || ps -o pid,user,args 2>/dev/null || ps";
+
+/// Summary of the device, with None for anything that couldn't be read
+#[derive(Debug, Default, Serialize)]
+pub struct AndroidInfo {
+    pub manufacturer: Option<String>,
+    pub model: Option<String>,
+    pub android_version: Option<String>,
+    pub sdk: Option<u32>,
+    pub battery: Option<BatteryInfo>,
+    pub wifi_ssid: Option<String>,
+    pub storage: Option<StorageInfo>,
+    /// Why each missing section is missing
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub unavailable: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatteryInfo {
+    pub level: u8,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub status: Option<&'static str>,
+    /// Which sources are powering the device (ac, usb, wireless)
+    pub powered_by: Vec<&'static str>,
+    /// Battery temperature in degrees Celsius
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub temperature_c: Option<f32>,
+}
+
+/// Usage of the /data partition, with sizes as df prints them (e.g. "12G")
+#[derive(Debug, Serialize)]
+pub struct StorageInfo {
+    pub size: String,
+    pub used: String,
+    pub available: String,
+    pub use_percent: Option<u8>,
+}
+
+/// Whether Termux can reach shared storage, for storage_status
+#[derive(Debug, Serialize)]
+pub struct StorageStatus {
+    /// /sdcard can be listed, i.e. Termux has the storage permission
+    pub sdcard_accessible: bool,
+    /// ~/storage exists, as created by termux-setup-storage
+    pub storage_dir: bool,
+    /// ~/storage/shared leads to /sdcard
+    pub shared_link: bool,
+    /// What to do when something is missing
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub guidance: Option<&'static str>,
+}
+
+/// Usage of the filesystem holding a path, for disk_usage
+#[derive(Debug, Serialize)]
+pub struct FilesystemUsage {
+    pub mounted_on: String,
+    pub size_bytes: u64,
+    pub used_bytes: u64,
+    pub available_bytes: u64,
+    pub use_percent: Option<u8>,
+}
+
+/// A directory and its total size, for disk_usage
+#[derive(Debug, Serialize)]
+pub struct DirUsage {
+    pub path: String,
+    pub size_bytes: u64,
+    /// size_bytes in the style of `du -h` (e.g. "1.5G")
+    pub size: String,
+}
+
+/// A process as ps lists it, for process_list
+/// Columns a ps implementation doesn't print are None
+#[derive(Debug, Serialize)]
+pub struct ProcessInfo {
+    pub pid: u32,
+    pub user: Option<String>,
+    /// %CPU, averaged over the process's lifetime as ps computes it
+    pub cpu: Option<f64>,
+    pub mem: Option<f64>,
+    pub command: String,
+}
+
+/// Parse the output of `PS_COMMAND`, finding columns by their header names
+/// The command is the last column and may contain spaces, so it takes the rest of the line
+/// Returns None if the header has no PID column or doesn't end with the command
+pub fn parse_ps(output: &str) -> Option<Vec<ProcessInfo>> {
+    let mut lines = output.lines().filter(|l| !l.trim().is_empty());
+    let header: Vec<String> = lines
+        .next()?
+        .split_whitespace()
+        .map(str::to_ascii_uppercase)
+        .collect();
+    let column = |names: &[&str]| header.iter().position(|h| names.contains(&h.as_str()));
+    let pid_col = column(&["PID"])?;
+    let command_col = header.len() - 1;
+    if column(&["ARGS", "COMMAND", "CMD", "CMDLINE", "NAME"]) != Some(command_col) {
+        return None;
+    }
+    let user_col = column(&["USER", "UID"]);
+    let cpu_col = column(&["%CPU", "C", "PCPU"]);
+    let mem_col = column(&["%MEM", "PMEM"]);
+
+    let processes = lines
+        .filter_map(|line| {
+            let mut fields = Vec::with_capacity(header.len());
+            let mut rest = line.trim_start();
+            while fields.len() < command_col {
+                let end = rest.find(char::is_whitespace)?;
+                fields.push(&rest[..end]);
+                rest = rest[end..].trim_start();
+            }
+            let number = |col: Option<usize>| col.and_then(|c| fields[c].parse().ok());
+            Some(ProcessInfo {
+                pid: fields[pid_col].parse().ok()?,
+                user: user_col.map(|c| fields[c].to_string()),
+                cpu: number(cpu_col),
+                mem: number(mem_col),
+                command: rest.trim_end().to_string(),
+            })
+        })
+        .collect();
+    Some(processes)
+}
+
+/// Parse `getprop` output, where each line is `[key]: [value]`
+pub fn parse_getprop(output: &str) -> HashMap<String, String> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let (key, value) = line.trim().split_once("]: [")?;
+            let key = key.strip_prefix('[')?;
+            let value = value.strip_suffix(']')?;
+            Some((key.to_string(), value.to_string()))
+        })
+        .collect()
+}
+
+/// Whether `key` can be a system property name, and so is safe to pass to getprop unquoted
+pub fn is_property_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || "._-:@".contains(c))
+}
+
+/// Parse `dumpsys battery`, which prints indented `key: value` lines
+/// Returns None if there is no level, e.g. when the dump was refused
+pub fn parse_battery(output: &str) -> Option<BatteryInfo> {
+    let fields: HashMap<&str, &str> = output
+        .lines()
+        .filter_map(|line| line.trim().split_once(": "))
+        .collect();
+
+    let level = fields.get("level")?.trim().parse().ok()?;
+    // BatteryManager.BATTERY_STATUS_* values
+    let status = fields.get("status").and_then(|s| match s.trim() {
+        "2" => Some("charging"),
+        "3" => Some("discharging"),
+        "4" => Some("not charging"),
+        "5" => Some("full"),
+        _ => None,
+    });
+    let powered_by = [
+        ("AC powered", "ac"),
+        ("USB powered", "usb"),
+        ("Wireless powered", "wireless"),
+    ]
+    .into_iter()
+    .filter(|(key, _)| fields.get(key).is_some_and(|v| v.trim() == "true"))
+    .map(|(_, source)| source)
+    .collect();
+    // Reported in tenths of a degree
+    let temperature_c = fields
+        .get("temperature")
+        .and_then(|t| t.trim().parse::<f32>().ok())
+        .map(|t| t / 10.0);
+
+    Some(BatteryInfo {
+        level,
+        status,
+        powered_by,
+        temperature_c,
+    })
+}
+
+/// Find the connected network's SSID in `dumpsys wifi`
+/// The current connection appears as `mWifiInfo SSID: "name", BSSID: ...`
+pub fn parse_wifi_ssid(output: &str) -> Option<String> {
+    let line = output.lines().find(|line| line.contains("mWifiInfo"))?;
+    let rest = &line[line.find("SSID: ")? + "SSID: ".len()..];
+    let ssid = match rest.strip_prefix('"') {
+        Some(quoted) => &quoted[..quoted.find('"')?],
+        None => rest.split(',').next()?.trim(),
+    };
+    // Shown when disconnected, or when location permission hides the name
+    if ssid.is_empty() || ssid == "<unknown ssid>" {
+        return None;
+    }
+    Some(ssid.to_string())
+}
+
+/// Parse `df -h <path>`, reading columns from the end of the last line since
+/// some df versions wrap a long filesystem name onto its own line
+pub fn parse_df(output: &str) -> Option<StorageInfo> {
+    let [size, used, available, use_percent, _mount] = df_columns(output)?;
+    Some(StorageInfo {
+        size: size.to_string(),
+        used: used.to_string(),
+        available: available.to_string(),
+        use_percent: use_percent.trim_end_matches('%').parse().ok(),
+    })
+}
+
+/// Parse `df -k <path>` (or `df -h`) into byte counts, where plain numbers are
+/// `block_size`-byte blocks
+pub fn parse_df_usage(output: &str, block_size: u64) -> Option<FilesystemUsage> {
+    let [size, used, available, use_percent, mount] = df_columns(output)?;
+    Some(FilesystemUsage {
+        mounted_on: mount.to_string(),
+        size_bytes: parse_size(size, block_size)?,
+        used_bytes: parse_size(used, block_size)?,
+        available_bytes: parse_size(available, block_size)?,
+        use_percent: use_percent.trim_end_matches('%').parse().ok(),
+    })
+}
+
+/// The size, used, available, use% and mount columns of df's last line
+/// A mount point containing spaces isn't supported, as df doesn't quote it
+fn df_columns(output: &str) -> Option<[&str; 5]> {
+    let line = output.lines().rev().find(|line| !line.trim().is_empty())?;
+    let fields: Vec<&str> = line.split_whitespace().collect();
+    if fields.len() < 5 {
+        return None;
+    }
+    let &[size, used, available, use_percent, mount] = &fields[fields.len() - 5..] else {
+        return None;
+    };
+    // Only the header was printed
+    if size == "Size" || size.ends_with("-blocks") {
+        return None;
+    }
+    Some([size, used, available, use_percent, mount])
+}
+
+/// Parse `du -d 1 <path>` output, one `size<TAB>path` line per directory
+/// du lists a directory after everything below it, so the last line is `path` itself;
+/// returns its total and the other directories, largest first
+pub fn parse_du(output: &str, block_size: u64) -> (Option<u64>, Vec<DirUsage>) {
+    let mut entries: Vec<DirUsage> = output
+        .lines()
+        .filter_map(|line| {
+            let (size, path) = line.split_once('\t')?;
+            let size_bytes = parse_size(size.trim(), block_size)?;
+            Some(DirUsage {
+                path: path.to_string(),
+                size_bytes,
+                size: format_size(size_bytes),
+            })
+        })
+        .collect();
+    let total = entries.pop().map(|root| root.size_bytes);
+    entries.sort_by(|a, b| b.size_bytes.cmp(&a.size_bytes).then(a.path.cmp(&b.path)));
+    (total, entries)
+}
+
+/// Parse a size printed by df or du: a count of `block_size`-byte blocks, or a
+/// human-readable size with a 1024-based suffix such as "12G", "1.5M" or "512K"
+pub fn parse_size(text: &str, block_size: u64) -> Option<u64> {
+    if let Ok(blocks) = text.parse::<u64>() {
+        return blocks.checked_mul(block_size);
+    }
+    let number_end = text
+        .find(|c: char| !c.is_ascii_digit() && c != '.')
+        .unwrap_or(text.len());
+    let (number, suffix) = text.split_at(number_end);
+    let number: f64 = number.parse().ok()?;
+    let exponent = match suffix.trim_end_matches(['B', 'i']) {
+        "" => 0,
+        "K" | "k" => 1,
+        "M" => 2,
+        "G" => 3,
+        "T" => 4,
+        "P" => 5,
+        _ => return None,
+    };
+    Some((number * 1024f64.powi(exponent)) as u64)
+}
+
+/// Format a byte count like `du -h`, with one decimal below 10 (e.g. "1.5G", "12M", "512")
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["K", "M", "G", "T", "P"];
+    if bytes < 1024 {
+        return bytes.to_string();
+    }
+    let mut value = bytes as f64 / 1024.0;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if value < 10.0 {
+        format!("{:.1}{}", value, UNITS[unit])
+    } else {
+        format!("{:.0}{}", value, UNITS[unit])
+    }
+}
+
+/// Parse the output of `STORAGE_STATUS_COMMAND`
+pub fn parse_storage_status(output: &str) -> StorageStatus {
+    let passed: Vec<&str> = output.lines().map(str::trim).collect();
+    let sdcard_accessible = passed.contains(&"sdcard");
+    let storage_dir = passed.contains(&"storage_dir");
+    let shared_link = passed.contains(&"shared_link");
+    let guidance = if !sdcard_accessible {
+        Some(
+            "Termux doesn't have the storage permission, so /sdcard can't be read. \
+             Run termux-setup-storage in the Termux app on the device and tap Allow; \
+             the permission is granted on the device's screen, not over SSH. If no dialog \
+             appears, allow Files and media for Termux in Android Settings > Apps > Termux > Permissions.",
+        )
+    } else if !storage_dir || !shared_link {
+        Some(
+            "/sdcard is readable, but the ~/storage shortcuts are missing. Run \
+             termux-setup-storage in the Termux app to create them (~/storage/shared, \
+             ~/storage/downloads, ~/storage/dcim, ...).",
+        )
+    } else {
+        None
+    };
+    StorageStatus {
+        sdcard_accessible,
+        storage_dir,
+        shared_link,
+        guidance,
+    }
+}
+
+/// Width and height from a PNG's header, or None if `bytes` isn't a PNG
+/// The IHDR chunk always comes first, right after the 8-byte signature
+pub fn png_dimensions(bytes: &[u8]) -> Option<(u32, u32)> {
+    if !bytes.starts_with(PNG_SIGNATURE) || bytes.get(12..16)? != b"IHDR" {
+        return None;
+    }
+    let width = u32::from_be_bytes(bytes.get(16..20)?.try_into().ok()?);
+    let height = u32::from_be_bytes(bytes.get(20..24)?.try_into().ok()?);
+    Some((width, height))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const GETPROP: &str = "\
+[ro.build.version.release]: [14]
+[ro.build.version.sdk]: [34]
+[ro.product.manufacturer]: [Google]
+[ro.product.model]: [Pixel 7]
+[persist.sys.timezone]: [Europe/Berlin]
+[ro.empty]: []
+";
+
+    const DUMPSYS_BATTERY: &str = "\
+Current Battery Service state:
+  AC powered: false
+  USB powered: true
+  Wireless powered: false
+  Max charging current: 500000
+  status: 2
+  health: 2
+  present: true
+  level: 87
+  scale: 100
+  voltage: 4182
+  temperature: 291
+  technology: Li-ion
+";
+
+    const DUMPSYS_WIFI: &str = "\
+Wi-Fi is enabled
+WifiInfo:
+mWifiInfo SSID: \"Home Net\", BSSID: 02:00:00:00:00:00, MAC: 02:00:00:00:00:00, Supplicant state: COMPLETED, Wi-Fi standard: 11ac, RSSI: -52
+";
+
+    const DF: &str = "\
+Filesystem      Size  Used Avail Use% Mounted on
+/dev/block/dm-48
+                 110G   61G   49G  56% /data
+";
+
+    #[test]
+    fn getprop_lines_become_properties() {
+        let props = parse_getprop(GETPROP);
+        assert_eq!(props["ro.product.model"], "Pixel 7");
+        assert_eq!(props["ro.build.version.release"], "14");
+        assert_eq!(props["ro.build.version.sdk"], "34");
+        assert_eq!(props["ro.empty"], "");
+        assert!(parse_getprop("getprop: not found").is_empty());
+    }
+
+    #[test]
+    fn battery_dump_is_parsed() {
+        let battery = parse_battery(DUMPSYS_BATTERY).unwrap();
+        assert_eq!(battery.level, 87);
+        assert_eq!(battery.status, Some("charging"));
+        assert_eq!(battery.powered_by, ["usb"]);
+        assert_eq!(battery.temperature_c, Some(29.1));
+        // Refused without the DUMP permission
+        assert!(
+            parse_battery("Permission Denial: can't dump Battery service from pid=1234").is_none()
+        );
+    }
+
+    #[test]
+    fn wifi_ssid_is_found_in_the_wifi_dump() {
+        assert_eq!(parse_wifi_ssid(DUMPSYS_WIFI).as_deref(), Some("Home Net"));
+        let hidden =
+            "mWifiInfo SSID: <unknown ssid>, BSSID: <none>, Supplicant state: DISCONNECTED";
+        assert_eq!(parse_wifi_ssid(hidden), None);
+        assert_eq!(parse_wifi_ssid(""), None);
+    }
+
+    #[test]
+    fn df_reads_a_wrapped_filesystem_line() {
+        let storage = parse_df(DF).unwrap();
+        assert_eq!(storage.size, "110G");
+        assert_eq!(storage.used, "61G");
+        assert_eq!(storage.available, "49G");
+        assert_eq!(storage.use_percent, Some(56));
+        assert!(parse_df("df: /data: Permission denied").is_none());
+    }
+
+    #[test]
+    fn df_usage_reads_blocks_and_human_sizes() {
+        let kib = "\
+Filesystem     1K-blocks     Used Available Use% Mounted on
+/dev/fuse      115008156 63491224  51385548  56% /storage/emulated
+";
+        let usage = parse_df_usage(kib, 1024).unwrap();
+        assert_eq!(usage.mounted_on, "/storage/emulated");
+        assert_eq!(usage.size_bytes, 115_008_156 * 1024);
+        assert_eq!(usage.used_bytes, 63_491_224 * 1024);
+        assert_eq!(usage.available_bytes, 51_385_548 * 1024);
+        assert_eq!(usage.use_percent, Some(56));
+
+        let human = parse_df_usage(DF, 1024).unwrap();
+        assert_eq!(human.size_bytes, 110 * 1024 * 1024 * 1024);
+        assert_eq!(human.mounted_on, "/data");
+        assert!(
+            parse_df_usage(
+                "Filesystem 1K-blocks Used Available Use% Mounted on\n",
+                1024
+            )
+            .is_none()
+        );
+    }
+
+    #[test]
+    fn du_lists_subdirectories_largest_first_with_the_total() {
+        let output = "\
+2048\t/sdcard/Music
+1572864\t/sdcard/DCIM
+du: /sdcard/Android/data: Permission denied
+40\t/sdcard/Download
+1574952\t/sdcard/
+";
+        let (total, dirs) = parse_du(output, 1024);
+        assert_eq!(total, Some(1_574_952 * 1024));
+        let listed: Vec<(&str, &str)> = dirs
+            .iter()
+            .map(|dir| (dir.path.as_str(), dir.size.as_str()))
+            .collect();
+        assert_eq!(
+            listed,
+            [
+                ("/sdcard/DCIM", "1.5G"),
+                ("/sdcard/Music", "2.0M"),
+                ("/sdcard/Download", "40K")
+            ]
+        );
+        let (total, dirs) = parse_du("", 1024);
+        assert!(total.is_none() && dirs.is_empty());
+    }
+
+    #[test]
+    fn sizes_parse_and_format_like_du() {
+        assert_eq!(parse_size("12", 1024), Some(12 * 1024));
+        assert_eq!(parse_size("512", 1), Some(512));
+        assert_eq!(parse_size("1.5M", 1024), Some(1_572_864));
+        assert_eq!(parse_size("12G", 1024), Some(12 << 30));
+        assert_eq!(parse_size("3KiB", 1024), Some(3072));
+        assert_eq!(parse_size("lots", 1024), None);
+        assert_eq!(parse_size("4X", 1024), None);
+
+        assert_eq!(format_size(900), "900");
+        assert_eq!(format_size(1536), "1.5K");
+        assert_eq!(format_size(12 << 20), "12M");
+        assert_eq!(format_size(5 << 40), "5.0T");
+    }
+
+    #[test]
+    fn png_dimensions_come_from_the_header() {
+        let mut png = PNG_SIGNATURE.to_vec();
+        png.extend_from_slice(b"\x00\x00\x00\x0dIHDR");
+        png.extend_from_slice(&720u32.to_be_bytes());
+        png.extend_from_slice(&1280u32.to_be_bytes());
+        assert_eq!(png_dimensions(&png), Some((720, 1280)));
+        assert_eq!(png_dimensions(&png[..20]), None);
+        assert_eq!(png_dimensions(b"GIF89a"), None);
+    }
+
+    #[test]
+    fn storage_status_is_fine_once_termux_setup_storage_ran() {
+        let status = parse_storage_status("storage_dir\nshared_link\nsdcard\n");
+        assert!(status.sdcard_accessible && status.storage_dir && status.shared_link);
+        assert_eq!(status.guidance, None);
+    }
+
+    #[test]
+    fn storage_status_explains_how_to_grant_the_permission() {
+        // No storage permission: ls /sdcard fails and termux-setup-storage never ran
+        let status = parse_storage_status("");
+        assert!(!status.sdcard_accessible && !status.storage_dir && !status.shared_link);
+        let guidance = status.guidance.unwrap();
+        assert!(guidance.contains("termux-setup-storage"), "{guidance}");
+        assert!(guidance.contains("tap Allow"), "{guidance}");
+
+        // ~/storage left behind after the permission was revoked
+        let status = parse_storage_status("storage_dir\n");
+        assert!(!status.sdcard_accessible && status.storage_dir);
+        assert!(status.guidance.unwrap().contains("storage permission"));
+
+        // Permission granted some other way, but the shortcuts were never made
+        let status = parse_storage_status("sdcard\n");
+        assert!(status.sdcard_accessible && !status.shared_link);
+        assert!(
+            status
+                .guidance
+                .unwrap()
+                .contains("~/storage shortcuts are missing")
+        );
+    }
+
+    #[test]
+    fn wake_lock_commands_map_to_the_termux_scripts() {
+        let acquire = wake_lock_command(true);
+        assert!(
+            acquire.starts_with("command -v termux-wake-lock "),
+            "{acquire}"
+        );
+        assert!(
+            acquire.ends_with("termux-wake-lock; echo \"wake_lock:$?\""),
+            "{acquire}"
+        );
+        let release = wake_lock_command(false);
+        assert!(
+            release.starts_with("command -v termux-wake-unlock "),
+            "{release}"
+        );
+        assert!(
+            release.ends_with("termux-wake-unlock; echo \"wake_lock:$?\""),
+            "{release}"
+        );
+    }
+
+    #[test]
+    fn wake_lock_output_tells_a_missing_script_from_a_failure() {
+        assert_eq!(parse_wake_lock("wake_lock:0\n"), WakeLockOutcome::Done);
+        assert_eq!(
+            parse_wake_lock("missing_tool\n"),
+            WakeLockOutcome::MissingTool
+        );
+        assert_eq!(
+            parse_wake_lock("Error: service not running\nwake_lock:1\n"),
+            WakeLockOutcome::Failed(Some(1))
+        );
+        assert_eq!(parse_wake_lock(""), WakeLockOutcome::Failed(None));
+    }
+
+    #[test]
+    fn procps_and_toybox_ps_output_is_parsed() {
+        let output = "\
+  PID USER         %CPU %MEM ARGS
+    1 root          0.0  0.1 init second_stage
+ 4321 u0_a123      12.5  3.4 /data/data/com.termux/files/usr/bin/python3 -m http.server 8000
+ 4400 u0_a123       0.0  0.0 [kworker/0:1]
+";
+        let processes = parse_ps(output).unwrap();
+        assert_eq!(processes.len(), 3);
+        let python = &processes[1];
+        assert_eq!(python.pid, 4321);
+        assert_eq!(python.user.as_deref(), Some("u0_a123"));
+        assert_eq!(python.cpu, Some(12.5));
+        assert_eq!(python.mem, Some(3.4));
+        assert_eq!(
+            python.command,
+            "/data/data/com.termux/files/usr/bin/python3 -m http.server 8000"
+        );
+        assert_eq!(processes[2].command, "[kworker/0:1]");
+    }
+
+    #[test]
+    fn busybox_ps_output_is_parsed() {
+        // ps -o pid,user,args
+        let processes =
+            parse_ps("PID   USER     COMMAND\n  812 u0_a123  sshd -D -p 8022\n").unwrap();
+        assert_eq!(processes.len(), 1);
+        assert_eq!(processes[0].pid, 812);
+        assert_eq!(processes[0].user.as_deref(), Some("u0_a123"));
+        assert_eq!((processes[0].cpu, processes[0].mem), (None, None));
+        assert_eq!(processes[0].command, "sshd -D -p 8022");
+
+        // Plain ps, with its default columns
+        let processes =
+            parse_ps("PID   USER     TIME  COMMAND\n    9 u0_a123   0:01 bash -l\n").unwrap();
+        assert_eq!(processes[0].pid, 9);
+        assert_eq!(processes[0].command, "bash -l");
+    }
+
+    #[test]
+    fn ps_output_without_pid_or_command_columns_is_rejected() {
+        assert!(parse_ps("USER COMMAND\nroot init\n").is_none());
+        assert!(parse_ps("PID COMMAND USER\n1 init root\n").is_none());
+        assert!(parse_ps("").is_none());
+    }
+}
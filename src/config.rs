@@ -1,37 +1,615 @@
 use crate::error::{Result, SshMcpError};
-use serde::{Deserialize, Serialize};
-use std::path::PathBuf;
+use regex::{Regex, RegexBuilder};
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use std::collections::HashMap;
+use std::net::{Ipv6Addr, ToSocketAddrs};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 const CONFIG_DIR_NAME: &str = "mcp-android-ssh";
 const CONFIG_FILE_NAME: &str = "config.toml";
+const KNOWN_HOSTS_FILE_NAME: &str = "known_hosts";
+const HISTORY_FILE_NAME: &str = "history.jsonl";
+/// Environment variable naming the config file to use instead of the default location
+pub const CONFIG_PATH_ENV: &str = "MCP_ANDROID_SSH_CONFIG";
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
     pub host: String,
     #[serde(default = "default_port")]
     pub port: u16,
     pub user: String,
     #[serde(skip_serializing_if = "Option::is_none")]
-    pub password: Option<String>,
+    pub password: Option<Redacted>,
+    /// Local command whose output is the password, run each time password auth is needed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_command: Option<String>,
+    /// File containing the password, read each time password auth is needed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_path: Option<String>,
+    /// Passphrase for an encrypted private key
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_passphrase: Option<Redacted>,
+    /// Authenticate with identities from the ssh-agent at `$SSH_AUTH_SOCK` before key_path/password
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub use_agent: bool,
+    /// Longest command string the tools will send (default: 128 KiB)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_command_length: Option<usize>,
+    /// Most stdout bytes execute/execute_read return per call (default: 64 KiB)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_output_bytes: Option<usize>,
+    /// Timeout execute_read uses when a call doesn't pass one, in seconds (default: 30)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_read_timeout_secs: Option<u64>,
+    /// Timeout execute uses when a call doesn't pass one, in seconds (default: 30)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_exec_timeout_secs: Option<u64>,
+    /// Seconds of silence before sending an SSH keepalive (default: 15, 0 disables)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keepalive_interval_secs: Option<u64>,
+    /// Seconds each connection attempt may take, handshake and auth included (default: 10)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_timeout_secs: Option<u64>,
+    /// Connection attempts before giving up (default: 3)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_retries: Option<u32>,
+    /// Seconds to wait between connection attempts (default: 2)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub connect_retry_delay_secs: Option<u64>,
+    /// Double the delay after each failed connection attempt
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub retry_backoff: bool,
+    /// Milliseconds between progress notifications from the stream tool (default: 500)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stream_flush_interval_ms: Option<u64>,
+    /// Let a command that is still printing output run past its timeout, until it
+    /// goes quiet or reaches adaptive_timeout_max_secs
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub adaptive_timeout: bool,
+    /// Longest a command may run under adaptive_timeout, in seconds (default: 900)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub adaptive_timeout_max_secs: Option<u64>,
+    /// When the session drops while an execute_read command runs, reconnect and run it
+    /// once more; execute is never retried, since its command may have had side effects
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub auto_retry_read_commands: bool,
+    /// Take the Termux wake lock after each connect, so Doze doesn't suspend Termux and
+    /// drop the session while the screen is off
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub acquire_wake_lock_on_connect: bool,
+    /// Locale exported as LC_ALL before every command, e.g. "C", so dates, numbers and
+    /// messages don't follow the device language (default: the device's own)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub force_locale: Option<String>,
+    /// Shell every command runs in, as `<shell> -c '<command>'`, e.g. "sh" or "bash"
+    /// (default: the user's login shell, which may be zsh or fish)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub remote_shell: Option<String>,
+    /// Most commands the command tools may run per minute, counting each batch entry (default: unlimited)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_commands_per_minute: Option<u32>,
+    /// known_hosts file used to verify the device's host key
+    /// (default: ~/.config/mcp-android-ssh/known_hosts)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub known_hosts_path: Option<String>,
+    /// Reject hosts not already in known_hosts instead of trusting them on first use
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub strict_host_key_checking: bool,
+    /// Resolve the host name when the config is loaded, so a typo fails early
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub resolve_host: bool,
+    /// Refuse a key file other users can read or replace, instead of only warning
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub refuse_insecure_key_perms: bool,
+    /// Also append every command to a JSON Lines file that outlives the session
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub persist_history: bool,
+    /// File persisted history is appended to
+    /// (default: ~/.config/mcp-android-ssh/history.jsonl)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub history_path: Option<String>,
+    /// Leave commands matching the blocklist out of the persisted history
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub history_skip_blocked: bool,
+    /// Let execute_read run pipelines of whitelisted commands (e.g. `grep x file | wc -l`)
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub allow_pipes_in_read: bool,
+    /// Remove execute and the other tools that run arbitrary commands (batch, stream,
+    /// run_script, start_job, shell_send), along with the tools that write files or stop
+    /// processes, so only commands execute_read allows can run
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub disable_execute: bool,
+    /// Whitelisted commands to look for with `command -v` before the first execute_read
+    /// on each device, so a missing one is reported with an install hint
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub probe_commands: Vec<String>,
+    /// Ciphers to offer, most preferred first, for servers that only accept some
+    /// (default: russh's list, e.g. chacha20-poly1305@openssh.com and aes256-gcm@openssh.com)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preferred_ciphers: Vec<String>,
+    /// Key exchange algorithms to offer, most preferred first (default: russh's list)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preferred_kex: Vec<String>,
+    /// Host key algorithms to accept, most preferred first (default: russh's list)
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub preferred_key_algorithms: Vec<String>,
+    /// Profile used when a tool call doesn't name one (default: the top-level settings)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub default_profile: Option<String>,
+    /// Return structured JSON from execute/execute_read by default
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub structured_output: bool,
+    /// Count a command that writes to stderr as failed even when it exits 0, in the
+    /// success flag and status of execute, execute_read and batch
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub fail_on_stderr: bool,
+    /// Overrides for the server instructions and tool descriptions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instructions: Option<InstructionsConfig>,
+    /// Changes to the execute_read command whitelist
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub whitelist: Option<WhitelistConfig>,
+    /// Commands the execute tool refuses to run
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub blocklist: Option<BlocklistConfig>,
+    /// Intermediate host the device is reached through
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub jump_host: Option<JumpHostConfig>,
+    /// Named devices, keyed by profile name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub profiles: HashMap<String, ProfileConfig>,
+}
+
+/// `[profiles.<name>]` section: another device, overriding the top-level connection settings
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ProfileConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<Redacted>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_command: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password_file: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub key_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_passphrase: Option<Redacted>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_agent: Option<bool>,
+    /// known_hosts file for this device (default: the top-level known_hosts path
+    /// followed by `.<profile>`)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub known_hosts_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub strict_host_key_checking: Option<bool>,
+}
+
+/// `[instructions]` section: customize how the assistant is told to use the server
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstructionsConfig {
+    /// Replacement for the built-in server instructions
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server: Option<String>,
+    /// File to read the server instructions from (used when `server` is unset)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub server_file: Option<String>,
+    /// Replacement tool descriptions, keyed by tool name
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub tools: HashMap<String, String>,
+}
+
+/// `[whitelist]` section: adjust which commands execute_read allows
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WhitelistConfig {
+    /// Commands to allow in addition to the built-in list
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub additional: Vec<String>,
+    /// Built-in commands to disallow
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub removed: Vec<String>,
+}
+
+/// `[blocklist]` section: commands execute refuses to run
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BlocklistConfig {
+    /// Refuse commands that start with any of these strings, including after `;`, `&`, `|`,
+    /// a newline, `$(`, a backtick, `(`, `{` or `!`, and past `sudo`, `env`, `nohup` or `command`
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub prefixes: Vec<String>,
+    /// Refuse commands matching any of these regexes anywhere in the command string
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub patterns: Vec<String>,
+    /// Ignore case when matching prefixes and patterns
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub case_insensitive: bool,
+}
+
+impl BlocklistConfig {
+    /// Compile the regex patterns
+    pub fn regexes(&self) -> Result<Vec<Regex>> {
+        self.patterns
+            .iter()
+            .map(|pattern| {
+                RegexBuilder::new(pattern)
+                    .case_insensitive(self.case_insensitive)
+                    .build()
+                    .map_err(|e| {
+                        SshMcpError::Config(format!(
+                            "Invalid [blocklist] pattern '{}': {}",
+                            pattern, e
+                        ))
+                    })
+            })
+            .collect()
+    }
+}
+
+/// `[jump_host]` section: bastion to tunnel the device connection through,
+/// authenticated with its own credentials
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct JumpHostConfig {
+    pub host: String,
+    #[serde(default = "default_jump_port")]
+    pub port: u16,
+    pub user: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_path: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_passphrase: Option<Redacted>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<Redacted>,
+}
+
+impl JumpHostConfig {
+    /// Get the jump host key path with tilde expanded
+    pub fn expanded_key_path(&self) -> Option<PathBuf> {
+        self.key_path
+            .as_ref()
+            .map(|p| PathBuf::from(shellexpand::tilde(p).to_string()))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            host: String::new(),
+            port: default_port(),
+            user: String::new(),
+            password: None,
+            password_command: None,
+            password_file: None,
+            key_path: None,
+            key_passphrase: None,
+            use_agent: false,
+            max_command_length: None,
+            max_output_bytes: None,
+            default_read_timeout_secs: None,
+            default_exec_timeout_secs: None,
+            keepalive_interval_secs: None,
+            connect_timeout_secs: None,
+            connect_retries: None,
+            connect_retry_delay_secs: None,
+            retry_backoff: false,
+            stream_flush_interval_ms: None,
+            adaptive_timeout: false,
+            adaptive_timeout_max_secs: None,
+            auto_retry_read_commands: false,
+            acquire_wake_lock_on_connect: false,
+            force_locale: None,
+            remote_shell: None,
+            max_commands_per_minute: None,
+            known_hosts_path: None,
+            strict_host_key_checking: false,
+            resolve_host: false,
+            refuse_insecure_key_perms: false,
+            persist_history: false,
+            history_path: None,
+            history_skip_blocked: false,
+            allow_pipes_in_read: false,
+            disable_execute: false,
+            probe_commands: Vec::new(),
+            preferred_ciphers: Vec::new(),
+            preferred_kex: Vec::new(),
+            preferred_key_algorithms: Vec::new(),
+            default_profile: None,
+            structured_output: false,
+            fail_on_stderr: false,
+            instructions: None,
+            whitelist: None,
+            blocklist: None,
+            jump_host: None,
+            profiles: HashMap::new(),
+        }
+    }
+}
+
+/// A profile name made safe to end a file name with; anything but letters, digits,
+/// `-`, `_` and `.` becomes `_`
+fn profile_file_suffix(name: &str) -> String {
+    name.chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || "-_.".contains(c) {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect()
+}
+
+/// Trim a host and drop the brackets around an IPv6 literal
+/// Hostnames, including mDNS `.local` names, and IPv4 addresses are kept as they are
+/// A zone written as in a URI (`fe80::1%25wlan0`) becomes the plain `%wlan0` form
+fn normalize_host(host: &str) -> String {
+    let host = host.trim();
+    let host = match host.strip_prefix('[').and_then(|h| h.strip_suffix(']')) {
+        Some(inner) if inner.contains(':') => inner,
+        _ => host,
+    };
+    match split_zone(host) {
+        Some((addr, zone)) if zone.starts_with("25") && zone.len() > 2 => {
+            format!("{}%{}", addr, &zone[2..])
+        }
+        _ => host.to_string(),
+    }
+}
+
+/// Split a scoped IPv6 literal such as `fe80::1%wlan0` into the address and its zone,
+/// which names the interface on this machine that reaches a link-local address
+/// Returns None for anything else, including hostnames
+pub fn split_zone(host: &str) -> Option<(Ipv6Addr, &str)> {
+    let (addr, zone) = host.split_once('%')?;
+    Some((addr.parse().ok()?, zone))
+}
+
+/// Catch addresses that can't work as a host, like a port written into it
+fn check_host(field: &str, host: &str) -> Result<()> {
+    if host.contains(char::is_whitespace) || host.contains('/') {
+        return Err(SshMcpError::Config(format!(
+            "{} '{}' is not a valid IP address or hostname",
+            field, host
+        )));
+    }
+    // Exactly one colon is host:port; IPv6 literals have at least two
+    if let Some((name, port)) = host.split_once(':')
+        && !port.contains(':')
+    {
+        let hint = if port.parse::<u16>().is_ok() {
+            format!(
+                " Put the port in 'port' instead: host = \"{}\", port = {}",
+                name, port
+            )
+        } else {
+            String::new()
+        };
+        return Err(SshMcpError::Config(format!(
+            "{} '{}' is not a valid IP address or hostname.{}",
+            field, host, hint
+        )));
+    }
+    if host.contains('%') {
+        let valid_zone = split_zone(host).is_some_and(|(_, zone)| {
+            !zone.is_empty()
+                && zone
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-".contains(c))
+        });
+        if !valid_zone {
+            return Err(SshMcpError::Config(format!(
+                "{} '{}' is not a valid scoped IPv6 address. Write it as fe80::1%wlan0: \
+                 the address, %, then the network interface on this machine that reaches the device",
+                field, host
+            )));
+        }
+    }
+    if host.contains(':') && host.contains(['[', ']']) {
+        return Err(SshMcpError::Config(format!(
+            "{} '{}' is not a valid IPv6 address. Write it as fe80::1 or [fe80::1], \
+             and put the port in 'port'",
+            field, host
+        )));
+    }
+    Ok(())
+}
+
+/// Describe what lets other users read or replace a private key, if anything:
+/// group or world access to the file, or a group or world writable directory holding it
+#[cfg(unix)]
+fn key_permission_problem(key_path: &Path) -> Option<String> {
+    use std::os::unix::fs::PermissionsExt;
+    let mode = std::fs::metadata(key_path).ok()?.permissions().mode() & 0o777;
+    if mode & 0o077 != 0 {
+        return Some(format!(
+            "SSH key file {} has permissions {:o}, so other users can read it. \
+             Fix: chmod 600 {}",
+            key_path.display(),
+            mode,
+            key_path.display()
+        ));
+    }
+    let dir = key_path.parent()?;
+    let dir_mode = std::fs::metadata(dir).ok()?.permissions().mode() & 0o777;
+    if dir_mode & 0o022 != 0 {
+        return Some(format!(
+            "Directory {} holding the SSH key has permissions {:o}, so other users can \
+             replace the key. Fix: chmod go-w {}",
+            dir.display(),
+            dir_mode,
+            dir.display()
+        ));
+    }
+    None
+}
+
+#[cfg(not(unix))]
+fn key_permission_problem(_key_path: &Path) -> Option<String> {
+    None
 }
 
 fn default_port() -> u16 {
     8022
 }
 
+fn default_jump_port() -> u16 {
+    22
+}
+
+const DEFAULT_MAX_COMMAND_LENGTH: usize = 128 * 1024;
+const DEFAULT_MAX_OUTPUT_BYTES: usize = 64 * 1024;
+const DEFAULT_TOOL_TIMEOUT_SECS: u64 = 30;
+const DEFAULT_KEEPALIVE_INTERVAL_SECS: u64 = 15;
+const DEFAULT_CONNECT_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_CONNECT_RETRIES: u32 = 3;
+const DEFAULT_CONNECT_RETRY_DELAY_SECS: u64 = 2;
+const DEFAULT_STREAM_FLUSH_INTERVAL_MS: u64 = 500;
+const DEFAULT_ADAPTIVE_TIMEOUT_MAX_SECS: u64 = 900;
+
+/// Config fields in display order, paired with their environment override
+const FIELDS: &[(&str, Option<&str>)] = &[
+    ("host", Some("ANDROID_SSH_HOST")),
+    ("port", Some("ANDROID_SSH_PORT")),
+    ("user", Some("ANDROID_SSH_USER")),
+    ("password", Some("ANDROID_SSH_PASSWORD")),
+    ("password_command", None),
+    ("password_file", None),
+    ("key_path", Some("ANDROID_SSH_KEY_PATH")),
+    ("key_passphrase", Some("ANDROID_SSH_KEY_PASSPHRASE")),
+    ("use_agent", Some("ANDROID_SSH_USE_AGENT")),
+    ("max_command_length", None),
+    ("max_output_bytes", None),
+    ("default_read_timeout_secs", None),
+    ("default_exec_timeout_secs", None),
+    ("keepalive_interval_secs", None),
+    ("connect_timeout_secs", None),
+    ("connect_retries", None),
+    ("connect_retry_delay_secs", None),
+    ("retry_backoff", None),
+    ("stream_flush_interval_ms", None),
+    ("adaptive_timeout", None),
+    ("adaptive_timeout_max_secs", None),
+    ("auto_retry_read_commands", None),
+    ("acquire_wake_lock_on_connect", None),
+    ("force_locale", None),
+    ("remote_shell", None),
+    ("max_commands_per_minute", None),
+    ("known_hosts_path", None),
+    ("strict_host_key_checking", None),
+    ("resolve_host", None),
+    ("refuse_insecure_key_perms", None),
+    ("persist_history", None),
+    ("history_path", None),
+    ("history_skip_blocked", None),
+    ("allow_pipes_in_read", None),
+    ("disable_execute", None),
+    ("probe_commands", None),
+    ("preferred_ciphers", None),
+    ("preferred_kex", None),
+    ("preferred_key_algorithms", None),
+    ("default_profile", Some("ANDROID_SSH_PROFILE")),
+    ("structured_output", None),
+    ("fail_on_stderr", None),
+];
+
+/// Where an effective configuration value came from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigSource {
+    File,
+    Env(&'static str),
+    Default,
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::File => write!(f, "file"),
+            ConfigSource::Env(var) => write!(f, "env: {}", var),
+            ConfigSource::Default => write!(f, "default"),
+        }
+    }
+}
+
+/// A secret from the config (a password or key passphrase), shown as `***` by Debug and
+/// Display so it can't end up in logs or error messages by accident
+#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, schemars::JsonSchema)]
+#[serde(transparent)]
+pub struct Redacted(String);
+
+impl Redacted {
+    /// The secret itself, for authenticating or masking it in command text
+    pub fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl From<String> for Redacted {
+    fn from(secret: String) -> Self {
+        Self(secret)
+    }
+}
+
+impl std::fmt::Debug for Redacted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("***")
+    }
+}
+
+impl std::fmt::Display for Redacted {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("***")
+    }
+}
+
 impl Config {
-    /// Get the config directory path (~/.config/mcp-android-ssh)
+    /// Get the config directory path (~/.config/mcp-android-ssh), or the directory
+    /// holding the file named by MCP_ANDROID_SSH_CONFIG
+    /// known_hosts and the history file default to this directory too
     pub fn config_dir() -> Result<PathBuf> {
+        if let Some(path) = Self::config_path_override()? {
+            return Ok(path
+                .parent()
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|| PathBuf::from("/")));
+        }
         dirs::config_dir()
             .map(|p| p.join(CONFIG_DIR_NAME))
             .ok_or_else(|| SshMcpError::Config("Cannot determine config directory".to_string()))
     }
 
-    /// Get the config file path (~/.config/mcp-android-ssh/config.toml)
+    /// Get the config file path (~/.config/mcp-android-ssh/config.toml, or MCP_ANDROID_SSH_CONFIG)
     pub fn config_file_path() -> Result<PathBuf> {
-        Ok(Self::config_dir()?.join(CONFIG_FILE_NAME))
+        match Self::config_path_override()? {
+            Some(path) => Ok(path),
+            None => Ok(Self::config_dir()?.join(CONFIG_FILE_NAME)),
+        }
+    }
+
+    /// The config file path from MCP_ANDROID_SSH_CONFIG, with tilde expanded and
+    /// made absolute so its directory is well defined
+    fn config_path_override() -> Result<Option<PathBuf>> {
+        let Some(value) = std::env::var_os(CONFIG_PATH_ENV).filter(|v| !v.is_empty()) else {
+            return Ok(None);
+        };
+        let value = value.to_str().ok_or_else(|| {
+            SshMcpError::Config(format!("{} is not valid UTF-8", CONFIG_PATH_ENV))
+        })?;
+        let path = PathBuf::from(shellexpand::tilde(value).as_ref());
+        std::path::absolute(&path).map(Some).map_err(|e| {
+            SshMcpError::Config(format!("Invalid {} '{}': {}", CONFIG_PATH_ENV, value, e))
+        })
     }
 
     /// Create config directory and template if they don't exist
@@ -64,8 +642,8 @@ impl Config {
             host: "192.168.1.100".to_string(),
             port: 8022,
             user: "u0_a555".to_string(),
-            password: None,
             key_path: Some("~/.ssh/id_ed25519".to_string()),
+            ..Default::default()
         };
 
         format!(
@@ -77,6 +655,7 @@ impl Config {
              \n\
              # Authentication (choose one method)\n\
              # key_path = \"~/.ssh/id_ed25519\"  # Recommended: SSH key auth\n\
+             # key_passphrase = \"...\"          # Only for passphrase-protected keys\n\
              # password = \"your_password\"       # Alternative: password auth\n\
              \n\
              # Quick Setup:\n\
@@ -106,41 +685,229 @@ impl Config {
     /// Load configuration from file with environment variable overrides
     /// Returns Ok(None) if config doesn't exist yet (first run)
     pub fn load() -> Result<Option<Self>> {
-        let config_path = match Self::ensure_config_exists()? {
-            Some(path) => path,
-            None => return Ok(None), // Config template created, needs editing
-        };
+        if Self::ensure_config_exists()?.is_none() {
+            return Ok(None); // Config template created, needs editing
+        }
+
+        let (config, _) = Self::load_with_sources()?;
+
+        // Validate configuration, including every profile
+        config.validate()?;
+        for name in config.profiles.keys() {
+            config.for_profile(Some(name))?;
+        }
+        config.for_profile(None)?;
+
+        Ok(Some(config))
+    }
+
+    /// Load configuration from file with environment variable overrides,
+    /// recording where each field's value came from
+    /// Does not validate, so an incomplete config can still be inspected
+    pub fn load_with_sources() -> Result<(Self, Vec<(&'static str, ConfigSource)>)> {
+        let config_path = Self::config_file_path()?;
 
         // Read and parse TOML
         let content = std::fs::read_to_string(&config_path)
             .map_err(|e| SshMcpError::Config(format!("Failed to read config file: {}", e)))?;
 
-        let mut config: Config = toml::from_str(&content)
-            .map_err(|e| SshMcpError::Config(format!("Failed to parse config file: {}", e)))?;
+        let table: toml::Table = parse_toml(&content)?;
+        let mut config: Config = parse_toml(&content)?;
+
+        let mut sources: Vec<(&'static str, ConfigSource)> = FIELDS
+            .iter()
+            .map(|(field, _)| {
+                let source = if table.contains_key(*field) {
+                    ConfigSource::File
+                } else {
+                    ConfigSource::Default
+                };
+                (*field, source)
+            })
+            .collect();
 
         // Environment variables override config file
-        if let Ok(host) = std::env::var("ANDROID_SSH_HOST") {
-            config.host = host;
-        }
-        if let Ok(port) = std::env::var("ANDROID_SSH_PORT") {
-            config.port = port
-                .parse()
-                .map_err(|e| SshMcpError::Config(format!("Invalid ANDROID_SSH_PORT: {}", e)))?;
-        }
-        if let Ok(user) = std::env::var("ANDROID_SSH_USER") {
-            config.user = user;
-        }
-        if let Ok(password) = std::env::var("ANDROID_SSH_PASSWORD") {
-            config.password = Some(password);
-        }
-        if let Ok(key_path) = std::env::var("ANDROID_SSH_KEY_PATH") {
-            config.key_path = Some(key_path);
+        for ((field, var), (_, source)) in FIELDS.iter().zip(sources.iter_mut()) {
+            let Some(var) = var else {
+                continue;
+            };
+            let Ok(value) = std::env::var(var) else {
+                continue;
+            };
+
+            match *field {
+                "host" => config.host = value,
+                "port" => {
+                    config.port = value
+                        .parse()
+                        .map_err(|e| SshMcpError::Config(format!("Invalid {}: {}", var, e)))?
+                }
+                "user" => config.user = value,
+                "password" => {
+                    // A password from the environment replaces any configured helper
+                    config.password = Some(value.into());
+                    config.password_command = None;
+                    config.password_file = None;
+                }
+                "key_path" => config.key_path = Some(value),
+                "key_passphrase" => config.key_passphrase = Some(value.into()),
+                "default_profile" => config.default_profile = Some(value),
+                "use_agent" => {
+                    config.use_agent = value
+                        .parse()
+                        .map_err(|e| SshMcpError::Config(format!("Invalid {}: {}", var, e)))?
+                }
+                _ => continue,
+            }
+            *source = ConfigSource::Env(var);
         }
 
-        // Validate configuration
-        config.validate()?;
+        config.normalize_hosts();
+        Ok((config, sources))
+    }
 
-        Ok(Some(config))
+    /// Display values for each config field, with secrets masked
+    /// Returns None for fields that are unset
+    pub fn display_fields(&self) -> Vec<(&'static str, Option<String>)> {
+        vec![
+            ("host", Some(format!("\"{}\"", self.host))),
+            ("port", Some(self.port.to_string())),
+            ("user", Some(format!("\"{}\"", self.user))),
+            (
+                "password",
+                self.password.as_ref().map(|_| "\"***\"".to_string()),
+            ),
+            (
+                "password_command",
+                self.password_command.as_ref().map(|c| format!("\"{}\"", c)),
+            ),
+            (
+                "password_file",
+                self.password_file.as_ref().map(|f| format!("\"{}\"", f)),
+            ),
+            (
+                "key_path",
+                self.key_path.as_ref().map(|k| format!("\"{}\"", k)),
+            ),
+            (
+                "key_passphrase",
+                self.key_passphrase.as_ref().map(|_| "\"***\"".to_string()),
+            ),
+            ("use_agent", Some(self.use_agent.to_string())),
+            (
+                "max_command_length",
+                Some(self.max_command_length().to_string()),
+            ),
+            (
+                "max_output_bytes",
+                Some(self.max_output_bytes().to_string()),
+            ),
+            (
+                "default_read_timeout_secs",
+                Some(self.default_read_timeout().to_string()),
+            ),
+            (
+                "default_exec_timeout_secs",
+                Some(self.default_exec_timeout().to_string()),
+            ),
+            (
+                "keepalive_interval_secs",
+                Some(
+                    self.keepalive_interval()
+                        .map_or(0, |interval| interval.as_secs())
+                        .to_string(),
+                ),
+            ),
+            (
+                "connect_timeout_secs",
+                Some(self.connect_timeout().as_secs().to_string()),
+            ),
+            ("connect_retries", Some(self.connect_retries().to_string())),
+            (
+                "connect_retry_delay_secs",
+                Some(self.retry_delay(1).as_secs().to_string()),
+            ),
+            ("retry_backoff", Some(self.retry_backoff.to_string())),
+            (
+                "stream_flush_interval_ms",
+                Some((self.stream_flush_interval().as_millis() as u64).to_string()),
+            ),
+            ("adaptive_timeout", Some(self.adaptive_timeout.to_string())),
+            (
+                "adaptive_timeout_max_secs",
+                Some(self.adaptive_timeout_max().as_secs().to_string()),
+            ),
+            (
+                "auto_retry_read_commands",
+                Some(self.auto_retry_read_commands.to_string()),
+            ),
+            (
+                "acquire_wake_lock_on_connect",
+                Some(self.acquire_wake_lock_on_connect.to_string()),
+            ),
+            (
+                "force_locale",
+                self.force_locale.as_ref().map(|l| format!("\"{}\"", l)),
+            ),
+            (
+                "remote_shell",
+                self.remote_shell.as_ref().map(|s| format!("\"{}\"", s)),
+            ),
+            (
+                "max_commands_per_minute",
+                self.max_commands_per_minute.map(|n| n.to_string()),
+            ),
+            (
+                "known_hosts_path",
+                self.known_hosts_path()
+                    .ok()
+                    .map(|p| format!("\"{}\"", p.display())),
+            ),
+            (
+                "strict_host_key_checking",
+                Some(self.strict_host_key_checking.to_string()),
+            ),
+            ("resolve_host", Some(self.resolve_host.to_string())),
+            (
+                "refuse_insecure_key_perms",
+                Some(self.refuse_insecure_key_perms.to_string()),
+            ),
+            ("persist_history", Some(self.persist_history.to_string())),
+            (
+                "history_path",
+                self.history_path()
+                    .ok()
+                    .map(|p| format!("\"{}\"", p.display())),
+            ),
+            (
+                "history_skip_blocked",
+                Some(self.history_skip_blocked.to_string()),
+            ),
+            (
+                "allow_pipes_in_read",
+                Some(self.allow_pipes_in_read.to_string()),
+            ),
+            ("disable_execute", Some(self.disable_execute.to_string())),
+            ("probe_commands", Some(toml_list(&self.probe_commands))),
+            (
+                "preferred_ciphers",
+                Some(toml_list(&self.preferred_ciphers)),
+            ),
+            ("preferred_kex", Some(toml_list(&self.preferred_kex))),
+            (
+                "preferred_key_algorithms",
+                Some(toml_list(&self.preferred_key_algorithms)),
+            ),
+            (
+                "default_profile",
+                self.default_profile.as_ref().map(|p| format!("\"{}\"", p)),
+            ),
+            (
+                "structured_output",
+                Some(self.structured_output.to_string()),
+            ),
+            ("fail_on_stderr", Some(self.fail_on_stderr.to_string())),
+        ]
     }
 
     /// Generate a helpful first-run error message
@@ -168,15 +935,276 @@ impl Config {
         )
     }
 
+    /// Connection settings for a profile: the top-level settings with the profile's
+    /// fields laid over them. None selects `default_profile`, or the top-level
+    /// settings if there is no default
+    pub fn for_profile(&self, name: Option<&str>) -> Result<Config> {
+        let Some(name) = name.or(self.default_profile.as_deref()) else {
+            return Ok(self.clone());
+        };
+        let Some(profile) = self.profiles.get(name) else {
+            let mut names: Vec<&str> = self.profiles.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            let available = if names.is_empty() {
+                "no profiles are configured".to_string()
+            } else {
+                format!("available profiles: {}", names.join(", "))
+            };
+            return Err(SshMcpError::Config(format!(
+                "Unknown profile '{}' ({})",
+                name, available
+            )));
+        };
+
+        let mut config = self.clone();
+        let profile = profile.clone();
+        config.host = profile.host.unwrap_or(config.host);
+        config.port = profile.port.unwrap_or(config.port);
+        config.user = profile.user.unwrap_or(config.user);
+        // A profile's password source replaces the top-level one rather than conflicting with it
+        if profile.password.is_some()
+            || profile.password_command.is_some()
+            || profile.password_file.is_some()
+        {
+            config.password = profile.password;
+            config.password_command = profile.password_command;
+            config.password_file = profile.password_file;
+        }
+        config.key_path = profile.key_path.or(config.key_path);
+        config.key_passphrase = profile.key_passphrase.or(config.key_passphrase);
+        config.use_agent = profile.use_agent.unwrap_or(config.use_agent);
+        // Each profile verifies against its own known_hosts, so a key trusted for one device
+        // is never accepted for another, e.g. a different phone given the same IP address
+        config.known_hosts_path = Some(match profile.known_hosts_path {
+            Some(path) => path,
+            None => format!(
+                "{}.{}",
+                self.known_hosts_path()?.display(),
+                profile_file_suffix(name)
+            ),
+        });
+        config.strict_host_key_checking = profile
+            .strict_host_key_checking
+            .unwrap_or(config.strict_host_key_checking);
+        config.validate().map_err(|e| match e {
+            SshMcpError::Config(msg) => SshMcpError::Config(format!("Profile '{}': {}", name, msg)),
+            e => e,
+        })?;
+        Ok(config)
+    }
+
+    /// Strip whitespace pasted around host and user names, and the brackets
+    /// around IPv6 literals (`[fe80::1]`), which russh expects bare
+    fn normalize_hosts(&mut self) {
+        fn trim(value: &mut String) {
+            *value = value.trim().to_string();
+        }
+        fn host(value: &mut String) {
+            *value = normalize_host(value);
+        }
+        host(&mut self.host);
+        trim(&mut self.user);
+        if let Some(ref mut jump) = self.jump_host {
+            host(&mut jump.host);
+            trim(&mut jump.user);
+        }
+        for profile in self.profiles.values_mut() {
+            profile.host.iter_mut().for_each(host);
+            profile.user.iter_mut().for_each(trim);
+        }
+    }
+
     /// Validate the configuration
     fn validate(&self) -> Result<()> {
+        if self.host.is_empty() {
+            return Err(SshMcpError::Config(
+                "'host' is empty. Set it to the device's IP address (run 'ip -4 addr show wlan0' \
+                 in Termux, or use the discover tool)"
+                    .to_string(),
+            ));
+        }
+        check_host("host", &self.host)?;
+        for (name, profile) in &self.profiles {
+            if let Some(ref host) = profile.host {
+                check_host(&format!("[profiles.{}] 'host'", name), host)?;
+            }
+        }
+        if self.port == 0 {
+            return Err(SshMcpError::Config(
+                "'port' must be between 1 and 65535 (Termux sshd listens on 8022)".to_string(),
+            ));
+        }
+        if let Some(ref jump) = self.jump_host {
+            if jump.host.is_empty() {
+                return Err(SshMcpError::Config(
+                    "[jump_host] 'host' is empty".to_string(),
+                ));
+            }
+            if jump.port == 0 {
+                return Err(SshMcpError::Config(
+                    "[jump_host] 'port' must be between 1 and 65535".to_string(),
+                ));
+            }
+            check_host("[jump_host] 'host'", &jump.host)?;
+        }
+
+        // Only the first hop has to resolve here; the device may be named relative to the jump host
+        if self.resolve_host {
+            let (host, port) = match self.jump_host {
+                Some(ref jump) => (jump.host.as_str(), jump.port),
+                None => (self.host.as_str(), self.port),
+            };
+            if let Err(e) = (host, port).to_socket_addrs() {
+                return Err(SshMcpError::Config(format!(
+                    "Cannot resolve host '{}': {}. Check the name for typos, or use the \
+                     device's IP address instead.",
+                    host, e
+                )));
+            }
+        }
+
         // Must have at least one auth method
-        if self.password.is_none() && self.key_path.is_none() {
+        if !self.has_password() && self.key_path.is_none() && !self.use_agent {
+            return Err(SshMcpError::Config(
+                "Must provide 'password' or 'key_path', or set use_agent = true, for authentication"
+                    .to_string(),
+            ));
+        }
+
+        let password_sources = [
+            self.password.is_some(),
+            self.password_command.is_some(),
+            self.password_file.is_some(),
+        ];
+        if password_sources.into_iter().filter(|set| *set).count() > 1 {
+            return Err(SshMcpError::Config(
+                "Set only one of 'password', 'password_command' and 'password_file'".to_string(),
+            ));
+        }
+
+        if let Some(path) = self.expanded_password_file()
+            && !path.is_file()
+        {
+            return Err(SshMcpError::Config(format!(
+                "Password file not found: {}",
+                path.display()
+            )));
+        }
+
+        // The jump host authenticates separately, so it needs its own credentials
+        if let Some(ref jump) = self.jump_host
+            && jump.key_path.is_none()
+            && jump.password.is_none()
+        {
+            return Err(SshMcpError::Config(
+                "[jump_host] must provide either 'password' or 'key_path' for authentication"
+                    .to_string(),
+            ));
+        }
+
+        if let Some(ref blocklist) = self.blocklist {
+            blocklist.regexes()?;
+        }
+
+        if self.connect_timeout_secs == Some(0) {
+            return Err(SshMcpError::Config(
+                "connect_timeout_secs must be at least 1".to_string(),
+            ));
+        }
+
+        if self.connect_retries == Some(0) {
+            return Err(SshMcpError::Config(
+                "connect_retries must be at least 1".to_string(),
+            ));
+        }
+
+        if self.max_commands_per_minute == Some(0) {
+            return Err(SshMcpError::Config(
+                "max_commands_per_minute must be at least 1 (leave it unset for no limit)"
+                    .to_string(),
+            ));
+        }
+
+        for (name, value) in [
+            ("default_read_timeout_secs", self.default_read_timeout_secs),
+            ("default_exec_timeout_secs", self.default_exec_timeout_secs),
+        ] {
+            if let Some(secs) = value
+                && !(1..=300).contains(&secs)
+            {
+                return Err(SshMcpError::Config(format!(
+                    "{} must be between 1 and 300 seconds",
+                    name
+                )));
+            }
+        }
+
+        if let Some(name) = self.probe_commands.iter().find(|name| {
+            name.is_empty()
+                || !name
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "-_.+".contains(c))
+        }) {
+            return Err(SshMcpError::Config(format!(
+                "probe_commands entry '{}' is not a command name",
+                name
+            )));
+        }
+
+        self.preferred_algorithms()?;
+
+        if self.adaptive_timeout_max_secs == Some(0) {
+            return Err(SshMcpError::Config(
+                "adaptive_timeout_max_secs must be at least 1".to_string(),
+            ));
+        }
+
+        if let Some(ref locale) = self.force_locale
+            && (locale.is_empty()
+                || !locale
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-@".contains(c)))
+        {
+            return Err(SshMcpError::Config(format!(
+                "force_locale '{}' is not a locale name; use e.g. \"C\" or \"en_US.UTF-8\"",
+                locale
+            )));
+        }
+
+        if let Some(ref shell) = self.remote_shell
+            && (shell.is_empty()
+                || !shell
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "._-/+".contains(c)))
+        {
+            return Err(SshMcpError::Config(format!(
+                "remote_shell '{}' must be a shell name or path without arguments, e.g. \"sh\" or \
+                 \"/data/data/com.termux/files/usr/bin/bash\"",
+                shell
+            )));
+        }
+
+        if self.stream_flush_interval_ms == Some(0) {
             return Err(SshMcpError::Config(
-                "Must provide either 'password' or 'key_path' for authentication".to_string(),
+                "stream_flush_interval_ms must be at least 1".to_string(),
             ));
         }
 
+        // Custom instructions file must exist if configured
+        if let Some(ref path) = self
+            .instructions
+            .as_ref()
+            .and_then(|i| i.server_file.clone())
+        {
+            let expanded_path = PathBuf::from(shellexpand::tilde(path).to_string());
+            if !expanded_path.is_file() {
+                return Err(SshMcpError::Config(format!(
+                    "Instructions file not found: {}",
+                    expanded_path.display()
+                )));
+            }
+        }
+
         // If key_path is provided, expand tilde and validate
         if let Some(ref key_path) = self.key_path {
             let expanded_path = PathBuf::from(shellexpand::tilde(key_path).to_string());
@@ -188,25 +1216,246 @@ impl Config {
                 )));
             }
 
-            #[cfg(unix)]
-            {
-                use std::os::unix::fs::PermissionsExt;
-                if let Ok(metadata) = std::fs::metadata(&expanded_path) {
-                    let mode = metadata.permissions().mode();
-                    if mode & 0o777 != 0o600 {
-                        tracing::warn!(
-                            "SSH key file has permissions {:o}, recommended 600: {}",
-                            mode & 0o777,
-                            expanded_path.display()
-                        );
-                    }
-                }
+            if let Some(problem) = self.check_key_permissions(&expanded_path)? {
+                tracing::warn!("{}", problem);
             }
         }
 
         Ok(())
     }
 
+    /// Check that other users can't read or replace the key file, failing if
+    /// refuse_insecure_key_perms is set and returning the problem otherwise
+    pub fn check_key_permissions(&self, key_path: &Path) -> Result<Option<String>> {
+        let Some(problem) = key_permission_problem(key_path) else {
+            return Ok(None);
+        };
+        if self.refuse_insecure_key_perms {
+            return Err(SshMcpError::Config(format!(
+                "{}\n\nThe key is not used because refuse_insecure_key_perms is set.",
+                problem
+            )));
+        }
+        Ok(Some(problem))
+    }
+
+    /// Whether a password, password_command or password_file is configured
+    pub fn has_password(&self) -> bool {
+        self.password.is_some() || self.password_command.is_some() || self.password_file.is_some()
+    }
+
+    /// Get the password file path with tilde expanded
+    fn expanded_password_file(&self) -> Option<PathBuf> {
+        self.password_file
+            .as_ref()
+            .map(|p| PathBuf::from(shellexpand::tilde(p).to_string()))
+    }
+
+    /// Get the password, running password_command or reading password_file if needed
+    /// The result is never stored, so a rotated password is picked up on the next connection
+    pub fn resolve_password(&self) -> Result<Option<String>> {
+        if let Some(ref password) = self.password {
+            return Ok(Some(password.expose().to_string()));
+        }
+
+        let output = if let Some(ref command) = self.password_command {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .stdin(std::process::Stdio::null())
+                .output()
+                .map_err(|e| {
+                    SshMcpError::Authentication(format!("Failed to run password_command: {}", e))
+                })?;
+            if !output.status.success() {
+                return Err(SshMcpError::Authentication(format!(
+                    "password_command failed ({}): {}",
+                    output.status,
+                    String::from_utf8_lossy(&output.stderr).trim()
+                )));
+            }
+            String::from_utf8(output.stdout).map_err(|_| {
+                SshMcpError::Authentication("password_command output is not UTF-8".to_string())
+            })?
+        } else if let Some(path) = self.expanded_password_file() {
+            std::fs::read_to_string(&path).map_err(|e| {
+                SshMcpError::Authentication(format!(
+                    "Failed to read password_file {}: {}",
+                    path.display(),
+                    e
+                ))
+            })?
+        } else {
+            return Ok(None);
+        };
+
+        Ok(Some(output.trim_end_matches(['\r', '\n']).to_string()))
+    }
+
+    /// Get the maximum command length, falling back to the default
+    pub fn max_command_length(&self) -> usize {
+        self.max_command_length
+            .unwrap_or(DEFAULT_MAX_COMMAND_LENGTH)
+    }
+
+    /// Get the output cap for execute/execute_read, falling back to the default
+    pub fn max_output_bytes(&self) -> usize {
+        self.max_output_bytes.unwrap_or(DEFAULT_MAX_OUTPUT_BYTES)
+    }
+
+    /// Get execute_read's timeout for calls that don't pass one, falling back to the default
+    pub fn default_read_timeout(&self) -> u64 {
+        self.default_read_timeout_secs
+            .unwrap_or(DEFAULT_TOOL_TIMEOUT_SECS)
+    }
+
+    /// Get execute's timeout for calls that don't pass one, falling back to the default
+    pub fn default_exec_timeout(&self) -> u64 {
+        self.default_exec_timeout_secs
+            .unwrap_or(DEFAULT_TOOL_TIMEOUT_SECS)
+    }
+
+    /// Get the keepalive interval, falling back to the default
+    /// Returns None when keepalives are disabled
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        match self
+            .keepalive_interval_secs
+            .unwrap_or(DEFAULT_KEEPALIVE_INTERVAL_SECS)
+        {
+            0 => None,
+            secs => Some(Duration::from_secs(secs)),
+        }
+    }
+
+    /// Build russh's algorithm preferences, replacing each list that is configured
+    /// Fails on a name russh doesn't implement, suggesting the closest one
+    pub fn preferred_algorithms(&self) -> Result<russh::Preferred> {
+        use russh::{cipher, kex, keys::key::ALL_KEY_TYPES};
+
+        let mut preferred = russh::Preferred::default();
+        if !self.preferred_ciphers.is_empty() {
+            // "none" and "clear" would send everything unencrypted
+            let supported: Vec<_> = cipher::ALL_CIPHERS
+                .iter()
+                .filter(|name| **name != &cipher::NONE && **name != &cipher::CLEAR)
+                .map(|name| (name.as_ref(), **name))
+                .collect();
+            preferred.cipher =
+                parse_algorithms("preferred_ciphers", &self.preferred_ciphers, &supported)?.into();
+        }
+        if !self.preferred_kex.is_empty() {
+            let supported: Vec<_> = kex::ALL_KEX_ALGORITHMS
+                .iter()
+                .filter(|name| **name != &kex::NONE)
+                .map(|name| (name.as_ref(), **name))
+                .collect();
+            let mut names = parse_algorithms("preferred_kex", &self.preferred_kex, &supported)?;
+            // Not key exchanges, but how the client advertises extension and strict-kex support
+            names.extend([
+                kex::EXTENSION_SUPPORT_AS_CLIENT,
+                kex::EXTENSION_OPENSSH_STRICT_KEX_AS_CLIENT,
+            ]);
+            preferred.kex = names.into();
+        }
+        if !self.preferred_key_algorithms.is_empty() {
+            let supported: Vec<_> = ALL_KEY_TYPES
+                .iter()
+                .map(|algorithm| (algorithm.as_str(), algorithm.clone()))
+                .collect();
+            preferred.key = parse_algorithms(
+                "preferred_key_algorithms",
+                &self.preferred_key_algorithms,
+                &supported,
+            )?
+            .into();
+        }
+        Ok(preferred)
+    }
+
+    /// Get the per-attempt connection timeout, falling back to the default
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(
+            self.connect_timeout_secs
+                .unwrap_or(DEFAULT_CONNECT_TIMEOUT_SECS),
+        )
+    }
+
+    /// Get the number of connection attempts, falling back to the default
+    pub fn connect_retries(&self) -> u32 {
+        self.connect_retries.unwrap_or(DEFAULT_CONNECT_RETRIES)
+    }
+
+    /// Get the delay after failed connection attempt `attempt` (counting from 1)
+    /// With retry_backoff the delay doubles each attempt
+    pub fn retry_delay(&self, attempt: u32) -> Duration {
+        let delay = self
+            .connect_retry_delay_secs
+            .unwrap_or(DEFAULT_CONNECT_RETRY_DELAY_SECS);
+        let factor = if self.retry_backoff {
+            1u64.checked_shl(attempt.saturating_sub(1))
+                .unwrap_or(u64::MAX)
+        } else {
+            1
+        };
+        Duration::from_secs(delay.saturating_mul(factor))
+    }
+
+    /// Get the stream tool's flush interval, falling back to the default
+    pub fn stream_flush_interval(&self) -> Duration {
+        Duration::from_millis(
+            self.stream_flush_interval_ms
+                .unwrap_or(DEFAULT_STREAM_FLUSH_INTERVAL_MS),
+        )
+    }
+
+    /// Get the hard limit on a command's run time under adaptive_timeout
+    pub fn adaptive_timeout_max(&self) -> Duration {
+        Duration::from_secs(
+            self.adaptive_timeout_max_secs
+                .unwrap_or(DEFAULT_ADAPTIVE_TIMEOUT_MAX_SECS),
+        )
+    }
+
+    /// Get the custom server instructions, if configured
+    /// Inline `server` text takes precedence over `server_file`
+    pub fn server_instructions(&self) -> Result<Option<String>> {
+        let Some(ref instructions) = self.instructions else {
+            return Ok(None);
+        };
+        if let Some(ref text) = instructions.server {
+            return Ok(Some(text.clone()));
+        }
+        let Some(ref path) = instructions.server_file else {
+            return Ok(None);
+        };
+        let expanded_path = PathBuf::from(shellexpand::tilde(path).to_string());
+        std::fs::read_to_string(&expanded_path)
+            .map(Some)
+            .map_err(|e| {
+                SshMcpError::Config(format!(
+                    "Failed to read instructions file {}: {}",
+                    expanded_path.display(),
+                    e
+                ))
+            })
+    }
+
+    /// Get the known_hosts path (with ~ replaced), falling back to the default
+    pub fn known_hosts_path(&self) -> Result<PathBuf> {
+        match self.known_hosts_path {
+            Some(ref path) => Ok(PathBuf::from(shellexpand::tilde(path).to_string())),
+            None => Ok(Self::config_dir()?.join(KNOWN_HOSTS_FILE_NAME)),
+        }
+    }
+
+    /// Get the persisted history path, defaulting to the config directory
+    pub fn history_path(&self) -> Result<PathBuf> {
+        match self.history_path {
+            Some(ref path) => Ok(PathBuf::from(shellexpand::tilde(path).to_string())),
+            None => Ok(Self::config_dir()?.join(HISTORY_FILE_NAME)),
+        }
+    }
+
     /// Get the expanded key path (with ~ replaced)
     pub fn expanded_key_path(&self) -> Option<PathBuf> {
         self.key_path
@@ -228,8 +1477,7 @@ impl Config {
         let content = std::fs::read_to_string(&config_path)
             .map_err(|e| SshMcpError::Config(format!("Failed to read config file: {}", e)))?;
 
-        let config: Config = toml::from_str(&content)
-            .map_err(|e| SshMcpError::Config(format!("Failed to parse config file: {}", e)))?;
+        let config: Config = parse_toml(&content)?;
 
         Ok(config)
     }
@@ -263,3 +1511,647 @@ impl Config {
         Ok(config_path)
     }
 }
+
+/// Parse config.toml, explaining errors by line and key instead of passing toml's message through
+fn parse_toml<T: DeserializeOwned>(content: &str) -> Result<T> {
+    toml::from_str(content).map_err(|e| {
+        SshMcpError::Config(format!(
+            "Failed to parse config file: {}",
+            describe_toml_error(content, &e)
+        ))
+    })
+}
+
+/// Describe a toml error as "line N: ...", naming the key it is about, with a
+/// suggestion for a misspelled key or a quoted number or boolean
+fn describe_toml_error(content: &str, error: &toml::de::Error) -> String {
+    let message = error.message().trim_end();
+    let Some(span) = error.span() else {
+        return message.to_string();
+    };
+    let start = span.start.min(content.len());
+    let line_number = content[..start].matches('\n').count() + 1;
+    let line_start = content[..start].rfind('\n').map_or(0, |i| i + 1);
+    let line_end = content[start..]
+        .find('\n')
+        .map_or(content.len(), |i| start + i);
+    let line = &content[line_start..line_end];
+    // Name the section for keys below a [table] header
+    let location = match content[..line_start]
+        .lines()
+        .map(str::trim)
+        .rfind(|l| l.starts_with('['))
+    {
+        Some(section) if !line.trim_start().starts_with('[') => {
+            format!("line {} in {}", line_number, section)
+        }
+        _ if line.trim_start().starts_with('[') => {
+            format!("line {} ({})", line_number, line.trim())
+        }
+        _ => format!("line {}", line_number),
+    };
+
+    // "unknown field `kye_path`, expected one of `host`, `port`, ..."
+    if let Some(rest) = message.strip_prefix("unknown field `")
+        && let Some((unknown, expected)) = rest.split_once('`')
+    {
+        let expected: Vec<&str> = expected.split('`').skip(1).step_by(2).collect();
+        let mut msg = format!("{}: unknown key `{}`", location, unknown);
+        match closest_key(unknown, &expected) {
+            Some(suggestion) => msg.push_str(&format!(". Did you mean `{}`?", suggestion)),
+            None if !expected.is_empty() => {
+                msg.push_str(&format!(". Valid keys here: {}", expected.join(", ")))
+            }
+            None => {}
+        }
+        return msg;
+    }
+
+    let key = line
+        .split_once('=')
+        .map(|(key, _)| key.trim().trim_matches('"'))
+        .filter(|key| !key.is_empty() && !key.starts_with('['));
+    let mut msg = match key {
+        Some(key) => format!("{}, key `{}`: {}", location, key, message),
+        None => format!("{}: {}", location, message),
+    };
+    if message.starts_with("invalid type: string") {
+        if message.contains("expected u") || message.contains("expected i") {
+            msg.push_str(". Write numbers without quotes, e.g. port = 8022");
+        } else if message.contains("expected a boolean") {
+            msg.push_str(". Write true or false without quotes");
+        }
+    }
+    msg
+}
+
+/// Look up each configured algorithm name in `supported`, keeping the configured order
+fn parse_algorithms<T: Clone>(
+    setting: &str,
+    names: &[String],
+    supported: &[(&str, T)],
+) -> Result<Vec<T>> {
+    names
+        .iter()
+        .map(|name| {
+            if let Some((_, algorithm)) = supported.iter().find(|(known, _)| known == name) {
+                return Ok(algorithm.clone());
+            }
+            let known: Vec<&str> = supported.iter().map(|(known, _)| *known).collect();
+            // OpenSSH's own algorithms carry a suffix, e.g. aes256-gcm@openssh.com
+            let suffixed = known.iter().copied().find(|known| {
+                known
+                    .strip_prefix(name.as_str())
+                    .is_some_and(|rest| rest.starts_with('@'))
+            });
+            let hint = match suffixed.or_else(|| closest_key(name, &known)) {
+                Some(suggestion) => format!("Did you mean '{}'?", suggestion),
+                None => format!("Supported: {}", known.join(", ")),
+            };
+            Err(SshMcpError::Config(format!(
+                "{} entry '{}' is not an algorithm this client supports. {}",
+                setting, name, hint
+            )))
+        })
+        .collect()
+}
+
+/// Format a list of strings as a TOML array
+fn toml_list(items: &[String]) -> String {
+    format!(
+        "[{}]",
+        items
+            .iter()
+            .map(|item| format!("\"{}\"", item))
+            .collect::<Vec<_>>()
+            .join(", ")
+    )
+}
+
+/// The expected key closest to a misspelled one, if it is close enough to be a typo
+fn closest_key<'a>(unknown: &str, expected: &[&'a str]) -> Option<&'a str> {
+    let unknown = unknown.to_lowercase();
+    expected
+        .iter()
+        .map(|key| (edit_distance(&unknown, key), *key))
+        .filter(|(distance, key)| *distance <= (key.len() / 3).max(1))
+        .min_by_key(|(distance, _)| *distance)
+        .map(|(_, key)| key)
+}
+
+/// Edit distance between two strings, where swapping adjacent characters counts as one edit
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.chars().collect();
+    let b: Vec<char> = b.chars().collect();
+    // rows[i][j] is the distance between the first i characters of a and the first j of b
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    rows[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn with_profiles(default_profile: Option<&str>) -> Config {
+        let mut config: Config = toml::from_str(
+            r#"
+            host = "192.168.1.10"
+            user = "phone-user"
+            password = "hunter2"
+            known_hosts_path = "/tmp/known_hosts"
+
+            [profiles.tablet]
+            host = "192.168.1.20"
+            port = 2222
+
+            [profiles.watch]
+            host = "192.168.1.30"
+            user = "watch-user"
+            "#,
+        )
+        .unwrap();
+        config.default_profile = default_profile.map(str::to_string);
+        config
+    }
+
+    #[test]
+    fn profile_fields_are_laid_over_the_top_level_settings() {
+        let tablet = with_profiles(None).for_profile(Some("tablet")).unwrap();
+        assert_eq!(tablet.host, "192.168.1.20");
+        assert_eq!(tablet.port, 2222);
+        assert_eq!(tablet.user, "phone-user");
+        assert_eq!(
+            tablet.known_hosts_path.as_deref(),
+            Some("/tmp/known_hosts.tablet")
+        );
+
+        let top_level = with_profiles(None).for_profile(None).unwrap();
+        assert_eq!(top_level.host, "192.168.1.10");
+        assert_eq!(top_level.port, default_port());
+    }
+
+    #[test]
+    fn no_profile_falls_back_to_default_profile() {
+        let config = with_profiles(Some("watch"));
+        let watch = config.for_profile(None).unwrap();
+        assert_eq!(watch.host, "192.168.1.30");
+        assert_eq!(watch.user, "watch-user");
+        // Naming a profile still wins over the default
+        assert_eq!(config.for_profile(Some("tablet")).unwrap().port, 2222);
+    }
+
+    #[test]
+    fn unknown_profile_lists_the_available_names() {
+        let error = with_profiles(None).for_profile(Some("laptop")).unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Configuration error: Unknown profile 'laptop' (available profiles: tablet, watch)"
+        );
+        let error = with_profiles(Some("laptop")).for_profile(None).unwrap_err();
+        assert!(error.to_string().contains("Unknown profile 'laptop'"));
+    }
+
+    #[test]
+    fn retry_delays_follow_the_config() {
+        let delays = |config: &Config| -> Vec<u64> {
+            (1..=4).map(|n| config.retry_delay(n).as_secs()).collect()
+        };
+        let mut config = Config::default();
+        assert_eq!(config.connect_retries(), 3);
+        assert_eq!(delays(&config), [2, 2, 2, 2]);
+
+        config.connect_retry_delay_secs = Some(5);
+        assert_eq!(delays(&config), [5, 5, 5, 5]);
+        config.retry_backoff = true;
+        assert_eq!(delays(&config), [5, 10, 20, 40]);
+        // Huge attempt counts saturate instead of overflowing
+        assert_eq!(config.retry_delay(200), Duration::from_secs(u64::MAX));
+    }
+
+    /// A config as load leaves it: normalized, then validated
+    fn loaded(host: &str, port: u16) -> Result<Config> {
+        let mut config = Config {
+            host: host.to_string(),
+            port,
+            user: " u0_a123 ".to_string(),
+            password: Some(Redacted::from("secret".to_string())),
+            ..Default::default()
+        };
+        config.normalize_hosts();
+        config.validate().map(|()| config)
+    }
+
+    #[test]
+    fn empty_or_blank_host_is_rejected() {
+        for host in ["", "   "] {
+            let error = loaded(host, 8022).unwrap_err().to_string();
+            assert!(error.contains("'host' is empty"), "{host:?}: {error}");
+        }
+    }
+
+    #[test]
+    fn port_zero_is_rejected() {
+        let error = loaded("192.168.1.5", 0).unwrap_err().to_string();
+        assert!(
+            error.contains("'port' must be between 1 and 65535"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn pasted_whitespace_is_trimmed_from_host_and_user() {
+        let config = loaded("192.168.1.5  ", 8022).unwrap();
+        assert_eq!(config.host, "192.168.1.5");
+        assert_eq!(config.user, "u0_a123");
+    }
+
+    #[test]
+    fn password_command_output_loses_its_trailing_newline() {
+        let config = Config {
+            password_command: Some("printf 'secret\\n'".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.resolve_password().unwrap().as_deref(),
+            Some("secret")
+        );
+
+        let failing = Config {
+            password_command: Some("echo locked >&2; exit 1".to_string()),
+            ..Default::default()
+        };
+        let error = failing.resolve_password().unwrap_err().to_string();
+        assert!(error.contains("password_command failed"), "{error}");
+        assert!(error.contains("locked"), "{error}");
+    }
+
+    #[test]
+    fn password_file_is_read_without_its_newline() {
+        let dir = crate::test_support::TempDir::new();
+        let path = dir.join("password");
+        std::fs::write(&path, "from-file\r\n").unwrap();
+        let config = Config {
+            password_file: Some(path.display().to_string()),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.resolve_password().unwrap().as_deref(),
+            Some("from-file")
+        );
+    }
+
+    #[test]
+    fn only_one_password_source_may_be_set() {
+        let config = Config {
+            host: "192.168.1.5".to_string(),
+            user: "u0_a123".to_string(),
+            password: Some(Redacted::from("secret".to_string())),
+            password_command: Some("pass show phone".to_string()),
+            ..Default::default()
+        };
+        let error = config.validate().unwrap_err().to_string();
+        assert!(error.contains("Set only one of"), "{error}");
+    }
+
+    #[test]
+    fn ipv6_literals_and_hostnames_are_accepted() {
+        for (host, normalized) in [
+            ("[fe80::1]", "fe80::1"),
+            ("fe80::1", "fe80::1"),
+            (" [2001:db8::5] ", "2001:db8::5"),
+            ("fe80::1%25wlan0", "fe80::1%wlan0"),
+            ("[fe80::1%wlan0]", "fe80::1%wlan0"),
+            ("pixel-7.local", "pixel-7.local"),
+            ("phone.example.com", "phone.example.com"),
+            ("192.168.1.5", "192.168.1.5"),
+        ] {
+            assert_eq!(loaded(host, 8022).unwrap().host, normalized, "{host:?}");
+        }
+    }
+
+    #[test]
+    fn hosts_with_a_port_or_stray_brackets_are_rejected() {
+        let error = loaded("192.168.1.5:8022", 8022).unwrap_err().to_string();
+        assert!(
+            error.contains("Put the port in 'port' instead: host = \"192.168.1.5\", port = 8022"),
+            "{error}"
+        );
+        let error = loaded("[fe80::1]:8022", 8022).unwrap_err().to_string();
+        assert!(error.contains("is not a valid IPv6 address"), "{error}");
+        let error = loaded("fe80::1%", 8022).unwrap_err().to_string();
+        assert!(
+            error.contains("is not a valid scoped IPv6 address"),
+            "{error}"
+        );
+    }
+
+    /// An existing key file in its own directory, with the given permissions on both
+    fn key_with_modes(dir: &crate::test_support::TempDir, key_mode: u32, dir_mode: u32) -> Config {
+        use std::os::unix::fs::PermissionsExt;
+        let ssh_dir = dir.join("ssh");
+        std::fs::create_dir(&ssh_dir).unwrap();
+        std::fs::write(ssh_dir.join("id_ed25519"), "not checked here").unwrap();
+        std::fs::set_permissions(
+            ssh_dir.join("id_ed25519"),
+            std::fs::Permissions::from_mode(key_mode),
+        )
+        .unwrap();
+        std::fs::set_permissions(&ssh_dir, std::fs::Permissions::from_mode(dir_mode)).unwrap();
+        Config {
+            key_path: Some(ssh_dir.join("id_ed25519").display().to_string()),
+            ..loaded("192.168.1.10", 8022).unwrap()
+        }
+    }
+
+    #[test]
+    fn readable_keys_are_warned_about_unless_refused() {
+        let dir = crate::test_support::TempDir::new();
+        let mut config = key_with_modes(&dir, 0o644, 0o700);
+        let key = config.expanded_key_path().unwrap();
+
+        let problem = config.check_key_permissions(&key).unwrap().unwrap();
+        assert!(problem.contains("has permissions 644"), "{problem}");
+        assert!(problem.contains("chmod 600"), "{problem}");
+        assert!(config.validate().is_ok());
+
+        config.refuse_insecure_key_perms = true;
+        let error = config.validate().unwrap_err();
+        assert!(matches!(error, SshMcpError::Config(_)));
+        assert!(
+            error
+                .to_string()
+                .contains("not used because refuse_insecure_key_perms is set"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn writable_key_directories_are_a_problem_too() {
+        let dir = crate::test_support::TempDir::new();
+        let config = key_with_modes(&dir, 0o600, 0o777);
+        let problem = config
+            .check_key_permissions(&config.expanded_key_path().unwrap())
+            .unwrap()
+            .unwrap();
+        assert!(problem.contains("has permissions 777"), "{problem}");
+        assert!(problem.contains("chmod go-w"), "{problem}");
+
+        let dir = crate::test_support::TempDir::new();
+        let config = Config {
+            refuse_insecure_key_perms: true,
+            ..key_with_modes(&dir, 0o600, 0o700)
+        };
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn default_timeouts_fall_back_to_30_and_stay_within_bounds() {
+        let config = loaded("192.168.1.10", 8022).unwrap();
+        assert_eq!(config.default_read_timeout(), 30);
+        assert_eq!(config.default_exec_timeout(), 30);
+
+        let config = Config {
+            default_read_timeout_secs: Some(10),
+            default_exec_timeout_secs: Some(300),
+            ..config
+        };
+        assert!(config.validate().is_ok());
+        assert_eq!(config.default_read_timeout(), 10);
+        assert_eq!(config.default_exec_timeout(), 300);
+
+        for (read, exec, name) in [
+            (Some(0), None, "default_read_timeout_secs"),
+            (None, Some(301), "default_exec_timeout_secs"),
+        ] {
+            let error = Config {
+                default_read_timeout_secs: read,
+                default_exec_timeout_secs: exec,
+                ..config.clone()
+            }
+            .validate()
+            .unwrap_err();
+            assert_eq!(
+                error.to_string(),
+                format!("Configuration error: {name} must be between 1 and 300 seconds")
+            );
+        }
+    }
+
+    fn parse_error(content: &str) -> String {
+        parse_toml::<Config>(content).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn misspelled_keys_are_named_with_a_suggestion() {
+        let error = parse_error("host = \"192.168.1.10\"\nkye_path = \"~/.ssh/id_ed25519\"\n");
+        assert_eq!(
+            error,
+            "Configuration error: Failed to parse config file: line 2: unknown key `kye_path`. \
+             Did you mean `key_path`?"
+        );
+
+        let error = parse_error("host = \"h\"\n\n[profiles.tablet]\nhots = \"h2\"\n");
+        assert!(
+            error.contains("line 4 in [profiles.tablet]: unknown key `hots`. Did you mean `host`?"),
+            "{error}"
+        );
+
+        let error = parse_error("host = \"h\"\nzzzzzzzz = 1\n");
+        assert!(
+            error.contains("unknown key `zzzzzzzz`. Valid keys here: host, port"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn wrong_types_name_the_key_and_how_to_write_it() {
+        let error = parse_error("host = \"h\"\nport = \"8022\"\n");
+        assert!(
+            error.contains("line 2, key `port`: invalid type: string"),
+            "{error}"
+        );
+        assert!(
+            error.ends_with("Write numbers without quotes, e.g. port = 8022"),
+            "{error}"
+        );
+
+        let error = parse_error("host = \"h\"\nuse_agent = \"yes\"\n");
+        assert!(error.contains("key `use_agent`"), "{error}");
+        assert!(
+            error.ends_with("Write true or false without quotes"),
+            "{error}"
+        );
+    }
+
+    #[tokio::test]
+    async fn config_path_env_redirects_every_read_and_write() {
+        let _env = crate::test_support::ENV_LOCK.lock().await;
+        let dir = crate::test_support::TempDir::new();
+        let path = dir.join("elsewhere/phone.toml");
+        unsafe { std::env::set_var(CONFIG_PATH_ENV, &path) };
+
+        assert_eq!(Config::config_file_path().unwrap(), path);
+        assert_eq!(Config::config_dir().unwrap(), dir.join("elsewhere"));
+
+        // First run writes the template there
+        assert!(Config::load().unwrap().is_none());
+        assert!(std::fs::read_to_string(&path).unwrap().contains("host"));
+
+        let saved = Config::save(&loaded("192.168.1.77", 8022).unwrap()).unwrap();
+        assert_eq!(saved, path);
+        assert_eq!(Config::load().unwrap().unwrap().host, "192.168.1.77");
+        assert_eq!(Config::load_existing().unwrap().host, "192.168.1.77");
+
+        unsafe { std::env::set_var(CONFIG_PATH_ENV, "relative.toml") };
+        assert_eq!(
+            Config::config_file_path().unwrap(),
+            std::env::current_dir().unwrap().join("relative.toml")
+        );
+        unsafe { std::env::remove_var(CONFIG_PATH_ENV) };
+    }
+
+    #[test]
+    fn debug_output_never_shows_a_secret() {
+        let config: Config = toml::from_str(
+            r#"
+            host = "192.168.1.10"
+            user = "phone-user"
+            password = "top-password"
+            key_passphrase = "top-passphrase"
+
+            [jump_host]
+            host = "bastion"
+            user = "jump"
+            password = "jump-password"
+
+            [profiles.tablet]
+            password = "tablet-password"
+            key_passphrase = "tablet-passphrase"
+            "#,
+        )
+        .unwrap();
+
+        let debug = format!("{config:?}");
+        for secret in [
+            "top-password",
+            "top-passphrase",
+            "jump-password",
+            "tablet-password",
+            "tablet-passphrase",
+        ] {
+            assert!(!debug.contains(secret), "{secret} in {debug}");
+        }
+        assert!(debug.contains("password: Some(***)"), "{debug}");
+        let password = config.password.unwrap();
+        assert_eq!(password.to_string(), "***");
+        assert_eq!(password.expose(), "top-password");
+    }
+
+    fn with_algorithms(ciphers: &[&str], kex: &[&str], keys: &[&str]) -> Config {
+        let list = |names: &[&str]| names.iter().map(|name| name.to_string()).collect();
+        Config {
+            preferred_ciphers: list(ciphers),
+            preferred_kex: list(kex),
+            preferred_key_algorithms: list(keys),
+            ..loaded("192.168.1.10", 8022).unwrap()
+        }
+    }
+
+    #[test]
+    fn configured_algorithms_replace_russhs_preferences_in_order() {
+        let preferred = with_algorithms(
+            &["aes128-ctr", "chacha20-poly1305@openssh.com"],
+            &["curve25519-sha256"],
+            &["ssh-ed25519", "rsa-sha2-256"],
+        )
+        .preferred_algorithms()
+        .unwrap();
+        let ciphers: Vec<&str> = preferred.cipher.iter().map(|name| name.as_ref()).collect();
+        assert_eq!(ciphers, ["aes128-ctr", "chacha20-poly1305@openssh.com"]);
+        let kex: Vec<&str> = preferred.kex.iter().map(|name| name.as_ref()).collect();
+        assert_eq!(kex[0], "curve25519-sha256");
+        assert!(
+            kex[1..]
+                .iter()
+                .all(|name| name.starts_with("ext-info-c") || name.starts_with("kex-strict-c")),
+            "{kex:?}"
+        );
+        let keys: Vec<&str> = preferred.key.iter().map(|key| key.as_str()).collect();
+        assert_eq!(keys, ["ssh-ed25519", "rsa-sha2-256"]);
+
+        // Lists left empty keep russh's defaults
+        let defaults = with_algorithms(&[], &[], &[])
+            .preferred_algorithms()
+            .unwrap();
+        assert_eq!(defaults.cipher, russh::Preferred::default().cipher);
+    }
+
+    #[test]
+    fn unknown_algorithms_are_rejected_with_a_suggestion() {
+        let error = with_algorithms(&["aes256-gcm"], &[], &[])
+            .validate()
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Configuration error: preferred_ciphers entry 'aes256-gcm' is not an algorithm this \
+             client supports. Did you mean 'aes256-gcm@openssh.com'?"
+        );
+        let error = with_algorithms(&[], &["curve25519-sha265"], &[])
+            .validate()
+            .unwrap_err();
+        assert!(
+            error
+                .to_string()
+                .contains("Did you mean 'curve25519-sha256'?"),
+            "{error}"
+        );
+        // Unencrypted transport is never allowed
+        let error = with_algorithms(&["none"], &[], &[]).validate().unwrap_err();
+        assert!(error.to_string().contains("entry 'none'"), "{error}");
+        let error = with_algorithms(&[], &[], &["ssh-dss-please"])
+            .validate()
+            .unwrap_err();
+        assert!(
+            error.to_string().contains("preferred_key_algorithms"),
+            "{error}"
+        );
+    }
+
+    #[test]
+    fn scoped_ipv6_hosts_split_into_address_and_zone() {
+        assert_eq!(
+            split_zone("fe80::1%wlan0"),
+            Some(("fe80::1".parse().unwrap(), "wlan0"))
+        );
+        assert_eq!(
+            split_zone("fe80::abcd:1234%usb-rndis.0"),
+            Some(("fe80::abcd:1234".parse().unwrap(), "usb-rndis.0"))
+        );
+        assert_eq!(split_zone("fe80::1"), None);
+        assert_eq!(split_zone("phone%wlan0"), None);
+        assert_eq!(split_zone("192.168.1.5"), None);
+
+        let config = loaded("[fe80::1%25wlan0]", 8022).unwrap();
+        assert_eq!(config.host, "fe80::1%wlan0");
+        let error = loaded("fe80::1%wlan 0", 8022).unwrap_err().to_string();
+        assert!(error.contains("is not a valid"), "{error}");
+        let error = loaded("fe80::1%wl/an0", 8022).unwrap_err().to_string();
+        assert!(error.contains("is not a valid"), "{error}");
+    }
+}
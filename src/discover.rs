@@ -0,0 +1,228 @@
+//! Local network scan for devices running an SSH server
+
+use crate::error::{Result, SshMcpError};
+use serde::Serialize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::AsyncReadExt;
+use tokio::net::TcpStream;
+use tokio::sync::Semaphore;
+use tokio::task::JoinSet;
+use tokio::time::timeout;
+
+/// Ports checked when none are given: Termux sshd, then the standard SSH port
+pub const DEFAULT_PORTS: &[u16] = &[8022, 22];
+
+/// Largest subnet that will be scanned (a /20)
+pub const MAX_HOSTS: u32 = 4096;
+
+/// A host that answered with an SSH banner
+#[derive(Debug, Clone, Serialize)]
+pub struct DiscoveredHost {
+    pub ip: String,
+    pub port: u16,
+    pub banner: String,
+}
+
+/// An IPv4 subnet in CIDR notation
+#[derive(Debug, Clone, Copy)]
+pub struct Subnet {
+    network: Ipv4Addr,
+    prefix: u8,
+}
+
+impl Subnet {
+    /// Parse `a.b.c.d/n`; host bits in the address are ignored
+    pub fn parse(cidr: &str) -> Result<Self> {
+        let invalid = || {
+            SshMcpError::Other(format!(
+                "Invalid subnet '{}': expected IPv4 CIDR like 192.168.1.0/24",
+                cidr
+            ))
+        };
+        let (addr, prefix) = cidr.trim().split_once('/').ok_or_else(invalid)?;
+        let addr: Ipv4Addr = addr.parse().map_err(|_| invalid())?;
+        let prefix: u8 = prefix.parse().map_err(|_| invalid())?;
+        if prefix > 32 {
+            return Err(invalid());
+        }
+        Ok(Self::new(addr, prefix))
+    }
+
+    fn new(addr: Ipv4Addr, prefix: u8) -> Self {
+        let mask = u32::MAX.checked_shl(32 - prefix as u32).unwrap_or(0);
+        Self {
+            network: Ipv4Addr::from(u32::from(addr) & mask),
+            prefix,
+        }
+    }
+
+    /// The /24 containing this machine's primary IPv4 address
+    pub fn local() -> Result<Self> {
+        // Connecting a UDP socket sends nothing; it only selects the outgoing interface
+        let local_ip = UdpSocket::bind("0.0.0.0:0")
+            .and_then(|socket| {
+                socket.connect("192.0.2.1:9")?;
+                socket.local_addr()
+            })
+            .map(|addr| addr.ip())
+            .map_err(|e| {
+                SshMcpError::Other(format!(
+                    "Could not determine the local network ({}). Pass a subnet like 192.168.1.0/24.",
+                    e
+                ))
+            })?;
+        match local_ip {
+            IpAddr::V4(ip) if !ip.is_unspecified() && !ip.is_loopback() => Ok(Self::new(ip, 24)),
+            ip => Err(SshMcpError::Other(format!(
+                "Local address {} is not on an IPv4 LAN. Pass a subnet like 192.168.1.0/24.",
+                ip
+            ))),
+        }
+    }
+
+    /// Number of addresses in the subnet, including network and broadcast
+    pub fn size(&self) -> u64 {
+        1u64 << (32 - self.prefix as u32)
+    }
+
+    /// Host addresses, skipping the network and broadcast addresses where they exist
+    pub fn hosts(&self) -> impl Iterator<Item = Ipv4Addr> {
+        let start = u32::from(self.network) as u64;
+        let (first, last) = if self.prefix >= 31 {
+            (start, start + self.size() - 1)
+        } else {
+            (start + 1, start + self.size() - 2)
+        };
+        (first..=last).map(|ip| Ipv4Addr::from(ip as u32))
+    }
+}
+
+impl std::fmt::Display for Subnet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}/{}", self.network, self.prefix)
+    }
+}
+
+/// Probe every host and port, returning those that present an SSH banner
+/// At most `concurrency` connections are open at once; each probe gets `per_host`
+/// to connect and send its banner
+pub async fn scan(
+    subnet: Subnet,
+    ports: &[u16],
+    per_host: Duration,
+    concurrency: usize,
+) -> Vec<DiscoveredHost> {
+    let permits = Arc::new(Semaphore::new(concurrency.max(1)));
+    let mut probes = JoinSet::new();
+
+    for ip in subnet.hosts() {
+        for &port in ports {
+            let permits = permits.clone();
+            probes.spawn(async move {
+                let _permit = permits.acquire_owned().await.ok()?;
+                let banner = probe(SocketAddr::new(IpAddr::V4(ip), port), per_host).await?;
+                Some(DiscoveredHost {
+                    ip: ip.to_string(),
+                    port,
+                    banner,
+                })
+            });
+        }
+    }
+
+    let mut found = Vec::new();
+    while let Some(result) = probes.join_next().await {
+        if let Ok(Some(host)) = result {
+            found.push(host);
+        }
+    }
+    found.sort_by_key(|h| (h.ip.parse::<Ipv4Addr>().ok(), h.port));
+    found
+}
+
+/// Connect and read the server's identification line, if it is an SSH server
+async fn probe(addr: SocketAddr, per_host: Duration) -> Option<String> {
+    timeout(per_host, async {
+        let mut stream = TcpStream::connect(addr).await.ok()?;
+        let mut buf = [0u8; 256];
+        let n = stream.read(&mut buf).await.ok()?;
+        let line = String::from_utf8_lossy(&buf[..n]);
+        let banner = line.lines().next()?.trim();
+        banner.starts_with("SSH-").then(|| banner.to_string())
+    })
+    .await
+    .ok()
+    .flatten()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::io::AsyncWriteExt;
+    use tokio::net::TcpListener;
+
+    /// A listener on loopback that greets every connection with `greeting`, or stays silent
+    async fn mock_server(greeting: Option<&'static [u8]>) -> u16 {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    if let Some(greeting) = greeting {
+                        let _ = stream.write_all(greeting).await;
+                    }
+                    tokio::time::sleep(Duration::from_secs(5)).await;
+                });
+            }
+        });
+        port
+    }
+
+    #[test]
+    fn subnet_parses_cidr_and_lists_hosts() {
+        let subnet = Subnet::parse("192.168.1.77/24").unwrap();
+        assert_eq!(subnet.to_string(), "192.168.1.0/24");
+        assert_eq!(subnet.size(), 256);
+        let hosts: Vec<_> = subnet.hosts().collect();
+        assert_eq!(hosts.len(), 254);
+        assert_eq!(hosts[0], Ipv4Addr::new(192, 168, 1, 1));
+        assert_eq!(hosts[253], Ipv4Addr::new(192, 168, 1, 254));
+
+        let single: Vec<_> = Subnet::parse("10.0.0.5/32").unwrap().hosts().collect();
+        assert_eq!(single, vec![Ipv4Addr::new(10, 0, 0, 5)]);
+        for bad in ["192.168.1.0", "192.168.1.0/33", "fe80::1/64", "host/24"] {
+            assert!(Subnet::parse(bad).is_err(), "{bad}");
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_reports_only_hosts_with_an_ssh_banner() {
+        let ssh = mock_server(Some(b"SSH-2.0-OpenSSH_9.6\r\n")).await;
+        let http = mock_server(Some(b"HTTP/1.1 400 Bad Request\r\n")).await;
+        let silent = mock_server(None).await;
+        // Bound and released, so nothing listens there
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+
+        let started = std::time::Instant::now();
+        let found = scan(
+            Subnet::parse("127.0.0.1/32").unwrap(),
+            &[ssh, http, silent, closed],
+            Duration::from_millis(300),
+            2,
+        )
+        .await;
+        assert_eq!(found.len(), 1, "{found:?}");
+        assert_eq!(found[0].ip, "127.0.0.1");
+        assert_eq!(found[0].port, ssh);
+        assert_eq!(found[0].banner, "SSH-2.0-OpenSSH_9.6");
+        // The silent host is given up on after the per-host timeout
+        assert!(started.elapsed() < Duration::from_secs(3));
+    }
+}
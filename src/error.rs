@@ -8,6 +8,14 @@ pub enum SshMcpError {
     #[error("SSH connection failed: {0}")]
     SshConnection(String),
 
+    /// The host name didn't resolve to an address
+    #[error("SSH connection failed: {0}")]
+    HostNotFound(String),
+
+    /// The address answered, but nothing accepted the connection on that port
+    #[error("SSH connection failed: {0}")]
+    ConnectionRefused(String),
+
     #[error("Command execution failed: {0}")]
     CommandExecution(String),
 
@@ -17,6 +25,9 @@ pub enum SshMcpError {
     #[error("Configuration error: {0}")]
     Config(String),
 
+    #[error("SFTP error: {0}")]
+    Sftp(String),
+
     #[error("Timeout error: {0}")]
     Timeout(String),
 
@@ -27,5 +38,82 @@ pub enum SshMcpError {
     Other(String),
 }
 
+impl SshMcpError {
+    /// Stable, machine-readable name for the kind of error, for clients to branch on
+    pub fn code(&self) -> &'static str {
+        match self {
+            SshMcpError::SshConnection(_) => "connection_failed",
+            SshMcpError::HostNotFound(_) => "host_not_found",
+            SshMcpError::ConnectionRefused(_) => "connection_refused",
+            SshMcpError::CommandExecution(_) => "command_failed",
+            SshMcpError::Authentication(_) => "auth_failed",
+            SshMcpError::Config(_) => "config_invalid",
+            SshMcpError::Sftp(_) => "sftp_failed",
+            SshMcpError::Timeout(_) => "timeout",
+            SshMcpError::Io(_) => "io_error",
+            SshMcpError::Other(_) => "error",
+        }
+    }
+
+    /// Whether the device couldn't be reached or logged into, so no later command can run either
+    pub fn is_connection_failure(&self) -> bool {
+        matches!(
+            self,
+            SshMcpError::SshConnection(_)
+                | SshMcpError::HostNotFound(_)
+                | SshMcpError::ConnectionRefused(_)
+                | SshMcpError::Authentication(_)
+        )
+    }
+}
+
 /// Convenience Result type that uses SshMcpError as the error type
 pub type Result<T> = std::result::Result<T, SshMcpError>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_variant_has_its_own_code() {
+        let cases = [
+            (
+                SshMcpError::SshConnection(String::new()),
+                "connection_failed",
+            ),
+            (SshMcpError::HostNotFound(String::new()), "host_not_found"),
+            (
+                SshMcpError::ConnectionRefused(String::new()),
+                "connection_refused",
+            ),
+            (
+                SshMcpError::CommandExecution(String::new()),
+                "command_failed",
+            ),
+            (SshMcpError::Authentication(String::new()), "auth_failed"),
+            (SshMcpError::Config(String::new()), "config_invalid"),
+            (SshMcpError::Sftp(String::new()), "sftp_failed"),
+            (SshMcpError::Timeout(String::new()), "timeout"),
+            (
+                SshMcpError::Io(std::io::Error::other("disk full")),
+                "io_error",
+            ),
+            (SshMcpError::Other(String::new()), "error"),
+        ];
+        for (error, code) in &cases {
+            assert_eq!(error.code(), *code, "{error:?}");
+        }
+        let mut codes: Vec<_> = cases.iter().map(|(_, code)| code).collect();
+        codes.sort();
+        codes.dedup();
+        assert_eq!(codes.len(), cases.len());
+    }
+
+    #[test]
+    fn only_reach_and_login_errors_are_connection_failures() {
+        assert!(SshMcpError::ConnectionRefused(String::new()).is_connection_failure());
+        assert!(SshMcpError::Authentication(String::new()).is_connection_failure());
+        assert!(!SshMcpError::Timeout(String::new()).is_connection_failure());
+        assert!(!SshMcpError::Config(String::new()).is_connection_failure());
+    }
+}
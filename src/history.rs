@@ -0,0 +1,177 @@
+//! History of commands run during this server session, optionally persisted
+//! as JSON Lines so it can be audited later
+
+use serde::Serialize;
+use std::collections::VecDeque;
+use std::io::Write;
+use std::path::PathBuf;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// How many commands the history keeps before dropping the oldest
+pub const HISTORY_CAPACITY: usize = 200;
+
+/// A command that was sent to the device
+#[derive(Debug, Clone, Serialize)]
+pub struct HistoryEntry {
+    pub command: String,
+    /// Seconds since the Unix epoch when the command started
+    pub timestamp: u64,
+    /// None if the command never produced an exit status (e.g. it timed out, or the
+    /// channel closed before the device sent one)
+    pub exit_code: Option<i32>,
+    pub duration_ms: u64,
+}
+
+/// Bounded ring buffer of recent commands
+#[derive(Debug, Default)]
+pub struct CommandHistory {
+    entries: VecDeque<HistoryEntry>,
+    /// JSON Lines file every entry is also appended to, if persistence is on
+    log_path: Option<PathBuf>,
+}
+
+impl CommandHistory {
+    /// A history that also appends each entry to the JSON Lines file at `path`
+    pub fn persisted(path: PathBuf) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            log_path: Some(path),
+        }
+    }
+
+    /// Record a command, evicting the oldest entry once full
+    /// The entry is appended to the log file too, unless `persist` is false
+    pub fn record(
+        &mut self,
+        command: String,
+        started: SystemTime,
+        exit_code: Option<i32>,
+        duration: Duration,
+        persist: bool,
+    ) {
+        if self.entries.len() == HISTORY_CAPACITY {
+            self.entries.pop_front();
+        }
+        let entry = HistoryEntry {
+            command,
+            timestamp: started
+                .duration_since(UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or(0),
+            exit_code,
+            duration_ms: duration.as_millis() as u64,
+        };
+        if persist
+            && let Some(ref path) = self.log_path
+            && let Err(e) = append_entry(path, &entry)
+        {
+            tracing::warn!("Failed to append to history file {}: {}", path.display(), e);
+        }
+        self.entries.push_back(entry);
+    }
+
+    /// The most recent `limit` entries, oldest first
+    pub fn recent(&self, limit: usize) -> Vec<HistoryEntry> {
+        let skip = self.entries.len().saturating_sub(limit);
+        self.entries.iter().skip(skip).cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+}
+
+/// Append one entry to a JSON Lines file, creating it readable only by the owner
+fn append_entry(path: &std::path::Path, entry: &HistoryEntry) -> std::io::Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut options = std::fs::OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut line = serde_json::to_string(entry)?;
+    line.push('\n');
+    // One write per line, so concurrent servers don't interleave within a line
+    options.open(path)?.write_all(line.as_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+
+    fn started_at(secs: u64) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    #[test]
+    fn full_history_evicts_the_oldest_entry() {
+        let mut history = CommandHistory::default();
+        for n in 0..HISTORY_CAPACITY + 5 {
+            history.record(
+                format!("echo {n}"),
+                started_at(n as u64),
+                Some(0),
+                Duration::ZERO,
+                true,
+            );
+        }
+        assert_eq!(history.len(), HISTORY_CAPACITY);
+        let all = history.recent(usize::MAX);
+        assert_eq!(all.first().unwrap().command, "echo 5");
+        assert_eq!(
+            all.last().unwrap().command,
+            format!("echo {}", HISTORY_CAPACITY + 4)
+        );
+
+        let last_two: Vec<_> = history.recent(2).into_iter().map(|e| e.command).collect();
+        assert_eq!(last_two, ["echo 203", "echo 204"]);
+    }
+
+    #[test]
+    fn persisted_entries_are_appended_as_json_lines() {
+        let dir = TempDir::new();
+        let path = dir.join("nested/history.jsonl");
+        let mut history = CommandHistory::persisted(path.clone());
+        history.record(
+            "ls -la".to_string(),
+            started_at(1_700_000_000),
+            Some(0),
+            Duration::from_millis(42),
+            true,
+        );
+        history.record(
+            "reboot".to_string(),
+            started_at(1_700_000_001),
+            None,
+            Duration::ZERO,
+            false,
+        );
+        history.record(
+            "echo \"hi\"".to_string(),
+            started_at(1_700_000_002),
+            Some(1),
+            Duration::from_millis(7),
+            true,
+        );
+
+        let contents = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            contents,
+            "{\"command\":\"ls -la\",\"timestamp\":1700000000,\"exit_code\":0,\"duration_ms\":42}\n\
+             {\"command\":\"echo \\\"hi\\\"\",\"timestamp\":1700000002,\"exit_code\":1,\"duration_ms\":7}\n"
+        );
+        // Left out of the file, but still in memory
+        assert_eq!(history.len(), 3);
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+            assert_eq!(mode & 0o777, 0o600);
+        }
+    }
+}
@@ -3,23 +3,82 @@
 //! A high-performance MCP (Model Context Protocol) server written in Rust
 //! that provides secure SSH access to Android devices.
 //!
-//! This server exposes three tools:
+//! This server exposes the following tools:
 //! - `setup`: Configure Android SSH connection interactively
+//! - `provision_key`: Generate a key pair, install it on the device and switch the config to it
+//! - `reload_config`: Apply config changes without restarting the server
+//! - `effective_config`: Show the merged configuration and value sources
+//! - `discover`: Scan the local network for SSH servers to find the device IP
+//! - `connection_status`: Check the SSH session and optionally reconnect
+//! - `capture_banner`: Show the SSH banner and login motd that commands don't see
+//! - `benchmark`: Measure latency and SFTP throughput to the device
+//! - `stats`: Report command, failure, timeout and reconnect counts since startup
+//! - `disconnect`: Close the SSH session until the next command
+//! - `power`: Reboot or shut down a rooted device
+//! - `forward_port`: Forward a local port to a host:port reached from the device
+//! - `list_forwards`: List open port forwards
+//! - `close_forward`: Close a port forward
 //! - `execute_read`: Execute whitelisted read-only commands
+//! - `list_read_only_commands`: List the execute_read whitelist by category
 //! - `execute`: Execute any command (with user approval)
+//! - `batch`: Run several commands in order and return each result
+//! - `stream`: Stream output from a long-running command until a duration or pattern
+//! - `send_signal`: Interrupt or kill a command the stream tool is running
+//! - `shell_send` / `shell_close`: Run commands in a persistent shell that keeps its environment
+//! - `run_script`: Run a multi-line script (inline or a local file) on the device
+//! - `list_packages`: List installed Termux packages or Android apps
+//! - `path_info`: Check whether a remote path exists and what it is
+//! - `make_dir`: Create a remote directory and confirm it exists
+//! - `copy_path` / `move_path`: Copy or move a remote path without silently overwriting
+//! - `diff_files`: Compare two remote files as a unified diff
+//! - `search_files`: Find files by name, depth and type as a list of paths
+//! - `android_info`: Summarize the device model, Android version, battery, Wi-Fi and storage
+//! - `get_property` / `list_properties`: Read Android system properties from getprop
+//! - `disk_usage`: Show free storage and the largest directories under a path
+//! - `storage_status`: Check whether Termux can access /sdcard and ~/storage
+//! - `keep_awake`: Take or release the Termux wake lock
+//! - `clipboard_get` / `clipboard_set`: Read or set the device clipboard through Termux:API
+//! - `notify`: Show a notification on the device through Termux:API
+//! - `screenshot`: Capture the screen as a PNG image
+//! - `start_job` / `job_status` / `kill_job`: Manage background jobs
+//! - `process_list` / `process_kill`: List processes and signal one by pid
+//! - `tail_lines`: Read the end of a remote file over SFTP
+//! - `tail_file`: Show the end of a remote file, optionally following it for a while
+//! - `logcat`: Dump recent Android log entries filtered by priority and tag
+//! - `journal`: Print recent systemd journal entries filtered by time and unit
+//! - `upload_file` / `download_file`: Copy files to and from the device over SFTP
+//! - `write_file`: Write text to a remote file without shell quoting
+//! - `compute_checksum`: Hash a remote file on the device or over SFTP
+//! - `verify_checksum`: Check a remote file against a known hash or a local file
+//! - `command_history`: List commands run this session
+//! - `set_working_directory`: Persist a working directory across execute calls
 //!
 //! The server communicates via JSON-RPC over stdin/stdout and is designed
 //! to be run as a subprocess by MCP clients like Claude Code.
 
+mod android;
 mod config;
+mod discover;
 mod error;
+mod history;
+mod output_store;
+mod rate_limit;
+mod shell;
 mod ssh;
+mod stats;
+#[cfg(test)]
+mod test_support;
 mod tools;
 
 use config::Config;
 use rmcp::{
-    ServerHandler, ServiceExt,
-    model::{Implementation, ServerCapabilities, ServerInfo},
+    ErrorData as McpError, RoleServer, ServerHandler, ServiceExt,
+    model::{
+        AnnotateAble, Implementation, ListResourcesResult, PaginatedRequestParam, RawResource,
+        ReadResourceRequestParam, ReadResourceResult, ResourceContents, ServerCapabilities,
+        ServerInfo,
+    },
+    service::RequestContext,
     tool_handler,
 };
 use tools::AndroidSshService;
@@ -37,7 +96,7 @@ async fn main() -> error::Result<()> {
 
     tracing::info!("Android SSH MCP Server starting...");
 
-    // Load configuration from ~/.config/mcp-android-ssh/config.toml
+    // Load configuration from ~/.config/mcp-android-ssh/config.toml (or $MCP_ANDROID_SSH_CONFIG)
     // If config doesn't exist, create template but don't fail - let first tool call handle it
     let config = match Config::load()? {
         Some(cfg) => {
@@ -57,98 +116,475 @@ async fn main() -> error::Result<()> {
 
     // Create MCP service with optional config (lazy connection on first use)
     let service = AndroidSshService::new(config);
+    // Shares the SSH clients with the running server, for disconnecting on shutdown
+    let sessions = service.clone();
+
+    // Listen for the MCP client killing the subprocess, including during the handshake
+    let mut signal = tokio::spawn(shutdown_signal());
 
     // Serve on stdio
     tracing::info!("Starting MCP server on stdio...");
-    let server = service
-        .serve(rmcp::transport::stdio())
-        .await
-        .map_err(|e| error::SshMcpError::Other(format!("Failed to start server: {}", e)))?;
+    let server = tokio::select! {
+        server = service.serve(rmcp::transport::stdio()) => server
+            .map_err(|e| error::SshMcpError::Other(format!("Failed to start server: {}", e)))?,
+        name = &mut signal => {
+            tracing::info!("Received {}, shutting down", name.unwrap_or("signal"));
+            return Ok(());
+        }
+    };
+
+    let cancel = server.cancellation_token();
+    tokio::spawn(async move {
+        if let Ok(name) = signal.await {
+            tracing::info!("Received {}, shutting down", name);
+            cancel.cancel();
+        }
+    });
 
     // Wait for completion
-    server
-        .waiting()
+    let waited = server.waiting().await;
+
+    // Close sessions so the device doesn't wait for them to time out; a tool call
+    // still holding a client can't be allowed to block exit
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, sessions.disconnect_all())
         .await
-        .map_err(|e| error::SshMcpError::Other(format!("Server error: {}", e)))?;
+        .is_err()
+    {
+        tracing::warn!("Timed out closing SSH sessions");
+    }
 
-    tracing::info!("Android SSH MCP Server shutting down");
+    waited.map_err(|e| error::SshMcpError::Other(format!("Server error: {}", e)))?;
+    tracing::info!("Android SSH MCP Server shut down cleanly");
     Ok(())
 }
 
+// How long shutdown waits for SSH sessions to close
+const SHUTDOWN_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(5);
+
+/// Wait for SIGINT, or SIGTERM on Unix, returning the signal's name
+async fn shutdown_signal() -> &'static str {
+    let interrupt = async {
+        if tokio::signal::ctrl_c().await.is_err() {
+            // No handler could be installed; never trigger shutdown from here
+            std::future::pending::<()>().await;
+        }
+    };
+
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{SignalKind, signal};
+        let terminate = async {
+            match signal(SignalKind::terminate()) {
+                Ok(mut term) => {
+                    term.recv().await;
+                }
+                Err(_) => std::future::pending::<()>().await,
+            }
+        };
+        tokio::select! {
+            _ = interrupt => "SIGINT",
+            _ = terminate => "SIGTERM",
+        }
+    }
+
+    #[cfg(not(unix))]
+    {
+        interrupt.await;
+        "Ctrl-C"
+    }
+}
+
+/// Built-in server instructions, replaceable via the `[instructions]` config section
+const INSTRUCTIONS: &str = "Android SSH MCP Server - Secure SSH access to Android devices.\n\n\
+    Use setup to configure your connection.\n\
+    Use execute_read for safe read-only commands (ls, cat, ps, etc.).\n\
+    Use execute for commands that modify the system (rm, mkdir, curl, etc.).\n\n\
+    ## setup Tool\n\
+    Configure Android SSH connection interactively. All parameters optional.\n\
+    Provide host, user, and key_path (or password). Missing info will be requested.\n\n\
+    **Examples:**\n\
+    - Complete setup: setup(host=\"192.168.1.100\", user=\"u0_a555\", key_path=\"~/.ssh/id_ed25519\")\n\
+    - Partial update: setup(host=\"192.168.1.101\")\n\n\
+    Setup connects and runs whoami with the new settings before saving. If that fails the settings are still \
+    saved, flagged as untested with the reason; pass test_connection=false to skip the test.\n\
+    After setup, call reload_config to apply it (no /mcp restart needed).\n\n\
+    ## provision_key Tool\n\
+    Once password login works, provision_key generates an ed25519 key pair locally (default \
+    ~/.ssh/mcp_android_ed25519), appends the public key to ~/.ssh/authorized_keys on the device over the \
+    current session and sets key_path in the config, so users don't need ssh-keygen or ssh-copy-id. \
+    Pass clear_password=true to also remove the stored password once the key is confirmed to work. \
+    Then call reload_config.\n\n\
+    ## reload_config Tool\n\
+    Re-read config.toml after setup or a manual edit; closes open sessions so the next command uses the new \
+    connection settings. An invalid config is rejected and the old one kept. \
+    Whitelist, blocklist, instructions, rate limit and history changes still need a server restart.\n\n\
+    ## effective_config Tool\n\
+    Show the configuration actually in effect, with each value annotated by its source \
+    (file, env, or default). Environment variables override the config file.\n\n\
+    ## discover Tool\n\
+    Find the device IP before setup: scans a subnet (default: this machine's /24) on ports 8022 and 22 \
+    and lists hosts that answer with an SSH banner. Works without any configuration. \
+    A banner mentioning OpenSSH on port 8022 is most likely Termux.\n\n\
+    ## connection_status Tool\n\
+    Report whether the SSH session is open, the host:port and user, the device's host key, and round-trip \
+    latency (by running 'true'). Pass reconnect=true to drop and re-establish the session, e.g. after \
+    Wi-Fi drops, instead of waiting for a command to fail.\n\n\
+    ## capture_banner Tool\n\
+    Commands run without a login shell, so the Termux motd never appears in their output. \
+    To see what an interactive login shows, call capture_banner(): it returns ssh_banner (sent by sshd \
+    before authentication, or null) and login_output (what a login shell prints before its first prompt).\n\n\
+    ## benchmark Tool\n\
+    To tell whether slowness is the device or the link, call benchmark(): it reports round-trip latency \
+    (ms, running 'true') and SFTP upload and download throughput (MB/s) for size_mb of random data \
+    (default 4). High latency with good throughput means many small commands are slow; \
+    low throughput means large transfers and outputs are.\n\n\
+    ## stats Tool\n\
+    Counters since the server started, for judging a flaky link: commands run, failures (SSH or channel \
+    errors, not non-zero exit codes), timeouts, connects, reconnects, failed connection attempts, \
+    bytes sent and received, and uptime. Call it before and after a task to compare.\n\n\
+    ## disconnect Tool\n\
+    Close the SSH session, e.g. to force re-authentication after changing keys, or to free the device's \
+    sshd when done. The next command reconnects on its own. Pass profile to pick a device, or all=true for every device. \
+    Background jobs keep running on the device.\n\n\
+    ## power Tool\n\
+    power(action=\"reboot\" | \"shutdown\" | \"reboot_recovery\", confirm=true) restarts or turns off the device \
+    through su. Only call it when the user asked for it; without confirm=true it only explains what would happen. \
+    It needs root. The session drops and is closed; after a reboot, wait a minute or two before the next command.\n\n\
+    ## forward_port Tool\n\
+    forward_port(remote_port=8080) listens on 127.0.0.1 on this machine (local_port, default any free port) and \
+    tunnels each connection through the SSH session to remote_host:remote_port as seen from the device \
+    (remote_host defaults to the device itself). Use it to reach a server running in Termux. It returns the local \
+    address and a forward id. Forwards stop when the session ends; list_forwards shows which are still active \
+    and close_forward(id) releases the local port.\n\n\
+    ## execute_read Tool\n\
+    Execute SAFE shell commands on Android via SSH. Whitelisted commands only - cannot write/delete.\n\
+    Returns stdout, stderr, exit code, and how long the command took.\n\n\
+    **Whitelisted commands (81 total):**\n\
+    - File viewing: ls, cat, head, tail, less, more, grep, rg, find, fd, tree, bat, eza, exa, locate\n\
+    - Path operations: cd, pwd, readlink, realpath, basename, dirname\n\
+    - System info: whoami, id, groups, which, whereis, type, hostname, uname, date, uptime\n\
+    - Display: echo, printf\n\
+    - Process monitoring: ps, top, htop, btop, lsof\n\
+    - Disk/filesystem: df, du, lsblk, blkid, stat, file\n\
+    - Memory/performance: free, vmstat, iostat, iotop, lsmem, lshw, lscpu\n\
+    - Network monitoring: netstat, ss, ping, traceroute, nslookup, dig, host\n\
+    - Text processing: wc, sort, uniq, cut, paste, tr, column\n\
+    - Comparison: diff, cmp, comm\n\
+    - Checksums: md5sum, sha1sum, sha256sum, sha512sum\n\
+    - Environment: env, printenv, getent, getconf\n\
+    - Binary viewers: xxd, hexdump, od, strings\n\
+    - Compressed viewers: zcat, bzcat, xzcat, gunzip, bunzip2, unxz\n\
+    - Data parsers: jq, yq, xmllint\n\
+    - Logs: journalctl\n\
+    - Hardware: lsmod, modinfo, lspci, lsusb\n\
+    - Shell: history, alias\n\
+    - Fonts: fc-list, fc-match\n\
+    - Test: test, true, false\n\n\
+    **Examples:**\n\
+    - List files: ls -la\n\
+    - Read file: cat ~/.bashrc\n\
+    - System info: uname -a\n\
+    - Disk usage: df -h\n\
+    - Running processes: ps aux\n\n\
+    Leading environment assignments (e.g. LC_ALL=C ls) are allowed, except variables that change \
+    what runs (PATH, LD_PRELOAD, LD_LIBRARY_PATH and other LD_* variables, IFS, ENV, BASH_ENV).\n\
+    Shell operators (; && || & | ` $( > >> < and newlines) are rejected, so each call runs one command; \
+    use execute for chains and redirects. Pipes between whitelisted commands work only if the \
+    allow_pipes_in_read config option is enabled.\n\
+    The whitelist may be adjusted per deployment via the [whitelist] config section.\n\
+    If a command isn't whitelisted, you'll get an error telling you to use 'execute' tool instead.\n\
+    Call list_read_only_commands to see the exact whitelist on this deployment before choosing a tool.\n\
+    Pass verify_available=true to check the command is installed before running it. \
+    A \"whitelisted but not installed\" error names the package to install with pkg install (via execute).\n\
+    With execute or execute_read, exit code 127 from a program that isn't installed comes with a \
+    hint naming the pkg install that provides it.\n\n\
+    ## execute Tool\n\
+    Execute ANY shell command on Android via SSH. Use for commands that write/modify/delete.\n\
+    Returns stdout, stderr, exit code, and how long the command took. \
+    When the request has a progress token, output is also sent as progress notifications while the command runs.\n\n\
+    **Use this for:**\n\
+    - System diagnostics: dumpsys (Android system information)\n\
+    - File operations: rm, mv, cp, mkdir, chmod, touch\n\
+    - Package management: pkg install, apt install, npm install\n\
+    - Downloads: curl, wget\n\
+    - Git operations: git clone, git pull, git commit\n\
+    - Service management: systemctl start/stop\n\
+    - File writing: use the write_file tool instead of echo > file\n\n\
+    **Examples:**\n\
+    - System diagnostics: dumpsys package com.termux\n\
+    - Create directory: mkdir ~/newdir\n\
+    - Remove file: rm oldfile.txt\n\
+    - Install package: pkg install git\n\
+    - Download: curl -O https://example.com/file\n\n\
+    Commands matching the [blocklist] config section are refused with the rule they matched; \
+    don't try to work around a blocked command.\n\
+    Pass dry_run=true to preview a command without running it: returns the exact command line \
+    (with working directory and env applied), whether it is blocked, and whether execute_read would accept it.\\
+    Pass run_as=\"root\" to run a command through su (or sudo) on a rooted device; if su is missing \
+    or the root manager refuses, the error says so - ask the user to grant Termux root rather than retrying.\n\n\
+    **IMPORTANT:** Always prefer execute_read for safe commands (ls, cat, ps, grep, etc.).\n\n\
+    ## batch Tool\n\
+    Run several commands in order in one call instead of one execute call each, \
+    e.g. batch(commands=[\"pkg update\", \"pkg install git\"], stop_on_error=true). \
+    Returns {command, status, stdout, stderr, exit_code} per command, where status is ok, failed, error or skipped. \
+    With stop_on_error=true the commands after the first failure are skipped. \
+    timeout covers the whole batch; commands not started in time are skipped. \
+    Each command runs in its own shell, so 'cd' doesn't carry over (use set_working_directory). \
+    Like execute, it can run anything and [blocklist] applies to every command.\n\n\
+    ## stream Tool\n\
+    For commands that never exit on their own (logcat, top, tail -f), which would hang execute. \
+    Runs the command on a PTY and sends new output as progress notifications every flush_interval_ms, \
+    until duration_secs (default 10, max 300) elapses or the output matches the stop_pattern regex. \
+    Then sends Ctrl-C and returns everything captured (the most recent max_output_bytes if longer).\n\
+    **Example:** stream(command=\"logcat -v brief\", duration_secs=30, stop_pattern=\"FATAL EXCEPTION\")\n\
+    The first progress notification gives a stream id. To stop a slow command early (e.g. find /), call \
+    send_signal(stream_id=N, signal=\"INT\") (or TERM, HUP, KILL) while it runs; stream_id can be omitted when \
+    only one stream is running. The stream call then returns the output captured so far.\n\n\
+    ## shell_send / shell_close Tools\n\
+    When later commands depend on earlier ones (export, cd, source, shell functions), run them in one shell: \
+    shell_send(command=\"export API=http://localhost:8080\") opens a shell and returns its session id; \
+    pass session_id=N to later shell_send calls to keep using it, and call shell_close(session_id=N) when done. \
+    A command still running at its timeout gets Ctrl-C and the shell stays open. \
+    Commands there can't read from stdin, and stdout and stderr arrive together.\n\n\
+    ## run_script Tool\n\
+    For anything longer than a one-liner, use run_script instead of chaining commands with ';' in execute: \
+    pass the script text with real newlines as script, or a local file as path. \
+    It runs with sh unless interpreter (e.g. \"bash\", \"python3\") or the script's #! line says otherwise, \
+    and args become $1, $2, ... \
+    Scripts can modify the system, so this is never available through execute_read. \
+    Scripts are limited to 1 MiB.\n\n\
+    ## list_packages Tool\n\
+    List installed packages as structured entries (name, version). \
+    source=\"termux\" (default) lists pkg/apt packages; source=\"android\" lists installed apps via pm.\n\n\
+    ## path_info Tool\n\
+    Check a remote path before reading or writing it instead of composing test/stat commands: \
+    returns {exists, is_dir, is_file, is_symlink, size_bytes, mode_octal, mtime}. \
+    For a symlink the type, size, mode and mtime are those of its target (null if the link is dangling).\n\n\
+    ## make_dir Tool\n\
+    Create a directory instead of running mkdir through execute: make_dir(remote_path=\"~/projects/app\", parents=true). \
+    Succeeds if the directory already exists, and fails clearly if a file is in the way.\n\n\
+    ## copy_path / move_path Tools\n\
+    Copy or move files and directories instead of running cp or mv through execute: \
+    copy_path(source=\"~/notes.txt\", dest=\"/sdcard/Download/\"). An existing destination is refused \
+    unless overwrite=true; only pass it when the user wants the file replaced. \
+    The result names the final path and is checked after the copy or move.\n\n\
+    ## diff_files Tool\n\
+    Compare two files instead of assembling a diff command: diff_files(path_a=\"~/config.old\", path_b=\"~/config\"). \
+    The result has 'identical' and a unified 'diff'; binary files (with NUL bytes) set 'binary' and get no diff.\n\n\
+    ## search_files Tool\n\
+    Prefer it over writing find predicates in execute: search_files(root=\"~/projects\", name_glob=\"*.py\", type=\"file\", max_depth=3). \
+    name_glob matches names, not paths. Returns 'paths' (at most max_results, default 200) and 'truncated' \
+    when there were more; narrow root or name_glob rather than raising the cap. Unreadable directories are listed under 'errors'.\n\n\
+    ## android_info Tool\n\
+    Structured device summary (model, Android version, battery, Wi-Fi SSID, /data free space) \
+    parsed from getprop, dumpsys and df. Prefer it over reading raw dumpsys output. \
+    Fields that can't be read are null, with the reason listed under 'unavailable'.\n\n\
+    ## get_property / list_properties Tools\n\
+    For a single system property call get_property(key=\"ro.build.version.release\") instead of grepping getprop output; \
+    an unset property comes back with value null and a note. list_properties(prefix=\"ro.product.\") returns matching name/value pairs.\n\n\
+    ## disk_usage Tool\n\
+    For \"why is storage full\", call disk_usage(path=\"/sdcard\", top=10) instead of df and du through execute. \
+    It returns the filesystem's size, used and available bytes and the largest subdirectories of path, sorted by size. \
+    Call it again on a large subdirectory to drill down. 'notes' lists directories du couldn't read.\n\n\
+    ## storage_status Tool\n\
+    Termux can't read /sdcard until termux-setup-storage has been run and the permission granted on the device. \
+    If a command on /sdcard fails with permission denied, call storage_status() and pass its 'guidance' on to the user; \
+    the grant needs someone at the device's screen, so don't retry until they have done it.\n\n\
+    ## keep_awake Tool\n\
+    With the screen off, Android's Doze can suspend Termux and drop the session. Before a long operation \
+    (a big pkg upgrade, a build, a large transfer), call keep_awake() to take the Termux wake lock, \
+    and keep_awake(release=true) afterwards to save battery.\n\n\
+    ## clipboard_get / clipboard_set Tools\n\
+    clipboard_get() returns what the user copied on the phone; clipboard_set(text=...) puts text there for them \
+    to paste. Use them when the user mentions copying or pasting on the device. \
+    Both need the Termux:API app and package; if they are missing, pass the returned guidance on.\n\n\
+    ## notify Tool\n\
+    notify(title=..., content=...) shows a notification on the phone, e.g. when a long install or build \
+    the user is waiting on has finished. priority=\"high\" pops it up on screen; don't use it for routine updates. \
+    Needs Termux:API, like the clipboard tools.\n\n\
+    ## screenshot Tool\n\
+    To see what is on the screen, call screenshot() instead of running screencap through execute; \
+    it returns the PNG as an image. Pass local_path to keep a copy, and root=true if screencap fails \
+    without root (this needs a rooted device).\n\n\
+    ## Background Jobs\n\
+    start_job runs a command in the background (output goes to a log file) and returns a job id. \
+    job_status shows whether a job is still running and its recent output. \
+    kill_job stops a job with a signal (TERM by default) and can remove its log. \
+    Only jobs started this session can be killed.\n\n\
+    ## process_list / process_kill Tools\n\
+    process_list(filter=\"python\") returns processes as rows of pid, user, cpu, mem and command; \
+    use it rather than reading ps output. process_kill(pid=N) sends TERM (or INT, HUP) and reports whether \
+    the process exited. KILL also needs confirm=true; only send it when TERM didn't work or the user asked.\n\n\
+    ## tail_lines Tool\n\
+    Return the last N lines (default 100) of a remote file plus its total size. \
+    Reads backward from the end over SFTP, so it stays fast on huge logs. \
+    Prefer it over 'tail' or 'cat' for large files.\n\n\
+    ## tail_file Tool\n\
+    tail_file(remote_path, lines=50) runs tail -n. To watch a growing log, pass follow_secs: new lines are \
+    streamed as progress notifications for that long, then everything captured is returned. \
+    Use it instead of running tail -f through execute, which never returns.\n\n\
+    ## logcat Tool\n\
+    Dump recent log entries (default 200, max 5000) without hanging, e.g. logcat(priority=\"E\") \
+    or logcat(tag=\"ActivityManager\", priority=\"W\"). Prefer it over running logcat through execute. \
+    Without root only Termux's own entries are visible. Use stream to follow the log live.\n\n\
+    ## journal Tool\n\
+    Print recent systemd journal entries without hanging, e.g. journal(unit=\"sshd.service\", since=\"1 hour ago\"). \
+    Only for Linux hosts running systemd; Android has no journal, so use logcat there. \
+    execute_read rejects journalctl -f/--follow, which never exits; use stream to follow the journal live.\n\n\
+    ## upload_file / download_file Tools\n\
+    Copy files between this machine and the device over SFTP. \
+    download_file(inline=true) also returns the contents of UTF-8 text files under 1 MiB. \
+    Remote directories can't be downloaded. A transfer that fails or times out leaves no partial file behind. \
+    Remote paths starting with ~/ are expanded to the device's home directory in these and the other file tools.\n\n\
+    ## write_file Tool\n\
+    Write text to a remote file exactly as given, over SFTP: write_file(remote_path=\".bashrc\", content=..., append=true). \
+    Prefer it over echo/cat redirects in execute, which break on quotes, $ and newlines. \
+    create_dirs=true creates missing parent directories. Content is limited to 1 MiB; use upload_file for more.\n\n\
+    ## compute_checksum Tool\n\
+    Compute md5/sha1/sha256/sha512 of a remote file. mode=\"device\" (default) runs sha256sum etc. \
+    on the device, best on slow networks. mode=\"stream\" hashes over SFTP with progress notifications, \
+    best when the device CPU is the bottleneck. Both can be cancelled.\n\n\
+    ## verify_checksum Tool\n\
+    Confirm a transfer arrived intact instead of comparing digests by eye. \
+    verify_checksum(remote_path=..., expected=\"<hex>\") checks against a known hash; \
+    verify_checksum(remote_path=..., local_path=...) hashes the local file too. \
+    Returns matches (true/false) with both digests. algorithm defaults to sha256.\n\n\
+    ## command_history Tool\n\
+    List the commands run on the device this session (command, timestamp, exit code, duration), \
+    most recent last. Useful for recalling what was tried or turning a session into a script.\n\n\
+    ## set_working_directory Tool\n\
+    Each command runs in a fresh shell, so 'cd' in one execute call doesn't carry over to the next. \
+    set_working_directory(path=\"/sdcard\") stores a directory (it must exist) that execute and execute_read \
+    then run in; their output reports it. set_working_directory(reset=true) returns to the login default.\n\n\
+    ## Interleaved Output\n\
+    stdout and stderr are normally returned separately. Pass merge_streams=true to execute or execute_read to run \
+    the command under a PTY, so errors appear in order among progress output (useful for pkg install). \
+    The exit code is unchanged.\n\n\
+    ## Environment Variables\n\
+    Pass env={\"LC_ALL\": \"C\"} to execute or execute_read instead of writing VAR=value into the command; \
+    values are quoted for you and apply to every command in a chain. execute_read rejects PATH, LD_* and \
+    other variables that change what runs.\n\n\
+    ## Structured Results\n\
+    Pass structured=true to execute or execute_read to also get {stdout, stderr, exit_code, success, duration_ms, cwd} \
+    as structured JSON, for branching on exit_code reliably.\n\n\
+    ## Error Codes\n\
+    Tool errors from SSH, SFTP and configuration failures carry structured content {\"error\": {\"code\", \"message\"}}. \
+    code is one of connection_failed, host_not_found, connection_refused, auth_failed, timeout, command_failed, \
+    sftp_failed, config_invalid, io_error or error; retry or reconnect on connection_refused and timeout, \
+    but fix the configuration first on host_not_found, auth_failed and config_invalid.\n\n\
+    ## Binary Output\n\
+    stdout that isn't valid UTF-8 (e.g. cat on an image) is returned base64-encoded with a note, \
+    rather than corrupted. Pass encoding=\"base64\" to always get base64, or encoding=\"utf8\" for lossy text.\n\n\
+    ## Rate Limit\n\
+    If max_commands_per_minute is configured, execute, execute_read and batch fail with \
+    \"Rate limited ... Retry after Ns\" once it is reached; wait that long instead of retrying immediately.\n\n\
+    ## Long Output\n\
+    execute and execute_read return at most 64 KiB of stdout by default (max_output_bytes). Truncated output ends \
+    with a marker giving the offset to re-run with to see the next page. Paging re-runs the command. \
+    The full stdout of a truncated result is also kept as an MCP resource (android-ssh://output/N, linked in the result); \
+    read that resource if your client supports it instead of re-running the command to page. \
+    To keep only the lines you need, pass grep=\"regex\" (and grep_invert=true to drop them instead); \
+    the result reports how many of the stdout lines were kept.\n\n\
+    ## Device Profiles\n\
+    If several devices are configured as profiles, pass profile=\"name\" to execute or execute_read to pick one; \
+    an unknown name returns the available profiles. setup(profile=\"name\", host=...) saves a new profile.\n\n\
+    ## Command Timeout\n\
+    execute, execute_read, run_script, and the file tools accept an optional 'timeout' parameter (1-300 seconds, default: 30).\n\
+    Use longer timeouts for package installations or long-running operations. \
+    The config can raise the default for execute and execute_read separately \
+    (default_exec_timeout_secs, default_read_timeout_secs); effective_config shows the values in use. \
+    If adaptive_timeout is enabled in the config, a command still printing output when its timeout \
+    passes keeps running until it is quiet for 10 seconds (up to adaptive_timeout_max_secs).";
+
+/// Added to the instructions when disable_execute has removed the command and write tools
+const EXECUTE_DISABLED_INSTRUCTIONS: &str = "\n\n## Execute Disabled\n\
+    This server's policy disables execute, batch, stream, run_script, start_job and shell_send, \
+    and the tools that write files or stop processes (write_file, upload_file, make_dir, \
+    copy_path, move_path, clipboard_set, provision_key, process_kill and power). \
+    Only the commands execute_read allows can run (see list_read_only_commands); \
+    if a task needs anything else, tell the user rather than looking for a way around it.";
+
 #[tool_handler]
 impl ServerHandler for AndroidSshService {
     fn get_info(&self) -> ServerInfo {
         ServerInfo {
-            capabilities: ServerCapabilities::builder().enable_tools().build(),
+            capabilities: ServerCapabilities::builder()
+                .enable_tools()
+                .enable_resources()
+                .build(),
             server_info: Implementation::from_build_env(),
-            instructions: Some(
-                "Android SSH MCP Server - Secure SSH access to Android devices.\n\n\
-                Use setup to configure your connection.\n\
-                Use execute_read for safe read-only commands (ls, cat, ps, etc.).\n\
-                Use execute for commands that modify the system (rm, mkdir, curl, etc.).\n\n\
-                ## setup Tool\n\
-                Configure Android SSH connection interactively. All parameters optional.\n\
-                Provide host, user, and key_path (or password). Missing info will be requested.\n\n\
-                **Examples:**\n\
-                - Complete setup: setup(host=\"192.168.1.100\", user=\"u0_a555\", key_path=\"~/.ssh/id_ed25519\")\n\
-                - Partial update: setup(host=\"192.168.1.101\")\n\n\
-                After setup, restart the server from /mcp menu.\n\n\
-                ## execute_read Tool\n\
-                Execute SAFE shell commands on Android via SSH. Whitelisted commands only - cannot write/delete.\n\
-                Returns stdout, stderr, and exit code.\n\n\
-                **Whitelisted commands (81 total):**\n\
-                - File viewing: ls, cat, head, tail, less, more, grep, rg, find, fd, tree, bat, eza, exa, locate\n\
-                - Path operations: cd, pwd, readlink, realpath, basename, dirname\n\
-                - System info: whoami, id, groups, which, whereis, type, hostname, uname, date, uptime\n\
-                - Display: echo, printf\n\
-                - Process monitoring: ps, top, htop, btop, lsof\n\
-                - Disk/filesystem: df, du, lsblk, blkid, stat, file\n\
-                - Memory/performance: free, vmstat, iostat, iotop, lsmem, lshw, lscpu\n\
-                - Network monitoring: netstat, ss, ping, traceroute, nslookup, dig, host\n\
-                - Text processing: wc, sort, uniq, cut, paste, tr, column\n\
-                - Comparison: diff, cmp, comm\n\
-                - Checksums: md5sum, sha1sum, sha256sum, sha512sum\n\
-                - Environment: env, printenv, getent, getconf\n\
-                - Binary viewers: xxd, hexdump, od, strings\n\
-                - Compressed viewers: zcat, bzcat, xzcat, gunzip, bunzip2, unxz\n\
-                - Data parsers: jq, yq, xmllint\n\
-                - Logs: journalctl\n\
-                - Hardware: lsmod, modinfo, lspci, lsusb\n\
-                - Shell: history, alias\n\
-                - Fonts: fc-list, fc-match\n\
-                - Test: test, true, false\n\n\
-                **Examples:**\n\
-                - List files: ls -la\n\
-                - Read file: cat ~/.bashrc\n\
-                - System info: uname -a\n\
-                - Disk usage: df -h\n\
-                - Running processes: ps aux\n\n\
-                If a command isn't whitelisted, you'll get an error telling you to use 'execute' tool instead.\n\n\
-                ## execute Tool\n\
-                Execute ANY shell command on Android via SSH. Use for commands that write/modify/delete.\n\
-                Returns stdout, stderr, and exit code.\n\n\
-                **Use this for:**\n\
-                - System diagnostics: dumpsys (Android system information)\n\
-                - File operations: rm, mv, cp, mkdir, chmod, touch\n\
-                - Package management: pkg install, apt install, npm install\n\
-                - Downloads: curl, wget\n\
-                - Git operations: git clone, git pull, git commit\n\
-                - Service management: systemctl start/stop\n\
-                - File writing: echo > file, cat > file\n\n\
-                **Examples:**\n\
-                - System diagnostics: dumpsys package com.termux\n\
-                - Create directory: mkdir ~/newdir\n\
-                - Remove file: rm oldfile.txt\n\
-                - Install package: pkg install git\n\
-                - Write file: echo 'content' > file.txt\n\
-                - Download: curl -O https://example.com/file\n\n\
-                **IMPORTANT:** Always prefer execute_read for safe commands (ls, cat, ps, grep, etc.).\n\n\
-                ## Command Timeout\n\
-                Both tools accept an optional 'timeout' parameter (1-300 seconds, default: 30).\n\
-                Use longer timeouts for package installations or long-running operations."
-                    .to_string(),
-            ),
+            instructions: Some({
+                let mut instructions = self
+                    .server_instructions
+                    .clone()
+                    .unwrap_or_else(|| INSTRUCTIONS.to_string());
+                if self.execute_disabled {
+                    instructions.push_str(EXECUTE_DISABLED_INSTRUCTIONS);
+                }
+                instructions
+            }),
             ..Default::default()
         }
     }
+
+    /// Outputs execute and execute_read truncated, most recent last
+    async fn list_resources(
+        &self,
+        _request: Option<PaginatedRequestParam>,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ListResourcesResult, McpError> {
+        let outputs = self.outputs.lock().await;
+        let resources = outputs
+            .iter()
+            .map(|(uri, output)| {
+                let mut resource = RawResource::new(uri, format!("stdout of {}", output.command));
+                resource.mime_type = Some(
+                    tools::output_mime_type(if output.base64 { "base64" } else { "utf8" })
+                        .to_string(),
+                );
+                resource.size = u32::try_from(output.text.len()).ok();
+                if let Ok(age) = output.created.elapsed() {
+                    resource.description = Some(format!("Captured {}s ago", age.as_secs()));
+                }
+                resource.no_annotation()
+            })
+            .collect();
+        Ok(ListResourcesResult::with_all_items(resources))
+    }
+
+    async fn read_resource(
+        &self,
+        request: ReadResourceRequestParam,
+        _context: RequestContext<RoleServer>,
+    ) -> Result<ReadResourceResult, McpError> {
+        let outputs = self.outputs.lock().await;
+        let Some(output) = outputs.get(&request.uri) else {
+            return Err(McpError::resource_not_found(
+                format!(
+                    "No stored output at {}; only the {} most recent truncated outputs are kept",
+                    request.uri,
+                    output_store::MAX_STORED_OUTPUTS
+                ),
+                None,
+            ));
+        };
+        let contents = if output.base64 {
+            ResourceContents::BlobResourceContents {
+                uri: request.uri,
+                mime_type: Some(tools::output_mime_type("base64").to_string()),
+                blob: output.text.clone(),
+                meta: None,
+            }
+        } else {
+            ResourceContents::TextResourceContents {
+                uri: request.uri,
+                mime_type: Some(tools::output_mime_type("utf8").to_string()),
+                text: output.text.clone(),
+                meta: None,
+            }
+        };
+        Ok(ReadResourceResult {
+            contents: vec![contents],
+        })
+    }
 }
@@ -0,0 +1,68 @@
+//! Full command output kept as MCP resources when a tool result only shows part of it
+
+use std::collections::BTreeMap;
+use std::time::SystemTime;
+
+/// Stored outputs are read back as `android-ssh://output/<id>`
+const OUTPUT_URI_PREFIX: &str = "android-ssh://output/";
+
+/// How many outputs are kept; the oldest is dropped to make room
+pub const MAX_STORED_OUTPUTS: usize = 20;
+
+/// Larger outputs aren't stored, and have to be paged with offset instead
+pub const MAX_STORED_OUTPUT_BYTES: usize = 16 * 1024 * 1024;
+
+#[derive(Debug)]
+pub struct StoredOutput {
+    pub command: String,
+    /// stdout as returned by the tool: text, or base64 when `base64` is set
+    pub text: String,
+    pub base64: bool,
+    pub created: SystemTime,
+}
+
+/// Recent outputs by id, which only ever grows so a URI never points at a different output
+#[derive(Debug, Default)]
+pub struct OutputStore {
+    outputs: BTreeMap<u32, StoredOutput>,
+    next_id: u32,
+}
+
+impl OutputStore {
+    /// Keep `text` and return the URI it can be read from, or None if it is too large
+    pub fn insert(&mut self, command: &str, text: &str, base64: bool) -> Option<String> {
+        if text.len() > MAX_STORED_OUTPUT_BYTES {
+            return None;
+        }
+        self.next_id += 1;
+        self.outputs.insert(
+            self.next_id,
+            StoredOutput {
+                command: command.to_string(),
+                text: text.to_string(),
+                base64,
+                created: SystemTime::now(),
+            },
+        );
+        while self.outputs.len() > MAX_STORED_OUTPUTS {
+            self.outputs.pop_first();
+        }
+        Some(output_uri(self.next_id))
+    }
+
+    pub fn get(&self, uri: &str) -> Option<&StoredOutput> {
+        let id = uri.strip_prefix(OUTPUT_URI_PREFIX)?.parse().ok()?;
+        self.outputs.get(&id)
+    }
+
+    /// Stored outputs with their URIs, oldest first
+    pub fn iter(&self) -> impl Iterator<Item = (String, &StoredOutput)> {
+        self.outputs
+            .iter()
+            .map(|(id, output)| (output_uri(*id), output))
+    }
+}
+
+fn output_uri(id: u32) -> String {
+    format!("{}{}", OUTPUT_URI_PREFIX, id)
+}
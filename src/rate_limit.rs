@@ -0,0 +1,94 @@
+//! Token bucket limiting how fast commands are sent to the device
+
+use std::time::{Duration, Instant};
+
+/// Allows `per_minute` commands in a burst, refilling one token every `60 / per_minute` seconds
+#[derive(Debug)]
+pub struct RateLimiter {
+    capacity: f64,
+    tokens: f64,
+    /// Tokens added per second
+    refill_rate: f64,
+    last_refill: Instant,
+}
+
+impl RateLimiter {
+    pub fn per_minute(per_minute: u32) -> Self {
+        let capacity = f64::from(per_minute);
+        Self {
+            capacity,
+            tokens: capacity,
+            refill_rate: capacity / 60.0,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take `count` tokens, or return how long until that many are available
+    /// Nothing is taken when the call is refused, so a retry after the wait succeeds
+    /// A count above the burst size is capped to it, so it waits for a full bucket
+    pub fn try_acquire(&mut self, count: u32) -> Result<(), Duration> {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill_rate).min(self.capacity);
+        self.last_refill = now;
+
+        let count = f64::from(count).min(self.capacity);
+        if self.tokens >= count {
+            self.tokens -= count;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64(
+            (count - self.tokens) / self.refill_rate,
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Pretend `secs` seconds have passed since the last call
+    fn advance(limiter: &mut RateLimiter, secs: u64) {
+        limiter.last_refill -= Duration::from_secs(secs);
+    }
+
+    #[test]
+    fn the_call_past_the_limit_is_refused_until_the_window_passes() {
+        let mut limiter = RateLimiter::per_minute(3);
+        for _ in 0..3 {
+            assert_eq!(limiter.try_acquire(1), Ok(()));
+        }
+        let wait = limiter.try_acquire(1).unwrap_err();
+        assert!(wait > Duration::from_secs(19) && wait <= Duration::from_secs(20));
+
+        advance(&mut limiter, 60);
+        for _ in 0..3 {
+            assert_eq!(limiter.try_acquire(1), Ok(()));
+        }
+        assert!(limiter.try_acquire(1).is_err());
+    }
+
+    #[test]
+    fn tokens_refill_gradually_and_a_refusal_takes_nothing() {
+        let mut limiter = RateLimiter::per_minute(6);
+        assert_eq!(limiter.try_acquire(6), Ok(()));
+        assert!(limiter.try_acquire(1).is_err());
+        assert!(limiter.try_acquire(1).is_err());
+
+        // One token every 10 seconds
+        advance(&mut limiter, 10);
+        assert_eq!(limiter.try_acquire(1), Ok(()));
+        assert!(limiter.try_acquire(1).is_err());
+    }
+
+    #[test]
+    fn a_count_above_the_burst_waits_for_a_full_bucket() {
+        let mut limiter = RateLimiter::per_minute(2);
+        assert_eq!(limiter.try_acquire(1), Ok(()));
+        assert!(limiter.try_acquire(5).is_err());
+
+        advance(&mut limiter, 60);
+        assert_eq!(limiter.try_acquire(5), Ok(()));
+        assert!(limiter.try_acquire(1).is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! Helpers for assembling POSIX shell command lines from untrusted fragments
+
+use std::collections::BTreeMap;
+
+/// Quote a string for safe interpolation into a POSIX shell command
+/// Wraps it in single quotes, inside which nothing is special, and writes each
+/// embedded single quote as `'\''`
+pub fn shell_quote(arg: &str) -> String {
+    format!("'{}'", arg.replace('\'', "'\\''"))
+}
+
+/// Quote a remote path like `shell_quote`, but leave a leading `~` or `~/` to expand to
+/// the home directory, as it would unquoted
+pub fn quote_remote_path(path: &str) -> String {
+    match path.strip_prefix('~') {
+        Some("") => "\"$HOME\"".to_string(),
+        Some(rest) if rest.starts_with('/') => format!("\"$HOME\"{}", shell_quote(rest)),
+        _ => shell_quote(path),
+    }
+}
+
+/// Whether `name` can be used as a shell variable name
+pub fn is_shell_identifier(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|first| first.is_ascii_alphabetic() || first == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Reject environment variable names that aren't valid shell identifiers
+pub fn check_env_names(env: &BTreeMap<String, String>) -> Result<(), String> {
+    match env.keys().find(|name| !is_shell_identifier(name)) {
+        Some(name) => Err(format!(
+            "Invalid environment variable name '{}': use letters, digits and underscores, \
+             not starting with a digit",
+            name
+        )),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRICKY: &[&str] = &[
+        "",
+        "plain",
+        "two words",
+        "it's",
+        "''",
+        "$HOME",
+        "${PATH:-x}",
+        "`id`",
+        "$(id)",
+        "line one\nline two",
+        "trailing newline\n",
+        "; rm -rf / #",
+        "a\\b",
+        "\"double\"",
+        "*?[]~!&|<>",
+    ];
+
+    #[test]
+    fn quoting_escapes_single_quotes_only() {
+        assert_eq!(shell_quote(""), "''");
+        assert_eq!(shell_quote("two words"), "'two words'");
+        assert_eq!(shell_quote("it's"), "'it'\\''s'");
+        assert_eq!(shell_quote("$HOME `id`"), "'$HOME `id`'");
+        assert_eq!(shell_quote("a\nb"), "'a\nb'");
+    }
+
+    #[test]
+    fn quoted_strings_reach_the_shell_unchanged() {
+        for input in TRICKY {
+            let output = std::process::Command::new("sh")
+                .arg("-c")
+                .arg(format!("printf %s {}", shell_quote(input)))
+                .output()
+                .unwrap();
+            assert!(output.status.success(), "{input:?}");
+            assert_eq!(String::from_utf8(output.stdout).unwrap(), *input);
+        }
+    }
+
+    #[test]
+    fn remote_paths_keep_a_leading_tilde() {
+        assert_eq!(quote_remote_path("~"), "\"$HOME\"");
+        assert_eq!(quote_remote_path("~/my file"), "\"$HOME\"'/my file'");
+        assert_eq!(quote_remote_path("~user/x"), "'~user/x'");
+        assert_eq!(quote_remote_path("/sdcard/it's"), "'/sdcard/it'\\''s'");
+    }
+}
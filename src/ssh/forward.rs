@@ -0,0 +1,153 @@
+//! Local port forwards: a listener on this machine whose connections are
+//! tunnelled through the SSH session (direct-tcpip) to a host:port seen from the device
+
+use super::client::ClientHandler;
+use crate::error::{Result, SshMcpError};
+use russh::client;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::{JoinHandle, JoinSet};
+
+// How often an idle forward checks whether its session has ended
+const SESSION_CHECK_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A running forward; the listener is closed when it is dropped
+pub struct PortForward {
+    pub local_addr: SocketAddr,
+    pub remote_host: String,
+    pub remote_port: u16,
+    session: Arc<client::Handle<ClientHandler>>,
+    task: JoinHandle<()>,
+}
+
+impl PortForward {
+    /// Whether the session carrying the forward is still open
+    /// A forward outlives a reconnect only as a closed listener, so it has to be reopened
+    pub fn is_active(&self) -> bool {
+        !self.session.is_closed() && !self.task.is_finished()
+    }
+}
+
+impl Drop for PortForward {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Bind 127.0.0.1:`local_port` (0 picks a free port) and start tunnelling each
+/// accepted connection to `remote_host:remote_port` over `session`
+pub(super) async fn start(
+    session: Arc<client::Handle<ClientHandler>>,
+    local_port: u16,
+    remote_host: &str,
+    remote_port: u16,
+) -> Result<PortForward> {
+    let listener = TcpListener::bind(("127.0.0.1", local_port))
+        .await
+        .map_err(|e| {
+            let hint = if e.kind() == std::io::ErrorKind::AddrInUse {
+                " - something on this machine is already listening there. Pick another \
+                 local_port, or pass 0 to use any free port"
+            } else {
+                ""
+            };
+            SshMcpError::Other(format!(
+                "Cannot listen on 127.0.0.1:{}: {}{}",
+                local_port, e, hint
+            ))
+        })?;
+    let local_addr = listener.local_addr()?;
+
+    let task = tokio::spawn(accept_loop(
+        listener,
+        session.clone(),
+        remote_host.to_string(),
+        remote_port,
+    ));
+    Ok(PortForward {
+        local_addr,
+        remote_host: remote_host.to_string(),
+        remote_port,
+        session,
+        task,
+    })
+}
+
+/// Accept connections until the session ends, tunnelling each in its own task
+/// The tunnels belong to the loop, so aborting it closes open connections too
+async fn accept_loop(
+    listener: TcpListener,
+    session: Arc<client::Handle<ClientHandler>>,
+    remote_host: String,
+    remote_port: u16,
+) {
+    let mut check = tokio::time::interval(SESSION_CHECK_INTERVAL);
+    let mut tunnels = JoinSet::new();
+    loop {
+        let (stream, peer) = tokio::select! {
+            accepted = listener.accept() => match accepted {
+                Ok(accepted) => accepted,
+                Err(e) => {
+                    tracing::warn!("Port forward stopped accepting connections: {}", e);
+                    return;
+                }
+            },
+            _ = check.tick() => {
+                if session.is_closed() {
+                    tracing::info!(
+                        "SSH session ended, closing forward to {}:{}",
+                        remote_host,
+                        remote_port
+                    );
+                    return;
+                }
+                // Reap finished tunnels so the set doesn't grow with every connection
+                while tunnels.try_join_next().is_some() {}
+                continue;
+            }
+        };
+        tunnels.spawn(tunnel(
+            stream,
+            peer,
+            session.clone(),
+            remote_host.clone(),
+            remote_port,
+        ));
+    }
+}
+
+/// Copy bytes both ways between a local connection and a direct-tcpip channel
+async fn tunnel(
+    mut stream: TcpStream,
+    peer: SocketAddr,
+    session: Arc<client::Handle<ClientHandler>>,
+    remote_host: String,
+    remote_port: u16,
+) {
+    let channel = match session
+        .channel_open_direct_tcpip(
+            remote_host.clone(),
+            remote_port as u32,
+            peer.ip().to_string(),
+            peer.port() as u32,
+        )
+        .await
+    {
+        Ok(channel) => channel,
+        Err(e) => {
+            tracing::warn!(
+                "Device could not connect to {}:{} for a forwarded connection: {}",
+                remote_host,
+                remote_port,
+                e
+            );
+            return;
+        }
+    };
+    let mut remote = channel.into_stream();
+    if let Err(e) = tokio::io::copy_bidirectional(&mut stream, &mut remote).await {
+        tracing::debug!("Forwarded connection from {} ended: {}", peer, e);
+    }
+}
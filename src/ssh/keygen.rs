@@ -0,0 +1,160 @@
+//! Generating a key pair for the device, for provision_key
+
+use crate::error::{Result, SshMcpError};
+use russh::keys::ssh_key::{LineEnding, rand_core::OsRng};
+use russh::keys::{Algorithm, PrivateKey};
+use std::io::Write;
+use std::path::Path;
+
+/// Comment stored in both halves of generated keys, so they are recognisable in authorized_keys
+const KEY_COMMENT: &str = "mcp-android-ssh";
+
+/// Generate an ed25519 key pair, writing the private key to `path` (mode 0600) and
+/// the public key to `path.pub`
+/// Never overwrites an existing file; returns the public key as an authorized_keys line
+pub fn generate_ed25519(path: &Path) -> Result<String> {
+    for file in [path.to_path_buf(), public_key_path(path)] {
+        if file.exists() {
+            return Err(SshMcpError::Config(format!(
+                "{} already exists. Pass a different key_path, or remove it first.",
+                file.display()
+            )));
+        }
+    }
+
+    let mut key = PrivateKey::random(&mut OsRng, Algorithm::Ed25519)
+        .map_err(|e| SshMcpError::Other(format!("Failed to generate key: {}", e)))?;
+    key.set_comment(KEY_COMMENT);
+    let private = key
+        .to_openssh(LineEnding::LF)
+        .map_err(|e| SshMcpError::Other(format!("Failed to encode private key: {}", e)))?;
+    let public = key
+        .public_key()
+        .to_openssh()
+        .map_err(|e| SshMcpError::Other(format!("Failed to encode public key: {}", e)))?;
+
+    if let Some(dir) = path.parent() {
+        std::fs::create_dir_all(dir)?;
+    }
+    write_new(path, private.as_bytes(), 0o600)?;
+    if let Err(e) = write_new(
+        &public_key_path(path),
+        format!("{}\n", public).as_bytes(),
+        0o644,
+    ) {
+        let _ = std::fs::remove_file(path);
+        return Err(e);
+    }
+    Ok(public)
+}
+
+/// `path` with `.pub` appended, where ssh-keygen puts the public half
+pub fn public_key_path(path: &Path) -> std::path::PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".pub");
+    name.into()
+}
+
+/// Create `path`, failing if it exists, with `mode` permissions from the start
+fn write_new(path: &Path, contents: &[u8], mode: u32) -> Result<()> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(mode);
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    options.open(path)?.write_all(contents)?;
+    Ok(())
+}
+
+/// Shell command that appends the public key read from stdin to `~/.ssh/authorized_keys`,
+/// creating the directory and file with private permissions, and starting on a new line
+/// if the file doesn't end with one
+pub fn authorized_keys_append_command() -> &'static str {
+    "umask 077 && mkdir -p ~/.ssh && \
+     { [ ! -s ~/.ssh/authorized_keys ] || [ -z \"$(tail -c 1 ~/.ssh/authorized_keys)\" ] || echo >> ~/.ssh/authorized_keys; } && \
+     cat >> ~/.ssh/authorized_keys && chmod 600 ~/.ssh/authorized_keys"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use std::os::unix::fs::PermissionsExt;
+    use std::process::{Command, Stdio};
+
+    fn mode(path: &Path) -> u32 {
+        std::fs::metadata(path).unwrap().permissions().mode() & 0o777
+    }
+
+    /// Run the append command locally with `home` as HOME, feeding it `line`
+    fn append(home: &Path, line: &str) {
+        let mut child = Command::new("sh")
+            .arg("-c")
+            .arg(authorized_keys_append_command())
+            .env("HOME", home)
+            .stdin(Stdio::piped())
+            .spawn()
+            .unwrap();
+        child
+            .stdin
+            .take()
+            .unwrap()
+            .write_all(line.as_bytes())
+            .unwrap();
+        assert!(child.wait().unwrap().success());
+    }
+
+    #[test]
+    fn generated_keys_are_private_and_match_their_public_half() {
+        let dir = TempDir::new();
+        let path = dir.join("keys/id_test");
+        let public = generate_ed25519(&path).unwrap();
+
+        assert_eq!(mode(&path), 0o600);
+        assert!(public.starts_with("ssh-ed25519 "));
+        assert!(public.ends_with(" mcp-android-ssh"));
+        assert_eq!(
+            std::fs::read_to_string(public_key_path(&path)).unwrap(),
+            format!("{public}\n")
+        );
+        let private = PrivateKey::read_openssh_file(&path).unwrap();
+        assert_eq!(private.public_key().to_openssh().unwrap(), public);
+    }
+
+    #[test]
+    fn existing_key_files_are_never_overwritten() {
+        let dir = TempDir::new();
+        let path = dir.join("id_test");
+        std::fs::write(public_key_path(&path), "old").unwrap();
+
+        let error = generate_ed25519(&path).unwrap_err();
+        assert!(error.to_string().contains("already exists"), "{error}");
+        assert!(!path.exists());
+        assert_eq!(
+            std::fs::read_to_string(public_key_path(&path)).unwrap(),
+            "old"
+        );
+    }
+
+    #[test]
+    fn public_keys_are_appended_on_their_own_line() {
+        let home = TempDir::new();
+        append(home.path(), "ssh-ed25519 AAAA first\n");
+        let authorized_keys = home.join(".ssh/authorized_keys");
+        assert_eq!(mode(&home.join(".ssh")), 0o700);
+        assert_eq!(mode(&authorized_keys), 0o600);
+
+        // A file edited by hand may lack the final newline
+        std::fs::write(&authorized_keys, "ssh-ed25519 AAAA first").unwrap();
+        append(home.path(), "ssh-ed25519 BBBB second\n");
+        append(home.path(), "ssh-ed25519 CCCC third\n");
+        assert_eq!(
+            std::fs::read_to_string(&authorized_keys).unwrap(),
+            "ssh-ed25519 AAAA first\nssh-ed25519 BBBB second\nssh-ed25519 CCCC third\n"
+        );
+    }
+}
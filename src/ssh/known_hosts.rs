@@ -0,0 +1,180 @@
+//! Host key verification against a known_hosts file (trust on first use)
+
+use crate::error::{Result, SshMcpError};
+use russh::keys::{self, HashAlg, PublicKey};
+use std::path::Path;
+
+/// Verify a server's host key against the known_hosts file at `path`
+///
+/// A key matching one recorded for the host is accepted. An unknown host is
+/// recorded and accepted (TOFU) unless `strict` is set, in which case it is
+/// rejected. A host with recorded keys that the key doesn't match is always
+/// rejected, including when the recorded key is of another type.
+pub fn verify_host_key(
+    host: &str,
+    port: u16,
+    key: &PublicKey,
+    path: &Path,
+    strict: bool,
+) -> Result<()> {
+    let fingerprint = key.fingerprint(HashAlg::Sha256);
+
+    let recorded = keys::known_hosts::known_host_keys_path(host, port, path).map_err(|e| {
+        SshMcpError::SshConnection(format!(
+            "Failed to read known_hosts file {}: {}",
+            path.display(),
+            e
+        ))
+    })?;
+
+    if recorded.iter().any(|(_, recorded)| recorded == key) {
+        tracing::info!(
+            "Host key for {}:{} matches known_hosts: {} ({})",
+            host,
+            port,
+            fingerprint,
+            key.algorithm()
+        );
+        return Ok(());
+    }
+
+    if let Some((line, pinned)) = recorded.first() {
+        return Err(SshMcpError::SshConnection(format!(
+            "Host key changed\n\n\
+             The host key for {}:{} does not match the {} key recorded at line {} of {}.\n\
+             Server key fingerprint: {} ({})\n\n\
+             This can mean someone is intercepting the connection. If the change is expected \
+             (e.g. Termux or openssh was reinstalled), verify the new fingerprint on the device \
+             and remove line {} from the known_hosts file.",
+            host,
+            port,
+            pinned.algorithm(),
+            line,
+            path.display(),
+            fingerprint,
+            key.algorithm(),
+            line
+        )));
+    }
+
+    if strict {
+        return Err(SshMcpError::SshConnection(format!(
+            "Unknown host key\n\n\
+             {}:{} is not in {} and strict_host_key_checking is enabled.\n\
+             Server key fingerprint: {} ({})\n\n\
+             Verify the fingerprint on the device (in Termux: \
+             ssh-keygen -lf $PREFIX/etc/ssh/ssh_host_ed25519_key.pub), then either add it to \
+             known_hosts or connect once with strict_host_key_checking = false.",
+            host,
+            port,
+            path.display(),
+            fingerprint,
+            key.algorithm()
+        )));
+    }
+
+    tracing::warn!(
+        "Trusting new host key for {}:{} on first use: {} ({})",
+        host,
+        port,
+        fingerprint,
+        key.algorithm()
+    );
+    if let Err(e) = keys::known_hosts::learn_known_hosts_path(host, port, key, path) {
+        tracing::warn!("Failed to record host key in {}: {}", path.display(), e);
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::TempDir;
+    use russh::keys::ssh_key::rand_core::OsRng;
+    use russh::keys::{Algorithm, EcdsaCurve, PrivateKey};
+
+    fn host_key(algorithm: Algorithm) -> PublicKey {
+        PrivateKey::random(&mut OsRng, algorithm)
+            .unwrap()
+            .public_key()
+            .clone()
+    }
+
+    fn ed25519() -> PublicKey {
+        host_key(Algorithm::Ed25519)
+    }
+
+    fn error_text(result: Result<()>) -> String {
+        result.unwrap_err().to_string()
+    }
+
+    #[test]
+    fn accepts_a_matching_key() {
+        let dir = TempDir::new();
+        let path = dir.join("known_hosts");
+        let key = ed25519();
+        keys::known_hosts::learn_known_hosts_path("phone", 8022, &key, &path).unwrap();
+        verify_host_key("phone", 8022, &key, &path, true).unwrap();
+    }
+
+    #[test]
+    fn learns_an_unknown_host_on_first_use() {
+        let dir = TempDir::new();
+        let path = dir.join("known_hosts");
+        let key = ed25519();
+        verify_host_key("phone", 8022, &key, &path, false).unwrap();
+        let recorded = keys::known_hosts::known_host_keys_path("phone", 8022, &path).unwrap();
+        assert_eq!(recorded.len(), 1);
+        assert_eq!(recorded[0].1, key);
+        verify_host_key("phone", 8022, &key, &path, true).unwrap();
+    }
+
+    #[test]
+    fn strict_checking_rejects_an_unknown_host() {
+        let dir = TempDir::new();
+        let path = dir.join("known_hosts");
+        let err = error_text(verify_host_key("phone", 8022, &ed25519(), &path, true));
+        assert!(err.contains("Unknown host key"), "{err}");
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn rejects_a_changed_key() {
+        let dir = TempDir::new();
+        let path = dir.join("known_hosts");
+        keys::known_hosts::learn_known_hosts_path("phone", 8022, &ed25519(), &path).unwrap();
+        let err = error_text(verify_host_key("phone", 8022, &ed25519(), &path, false));
+        assert!(err.contains("Host key changed"), "{err}");
+        assert!(err.contains("ssh-ed25519 key recorded at line"), "{err}");
+    }
+
+    #[test]
+    fn rejects_a_key_of_another_algorithm() {
+        let dir = TempDir::new();
+        let path = dir.join("known_hosts");
+        keys::known_hosts::learn_known_hosts_path("phone", 8022, &ed25519(), &path).unwrap();
+        let ecdsa = host_key(Algorithm::Ecdsa {
+            curve: EcdsaCurve::NistP256,
+        });
+        let err = error_text(verify_host_key("phone", 8022, &ecdsa, &path, false));
+        assert!(err.contains("Host key changed"), "{err}");
+        assert!(err.contains("ssh-ed25519 key recorded at line"), "{err}");
+        let recorded = keys::known_hosts::known_host_keys_path("phone", 8022, &path).unwrap();
+        assert_eq!(recorded.len(), 1);
+    }
+
+    #[test]
+    fn fingerprint_matches_the_openssh_format() {
+        use base64::Engine;
+        use sha2::{Digest, Sha256};
+
+        let key = ed25519();
+        let fingerprint = key.fingerprint(HashAlg::Sha256).to_string();
+        // What ssh-keygen -l prints: unpadded base64 of the SHA-256 of the key blob
+        let digest = Sha256::digest(key.to_bytes().unwrap());
+        let expected = base64::engine::general_purpose::STANDARD_NO_PAD.encode(digest);
+        assert_eq!(fingerprint, format!("SHA256:{}", expected));
+        assert_eq!(fingerprint.len(), "SHA256:".len() + 43);
+        assert!(!fingerprint.ends_with('='));
+    }
+}
@@ -1,3 +1,7 @@
 pub mod client;
+pub mod forward;
+pub mod keygen;
+pub mod known_hosts;
+pub mod shell;
 
-pub use client::SshClient;
+pub use client::{CommandResult, ExecOptions, SshClient};
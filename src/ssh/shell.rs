@@ -0,0 +1,238 @@
+//! Persistent shells: one PTY channel kept open across tool calls, so `cd`, `export`
+//! and shell functions carry over from one command to the next
+
+use crate::error::{Result, SshMcpError};
+use crate::stats::ConnectionStats;
+use russh::keys::ssh_key::rand_core::{OsRng, RngCore};
+use russh::{Channel, ChannelMsg, client};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// Sent before anything else: no echo of what is written, no prompts and, in bash, no
+/// line editing (which adds escape sequences), so the channel carries only what commands print
+const SHELL_SETUP: &str = "stty -echo 2>/dev/null; \
+     [ -n \"$BASH_VERSION\" ] && set +o emacs +o vi; \
+     PS1=''; PS2=''; unset PROMPT_COMMAND\n";
+
+/// How long the shell gets to start, and to settle again after a command is interrupted
+const SYNC_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An open shell, owned by the service between shell_send calls
+pub struct ShellSession {
+    channel: Channel<client::Msg>,
+    /// Printed after each command with its exit status; random, so no output can fake it
+    sentinel: String,
+    stats: Arc<ConnectionStats>,
+    pub commands_run: u32,
+    closed: bool,
+}
+
+/// What one command printed, stdout and stderr together as the PTY delivered them
+#[derive(Debug)]
+pub struct ShellOutput {
+    pub output: String,
+    /// `$?` after the command; None if the shell exited before reporting it
+    pub exit_code: Option<i32>,
+}
+
+/// How waiting for the sentinel ended, when it didn't arrive
+enum ReadEnd {
+    TimedOut(String),
+    Closed(String, Option<i32>),
+}
+
+impl ShellSession {
+    /// Start the shell on `channel` and wait until it is ready for commands
+    /// Anything the login prints first, such as the Termux motd, is discarded
+    pub(super) async fn start(
+        channel: Channel<client::Msg>,
+        stats: Arc<ConnectionStats>,
+    ) -> Result<Self> {
+        let mut shell = Self {
+            channel,
+            sentinel: format!("{:016x}", OsRng.next_u64()),
+            stats,
+            commands_run: 0,
+            closed: false,
+        };
+        shell.write(SHELL_SETUP).await?;
+        shell.write(&shell.marker_command()).await?;
+        match shell.read_until_marker(SYNC_TIMEOUT).await {
+            Ok(_) => Ok(shell),
+            Err(ReadEnd::TimedOut(_)) => Err(SshMcpError::Timeout(format!(
+                "The shell did not become ready within {} seconds",
+                SYNC_TIMEOUT.as_secs()
+            ))),
+            Err(ReadEnd::Closed(output, _)) => Err(SshMcpError::CommandExecution(format!(
+                "The shell exited as soon as it started: {}",
+                output.trim()
+            ))),
+        }
+    }
+
+    /// Whether the shell has exited or its channel was lost
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+
+    /// Run `command` in the shell and return its output once it finishes
+    /// A command still running at `timeout` is interrupted with Ctrl-C and the shell
+    /// is kept; if it doesn't settle after that, the shell is closed
+    pub async fn send(&mut self, command: &str, timeout: Duration) -> Result<ShellOutput> {
+        if self.closed {
+            return Err(SshMcpError::CommandExecution(
+                "The shell has already exited".to_string(),
+            ));
+        }
+        let started = Instant::now();
+        self.stats.record_command();
+        self.write(&format!("{}\n{}", command, self.marker_command()))
+            .await?;
+        self.commands_run += 1;
+
+        match self.read_until_marker(timeout).await {
+            Ok(output) => Ok(output),
+            Err(ReadEnd::Closed(output, exit_code)) => Ok(ShellOutput { output, exit_code }),
+            Err(ReadEnd::TimedOut(output)) => {
+                self.stats.record_timeout();
+                // The line discipline turns ETX into SIGINT and drops the queued marker,
+                // so it is sent again to find where the shell is
+                self.write("\x03").await?;
+                self.write(&self.marker_command()).await?;
+                let state = match self.read_until_marker(SYNC_TIMEOUT).await {
+                    Ok(_) => "it was interrupted with Ctrl-C and the shell is still open",
+                    Err(_) => {
+                        self.close().await;
+                        "it did not stop on Ctrl-C, so the shell was closed"
+                    }
+                };
+                Err(SshMcpError::Timeout(format!(
+                    "Command timed out after {} seconds ({:.2}s elapsed); {}. Output so far:\n{}",
+                    timeout.as_secs(),
+                    started.elapsed().as_secs_f64(),
+                    state,
+                    output
+                )))
+            }
+        }
+    }
+
+    /// End the shell and its channel
+    pub async fn close(&mut self) {
+        self.closed = true;
+        let _ = self.channel.eof().await;
+        let _ = self.channel.close().await;
+    }
+
+    /// Prints `<sentinel>:<status>` on a line of its own
+    /// The sentinel is split in two so an echo of this line can't be mistaken for it
+    fn marker_command(&self) -> String {
+        format!(
+            "printf '\\n%s%s:%s\\n' {} {} \"$?\"\n",
+            &self.sentinel[..8],
+            &self.sentinel[8..]
+        )
+    }
+
+    async fn write(&mut self, text: &str) -> Result<()> {
+        self.stats.record_bytes(text.len(), 0);
+        if let Err(e) = self.channel.data(text.as_bytes()).await {
+            self.closed = true;
+            return Err(SshMcpError::CommandExecution(format!(
+                "Failed to write to the shell: {}",
+                e
+            )));
+        }
+        Ok(())
+    }
+
+    /// Collect output until the sentinel line arrives, returning what came before it
+    async fn read_until_marker(
+        &mut self,
+        limit: Duration,
+    ) -> std::result::Result<ShellOutput, ReadEnd> {
+        let deadline = tokio::time::Instant::now() + limit;
+        let mut received = Vec::new();
+        let mut exit_status = None;
+        loop {
+            let msg = match tokio::time::timeout_at(deadline, self.channel.wait()).await {
+                Ok(msg) => msg,
+                Err(_) => return Err(ReadEnd::TimedOut(decode(&received))),
+            };
+            match msg {
+                Some(ChannelMsg::Data { data }) | Some(ChannelMsg::ExtendedData { data, .. }) => {
+                    self.stats.record_bytes(0, data.len());
+                    // Only the new bytes need searching, and the tail of the old ones in case
+                    // the marker line started there but hadn't ended yet
+                    let scan_from = received.len().saturating_sub(self.sentinel.len() + 32);
+                    received.extend_from_slice(&data);
+                    if let Some(output) = self.take_marker(&received, scan_from) {
+                        return Ok(output);
+                    }
+                }
+                Some(ChannelMsg::ExitStatus { exit_status: code }) => {
+                    exit_status = Some(code as i32);
+                }
+                Some(ChannelMsg::Close) | None => {
+                    self.closed = true;
+                    return Err(ReadEnd::Closed(decode(&received), exit_status));
+                }
+                Some(_) => {}
+            }
+        }
+    }
+
+    /// Split off the output before a complete sentinel line, with the exit status it reports
+    /// The sentinel is searched for from `scan_from` on
+    fn take_marker(&self, received: &[u8], scan_from: usize) -> Option<ShellOutput> {
+        let sentinel = self.sentinel.as_bytes();
+        let start = scan_from
+            + received[scan_from..]
+                .windows(sentinel.len())
+                .position(|window| window == sentinel)?;
+        let line = &received[start + sentinel.len()..];
+        let status = line.strip_prefix(b":")?;
+        let status = &status[..status.iter().position(|&b| b == b'\n')?];
+        // The marker's leading newline ends output that didn't end its last line
+        let output = decode(&received[..start]);
+        let output = output.strip_suffix('\n').unwrap_or(&output);
+        Some(ShellOutput {
+            output: output.to_string(),
+            exit_code: String::from_utf8_lossy(status).trim().parse().ok(),
+        })
+    }
+}
+
+/// Read what a freshly started login shell on `channel` prints before any input, such as the
+/// motd and the first prompt, then close it
+/// Reading stops once nothing has arrived for `quiet`, or at `limit`
+pub(super) async fn read_login_output(
+    channel: Channel<client::Msg>,
+    stats: &ConnectionStats,
+    quiet: Duration,
+    limit: Duration,
+) -> String {
+    let mut channel = channel;
+    let deadline = tokio::time::Instant::now() + limit;
+    let mut received = Vec::new();
+    loop {
+        let wait = deadline.min(tokio::time::Instant::now() + quiet);
+        match tokio::time::timeout_at(wait, channel.wait()).await {
+            Ok(Some(ChannelMsg::Data { data }))
+            | Ok(Some(ChannelMsg::ExtendedData { data, .. })) => {
+                stats.record_bytes(0, data.len());
+                received.extend_from_slice(&data);
+            }
+            Ok(Some(ChannelMsg::Close)) | Ok(None) | Err(_) => break,
+            Ok(Some(_)) => {}
+        }
+    }
+    let _ = channel.eof().await;
+    let _ = channel.close().await;
+    decode(&received)
+}
+
+/// Output as text, with the PTY's CRLF line endings turned back into LF
+fn decode(bytes: &[u8]) -> String {
+    String::from_utf8_lossy(bytes).replace("\r\n", "\n")
+}
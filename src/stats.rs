@@ -0,0 +1,136 @@
+//! Counters for judging connection quality, shared by every client the server creates
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// Totals since the server started, updated as commands run and sessions connect
+#[derive(Debug)]
+pub struct ConnectionStats {
+    started: Instant,
+    commands: AtomicU64,
+    failures: AtomicU64,
+    timeouts: AtomicU64,
+    connects: AtomicU64,
+    reconnects: AtomicU64,
+    connect_failures: AtomicU64,
+    bytes_sent: AtomicU64,
+    bytes_received: AtomicU64,
+}
+
+/// A copy of the counters at one moment, as reported by the stats tool
+#[derive(Debug, Serialize)]
+pub struct StatsSnapshot {
+    pub uptime_secs: u64,
+    /// Commands sent to a device, whatever their outcome
+    pub commands: u64,
+    /// Commands that ended in an SSH or channel error rather than an exit code
+    /// A non-zero exit code isn't a failure
+    pub failures: u64,
+    /// Commands stopped by their timeout; not counted in failures
+    pub timeouts: u64,
+    /// Sessions opened, including reconnects
+    pub connects: u64,
+    /// Sessions opened again after an earlier one closed or dropped
+    pub reconnects: u64,
+    /// Connection attempts that failed, counting each retry
+    pub connect_failures: u64,
+    /// Command lines and stdin written to devices
+    pub bytes_sent: u64,
+    /// stdout and stderr read back from devices
+    pub bytes_received: u64,
+}
+
+impl Default for ConnectionStats {
+    fn default() -> Self {
+        Self {
+            started: Instant::now(),
+            commands: AtomicU64::new(0),
+            failures: AtomicU64::new(0),
+            timeouts: AtomicU64::new(0),
+            connects: AtomicU64::new(0),
+            reconnects: AtomicU64::new(0),
+            connect_failures: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+        }
+    }
+}
+
+impl ConnectionStats {
+    pub fn record_command(&self) {
+        self.commands.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_failure(&self) {
+        self.failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_timeout(&self) {
+        self.timeouts.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count an opened session, as a reconnect if the client had connected before
+    pub fn record_connect(&self, reconnect: bool) {
+        self.connects.fetch_add(1, Ordering::Relaxed);
+        if reconnect {
+            self.reconnects.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub fn record_connect_failure(&self) {
+        self.connect_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub fn record_bytes(&self, sent: usize, received: usize) {
+        self.bytes_sent.fetch_add(sent as u64, Ordering::Relaxed);
+        self.bytes_received
+            .fetch_add(received as u64, Ordering::Relaxed);
+    }
+
+    pub fn snapshot(&self) -> StatsSnapshot {
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        StatsSnapshot {
+            uptime_secs: self.started.elapsed().as_secs(),
+            commands: load(&self.commands),
+            failures: load(&self.failures),
+            timeouts: load(&self.timeouts),
+            connects: load(&self.connects),
+            reconnects: load(&self.reconnects),
+            connect_failures: load(&self.connect_failures),
+            bytes_sent: load(&self.bytes_sent),
+            bytes_received: load(&self.bytes_received),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters_add_up_in_the_snapshot() {
+        let stats = ConnectionStats::default();
+        stats.record_connect(false);
+        for _ in 0..3 {
+            stats.record_command();
+        }
+        stats.record_failure();
+        stats.record_timeout();
+        stats.record_connect_failure();
+        stats.record_connect(true);
+        stats.record_bytes(10, 200);
+        stats.record_bytes(5, 0);
+
+        let snapshot = stats.snapshot();
+        assert_eq!(snapshot.commands, 3);
+        assert_eq!(snapshot.failures, 1);
+        assert_eq!(snapshot.timeouts, 1);
+        assert_eq!(snapshot.connects, 2);
+        assert_eq!(snapshot.reconnects, 1);
+        assert_eq!(snapshot.connect_failures, 1);
+        assert_eq!(snapshot.bytes_sent, 15);
+        assert_eq!(snapshot.bytes_received, 200);
+        assert_eq!(snapshot.uptime_secs, 0);
+    }
+}
@@ -0,0 +1,111 @@
+//! A minimal ssh-agent on a Unix socket, holding keys given by the test
+//!
+//! Only listing identities and signing are answered; every other request fails.
+
+use super::TempDir;
+use russh::keys::PrivateKey;
+use russh::keys::signature::Signer;
+use russh::keys::ssh_encoding::Encode;
+use russh::keys::ssh_key::Signature;
+use std::path::PathBuf;
+use std::sync::Arc;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{UnixListener, UnixStream};
+use tokio::task::JoinHandle;
+
+const FAILURE: u8 = 5;
+const REQUEST_IDENTITIES: u8 = 11;
+const IDENTITIES_ANSWER: u8 = 12;
+const SIGN_REQUEST: u8 = 13;
+const SIGN_RESPONSE: u8 = 14;
+
+/// A running agent; it stops accepting connections when dropped
+pub struct TestAgent {
+    /// What SSH_AUTH_SOCK should point at
+    pub socket: PathBuf,
+    _dir: TempDir,
+    task: JoinHandle<()>,
+}
+
+impl TestAgent {
+    pub fn start(keys: Vec<PrivateKey>) -> Self {
+        let dir = TempDir::new();
+        let socket = dir.join("agent.sock");
+        let listener = UnixListener::bind(&socket).unwrap();
+        let keys = Arc::new(keys);
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                tokio::spawn(serve(stream, keys.clone()));
+            }
+        });
+        TestAgent {
+            socket,
+            _dir: dir,
+            task,
+        }
+    }
+}
+
+impl Drop for TestAgent {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+async fn serve(mut stream: UnixStream, keys: Arc<Vec<PrivateKey>>) {
+    loop {
+        let Ok(len) = stream.read_u32().await else {
+            return;
+        };
+        let mut request = vec![0; len as usize];
+        if stream.read_exact(&mut request).await.is_err() {
+            return;
+        }
+        let reply = match request.split_first() {
+            Some((&REQUEST_IDENTITIES, _)) => identities(&keys),
+            Some((&SIGN_REQUEST, body)) => sign(&keys, body).unwrap_or_else(|| vec![FAILURE]),
+            _ => vec![FAILURE],
+        };
+        let mut framed = (reply.len() as u32).to_be_bytes().to_vec();
+        framed.extend(reply);
+        if stream.write_all(&framed).await.is_err() {
+            return;
+        }
+    }
+}
+
+fn identities(keys: &[PrivateKey]) -> Vec<u8> {
+    let mut reply = vec![IDENTITIES_ANSWER];
+    reply.extend((keys.len() as u32).to_be_bytes());
+    for key in keys {
+        put_string(&mut reply, &key.public_key().to_bytes().unwrap());
+        put_string(&mut reply, key.comment().as_bytes());
+    }
+    reply
+}
+
+/// Sign the data in a SIGN_REQUEST body with the key whose public blob it names
+fn sign(keys: &[PrivateKey], body: &[u8]) -> Option<Vec<u8>> {
+    let (blob, rest) = take_string(body)?;
+    let (data, _flags) = take_string(rest)?;
+    let key = keys
+        .iter()
+        .find(|key| key.public_key().to_bytes().is_ok_and(|b| b == blob))?;
+    let signature: Signature = key.try_sign(data).ok()?;
+    let mut encoded = Vec::new();
+    signature.encode(&mut encoded).ok()?;
+    let mut reply = vec![SIGN_RESPONSE];
+    put_string(&mut reply, &encoded);
+    Some(reply)
+}
+
+fn put_string(out: &mut Vec<u8>, bytes: &[u8]) {
+    out.extend((bytes.len() as u32).to_be_bytes());
+    out.extend(bytes);
+}
+
+fn take_string(input: &[u8]) -> Option<(&[u8], &[u8])> {
+    let len = u32::from_be_bytes(input.get(..4)?.try_into().ok()?) as usize;
+    let rest = &input[4..];
+    Some((rest.get(..len)?, &rest[len..]))
+}
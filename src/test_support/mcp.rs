@@ -0,0 +1,69 @@
+//! A stand-in MCP client, for calling tools that take a RequestContext
+//!
+//! The server side runs over an in-memory pipe without the initialize handshake;
+//! whatever the server sends back, such as progress notifications, is collected.
+
+use crate::tools::AndroidSshService;
+use rmcp::RoleServer;
+use rmcp::model::{NumberOrString, ProgressToken, RequestId};
+use rmcp::service::{RequestContext, RunningService, serve_directly};
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::sync::mpsc::{UnboundedReceiver, unbounded_channel};
+
+/// How long `progress_messages` waits for another notification before giving up
+const QUIET_PERIOD: Duration = Duration::from_millis(200);
+
+pub struct TestClient {
+    running: RunningService<RoleServer, AndroidSshService>,
+    received: UnboundedReceiver<serde_json::Value>,
+}
+
+impl TestClient {
+    pub fn start() -> Self {
+        let (server_end, client_end) = tokio::io::duplex(64 * 1024);
+        let running = serve_directly(AndroidSshService::new(None), server_end, None);
+        let (sender, received) = unbounded_channel();
+        tokio::spawn(async move {
+            let mut lines = BufReader::new(client_end).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                if let Ok(message) = serde_json::from_str(&line) {
+                    let _ = sender.send(message);
+                }
+            }
+        });
+        TestClient { running, received }
+    }
+
+    /// Context for one tool call, asking for progress notifications
+    pub fn context(&self) -> RequestContext<RoleServer> {
+        let mut context = RequestContext {
+            ct: Default::default(),
+            id: RequestId::Number(1),
+            meta: Default::default(),
+            extensions: Default::default(),
+            peer: self.running.peer().clone(),
+        };
+        context
+            .meta
+            .set_progress_token(ProgressToken(NumberOrString::Number(1)));
+        context
+    }
+
+    /// The messages of the progress notifications received since the last call
+    pub async fn progress_messages(&mut self) -> Vec<String> {
+        let mut messages = Vec::new();
+        while let Ok(Some(message)) = tokio::time::timeout(QUIET_PERIOD, self.received.recv()).await
+        {
+            if message["method"] == "notifications/progress" {
+                messages.push(
+                    message["params"]["message"]
+                        .as_str()
+                        .unwrap_or("")
+                        .to_string(),
+                );
+            }
+        }
+        messages
+    }
+}
@@ -0,0 +1,678 @@
+//! An SSH server on loopback standing in for the device
+//!
+//! Commands run in a local `sh` with a temporary home directory, SFTP serves the local
+//! filesystem, and direct-tcpip channels connect out from this machine. A requested PTY
+//! is imitated by sending stderr down stdout, turning newlines into CRLF, and turning
+//! Ctrl-C into SIGINT for the command's process group. Commands find stand-ins for
+//! device programs such as `su` first on PATH, once a test adds them with `fake_command`.
+
+use super::TempDir;
+use crate::config::{Config, Redacted};
+use russh::keys::ssh_key::rand_core::OsRng;
+use russh::keys::{Algorithm, PrivateKey, PublicKey};
+use russh::server::{self, Auth, Handle, Msg, Session};
+use russh::{Channel, ChannelId, ChannelMsg, CryptoVec, Disconnect, Sig};
+use russh_sftp::protocol::{
+    Attrs, Data, File, FileAttributes, Handle as SftpHandle, Name, OpenFlags, Status, StatusCode,
+};
+use std::collections::HashMap;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::os::unix::process::ExitStatusExt;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::task::JoinHandle;
+
+pub const USER: &str = "tester";
+pub const PASSWORD: &str = "secret";
+
+/// A running server; it stops accepting connections when dropped
+pub struct TestSshd {
+    pub port: u16,
+    /// HOME for commands, and where relative SFTP paths start
+    pub home: TempDir,
+    /// Holds the known_hosts file of the configs handed out
+    client_dir: TempDir,
+    /// Put first on PATH for commands, holding the programs added by `fake_command`
+    bin: TempDir,
+    task: JoinHandle<()>,
+    state: Arc<State>,
+}
+
+struct State {
+    home: PathBuf,
+    bin: PathBuf,
+    /// Keys clients have signed in with, or tried to, in order
+    offered_keys: std::sync::Mutex<Vec<PublicKey>>,
+    /// Session channels opened, one per command, shell or SFTP session
+    channels: AtomicUsize,
+    /// TCP connections accepted
+    connections: AtomicUsize,
+    /// Close command channels without sending an exit status or signal
+    withhold_exit_status: AtomicBool,
+    /// Disconnect instead of running the next command
+    drop_next_command: AtomicBool,
+    /// Sent before authentication, and printed when a shell starts, like an sshd Banner and motd
+    banner: std::sync::Mutex<Option<(String, String)>>,
+}
+
+impl TestSshd {
+    pub async fn start() -> Self {
+        Self::start_with(|_| {}).await
+    }
+
+    /// Like `start`, letting the test adjust the server's settings first
+    pub async fn start_with(configure: impl FnOnce(&mut server::Config)) -> Self {
+        let home = TempDir::new();
+        let bin = TempDir::new();
+        let state = Arc::new(State {
+            home: home.path().to_path_buf(),
+            bin: bin.path().to_path_buf(),
+            offered_keys: Default::default(),
+            channels: AtomicUsize::new(0),
+            connections: AtomicUsize::new(0),
+            withhold_exit_status: AtomicBool::new(false),
+            drop_next_command: AtomicBool::new(false),
+            banner: Default::default(),
+        });
+        let mut config = server::Config {
+            keys: vec![PrivateKey::random(&mut OsRng, Algorithm::Ed25519).unwrap()],
+            auth_rejection_time: Duration::ZERO,
+            auth_rejection_time_initial: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        configure(&mut config);
+        let config = Arc::new(config);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        let accepting = state.clone();
+        let task = tokio::spawn(async move {
+            while let Ok((stream, _)) = listener.accept().await {
+                accepting.connections.fetch_add(1, Ordering::SeqCst);
+                let handler = Connection {
+                    state: accepting.clone(),
+                };
+                let config = config.clone();
+                tokio::spawn(async move {
+                    if let Ok(session) = server::run_stream(config, stream, handler).await {
+                        let _ = session.await;
+                    }
+                });
+            }
+        });
+
+        TestSshd {
+            port,
+            home,
+            client_dir: TempDir::new(),
+            bin,
+            task,
+            state,
+        }
+    }
+
+    /// Let `key` sign in, by adding it to `~/.ssh/authorized_keys` in the server's home
+    pub fn authorize_key(&self, key: &PublicKey) {
+        let ssh_dir = self.home.join(".ssh");
+        std::fs::create_dir_all(&ssh_dir).unwrap();
+        let mut file = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(ssh_dir.join("authorized_keys"))
+            .unwrap();
+        writeln!(file, "{}", key.to_openssh().unwrap()).unwrap();
+    }
+
+    /// Install a shell script as the program `name`, found before the real one on PATH
+    pub fn fake_command(&self, name: &str, script: &str) {
+        let path = self.bin.join(name);
+        std::fs::write(&path, format!("#!/bin/sh\n{}\n", script)).unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+    }
+
+    /// Close every later command's channel without an exit status, like a device that drops it
+    pub fn withhold_exit_status(&self) {
+        self.state
+            .withhold_exit_status
+            .store(true, Ordering::SeqCst);
+    }
+
+    /// Drop the connection when the next command starts, like a network blip mid-command
+    pub fn drop_next_command(&self) {
+        self.state.drop_next_command.store(true, Ordering::SeqCst);
+    }
+
+    /// Send `banner` to clients before they authenticate, and print `motd` at the start of
+    /// every shell; commands see neither, as with OpenSSH
+    pub fn set_banner(&self, banner: &str, motd: &str) {
+        *self.state.banner.lock().unwrap() = Some((banner.to_string(), motd.to_string()));
+    }
+
+    /// How many session channels clients have opened
+    pub fn channels_opened(&self) -> usize {
+        self.state.channels.load(Ordering::SeqCst)
+    }
+
+    /// How many connections clients have made
+    pub fn connections(&self) -> usize {
+        self.state.connections.load(Ordering::SeqCst)
+    }
+
+    /// Keys clients have signed in with, or tried to, in order
+    pub fn offered_keys(&self) -> Vec<PublicKey> {
+        self.state.offered_keys.lock().unwrap().clone()
+    }
+
+    /// A config that logs in to this server with a password and trusts its key on first use
+    pub fn config(&self) -> Config {
+        Config {
+            host: "127.0.0.1".to_string(),
+            port: self.port,
+            user: USER.to_string(),
+            password: Some(Redacted::from(PASSWORD.to_string())),
+            known_hosts_path: Some(self.client_dir.join("known_hosts").display().to_string()),
+            connect_retries: Some(1),
+            ..Default::default()
+        }
+    }
+}
+
+impl Drop for TestSshd {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+struct Connection {
+    state: Arc<State>,
+}
+
+impl server::Handler for Connection {
+    type Error = russh::Error;
+
+    async fn authentication_banner(&mut self) -> Result<Option<String>, Self::Error> {
+        let banner = self.state.banner.lock().unwrap().clone();
+        Ok(banner.map(|(banner, _)| banner))
+    }
+
+    async fn auth_password(&mut self, user: &str, password: &str) -> Result<Auth, Self::Error> {
+        Ok(if user == USER && password == PASSWORD {
+            Auth::Accept
+        } else {
+            Auth::reject()
+        })
+    }
+
+    async fn auth_publickey(&mut self, user: &str, key: &PublicKey) -> Result<Auth, Self::Error> {
+        self.state.offered_keys.lock().unwrap().push(key.clone());
+        let authorized = std::fs::read_to_string(self.state.home.join(".ssh/authorized_keys"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| PublicKey::from_openssh(line).ok())
+            .any(|authorized| authorized.key_data() == key.key_data());
+        Ok(if user == USER && authorized {
+            Auth::Accept
+        } else {
+            Auth::reject()
+        })
+    }
+
+    async fn channel_open_direct_tcpip(
+        &mut self,
+        channel: Channel<Msg>,
+        host_to_connect: &str,
+        port_to_connect: u32,
+        _originator_address: &str,
+        _originator_port: u32,
+        _session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        let Ok(mut target) = TcpStream::connect((host_to_connect, port_to_connect as u16)).await
+        else {
+            return Ok(false);
+        };
+        tokio::spawn(async move {
+            let mut channel = channel.into_stream();
+            let _ = tokio::io::copy_bidirectional(&mut channel, &mut target).await;
+        });
+        Ok(true)
+    }
+
+    async fn channel_open_session(
+        &mut self,
+        channel: Channel<Msg>,
+        session: &mut Session,
+    ) -> Result<bool, Self::Error> {
+        self.state.channels.fetch_add(1, Ordering::SeqCst);
+        tokio::spawn(serve_session(channel, session.handle(), self.state.clone()));
+        Ok(true)
+    }
+}
+
+/// Answer the requests on a session channel until one of them starts something
+async fn serve_session(mut channel: Channel<Msg>, handle: Handle, state: Arc<State>) {
+    let id = channel.id();
+    let mut pty = false;
+    while let Some(msg) = channel.wait().await {
+        match msg {
+            ChannelMsg::RequestPty { .. } => {
+                pty = true;
+                let _ = handle.channel_success(id).await;
+            }
+            ChannelMsg::Exec { command, .. } => {
+                let command = String::from_utf8_lossy(&command).into_owned();
+                let _ = handle.channel_success(id).await;
+                if state.drop_next_command.swap(false, Ordering::SeqCst) {
+                    let _ = handle
+                        .disconnect(Disconnect::ConnectionLost, String::new(), String::new())
+                        .await;
+                    return;
+                }
+                return run_process(channel, handle, &state, Some(&command), pty).await;
+            }
+            ChannelMsg::RequestShell { .. } => {
+                let _ = handle.channel_success(id).await;
+                return run_process(channel, handle, &state, None, pty).await;
+            }
+            ChannelMsg::RequestSubsystem { name, .. } if name == "sftp" => {
+                let _ = handle.channel_success(id).await;
+                let sftp = LocalSftp {
+                    home: state.home.clone(),
+                    ..Default::default()
+                };
+                return russh_sftp::server::run(channel.into_stream(), sftp).await;
+            }
+            ChannelMsg::RequestSubsystem { .. } => {
+                let _ = handle.channel_failure(id).await;
+            }
+            _ => {}
+        }
+    }
+}
+
+/// Run `command` with `sh -c`, or an interactive `sh` for a shell, until it exits
+async fn run_process(
+    mut channel: Channel<Msg>,
+    handle: Handle,
+    state: &State,
+    command: Option<&str>,
+    pty: bool,
+) {
+    let id = channel.id();
+    let mut process = tokio::process::Command::new("sh");
+    match command {
+        Some(command) => process.arg("-c").arg(command),
+        None => process.arg("-i"),
+    };
+    let path = std::env::var("PATH").unwrap_or_default();
+    process
+        .current_dir(&state.home)
+        .env("HOME", &state.home)
+        .env("PATH", format!("{}:{}", state.bin.display(), path))
+        .stdin(Stdio::piped())
+        .process_group(0)
+        .kill_on_drop(true);
+    let merged = if pty {
+        let (reader, writer) = std::io::pipe().unwrap();
+        process.stdout(writer.try_clone().unwrap()).stderr(writer);
+        Some(reader)
+    } else {
+        process.stdout(Stdio::piped()).stderr(Stdio::piped());
+        None
+    };
+    let motd = state.banner.lock().unwrap().clone().map(|(_, motd)| motd);
+    if let (None, Some(motd)) = (command, motd) {
+        let _ = handle.data(id, CryptoVec::from(motd.into_bytes())).await;
+    }
+    let mut child = process.spawn().unwrap();
+    // Closes this side's copies of the pipe's write end, so the pump sees EOF
+    drop(process);
+    let group = child.id().unwrap();
+
+    let mut pumps = Vec::new();
+    match merged {
+        Some(reader) => {
+            let reader = tokio::net::unix::pipe::Receiver::from_owned_fd(reader.into()).unwrap();
+            pumps.push(tokio::spawn(pump(reader, handle.clone(), id, None, true)));
+        }
+        None => {
+            let stdout = child.stdout.take().unwrap();
+            let stderr = child.stderr.take().unwrap();
+            pumps.push(tokio::spawn(pump(stdout, handle.clone(), id, None, false)));
+            pumps.push(tokio::spawn(pump(
+                stderr,
+                handle.clone(),
+                id,
+                Some(1),
+                false,
+            )));
+        }
+    }
+
+    let mut stdin = child.stdin.take();
+    let status = loop {
+        tokio::select! {
+            status = child.wait() => break status.unwrap(),
+            msg = channel.wait() => match msg {
+                Some(ChannelMsg::Data { data }) => {
+                    let mut data = data.to_vec();
+                    if pty && data.contains(&0x03) {
+                        data.retain(|&b| b != 0x03);
+                        signal_group(group, &Sig::INT);
+                    }
+                    if let Some(stdin) = stdin.as_mut() {
+                        let _ = stdin.write_all(&data).await;
+                    }
+                }
+                Some(ChannelMsg::Eof) => stdin = None,
+                Some(ChannelMsg::Signal { signal }) => signal_group(group, &signal),
+                Some(_) => {}
+                None => {
+                    signal_group(group, &Sig::KILL);
+                    break child.wait().await.unwrap();
+                }
+            },
+        }
+    };
+
+    for pump in pumps {
+        let _ = pump.await;
+    }
+    match (status.code(), status.signal()) {
+        _ if state.withhold_exit_status.load(Ordering::SeqCst) => {}
+        (Some(code), _) => {
+            let _ = handle.exit_status_request(id, code as u32).await;
+        }
+        (None, Some(signal)) => {
+            let signal = match signal {
+                1 => Sig::HUP,
+                2 => Sig::INT,
+                9 => Sig::KILL,
+                15 => Sig::TERM,
+                other => Sig::Custom(other.to_string()),
+            };
+            let _ = handle
+                .exit_signal_request(id, signal, false, String::new(), String::new())
+                .await;
+        }
+        (None, None) => {}
+    }
+    let _ = handle.eof(id).await;
+    let _ = handle.close(id).await;
+}
+
+/// Copy a process's output to the channel, as extended data `ext` when given
+async fn pump(
+    mut reader: impl AsyncRead + Unpin,
+    handle: Handle,
+    id: ChannelId,
+    ext: Option<u32>,
+    crlf: bool,
+) {
+    let mut buf = vec![0; 32 * 1024];
+    loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) | Err(_) => break,
+            Ok(n) => n,
+        };
+        let data = if crlf {
+            let mut data = Vec::with_capacity(n * 2);
+            for &byte in &buf[..n] {
+                if byte == b'\n' {
+                    data.push(b'\r');
+                }
+                data.push(byte);
+            }
+            CryptoVec::from(data)
+        } else {
+            CryptoVec::from_slice(&buf[..n])
+        };
+        let sent = match ext {
+            Some(ext) => handle.extended_data(id, ext, data).await,
+            None => handle.data(id, data).await,
+        };
+        if sent.is_err() {
+            break;
+        }
+    }
+}
+
+fn signal_group(group: u32, signal: &Sig) {
+    let name = match signal {
+        Sig::HUP => "HUP",
+        Sig::INT => "INT",
+        Sig::KILL => "KILL",
+        Sig::QUIT => "QUIT",
+        Sig::TERM => "TERM",
+        Sig::USR1 => "USR1",
+        Sig::Custom(name) => name,
+        _ => "TERM",
+    };
+    let _ = std::process::Command::new("kill")
+        .args(["-s", name, "--", &format!("-{}", group)])
+        .stderr(Stdio::null())
+        .status();
+}
+
+/// An SFTP server over the local filesystem, with relative paths under `home`
+#[derive(Default)]
+struct LocalSftp {
+    home: PathBuf,
+    next_handle: u32,
+    files: HashMap<String, std::fs::File>,
+    /// Listings not yet sent by readdir
+    dirs: HashMap<String, Vec<File>>,
+}
+
+impl LocalSftp {
+    fn path(&self, path: &str) -> PathBuf {
+        self.home.join(path)
+    }
+
+    fn new_handle(&mut self) -> String {
+        self.next_handle += 1;
+        self.next_handle.to_string()
+    }
+}
+
+fn ok(id: u32) -> Status {
+    Status {
+        id,
+        status_code: StatusCode::Ok,
+        error_message: "Ok".to_string(),
+        language_tag: "en-US".to_string(),
+    }
+}
+
+fn status_code(e: std::io::Error) -> StatusCode {
+    match e.kind() {
+        std::io::ErrorKind::NotFound => StatusCode::NoSuchFile,
+        std::io::ErrorKind::PermissionDenied => StatusCode::PermissionDenied,
+        _ => StatusCode::Failure,
+    }
+}
+
+/// Attributes with the full mode, so symlinks show up as such
+fn attributes(metadata: &std::fs::Metadata) -> FileAttributes {
+    FileAttributes {
+        permissions: Some(std::os::unix::fs::MetadataExt::mode(metadata)),
+        ..FileAttributes::from(metadata)
+    }
+}
+
+impl russh_sftp::server::Handler for LocalSftp {
+    type Error = StatusCode;
+
+    fn unimplemented(&self) -> Self::Error {
+        StatusCode::OpUnsupported
+    }
+
+    async fn open(
+        &mut self,
+        id: u32,
+        filename: String,
+        pflags: OpenFlags,
+        attrs: FileAttributes,
+    ) -> Result<SftpHandle, Self::Error> {
+        let file = std::fs::OpenOptions::new()
+            .read(pflags.contains(OpenFlags::READ))
+            .write(pflags.contains(OpenFlags::WRITE))
+            .append(pflags.contains(OpenFlags::APPEND))
+            .create(pflags.contains(OpenFlags::CREATE))
+            .truncate(pflags.contains(OpenFlags::TRUNCATE))
+            .create_new(pflags.contains(OpenFlags::EXCLUDE))
+            .mode(attrs.permissions.unwrap_or(0o644) & 0o7777)
+            .open(self.path(&filename))
+            .map_err(status_code)?;
+        let handle = self.new_handle();
+        self.files.insert(handle.clone(), file);
+        Ok(SftpHandle { id, handle })
+    }
+
+    async fn close(&mut self, id: u32, handle: String) -> Result<Status, Self::Error> {
+        self.files.remove(&handle);
+        self.dirs.remove(&handle);
+        Ok(ok(id))
+    }
+
+    async fn read(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        len: u32,
+    ) -> Result<Data, Self::Error> {
+        let file = self.files.get_mut(&handle).ok_or(StatusCode::Failure)?;
+        file.seek(SeekFrom::Start(offset)).map_err(status_code)?;
+        let mut data = Vec::new();
+        file.take(len as u64)
+            .read_to_end(&mut data)
+            .map_err(status_code)?;
+        if data.is_empty() {
+            return Err(StatusCode::Eof);
+        }
+        Ok(Data { id, data })
+    }
+
+    async fn write(
+        &mut self,
+        id: u32,
+        handle: String,
+        offset: u64,
+        data: Vec<u8>,
+    ) -> Result<Status, Self::Error> {
+        let file = self.files.get_mut(&handle).ok_or(StatusCode::Failure)?;
+        file.seek(SeekFrom::Start(offset)).map_err(status_code)?;
+        file.write_all(&data).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn lstat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let metadata = std::fs::symlink_metadata(self.path(&path)).map_err(status_code)?;
+        Ok(Attrs {
+            id,
+            attrs: attributes(&metadata),
+        })
+    }
+
+    async fn stat(&mut self, id: u32, path: String) -> Result<Attrs, Self::Error> {
+        let metadata = std::fs::metadata(self.path(&path)).map_err(status_code)?;
+        Ok(Attrs {
+            id,
+            attrs: attributes(&metadata),
+        })
+    }
+
+    async fn fstat(&mut self, id: u32, handle: String) -> Result<Attrs, Self::Error> {
+        let file = self.files.get(&handle).ok_or(StatusCode::Failure)?;
+        let metadata = file.metadata().map_err(status_code)?;
+        Ok(Attrs {
+            id,
+            attrs: attributes(&metadata),
+        })
+    }
+
+    async fn setstat(
+        &mut self,
+        id: u32,
+        path: String,
+        attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        let path = self.path(&path);
+        if let Some(mode) = attrs.permissions {
+            let permissions = std::fs::Permissions::from_mode(mode & 0o7777);
+            std::fs::set_permissions(&path, permissions).map_err(status_code)?;
+        }
+        Ok(ok(id))
+    }
+
+    async fn opendir(&mut self, id: u32, path: String) -> Result<SftpHandle, Self::Error> {
+        let mut files = Vec::new();
+        for entry in std::fs::read_dir(self.path(&path)).map_err(status_code)? {
+            let entry = entry.map_err(status_code)?;
+            let metadata = entry.path().symlink_metadata().map_err(status_code)?;
+            files.push(File::new(
+                entry.file_name().to_string_lossy(),
+                attributes(&metadata),
+            ));
+        }
+        let handle = self.new_handle();
+        self.dirs.insert(handle.clone(), files);
+        Ok(SftpHandle { id, handle })
+    }
+
+    async fn readdir(&mut self, id: u32, handle: String) -> Result<Name, Self::Error> {
+        let files = self.dirs.get_mut(&handle).ok_or(StatusCode::Failure)?;
+        if files.is_empty() {
+            return Err(StatusCode::Eof);
+        }
+        Ok(Name {
+            id,
+            files: std::mem::take(files),
+        })
+    }
+
+    async fn remove(&mut self, id: u32, filename: String) -> Result<Status, Self::Error> {
+        std::fs::remove_file(self.path(&filename)).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn mkdir(
+        &mut self,
+        id: u32,
+        path: String,
+        _attrs: FileAttributes,
+    ) -> Result<Status, Self::Error> {
+        std::fs::create_dir(self.path(&path)).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn rmdir(&mut self, id: u32, path: String) -> Result<Status, Self::Error> {
+        std::fs::remove_dir(self.path(&path)).map_err(status_code)?;
+        Ok(ok(id))
+    }
+
+    async fn realpath(&mut self, id: u32, path: String) -> Result<Name, Self::Error> {
+        let path = self.path(&path);
+        let path = path.canonicalize().unwrap_or(path);
+        Ok(Name {
+            id,
+            files: vec![File::dummy(path.to_string_lossy())],
+        })
+    }
+
+    async fn rename(
+        &mut self,
+        id: u32,
+        oldpath: String,
+        newpath: String,
+    ) -> Result<Status, Self::Error> {
+        std::fs::rename(self.path(&oldpath), self.path(&newpath)).map_err(status_code)?;
+        Ok(ok(id))
+    }
+}
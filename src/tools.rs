@@ -1,468 +1,10329 @@
-use crate::ssh::SshClient;
+use crate::android::{self, AndroidInfo};
+use crate::config::{BlocklistConfig, Redacted, WhitelistConfig};
+use crate::discover;
+use crate::error::SshMcpError;
+use crate::history::CommandHistory;
+use crate::output_store::OutputStore;
+use crate::rate_limit::RateLimiter;
+use crate::shell::{check_env_names, is_shell_identifier, quote_remote_path, shell_quote};
+use crate::ssh::{
+    CommandResult, ExecOptions, SshClient, forward::PortForward, keygen, shell::ShellSession,
+};
+use crate::stats::ConnectionStats;
+use regex::Regex;
 use rmcp::{
+    Peer, RoleServer,
     handler::server::{router::tool::ToolRouter, wrapper::Parameters},
-    model::{CallToolResult, Content, ErrorData as McpError},
+    model::{
+        CallToolResult, Content, ErrorData as McpError, ProgressNotificationParam, ProgressToken,
+        RawResource,
+    },
     schemars::JsonSchema,
+    service::RequestContext,
     tool, tool_router,
 };
-use serde::Deserialize;
+use russh_sftp::client::SftpSession;
+use serde::{Deserialize, Serialize};
+use sha2::digest::DynDigest;
+use std::collections::{BTreeMap, HashMap, HashSet};
 use std::sync::Arc;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Instant, SystemTime};
+use tokio::io::AsyncReadExt;
 use tokio::sync::Mutex;
 
-// Read-only commands whitelist (81 commands from Python implementation)
-const READ_ONLY_COMMANDS: &[&str] = &[
-    // File viewing
-    "ls",
-    "cat",
-    "head",
-    "tail",
-    "less",
-    "more",
-    "grep",
-    "rg",
-    "find",
-    "fd",
-    "tree",
-    "bat",
-    "eza",
-    "exa",
-    "locate",
-    // Path operations
-    "cd",
-    "pwd",
-    "readlink",
-    "realpath",
-    "basename",
-    "dirname",
-    // Identity/system info
-    "whoami",
-    "id",
-    "groups",
-    "which",
-    "whereis",
-    "type",
-    "hostname",
-    "uname",
-    "date",
-    "uptime",
-    // Display/output
-    "echo",
-    "printf",
-    // Process monitoring
-    "ps",
-    "top",
-    "htop",
-    "btop",
-    "lsof",
-    // Disk/filesystem info
-    "df",
-    "du",
-    "lsblk",
-    "blkid",
-    "stat",
-    "file",
-    // Memory/performance monitoring
-    "free",
-    "vmstat",
-    "iostat",
-    "iotop",
-    "lsmem",
-    "lshw",
-    "lscpu",
-    // Network monitoring
-    "netstat",
-    "ss",
-    "ping",
-    "traceroute",
-    "nslookup",
-    "dig",
-    "host",
-    // Text processing
-    "wc",
-    "sort",
-    "uniq",
-    "cut",
-    "paste",
-    "tr",
-    "column",
-    // Comparison tools
-    "diff",
-    "cmp",
-    "comm",
-    // Checksums
-    "md5sum",
-    "sha1sum",
-    "sha256sum",
-    "sha512sum",
-    // Environment info
-    "env",
-    "printenv",
-    "getent",
-    "getconf",
-    // Binary/hex viewers
-    "xxd",
-    "hexdump",
-    "od",
-    "strings",
-    // Compressed file viewers
-    "zcat",
-    "bzcat",
-    "xzcat",
-    "gunzip",
-    "bunzip2",
-    "unxz",
-    // Data parsers
-    "jq",
-    "yq",
-    "xmllint",
-    // Log viewing
-    "journalctl",
-    // Hardware/module info
-    "lsmod",
-    "modinfo",
-    "lspci",
-    "lsusb",
-    // Shell info
-    "history",
-    "alias",
-    // Font info
-    "fc-list",
-    "fc-match",
-    // Test/null commands
-    "test",
-    "true",
-    "false",
+// Read-only commands whitelist (81 commands from Python implementation), by category
+const READ_ONLY_COMMANDS: &[(&str, &[&str])] = &[
+    (
+        "File viewing",
+        &[
+            "ls", "cat", "head", "tail", "less", "more", "grep", "rg", "find", "fd", "tree", "bat",
+            "eza", "exa", "locate",
+        ],
+    ),
+    (
+        "Path operations",
+        &["cd", "pwd", "readlink", "realpath", "basename", "dirname"],
+    ),
+    (
+        "Identity/system info",
+        &[
+            "whoami", "id", "groups", "which", "whereis", "type", "hostname", "uname", "date",
+            "uptime",
+        ],
+    ),
+    ("Display/output", &["echo", "printf"]),
+    ("Process monitoring", &["ps", "top", "htop", "btop", "lsof"]),
+    (
+        "Disk/filesystem info",
+        &["df", "du", "lsblk", "blkid", "stat", "file"],
+    ),
+    (
+        "Memory/performance monitoring",
+        &[
+            "free", "vmstat", "iostat", "iotop", "lsmem", "lshw", "lscpu",
+        ],
+    ),
+    (
+        "Network monitoring",
+        &[
+            "netstat",
+            "ss",
+            "ping",
+            "traceroute",
+            "nslookup",
+            "dig",
+            "host",
+        ],
+    ),
+    (
+        "Text processing",
+        &["wc", "sort", "uniq", "cut", "paste", "tr", "column"],
+    ),
+    ("Comparison tools", &["diff", "cmp", "comm"]),
+    (
+        "Checksums",
+        &["md5sum", "sha1sum", "sha256sum", "sha512sum"],
+    ),
+    (
+        "Environment info",
+        &["env", "printenv", "getent", "getconf"],
+    ),
+    ("Binary/hex viewers", &["xxd", "hexdump", "od", "strings"]),
+    (
+        "Compressed file viewers",
+        &["zcat", "bzcat", "xzcat", "gunzip", "bunzip2", "unxz"],
+    ),
+    ("Data parsers", &["jq", "yq", "xmllint"]),
+    ("Log viewing", &["journalctl"]),
+    (
+        "Hardware/module info",
+        &["lsmod", "modinfo", "lspci", "lsusb"],
+    ),
+    ("Shell info", &["history", "alias"]),
+    ("Font info", &["fc-list", "fc-match"]),
+    ("Test/null commands", &["test", "true", "false"]),
 ];
 
-fn is_read_only(command: &str) -> bool {
-    let cmd = command.split_whitespace().next().unwrap_or("");
-    READ_ONLY_COMMANDS.contains(&cmd)
+// Variables that change which binary or library a command loads, so a
+// whitelisted command run with them set is no longer read-only
+const DANGEROUS_ENV_VARS: &[&str] = &["PATH", "IFS", "ENV", "BASH_ENV", "SHELLOPTS", "PS4"];
+
+/// Get the variable name if `token` is a `NAME=value` assignment
+fn env_assignment_name(token: &str) -> Option<&str> {
+    let (name, _) = token.split_once('=')?;
+    is_shell_identifier(name).then_some(name)
 }
 
-#[derive(Clone)]
-pub struct AndroidSshService {
-    pub(crate) ssh_client: Arc<Mutex<Option<SshClient>>>,
-    pub tool_router: ToolRouter<Self>,
+fn is_dangerous_env_var(name: &str) -> bool {
+    name.starts_with("LD_") || DANGEROUS_ENV_VARS.contains(&name)
 }
 
-impl AndroidSshService {
-    pub fn new(config: Option<crate::config::Config>) -> Self {
-        let ssh_client = config.map(SshClient::new);
-        Self {
-            ssh_client: Arc::new(Mutex::new(ssh_client)),
-            tool_router: Self::tool_router(),
-        }
+/// Termux package providing a command, for install hints
+/// Most packages are named after their command; these are the common exceptions
+fn termux_package(command: &str) -> &str {
+    match command {
+        "rg" => "ripgrep",
+        "locate" => "mlocate",
+        "ps" | "top" | "free" | "vmstat" | "uptime" | "pgrep" | "pkill" => "procps",
+        "lsblk" | "blkid" | "lsmem" | "lscpu" | "column" | "whereis" => "util-linux",
+        "iostat" => "sysstat",
+        "netstat" | "ifconfig" => "net-tools",
+        "ss" | "ip" => "iproute2",
+        "dig" | "nslookup" | "host" => "dnsutils",
+        "diff" | "cmp" => "diffutils",
+        "find" | "xargs" => "findutils",
+        "hostname" => "inetutils",
+        "md5sum" | "sha1sum" | "sha256sum" | "sha512sum" => "coreutils",
+        _ => command,
     }
 }
 
-#[derive(Debug, Deserialize, JsonSchema)]
-pub struct ExecuteRequest {
-    /// The shell command to execute
-    pub command: String,
-    /// Command timeout in seconds (default: 30, max: 300)
-    #[serde(default = "default_timeout")]
-    pub timeout: u64,
+/// Message for a whitelisted command that isn't installed on the device
+fn not_installed_message(command: &str) -> String {
+    format!(
+        "Command '{}' is whitelisted but not installed on this device. \
+         Install it with: pkg install {}",
+        command,
+        termux_package(command)
+    )
 }
 
-#[derive(Debug, Deserialize, JsonSchema)]
-pub struct SetupRequest {
-    /// Android device IP address (e.g., 192.168.1.100)
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub host: Option<String>,
-    /// SSH port (default: 8022 for Termux)
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub port: Option<u16>,
-    /// Termux username (run 'whoami' in Termux)
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub user: Option<String>,
-    /// Path to SSH private key (recommended, e.g., ~/.ssh/id_ed25519)
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub key_path: Option<String>,
-    /// SSH password (alternative to key_path, not recommended)
-    #[serde(skip_serializing_if = "Option::is_none")]
-    pub password: Option<String>,
+/// A command the shell couldn't find, as execute and execute_read report it
+#[derive(Debug, PartialEq, Serialize)]
+struct MissingCommand {
+    name: String,
+    /// Termux package that provides it
+    package: String,
 }
 
-fn default_timeout() -> u64 {
-    30
+/// Spot a command that failed because its program isn't installed: exit code 127 with
+/// the shell's "not found" message, from bash, zsh, fish, dash, mksh or busybox sh, or
+/// from Termux's command-not-found handler, whose suggested package is used if it gives one
+/// A missing path such as ./build.sh isn't a package problem, so it isn't reported
+fn missing_command(result: &CommandResult) -> Option<MissingCommand> {
+    if result.exit_code != Some(127) {
+        return None;
+    }
+    // With merge_streams the message is at the end of stdout instead
+    let text = if result.stderr.trim().is_empty() {
+        let lines: Vec<&str> = result.stdout.lines().collect();
+        lines[lines.len().saturating_sub(5)..].join("\n")
+    } else {
+        result.stderr.clone()
+    };
+
+    let reported = |line: &str| -> Option<String> {
+        let line = line.trim();
+        let name = if let Some(rest) = line.split_once("command not found: ") {
+            rest.1
+        } else if let Some(rest) = line.split_once("Unknown command: ") {
+            rest.1
+        } else if let Some(rest) = line.strip_prefix("The program ") {
+            rest.split_once(" is not installed")?.0
+        } else if let Some(rest) = line.strip_prefix("No command ") {
+            rest.split_once(" found")?.0
+        } else {
+            let before = [
+                ": command not found",
+                ": inaccessible or not found",
+                ": not found",
+            ]
+            .iter()
+            .find_map(|suffix| line.strip_suffix(suffix))?;
+            before.rsplit(": ").next()?
+        };
+        let name = name.trim().trim_matches(['\'', '"', '`']);
+        (!name.is_empty() && !name.contains(['/', ' '])).then(|| name.to_string())
+    };
+    let name = text.lines().find_map(reported)?;
+    let suggested = text.lines().find_map(|line| {
+        let rest = line.split_once("pkg install ")?.1;
+        rest.split_whitespace().next().map(str::to_string)
+    });
+    Some(MissingCommand {
+        package: suggested.unwrap_or_else(|| termux_package(&name).to_string()),
+        name,
+    })
 }
 
-#[tool_router]
-impl AndroidSshService {
-    #[tool(
-        description = "Execute safe read-only shell commands on Android via SSH (81 whitelisted commands)"
-    )]
-    async fn execute_read(
-        &self,
-        Parameters(request): Parameters<ExecuteRequest>,
-    ) -> Result<CallToolResult, McpError> {
-        // Check if client exists (config was loaded)
-        let mut client_guard = self.ssh_client.lock().await;
-        if client_guard.is_none() {
-            return Ok(CallToolResult::error(vec![Content::text(
-                crate::config::Config::first_run_message(),
-            )]));
-        }
+/// Shell loop printing which of `names` are installed, one per line
+fn probe_command(names: &[String]) -> String {
+    let quoted: Vec<String> = names.iter().map(|name| shell_quote(name)).collect();
+    format!(
+        "for c in {}; do command -v \"$c\" >/dev/null 2>&1 && echo \"$c\"; done",
+        quoted.join(" ")
+    )
+}
 
-        // Validate timeout
-        if request.timeout == 0 || request.timeout > 300 {
-            return Ok(CallToolResult::error(vec![Content::text(
-                "Timeout must be between 1 and 300 seconds".to_string(),
-            )]));
-        }
+/// Split `names` into installed and missing, from the output of `probe_command`
+fn parse_probe<'a>(names: &'a [String], output: &str) -> (Vec<&'a str>, Vec<&'a str>) {
+    let found: HashSet<&str> = output.lines().map(str::trim).collect();
+    names
+        .iter()
+        .map(String::as_str)
+        .partition(|name| found.contains(name))
+}
 
-        // Check whitelist
-        if !is_read_only(&request.command) {
-            let cmd_name = request.command.split_whitespace().next().unwrap_or("");
-            return Ok(CallToolResult::error(vec![Content::text(format!(
-                "Command '{}' is not whitelisted as read-only. Use execute tool instead.",
-                cmd_name
-            ))]));
+/// Build the execute_read whitelist: the built-in commands adjusted by `[whitelist]`
+fn read_only_commands(whitelist: Option<&WhitelistConfig>) -> HashSet<String> {
+    let mut commands: HashSet<String> = READ_ONLY_COMMANDS
+        .iter()
+        .flat_map(|(_, names)| names.iter())
+        .map(|c| c.to_string())
+        .collect();
+    let Some(whitelist) = whitelist else {
+        return commands;
+    };
+
+    for name in &whitelist.removed {
+        if !commands.remove(name) {
+            tracing::warn!(
+                "Whitelist removes '{}', which is not a built-in read-only command",
+                name
+            );
         }
+    }
+    commands.extend(whitelist.additional.iter().cloned());
+    commands
+}
 
-        // Execute command
-        let client = client_guard.as_mut().unwrap();
-        match client
-            .execute_command(&request.command, request.timeout)
-            .await
-        {
-            Ok(result) => {
-                // Format output nicely
-                let mut output = String::new();
-
-                // Add stdout if present
-                if !result.stdout.is_empty() {
-                    output.push_str(&result.stdout);
-                    if !output.ends_with('\n') {
-                        output.push('\n');
-                    }
-                }
+/// Commands the execute tool refuses to run, built from `[blocklist]`
+#[derive(Debug, Default)]
+pub(crate) struct Blocklist {
+    /// Each prefix as configured, paired with the form commands are compared against
+    prefixes: Vec<(String, String)>,
+    regexes: Vec<Regex>,
+    case_insensitive: bool,
+}
 
-                // Add stderr if present
-                if !result.stderr.is_empty() {
-                    if !output.is_empty() {
-                        output.push('\n');
-                    }
-                    output.push_str("stderr:\n");
-                    output.push_str(&result.stderr);
-                    if !output.ends_with('\n') {
-                        output.push('\n');
-                    }
-                }
+impl Blocklist {
+    /// Patterns were validated when the config was loaded, so invalid ones are skipped here
+    fn new(config: Option<&BlocklistConfig>) -> Self {
+        let Some(config) = config else {
+            return Self::default();
+        };
+        let fold = |text: &str| {
+            if config.case_insensitive {
+                text.to_lowercase()
+            } else {
+                text.to_string()
+            }
+        };
+        Self {
+            prefixes: config
+                .prefixes
+                .iter()
+                .filter(|p| !p.trim().is_empty())
+                .map(|p| (p.clone(), blocklist_form(&split_words(&fold(p)))))
+                .collect(),
+            regexes: config.regexes().unwrap_or_default(),
+            case_insensitive: config.case_insensitive,
+        }
+    }
 
-                // Always show status line
-                if !output.is_empty() {
-                    output.push('\n');
-                }
+    /// Return an error naming the first rule the command matches
+    /// Prefixes are checked at the start of every command in a chain or substitution,
+    /// past wrappers such as `sudo`, so `ls; rm -rf /`, `echo $(rm -rf /)` and
+    /// `sudo rm -rf /` are all caught by an `rm -rf /` prefix, as are `rm  -rf /`
+    /// and `/bin/rm -rf /` since both sides are compared in blocklist_form
+    fn check(&self, command: &str) -> std::result::Result<(), String> {
+        let command_folded;
+        let folded = if self.case_insensitive {
+            command_folded = command.to_lowercase();
+            command_folded.as_str()
+        } else {
+            command
+        };
+        let starts: Vec<String> = folded
+            .split([';', '&', '|', '\n', '`', '(', '{', '!'])
+            .map(|segment| blocklist_form(&command_words(segment)))
+            .collect();
+        if let Some((prefix, _)) = self.prefixes.iter().find(|(_, folded)| {
+            starts
+                .iter()
+                .any(|start| start.starts_with(folded.as_str()))
+        }) {
+            return Err(format!(
+                "Command blocked by [blocklist] prefix '{}'",
+                prefix
+            ));
+        }
+        if let Some(regex) = self.regexes.iter().find(|regex| regex.is_match(command)) {
+            return Err(format!(
+                "Command blocked by [blocklist] pattern '{}'",
+                regex.as_str()
+            ));
+        }
+        Ok(())
+    }
+}
 
-                if result.exit_code == 0 {
-                    output.push_str("✓ Success");
-                } else {
-                    output.push_str(&format!("✗ Failed (exit code: {})", result.exit_code));
-                }
+/// Commands that run the rest of their arguments as a command
+const COMMAND_WRAPPERS: &[&str] = &["sudo", "env", "nohup", "command"];
 
-                Ok(CallToolResult::success(vec![Content::text(output)]))
+/// The words of the command a chain segment runs: past `NAME=value` assignments,
+/// which may have quoted values, and wrappers from COMMAND_WRAPPERS with their
+/// `-` options
+fn command_words(segment: &str) -> Vec<&str> {
+    let mut words = split_words(segment);
+    let mut wrapped = false;
+    let start = words
+        .iter()
+        .position(|word| {
+            if COMMAND_WRAPPERS.contains(&command_name(word)) {
+                wrapped = true;
+                return false;
             }
-            Err(e) => Ok(CallToolResult::error(vec![Content::text(format!(
-                "Command execution failed: {}",
-                e
-            ))])),
+            // Options only count after a wrapper, so `-rf` in `rm -rf` is kept
+            !(wrapped && word.starts_with('-')) && env_assignment_name(word).is_none()
+        })
+        .unwrap_or(words.len());
+    words.drain(..start);
+    words
+}
+
+/// The file name of a command word, so `/system/bin/rm` is `rm`
+fn command_name(word: &str) -> &str {
+    word.rsplit('/')
+        .next()
+        .filter(|name| !name.is_empty())
+        .unwrap_or(word)
+}
+
+/// The form commands and blocklist prefixes are compared in: words joined by single
+/// spaces, with the command named by its file name
+fn blocklist_form(words: &[&str]) -> String {
+    let mut form = String::new();
+    for (i, word) in words.iter().enumerate() {
+        if i == 0 {
+            form.push_str(command_name(word));
+        } else {
+            form.push(' ');
+            form.push_str(word);
         }
     }
+    form
+}
 
-    #[tool(
-        description = "Execute any shell command on Android via SSH, including write/modify/delete operations"
-    )]
-    async fn execute(
-        &self,
-        Parameters(request): Parameters<ExecuteRequest>,
-    ) -> Result<CallToolResult, McpError> {
-        // Check if client exists (config was loaded)
-        let mut client_guard = self.ssh_client.lock().await;
-        if client_guard.is_none() {
-            return Ok(CallToolResult::error(vec![Content::text(
-                crate::config::Config::first_run_message(),
-            )]));
-        }
+/// Commands in one whitelist category, as listed by list_read_only_commands
+#[derive(Debug, Serialize)]
+pub struct CommandCategory {
+    pub category: String,
+    pub commands: Vec<String>,
+}
 
-        // Validate timeout
-        if request.timeout == 0 || request.timeout > 300 {
-            return Ok(CallToolResult::error(vec![Content::text(
-                "Timeout must be between 1 and 300 seconds".to_string(),
-            )]));
-        }
+/// Group the effective whitelist by the built-in categories
+/// Commands added through `[whitelist]` go in their own category at the end
+fn categorize_read_only_commands(allowed: &HashSet<String>) -> Vec<CommandCategory> {
+    let mut categories: Vec<CommandCategory> = READ_ONLY_COMMANDS
+        .iter()
+        .map(|(category, names)| CommandCategory {
+            category: category.to_string(),
+            commands: names
+                .iter()
+                .filter(|name| allowed.contains(**name))
+                .map(|name| name.to_string())
+                .collect(),
+        })
+        .filter(|category| !category.commands.is_empty())
+        .collect();
 
-        // Execute command
-        let client = client_guard.as_mut().unwrap();
-        match client
-            .execute_command(&request.command, request.timeout)
-            .await
-        {
-            Ok(result) => {
-                // Format output nicely
-                let mut output = String::new();
-
-                // Add stdout if present
-                if !result.stdout.is_empty() {
-                    output.push_str(&result.stdout);
-                    if !output.ends_with('\n') {
-                        output.push('\n');
-                    }
-                }
+    let mut additional: Vec<String> = allowed
+        .iter()
+        .filter(|name| {
+            !READ_ONLY_COMMANDS
+                .iter()
+                .any(|(_, names)| names.contains(&name.as_str()))
+        })
+        .cloned()
+        .collect();
+    if !additional.is_empty() {
+        additional.sort_unstable();
+        categories.push(CommandCategory {
+            category: "Added by [whitelist] config".to_string(),
+            commands: additional,
+        });
+    }
+    categories
+}
 
-                // Add stderr if present
-                if !result.stderr.is_empty() {
-                    if !output.is_empty() {
-                        output.push('\n');
-                    }
-                    output.push_str("stderr:\n");
-                    output.push_str(&result.stderr);
-                    if !output.ends_with('\n') {
-                        output.push('\n');
-                    }
-                }
+/// Quoting state while scanning a shell command
+#[derive(PartialEq)]
+enum Quote {
+    None,
+    Single,
+    Double,
+}
 
-                // Always show status line
-                if !output.is_empty() {
-                    output.push('\n');
-                }
+impl Quote {
+    /// Step past the byte at `i`, updating the state for quotes it opens or closes
+    /// Returns how many bytes were consumed, which is two for a backslash escape
+    /// outside single quotes
+    fn advance(&mut self, bytes: &[u8], i: usize) -> usize {
+        match (&*self, bytes[i]) {
+            (Quote::Single, b'\'') => *self = Quote::None,
+            (Quote::Single, _) => {}
+            (_, b'\\') => return 2,
+            (Quote::Double, b'"') => *self = Quote::None,
+            (Quote::None, b'\'') => *self = Quote::Single,
+            (Quote::None, b'"') => *self = Quote::Double,
+            _ => {}
+        }
+        1
+    }
+}
 
-                if result.exit_code == 0 {
-                    output.push_str("✓ Success");
-                } else {
-                    output.push_str(&format!("✗ Failed (exit code: {})", result.exit_code));
-                }
+/// Split a command into pipeline segments at unquoted `|`
+/// Fails with the first other shell operator that could chain, substitute, or
+/// redirect commands; quotes and backslash escapes are honoured, and inside
+/// double quotes only command substitution is still active
+fn split_pipeline(command: &str) -> std::result::Result<Vec<&str>, &'static str> {
+    let bytes = command.as_bytes();
+    let mut quote = Quote::None;
+    let mut segments = Vec::new();
+    let mut segment_start = 0;
+    let mut i = 0;
+    while i < bytes.len() {
+        let next = bytes.get(i + 1).copied();
+        match (&quote, bytes[i]) {
+            (Quote::Single, _) | (_, b'\\') => {}
+            (_, b'`') => return Err("`"),
+            (_, b'$') if next == Some(b'(') => return Err("$("),
+            (Quote::Double, _) => {}
+            (Quote::None, b';') => return Err(";"),
+            (Quote::None, b'\n') => return Err("newline"),
+            (Quote::None, b'&') if next == Some(b'&') => return Err("&&"),
+            (Quote::None, b'&') => return Err("&"),
+            (Quote::None, b'|') if next == Some(b'|') => return Err("||"),
+            (Quote::None, b'|') => {
+                segments.push(&command[segment_start..i]);
+                segment_start = i + 1;
+            }
+            (Quote::None, b'>') if next == Some(b'>') => return Err(">>"),
+            (Quote::None, b'>') => return Err(">"),
+            (Quote::None, b'<') => return Err("<"),
+            (Quote::None, _) => {}
+        }
+        i += quote.advance(bytes, i);
+    }
+    segments.push(&command[segment_start..]);
+    Ok(segments)
+}
 
-                Ok(CallToolResult::success(vec![Content::text(output)]))
+/// Split a command into words at unquoted whitespace, keeping each word's quotes
+/// so `X=" ls " rm` is the two words `X=" ls "` and `rm`, as the shell sees it
+fn split_words(command: &str) -> Vec<&str> {
+    let bytes = command.as_bytes();
+    let mut quote = Quote::None;
+    let mut words = Vec::new();
+    let mut word_start = None;
+    let mut i = 0;
+    while i < bytes.len() {
+        if quote == Quote::None && bytes[i].is_ascii_whitespace() {
+            if let Some(start) = word_start.take() {
+                words.push(&command[start..i]);
             }
-            Err(e) => Ok(CallToolResult::error(vec![Content::text(format!(
-                "Command execution failed: {}",
-                e
-            ))])),
+            i += 1;
+            continue;
         }
+        word_start.get_or_insert(i);
+        i += quote.advance(bytes, i);
+    }
+    if let Some(start) = word_start {
+        words.push(&command[start..]);
     }
+    words
+}
 
-    #[tool(
-        description = "Configure Android SSH connection - provide credentials to connect to your Android device"
-    )]
-    async fn setup(
-        &self,
-        Parameters(request): Parameters<SetupRequest>,
-    ) -> Result<CallToolResult, McpError> {
-        // Try to load existing config, or create empty one
-        let existing_config = crate::config::Config::load_existing().ok();
+/// Check whether a command may run through execute_read
+/// Shell operators are rejected, except `|` between whitelisted commands when
+/// `allow_pipes` is set, since they would let a whitelisted command launch others
+/// Returns the name of the first command that would run
+fn check_read_only<'a>(
+    command: &'a str,
+    allowed: &HashSet<String>,
+    allow_pipes: bool,
+) -> std::result::Result<&'a str, String> {
+    let segments = split_pipeline(command).map_err(|op| {
+        format!(
+            "Shell operator '{}' is not allowed in execute_read because it can chain, \
+             substitute, or redirect commands. Use execute tool instead.",
+            op
+        )
+    })?;
+    if segments.len() > 1 && !allow_pipes {
+        return Err(
+            "Pipes ('|') are not allowed in execute_read. Use execute tool instead, \
+             or set allow_pipes_in_read = true in the config to allow pipelines of \
+             whitelisted commands."
+                .to_string(),
+        );
+    }
 
-        // Merge with provided values
-        let host = request
-            .host
-            .or_else(|| existing_config.as_ref().map(|c| c.host.clone()));
-        let port = request
-            .port
-            .or_else(|| existing_config.as_ref().map(|c| c.port));
-        let user = request
-            .user
-            .or_else(|| existing_config.as_ref().map(|c| c.user.clone()));
-        let key_path = request
-            .key_path
-            .or_else(|| existing_config.as_ref().and_then(|c| c.key_path.clone()));
-        let password = request
-            .password
-            .or_else(|| existing_config.as_ref().and_then(|c| c.password.clone()));
+    let mut first = None;
+    for segment in segments {
+        let name = check_read_only_command(segment, allowed)?;
+        first.get_or_insert(name);
+    }
+    Ok(first.unwrap_or_default())
+}
 
-        // Check what's missing
-        let mut missing = Vec::new();
-        if host.is_none() {
-            missing.push("host");
+/// Check a single simple command against the whitelist
+/// Leading `NAME=value` assignments are skipped so the real command is checked
+/// against the whitelist, with quoted values kept whole, unless they set a variable that can hijack execution
+/// Returns the name of the command that would run
+fn check_read_only_command<'a>(
+    command: &'a str,
+    allowed: &HashSet<String>,
+) -> std::result::Result<&'a str, String> {
+    let mut cmd = "";
+    let words = split_words(command);
+    let mut tokens = words.into_iter();
+    for token in tokens.by_ref() {
+        match env_assignment_name(token) {
+            Some(name) if is_dangerous_env_var(name) => return Err(dangerous_env_var_error(name)),
+            Some(_) => continue,
+            None => {
+                cmd = token;
+                break;
+            }
         }
-        if user.is_none() {
-            missing.push("user");
+    }
+
+    if allowed.contains(cmd) {
+        match cmd {
+            "env" => check_env_arguments(tokens)?,
+            _ => check_command_options(cmd, tokens)?,
         }
-        if key_path.is_none() && password.is_none() {
-            missing.push("key_path or password");
+        if cmd == "journalctl" && journalctl_follows(command) {
+            return Err(
+                "journalctl -f follows the journal forever, and execute_read waits for \
+                 the command to exit. Use the journal tool for recent entries, or stream to \
+                 follow it live."
+                    .to_string(),
+            );
         }
+        Ok(cmd)
+    } else {
+        Err(format!(
+            "Command '{}' is not whitelisted as read-only. Use execute tool instead.",
+            cmd
+        ))
+    }
+}
 
-        // If anything is missing, return helpful message
-        if !missing.is_empty() {
-            let mut msg = String::from("Setup incomplete. Missing:\n\n");
+fn dangerous_env_var_error(name: &str) -> String {
+    format!(
+        "Setting '{}' is not allowed in execute_read because it changes what the \
+         command loads or runs. Use execute tool instead.",
+        name
+    )
+}
 
-            if missing.contains(&"host") {
-                msg.push_str("• host - Your Android device IP\n");
-                msg.push_str("  Find it: Run 'ifconfig wlan0' in Termux\n\n");
+/// Check the arguments of a whitelisted `env`
+/// Printing the environment is read-only, but `env` runs any command that follows its
+/// options and assignments, so only those may appear
+fn check_env_arguments<'a>(
+    mut args: impl Iterator<Item = &'a str>,
+) -> std::result::Result<(), String> {
+    while let Some(arg) = args.next() {
+        match arg {
+            "-" | "-i" | "--ignore-environment" | "-0" | "--null" => {}
+            "-u" | "--unset" => {
+                args.next();
             }
+            _ if arg.starts_with("--unset=") || arg.starts_with("-u") => {}
+            _ => match env_assignment_name(arg) {
+                Some(name) if is_dangerous_env_var(name) => {
+                    return Err(dangerous_env_var_error(name));
+                }
+                Some(_) => {}
+                None => {
+                    return Err(format!(
+                        "env can only print the environment in execute_read, not run \
+                         '{}'. Use execute tool instead.",
+                        arg
+                    ));
+                }
+            },
+        }
+    }
+    Ok(())
+}
 
-            if missing.contains(&"user") {
-                msg.push_str("• user - Your Termux username\n");
-                msg.push_str("  Find it: Run 'whoami' in Termux\n\n");
-            }
+/// find actions that run commands or change files rather than only printing
+const FIND_WRITE_ACTIONS: &[&str] = &[
+    "-exec", "-execdir", "-ok", "-okdir", "-delete", "-fprint", "-fprint0", "-fprintf", "-fls",
+];
 
-            if missing.contains(&"key_path or password") {
-                msg.push_str("• Authentication - Choose one:\n");
-                msg.push_str("  SSH key (recommended):\n");
-                msg.push_str("    Generate: ssh-keygen -t ed25519 -f ~/.ssh/id_ed25519\n");
-                msg.push_str(
-                    "    Copy to device: ssh-copy-id -p 8022 -i ~/.ssh/id_ed25519.pub USER@HOST\n",
-                );
-                msg.push_str("    Then provide: key_path = \"~/.ssh/id_ed25519\"\n\n");
-                msg.push_str("  OR password (less secure):\n");
-                msg.push_str("    Set Termux password: Run 'passwd' in Termux\n");
-                msg.push_str("    Then provide: password = \"your_password\"\n\n");
-            }
+/// Options of whitelisted commands that run other programs or write files
+const WRITE_OPTIONS: &[(&str, &[&str])] = &[
+    ("find", FIND_WRITE_ACTIONS),
+    ("fd", &["-x", "-X", "--exec", "--exec-batch"]),
+    ("rg", &["--pre", "--pre-glob"]),
+    ("sort", &["-o", "--output", "--compress-program"]),
+];
 
-            if let Some(ref h) = host {
-                msg.push_str(&format!("Current: host = \"{}\"\n", h));
+/// Check the arguments of a whitelisted command for options that run or write anything
+/// Quotes and backslashes are dropped before comparing, as the shell would; `$'...'`
+/// quoting can spell any option, so it is refused outright. Long options also match
+/// with `=value` or abbreviated, as getopt accepts them, and single-letter ones
+/// anywhere in a cluster such as `-uo`
+fn check_command_options<'a>(
+    cmd: &str,
+    args: impl Iterator<Item = &'a str>,
+) -> std::result::Result<(), String> {
+    let Some((_, options)) = WRITE_OPTIONS.iter().find(|(name, _)| *name == cmd) else {
+        return Ok(());
+    };
+    for arg in args {
+        let unquoted: String = arg.chars().filter(|c| !"'\"\\".contains(*c)).collect();
+        let matches = |option: &&str| {
+            if let Some(name) = unquoted.strip_prefix("--") {
+                let name = name.split('=').next().unwrap_or_default();
+                !name.is_empty()
+                    && option
+                        .strip_prefix("--")
+                        .is_some_and(|o| o.starts_with(name))
+            } else if let Some(letter) = option.strip_prefix('-').filter(|o| o.len() == 1) {
+                unquoted.len() > 1 && unquoted.starts_with('-') && unquoted[1..].contains(letter)
+            } else {
+                unquoted == *option
             }
-            if let Some(ref u) = user {
-                msg.push_str(&format!("Current: user = \"{}\"\n", u));
-            }
-            if let Some(ref k) = key_path {
-                msg.push_str(&format!("Current: key_path = \"{}\"\n", k));
+        };
+        if arg.contains("$'") || options.iter().any(matches) {
+            return Err(format!(
+                "{} {} is not allowed in execute_read because it runs commands or changes \
+                 files. Use execute tool instead.",
+                cmd, arg
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Whether a journalctl invocation asks to follow the journal (`-f`, `--follow`, or `f` in a
+/// cluster of short flags such as `-kf`), which never exits
+/// Letters after one that takes a value (`-uf` is unit "f") belong to that value
+fn journalctl_follows(command: &str) -> bool {
+    const TAKES_VALUE: &[char] = &[
+        'u', 'n', 'p', 't', 'S', 'U', 'o', 'D', 'M', 'g', 'b', 'c', 'F',
+    ];
+    command
+        .split_whitespace()
+        .skip_while(|token| *token != "journalctl")
+        .skip(1)
+        .take_while(|token| *token != "--")
+        .any(|token| {
+            if token == "--follow" {
+                return true;
             }
-            if password.is_some() {
-                msg.push_str("Current: password = \"***\"\n");
+            let Some(flags) = token.strip_prefix('-').filter(|f| !f.starts_with('-')) else {
+                return false;
+            };
+            for flag in flags.chars() {
+                if flag == 'f' {
+                    return true;
+                }
+                if TAKES_VALUE.contains(&flag) {
+                    break;
+                }
             }
+            false
+        })
+}
 
-            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+/// Build a journalctl invocation that prints the last `lines` entries and exits
+/// `since` accepts anything journalctl does, like "1 hour ago" or "2024-01-01 10:00"
+fn journal_command(
+    lines: usize,
+    since: Option<&str>,
+    unit: Option<&str>,
+) -> std::result::Result<String, String> {
+    let mut command = format!("journalctl --no-pager -n {}", lines);
+    if let Some(since) = since {
+        if since.trim().is_empty() {
+            return Err("since can't be empty".to_string());
         }
+        command.push_str(&format!(" --since {}", shell_quote(since.trim())));
+    }
+    if let Some(unit) = unit {
+        if unit.is_empty() || unit.contains(char::is_whitespace) {
+            return Err(format!(
+                "Invalid unit '{}': units can't be empty or contain whitespace",
+                unit
+            ));
+        }
+        command.push_str(&format!(" -u {}", shell_quote(unit)));
+    }
+    Ok(command)
+}
 
-        // All required fields present - create config
-        let config = crate::config::Config {
-            host: host.unwrap(),
-            port: port.unwrap_or(8022),
-            user: user.unwrap(),
-            password,
-            key_path,
-        };
+/// Reject commands longer than the configured maximum
+fn check_command_length(command: &str, max_length: usize) -> std::result::Result<(), String> {
+    if command.len() > max_length {
+        return Err(format!(
+            "Command is too long ({} bytes, max {} bytes).\n\n\
+             Large content (e.g. inlined base64 or file contents) should not be embedded in \
+             the command string. Put it in a script and use run_script, which sends the \
+             content over stdin instead.",
+            command.len(),
+            max_length
+        ));
+    }
+    Ok(())
+}
 
-        // Save config
-        match crate::config::Config::save(&config) {
-            Ok(path) => {
-                let msg = format!(
+/// Parse `dpkg-query -W -f='${Package}\t${Version}\n'` output
+fn parse_dpkg_packages(output: &str) -> Vec<PackageEntry> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let mut parts = line.splitn(2, '\t');
+            let name = parts.next()?.trim();
+            if name.is_empty() {
+                return None;
+            }
+            let version = parts
+                .next()
+                .map(str::trim)
+                .filter(|v| !v.is_empty())
+                .map(String::from);
+            Some(PackageEntry {
+                name: name.to_string(),
+                version,
+            })
+        })
+        .collect()
+}
+
+/// Parse `pm list packages --show-versioncode` output
+/// Lines look like `package:com.termux versionCode:118`; older devices omit the version
+fn parse_pm_packages(output: &str) -> Vec<PackageEntry> {
+    output
+        .lines()
+        .filter_map(|line| {
+            let rest = line.trim().strip_prefix("package:")?;
+            let mut parts = rest.split_whitespace();
+            let name = parts.next()?.to_string();
+            let version = parts
+                .find_map(|p| p.strip_prefix("versionCode:"))
+                .map(String::from);
+            Some(PackageEntry { name, version })
+        })
+        .collect()
+}
+
+/// Shell snippet printing the hex mode of `path` itself, then `<hex mode> <size> <mtime>`
+/// for what it points to; GNU, busybox and toybox stat all agree on these `-c` formats
+/// Prints nothing when the path doesn't exist, and only the first line for a dangling symlink
+fn path_stat_command(path: &str) -> String {
+    let path = quote_remote_path(path);
+    format!(
+        "if [ -e {p} ] || [ -L {p} ]; then stat -c '%f' -- {p}; \
+         stat -L -c '%f %s %Y' -- {p} 2>/dev/null; fi; true",
+        p = path
+    )
+}
+
+/// How make_dir_command found or left the path, from the marker it prints last
+#[derive(Debug, PartialEq)]
+enum MakeDirOutcome {
+    Created,
+    AlreadyExists,
+    /// Something other than a directory is at the path
+    NotADirectory,
+    /// mkdir failed; its error is in stderr
+    Failed,
+}
+
+/// Build a command that creates `path` (and missing parents with `parents`) and checks
+/// the result is a directory, printing a marker parse_make_dir reads back
+fn make_dir_command(path: &str, parents: bool) -> String {
+    let flag = if parents { "-p " } else { "" };
+    format!(
+        "p={}; if [ -d \"$p\" ]; then echo exists; \
+         elif [ -e \"$p\" ] || [ -L \"$p\" ]; then echo not_dir; \
+         elif mkdir {}-- \"$p\" && [ -d \"$p\" ]; then echo created; \
+         else echo failed; fi",
+        quote_remote_path(path),
+        flag
+    )
+}
+
+/// First non-empty line of a command's stderr, or of stdout if stderr is empty, to explain a failure
+fn first_output_line(result: &CommandResult) -> &str {
+    [&result.stderr, &result.stdout]
+        .into_iter()
+        .find_map(|out| out.lines().find(|l| !l.trim().is_empty()))
+        .unwrap_or("no output")
+        .trim()
+}
+
+/// Commands for disk_usage: df for the filesystem holding `path`, and du for its
+/// immediate subdirectories, both in KiB
+/// The trailing slash makes du follow a symlinked directory such as /sdcard
+fn disk_usage_commands(path: &str) -> (String, String) {
+    let quoted = quote_remote_path(path);
+    let dir = quote_remote_path(&format!("{}/", path.trim_end_matches('/')));
+    (format!("df -k {}", quoted), format!("du -k -d 1 {}", dir))
+}
+
+/// Whether copy_path or move_path is running
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum TransferOp {
+    Copy,
+    Move,
+}
+
+impl TransferOp {
+    fn verb(self) -> &'static str {
+        match self {
+            TransferOp::Copy => "copy",
+            TransferOp::Move => "move",
+        }
+    }
+}
+
+/// How transfer_command left the paths, from the marker it prints last
+#[derive(Debug, PartialEq)]
+enum TransferOutcome {
+    Done,
+    SourceMissing,
+    /// The target exists and overwrite wasn't set
+    Exists,
+    /// The target is an existing directory, which is never replaced
+    TargetIsDir,
+    /// cp or mv failed, or the result didn't check out; the error is in stderr
+    Failed,
+}
+
+/// Build a command that copies or moves `source` to `dest`, printing the final path as
+/// `target:<path>` and then a marker parse_transfer reads back
+/// Like cp and mv, a `dest` that is a directory receives the source inside it
+/// An existing target is refused unless `overwrite` is set, and the result is checked
+/// afterwards: the target exists and, for a move, the source is gone
+fn transfer_command(op: TransferOp, source: &str, dest: &str, overwrite: bool) -> String {
+    let source = match source.trim_end_matches('/') {
+        "" => "/",
+        trimmed => trimmed,
+    };
+    let on_existing = if overwrite {
+        ":"
+    } else {
+        "echo exists; exit 0"
+    };
+    let (run, verify) = match op {
+        TransferOp::Copy => ("cp -R", ""),
+        TransferOp::Move => ("mv -f", " && [ ! -e \"$s\" ] && [ ! -L \"$s\" ]"),
+    };
+    format!(
+        "s={}; d={}; \
+         if [ ! -e \"$s\" ] && [ ! -L \"$s\" ]; then echo no_source; exit 0; fi; \
+         if [ -d \"$d\" ]; then t=\"${{d%/}}/${{s##*/}}\"; else t=\"$d\"; fi; \
+         printf 'target:%s\\n' \"$t\"; \
+         if [ -e \"$t\" ] || [ -L \"$t\" ]; then \
+         if [ -d \"$t\" ] && [ ! -L \"$t\" ]; then echo target_dir; exit 0; fi; {}; fi; \
+         if {} -- \"$s\" \"$t\" && {{ [ -e \"$t\" ] || [ -L \"$t\" ]; }}{}; \
+         then echo done; else echo failed; fi",
+        quote_remote_path(source),
+        quote_remote_path(dest),
+        on_existing,
+        run,
+        verify
+    )
+}
+
+/// Parse the output of `transfer_command`: the outcome and the path it resolved to
+fn parse_transfer(output: &str) -> (TransferOutcome, Option<&str>) {
+    let target = output
+        .lines()
+        .find_map(|l| l.strip_prefix("target:"))
+        .filter(|t| !t.is_empty());
+    let outcome = match output.lines().map(str::trim).rfind(|l| !l.is_empty()) {
+        Some("done") => TransferOutcome::Done,
+        Some("no_source") => TransferOutcome::SourceMissing,
+        Some("exists") => TransferOutcome::Exists,
+        Some("target_dir") => TransferOutcome::TargetIsDir,
+        _ => TransferOutcome::Failed,
+    };
+    (outcome, target)
+}
+
+/// Tools removed by disable_execute: those that run any command the caller gives and
+/// the ones that manage what they started, then those that write files or stop
+/// processes, which could replace a whitelisted binary or do what a command would
+const EXECUTE_DISABLED_TOOLS: [&str; 19] = [
+    "execute",
+    "batch",
+    "stream",
+    "send_signal",
+    "run_script",
+    "start_job",
+    "job_status",
+    "kill_job",
+    "shell_send",
+    "shell_close",
+    "write_file",
+    "upload_file",
+    "make_dir",
+    "copy_path",
+    "move_path",
+    "clipboard_set",
+    "provision_key",
+    "process_kill",
+    "power",
+];
+
+/// Most shells shell_send keeps open at once, each holding a channel on the device's sshd
+const MAX_SHELL_SESSIONS: usize = 4;
+
+/// Lines of context diff_files shows around each change by default, as `diff -u` does
+const DEFAULT_DIFF_CONTEXT: u32 = 3;
+
+/// How diff_files found the two files, from the marker diff_command prints last
+#[derive(Debug, PartialEq)]
+enum DiffOutcome<'a> {
+    Identical,
+    /// Unified diff of two text files that differ
+    Differ(&'a str),
+    /// At least one file has NUL bytes, so only whether they differ is reported
+    BinaryDiffer,
+    Missing(&'a str),
+    IsDir(&'a str),
+    Unreadable(&'a str),
+    /// A command the comparison needs isn't installed
+    MissingTool(&'a str),
+    /// diff failed; the error is in stderr
+    Failed,
+}
+
+/// Build a command that compares two files, printing a unified diff and then a marker
+/// parse_diff reads back
+/// cmp settles identical files first; a file with a NUL byte counts as binary and
+/// isn't passed to diff, so the result never holds raw binary data
+fn diff_command(path_a: &str, path_b: &str, context_lines: u32) -> String {
+    format!(
+        "for c in cmp tr diff; do \
+         command -v \"$c\" >/dev/null 2>&1 || {{ printf 'missing_tool:%s\\n' \"$c\"; exit 0; }}; done; \
+         a={}; b={}; \
+         for p in \"$a\" \"$b\"; do \
+         if [ ! -e \"$p\" ]; then printf 'missing:%s\\n' \"$p\"; exit 0; fi; \
+         if [ -d \"$p\" ]; then printf 'is_dir:%s\\n' \"$p\"; exit 0; fi; \
+         if [ ! -r \"$p\" ]; then printf 'unreadable:%s\\n' \"$p\"; exit 0; fi; done; \
+         if cmp -s -- \"$a\" \"$b\"; then echo identical; exit 0; fi; \
+         for p in \"$a\" \"$b\"; do \
+         if ! tr -d '\\000' < \"$p\" | cmp -s - \"$p\"; then echo binary; exit 0; fi; done; \
+         diff -U {} -- \"$a\" \"$b\"; \
+         if [ $? -eq 1 ]; then echo differ; else echo failed; fi",
+        quote_remote_path(path_a),
+        quote_remote_path(path_b),
+        context_lines
+    )
+}
+
+/// Parse the output of `diff_command`
+fn parse_diff(output: &str) -> DiffOutcome<'_> {
+    let body = output.trim_end();
+    let (diff, marker) = match body.rsplit_once('\n') {
+        Some((diff, marker)) => (diff, marker.trim()),
+        None => ("", body.trim()),
+    };
+    if let Some(path) = marker.strip_prefix("missing:") {
+        return DiffOutcome::Missing(path);
+    }
+    if let Some(path) = marker.strip_prefix("is_dir:") {
+        return DiffOutcome::IsDir(path);
+    }
+    if let Some(path) = marker.strip_prefix("unreadable:") {
+        return DiffOutcome::Unreadable(path);
+    }
+    if let Some(name) = marker.strip_prefix("missing_tool:") {
+        return DiffOutcome::MissingTool(name);
+    }
+    match marker {
+        "identical" => DiffOutcome::Identical,
+        "binary" => DiffOutcome::BinaryDiffer,
+        "differ" => DiffOutcome::Differ(diff),
+        _ => DiffOutcome::Failed,
+    }
+}
+
+/// Paths search_files returns when max_results isn't given, and the most it accepts
+const DEFAULT_SEARCH_RESULTS: usize = 200;
+const MAX_SEARCH_RESULTS: usize = 10_000;
+
+/// stderr lines from find (usually permission errors) kept in a search_files result
+const MAX_SEARCH_ERRORS: usize = 10;
+
+/// Build a find command for search_files, printing a status line and then one path per line
+/// head stops find once one more path than `max_results` is printed, so a search of a
+/// large tree ends early and the extra line shows the list was cut off
+fn search_command(
+    root: &str,
+    name_glob: Option<&str>,
+    max_depth: Option<u32>,
+    kind: Option<SearchType>,
+    max_results: usize,
+) -> String {
+    // -maxdepth is an option, and find warns unless it comes before the tests
+    let mut predicates = String::new();
+    if let Some(depth) = max_depth {
+        predicates.push_str(&format!(" -maxdepth {}", depth));
+    }
+    match kind {
+        Some(SearchType::File) => predicates.push_str(" -type f"),
+        Some(SearchType::Dir) => predicates.push_str(" -type d"),
+        None => {}
+    }
+    if let Some(glob) = name_glob {
+        predicates.push_str(&format!(" -name {}", shell_quote(glob)));
+    }
+    format!(
+        "command -v find >/dev/null 2>&1 || {{ echo search:missing_tool; exit 0; }}; \
+         r={}; \
+         if [ ! -e \"$r\" ]; then echo search:missing; exit 0; fi; \
+         if [ ! -d \"$r\" ]; then echo search:not_dir; exit 0; fi; \
+         echo search:ok; \
+         find \"$r\"{} -print | head -n {}",
+        quote_remote_path(root),
+        predicates,
+        max_results + 1
+    )
+}
+
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchType {
+    /// Regular files only
+    File,
+    /// Directories only
+    Dir,
+}
+
+/// How search_files went, from the status line search_command prints first
+#[derive(Debug, PartialEq)]
+enum SearchOutcome<'a> {
+    /// Paths found, and whether there were more than max_results
+    Found(Vec<&'a str>, bool),
+    Missing,
+    NotDir,
+    MissingTool,
+    /// The status line is missing, so the command didn't run as expected
+    Failed,
+}
+
+/// Parse the output of `search_command`
+fn parse_search(output: &str, max_results: usize) -> SearchOutcome<'_> {
+    let mut lines = output.lines();
+    match lines.next().map(str::trim) {
+        Some("search:ok") => {
+            let mut paths: Vec<&str> = lines.filter(|l| !l.is_empty()).collect();
+            let truncated = paths.len() > max_results;
+            paths.truncate(max_results);
+            SearchOutcome::Found(paths, truncated)
+        }
+        Some("search:missing") => SearchOutcome::Missing,
+        Some("search:not_dir") => SearchOutcome::NotDir,
+        Some("search:missing_tool") => SearchOutcome::MissingTool,
+        _ => SearchOutcome::Failed,
+    }
+}
+
+fn parse_make_dir(output: &str) -> MakeDirOutcome {
+    match output.lines().map(str::trim).rfind(|l| !l.is_empty()) {
+        Some("created") => MakeDirOutcome::Created,
+        Some("exists") => MakeDirOutcome::AlreadyExists,
+        Some("not_dir") => MakeDirOutcome::NotADirectory,
+        _ => MakeDirOutcome::Failed,
+    }
+}
+
+/// Parse the output of `path_stat_command`
+fn parse_path_stat(path: &str, output: &str) -> Option<PathInfo> {
+    const S_IFMT: u32 = 0o170000;
+    let mut lines = output.lines().map(str::trim).filter(|l| !l.is_empty());
+    let Some(own_mode) = lines.next() else {
+        return Some(PathInfo::missing(path));
+    };
+    let own_mode = u32::from_str_radix(own_mode, 16).ok()?;
+
+    let mut info = PathInfo::missing(path);
+    info.exists = true;
+    info.is_symlink = own_mode & S_IFMT == 0o120000;
+    if let Some(target) = lines.next() {
+        let mut fields = target.split_whitespace();
+        let mode = u32::from_str_radix(fields.next()?, 16).ok()?;
+        info.is_dir = mode & S_IFMT == 0o040000;
+        info.is_file = mode & S_IFMT == 0o100000;
+        info.size_bytes = Some(fields.next()?.parse().ok()?);
+        info.mtime = Some(fields.next()?.parse().ok()?);
+        info.mode_octal = Some(format!("{:04o}", mode & 0o7777));
+    }
+    Some(info)
+}
+
+/// Build a logcat invocation that dumps the last `lines` entries and exits
+/// A tag keeps only that tag's messages (`TAG:P *:S`); a priority alone applies to every tag
+fn logcat_command(
+    lines: usize,
+    priority: Option<LogcatPriority>,
+    tag: Option<&str>,
+) -> std::result::Result<String, String> {
+    let mut command = format!("logcat -d -v threadtime -t {}", lines);
+    let level = priority.map_or('V', LogcatPriority::letter);
+    match tag {
+        Some(tag) => {
+            if tag.is_empty() || tag.contains(':') || tag.contains(char::is_whitespace) {
+                return Err(format!(
+                    "Invalid tag '{}': tags can't be empty or contain ':' or whitespace",
+                    tag
+                ));
+            }
+            command.push_str(&format!(
+                " {} '*:S'",
+                shell_quote(&format!("{}:{}", tag, level))
+            ));
+        }
+        None if priority.is_some() => command.push_str(&format!(" '*:{}'", level)),
+        None => {}
+    }
+    Ok(command)
+}
+
+// Read size and progress notification spacing for streamed checksums
+const CHECKSUM_CHUNK_SIZE: usize = 256 * 1024;
+const CHECKSUM_PROGRESS_INTERVAL: u64 = 4 * 1024 * 1024;
+
+/// Stream a remote file over SFTP and hash it locally
+/// Sends progress notifications if the caller supplied a progress token
+async fn stream_checksum(
+    sftp: &SftpSession,
+    path: &str,
+    algorithm: ChecksumAlgorithm,
+    context: &RequestContext<RoleServer>,
+) -> std::result::Result<(String, u64), String> {
+    let total = sftp
+        .metadata(path)
+        .await
+        .map_err(|e| format!("Cannot stat {}: {}", path, e))?
+        .len();
+    let mut file = sftp
+        .open(path)
+        .await
+        .map_err(|e| format!("Cannot open {}: {}", path, e))?;
+
+    let progress_token = context.meta.get_progress_token();
+    let peer: &Peer<RoleServer> = &context.peer;
+
+    let mut hasher = algorithm.hasher();
+    let mut buf = vec![0u8; CHECKSUM_CHUNK_SIZE];
+    let mut hashed: u64 = 0;
+    let mut last_report: u64 = 0;
+    loop {
+        let n = file
+            .read(&mut buf)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", path, e))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+        hashed += n as u64;
+
+        if let Some(ref token) = progress_token
+            && hashed - last_report >= CHECKSUM_PROGRESS_INTERVAL
+        {
+            last_report = hashed;
+            let _ = peer
+                .notify_progress(ProgressNotificationParam {
+                    progress_token: token.clone(),
+                    progress: hashed as f64,
+                    total: Some(total as f64),
+                    message: Some(format!("Hashed {} of {} bytes", hashed, total)),
+                })
+                .await;
+        }
+    }
+
+    Ok((hex_digest(hasher), hashed))
+}
+
+// Output execute gathers before sending it as a progress notification, and how long
+// a smaller amount may wait, so a command printing slowly still shows up
+const EXECUTE_PROGRESS_BYTES: usize = 4 * 1024;
+const EXECUTE_PROGRESS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(1);
+
+/// Run `work` while sending the output chunks arriving on `chunks` as progress notifications,
+/// with the text in the message and the bytes received so far as the progress
+/// Output still queued when `work` finishes is sent before its result is returned
+async fn with_output_progress<T>(
+    work: impl std::future::Future<Output = T>,
+    mut chunks: tokio::sync::mpsc::UnboundedReceiver<Vec<u8>>,
+    token: ProgressToken,
+    peer: &Peer<RoleServer>,
+) -> T {
+    let mut pending = Vec::new();
+    let mut received: u64 = 0;
+    let notify = |pending: &mut Vec<u8>, received: u64, finished: bool| {
+        // A character split between chunks waits for the rest of it, unless no more is coming
+        let keep = match std::str::from_utf8(pending) {
+            Err(e) if !finished && e.error_len().is_none() => pending.len() - e.valid_up_to(),
+            _ => 0,
+        };
+        let held = pending.split_off(pending.len() - keep);
+        let text = String::from_utf8_lossy(pending).replace("\r\n", "\n");
+        *pending = held;
+        (!text.is_empty()).then(|| {
+            peer.notify_progress(ProgressNotificationParam {
+                progress_token: token.clone(),
+                progress: received as f64,
+                total: None,
+                message: Some(text),
+            })
+        })
+    };
+
+    tokio::pin!(work);
+    let mut flush = tokio::time::interval(EXECUTE_PROGRESS_INTERVAL);
+    loop {
+        tokio::select! {
+            result = &mut work => {
+                while let Ok(chunk) = chunks.try_recv() {
+                    received += chunk.len() as u64;
+                    pending.extend_from_slice(&chunk);
+                }
+                if let Some(sent) = notify(&mut pending, received, true) {
+                    let _ = sent.await;
+                }
+                return result;
+            }
+            Some(chunk) = chunks.recv() => {
+                received += chunk.len() as u64;
+                pending.extend_from_slice(&chunk);
+                if pending.len() >= EXECUTE_PROGRESS_BYTES
+                    && let Some(sent) = notify(&mut pending, received, false)
+                {
+                    let _ = sent.await;
+                }
+            }
+            _ = flush.tick() => {
+                if let Some(sent) = notify(&mut pending, received, false) {
+                    let _ = sent.await;
+                }
+            }
+        }
+    }
+}
+
+/// Hash a file on this machine, for comparing with a remote checksum
+async fn local_checksum(
+    path: &std::path::Path,
+    algorithm: ChecksumAlgorithm,
+) -> std::result::Result<String, String> {
+    let mut file = tokio::fs::File::open(path)
+        .await
+        .map_err(|e| format!("Cannot open {}: {}", path.display(), e))?;
+    let mut hasher = algorithm.hasher();
+    let mut buf = vec![0u8; CHECKSUM_CHUNK_SIZE];
+    loop {
+        let n = file
+            .read(&mut buf)
+            .await
+            .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+        if n == 0 {
+            break;
+        }
+        hasher.update(&buf[..n]);
+    }
+    Ok(hex_digest(hasher))
+}
+
+fn hex_digest(hasher: Box<dyn DynDigest + Send>) -> String {
+    hasher
+        .finalize()
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect()
+}
+
+/// Format a command result as stdout, stderr, and a status line
+fn format_command_result(result: &CommandResult) -> String {
+    format_command_result_with(result, false)
+}
+
+/// Like format_command_result, reporting a command that exited 0 as failed if it wrote
+/// to stderr and `fail_on_stderr` is set
+fn format_command_result_with(result: &CommandResult, fail_on_stderr: bool) -> String {
+    // Format output nicely
+    let mut output = String::new();
+
+    // Add stdout if present
+    if !result.stdout.is_empty() {
+        output.push_str(&result.stdout);
+        if !output.ends_with('\n') {
+            output.push('\n');
+        }
+    }
+
+    // Add stderr if present
+    if !result.stderr.is_empty() {
+        if !output.is_empty() {
+            output.push('\n');
+        }
+        output.push_str("stderr:\n");
+        output.push_str(&result.stderr);
+        if !output.ends_with('\n') {
+            output.push('\n');
+        }
+    }
+
+    // Always show status line
+    if !output.is_empty() {
+        output.push('\n');
+    }
+
+    let seconds = result.duration_ms as f64 / 1000.0;
+    match result.exit_code {
+        Some(0) if fail_on_stderr && result.fails_on_stderr() => output.push_str(&format!(
+            "✗ Failed (wrote to stderr, exit code: 0, {:.2}s; fail_on_stderr is set)",
+            seconds
+        )),
+        Some(0) => output.push_str(&format!("✓ Success ({:.2}s)", seconds)),
+        Some(code) => output.push_str(&format!("✗ Failed (exit code: {}, {:.2}s)", code, seconds)),
+        None => output.push_str(&format!(
+            "? Unknown result (exit code: unknown, {:.2}s): the channel closed before the \
+             device sent an exit status, so the command may not have finished",
+            seconds
+        )),
+    }
+
+    output
+}
+
+/// The last `max_bytes` of `output`, from the start of a line, after a note saying how
+/// much was left out; the most recent output is usually what matters
+fn tail_of_output(output: &str, max_bytes: usize) -> String {
+    if output.len() <= max_bytes {
+        return output.to_string();
+    }
+    let start = floor_char_boundary(output, output.len() - max_bytes);
+    let start = output[start..].find('\n').map_or(start, |i| start + i + 1);
+    format!(
+        "[output truncated: {} earlier bytes omitted]\n{}",
+        start,
+        &output[start..]
+    )
+}
+
+/// Largest index <= `index` that falls on a UTF-8 character boundary
+fn floor_char_boundary(text: &str, index: usize) -> usize {
+    let mut index = index.min(text.len());
+    while !text.is_char_boundary(index) {
+        index -= 1;
+    }
+    index
+}
+
+/// The part of stdout returned by one execute/execute_read call
+struct OutputPage {
+    start: usize,
+    end: usize,
+    total_bytes: usize,
+}
+
+impl OutputPage {
+    /// Select up to `max_bytes` of `text` starting at `offset`, on character boundaries
+    fn new(text: &str, offset: usize, max_bytes: usize) -> Self {
+        let start = floor_char_boundary(text, offset);
+        let end = floor_char_boundary(text, start.saturating_add(max_bytes));
+        Self {
+            start,
+            end,
+            total_bytes: text.len(),
+        }
+    }
+
+    /// Note explaining what was left out, or None if this is all of the output
+    fn marker(&self) -> Option<String> {
+        if self.end < self.total_bytes {
+            Some(format!(
+                "[output truncated: showing bytes {}-{} of {}, {} bytes omitted; \
+                 re-run with offset={} to see more]",
+                self.start,
+                self.end,
+                self.total_bytes,
+                self.total_bytes - (self.end - self.start),
+                self.end
+            ))
+        } else if self.start > 0 {
+            Some(format!(
+                "[showing bytes {}-{} of {}]",
+                self.start, self.end, self.total_bytes
+            ))
+        } else {
+            None
+        }
+    }
+}
+
+/// Lines kept by an execute/execute_read grep filter, reported with the result
+#[derive(Debug, Serialize)]
+struct LineFilter {
+    pattern: String,
+    invert: bool,
+    matched_lines: usize,
+    total_lines: usize,
+}
+
+/// Keep only the stdout lines that match `pattern` (or don't, with `invert`)
+/// Binary output is filtered as its lossy text, since matching lines are text anyway
+fn filter_stdout(result: &mut CommandResult, pattern: &Regex, invert: bool) -> LineFilter {
+    result.stdout_bytes = None;
+    let mut total_lines = 0;
+    let mut kept = String::new();
+    for line in result.stdout.split_inclusive('\n') {
+        total_lines += 1;
+        if pattern.is_match(line.trim_end_matches(['\n', '\r'])) != invert {
+            kept.push_str(line);
+        }
+    }
+    let matched_lines = kept.split_inclusive('\n').count();
+    result.stdout = kept;
+    LineFilter {
+        pattern: pattern.as_str().to_string(),
+        invert,
+        matched_lines,
+        total_lines,
+    }
+}
+
+/// Compile an execute/execute_read grep pattern, or return the error to hand back
+fn compile_grep(pattern: Option<&str>) -> std::result::Result<Option<Regex>, CallToolResult> {
+    pattern.map(Regex::new).transpose().map_err(|e| {
+        CallToolResult::error(vec![Content::text(format!("Invalid grep pattern: {}", e))])
+    })
+}
+
+/// How stdout is returned from execute/execute_read
+#[derive(Debug, Default, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum OutputEncoding {
+    /// Text, with bytes that aren't valid UTF-8 replaced by �
+    Utf8,
+    /// Base64 of the exact bytes
+    Base64,
+    /// Text if it is valid UTF-8, otherwise base64
+    #[default]
+    Auto,
+}
+
+/// Re-encode stdout as requested, returning the encoding used and a note for binary output
+fn encode_stdout(
+    result: &mut CommandResult,
+    encoding: OutputEncoding,
+) -> (&'static str, Option<&'static str>) {
+    use base64::Engine;
+    let base64 = |bytes: &[u8]| base64::engine::general_purpose::STANDARD.encode(bytes);
+    match (encoding, result.stdout_bytes.take()) {
+        (OutputEncoding::Utf8, Some(_)) => (
+            "utf8",
+            Some(
+                "stdout is not valid UTF-8, so invalid bytes were replaced with �. \
+                 Re-run with encoding=\"base64\" to get the exact bytes.",
+            ),
+        ),
+        (OutputEncoding::Base64, bytes) => {
+            result.stdout = base64(bytes.as_deref().unwrap_or(result.stdout.as_bytes()));
+            ("base64", None)
+        }
+        (OutputEncoding::Auto, Some(bytes)) => {
+            result.stdout = base64(&bytes);
+            (
+                "base64",
+                Some("stdout is binary (not valid UTF-8), so it is returned base64-encoded."),
+            )
+        }
+        (_, None) => ("utf8", None),
+    }
+}
+
+/// How execute and execute_read report a result, from the request or the config defaults
+#[derive(Debug, Clone, Copy)]
+struct ResultOptions {
+    /// Add the fields as JSON structured content
+    structured: bool,
+    /// Report a command that exited 0 but wrote to stderr as failed
+    fail_on_stderr: bool,
+}
+
+/// Build the execute/execute_read result: the formatted text, plus the same
+/// fields as JSON structured content when `structured` is set
+/// Stdout is cut down to `page`, with a marker saying how to fetch the rest, and a
+/// resource link when the full stdout was kept at `resource_uri`
+fn command_tool_result(
+    mut result: CommandResult,
+    page: OutputPage,
+    cwd: Option<String>,
+    options: ResultOptions,
+    (encoding, note): (&'static str, Option<&'static str>),
+    filter: Option<LineFilter>,
+    resource_uri: Option<String>,
+) -> CallToolResult {
+    let missing = missing_command(&result);
+    let marker = page.marker();
+    result.stdout = result.stdout[page.start..page.end].to_string();
+    if let Some(ref marker) = marker {
+        if !result.stdout.is_empty() && !result.stdout.ends_with('\n') {
+            result.stdout.push('\n');
+        }
+        result.stdout.push_str(marker);
+    }
+
+    let mut output = format_command_result_with(&result, options.fail_on_stderr);
+    if let Some(ref missing) = missing {
+        output.push_str(&format!(
+            "\nHint: '{}' is not installed on the device. Install it with: pkg install {}",
+            missing.name, missing.package
+        ));
+    }
+    if let Some(ref dir) = cwd {
+        output.push_str(&format!("\nWorking directory: {}", dir));
+    }
+    if let Some(note) = note {
+        output.push_str(&format!("\nNote: {}", note));
+    }
+    if let Some(ref filter) = filter {
+        output.push_str(&format!(
+            "\ngrep{} '{}': {} of {} stdout lines kept",
+            if filter.invert { " (inverted)" } else { "" },
+            filter.pattern,
+            filter.matched_lines,
+            filter.total_lines
+        ));
+    }
+
+    if let Some(ref uri) = resource_uri {
+        output.push_str(&format!(
+            "\nFull output: {} (read it as an MCP resource instead of paging)",
+            uri
+        ));
+    }
+    let mut content = vec![Content::text(output)];
+    if let Some(ref uri) = resource_uri {
+        let mut link = RawResource::new(uri.clone(), "stdout");
+        link.description = Some("Full stdout of the command".to_string());
+        link.mime_type = Some(output_mime_type(encoding).to_string());
+        link.size = u32::try_from(page.total_bytes).ok();
+        content.push(Content::resource_link(link));
+    }
+    let mut tool_result = CallToolResult::success(content);
+    if options.structured {
+        tool_result.structured_content = Some(serde_json::json!({
+            "stdout": result.stdout,
+            "stderr": result.stderr,
+            "exit_code": result.exit_code,
+            "success": result.success() && !(options.fail_on_stderr && result.fails_on_stderr()),
+            "duration_ms": result.duration_ms,
+            "cwd": cwd,
+            "stdout_encoding": encoding,
+            "stdout_total_bytes": page.total_bytes,
+            "next_offset": (page.end < page.total_bytes).then_some(page.end),
+            "resource_uri": resource_uri,
+            "grep": filter,
+            "missing_command": missing,
+        }));
+    }
+    tool_result
+}
+
+/// MIME type of stdout stored as a resource, by the encoding it was returned in
+pub(crate) fn output_mime_type(encoding: &str) -> &'static str {
+    if encoding == "base64" {
+        "application/octet-stream"
+    } else {
+        "text/plain"
+    }
+}
+
+/// Error result for a failed operation: `context: error` as text, plus the error's
+/// code as structured content so clients can tell e.g. a timeout from a refused connection
+fn ssh_error_result(context: &str, error: &SshMcpError) -> CallToolResult {
+    let mut result = CallToolResult::error(vec![Content::text(format!("{}: {}", context, error))]);
+    result.structured_content = Some(serde_json::json!({
+        "error": {
+            "code": error.code(),
+            "message": error.to_string(),
+        }
+    }));
+    result
+}
+
+/// Save setup values as `[profiles.<name>]`, laid over the existing top-level settings
+async fn setup_profile(name: &str, request: SetupRequest) -> CallToolResult {
+    let test = request.test_connection.unwrap_or(true);
+    let Ok(mut config) = crate::config::Config::load_existing() else {
+        return CallToolResult::error(vec![Content::text(
+            "Profiles extend the base configuration, which doesn't exist yet.\n\
+             Run setup without a profile first."
+                .to_string(),
+        )]);
+    };
+
+    let profile = config.profiles.entry(name.to_string()).or_default();
+    profile.host = request.host.or(profile.host.take());
+    profile.port = request.port.or(profile.port);
+    profile.user = request.user.or(profile.user.take());
+    profile.key_path = request.key_path.or(profile.key_path.take());
+    if request.password.is_some() {
+        profile.password_command = None;
+        profile.password_file = None;
+    }
+    profile.password = request.password.or(profile.password.take());
+    profile.use_agent = request.use_agent.or(profile.use_agent);
+    let Some(host) = profile.host.clone() else {
+        return CallToolResult::error(vec![Content::text(format!(
+            "Profile '{}' needs a host - the device's IP address.\n  \
+             Find it: Run 'ifconfig wlan0' in Termux",
+            name
+        ))]);
+    };
+    let port = profile.port.unwrap_or(config.port);
+    let user = profile.user.clone().unwrap_or_else(|| config.user.clone());
+
+    let tested = if test {
+        match config.for_profile(Some(name)) {
+            Ok(profile_config) => test_connection(profile_config)
+                .await
+                .unwrap_or_else(|e| connection_test_failed(&e)),
+            Err(e) => connection_test_failed(&e),
+        }
+    } else {
+        String::new()
+    };
+
+    match crate::config::Config::save(&config) {
+        Ok(path) => CallToolResult::success(vec![Content::text(format!(
+            "✓ Profile '{}' saved to: {}\n\n\
+             Connection details:\n\
+             • Host: {}:{}\n\
+             • User: {}\n\n\
+             {}\
+             Settings not given for the profile are taken from the top-level config.\n\
+             After calling reload_config, pass profile=\"{}\" to execute or execute_read.",
+            name,
+            path.display(),
+            host,
+            port,
+            user,
+            tested,
+            name
+        ))]),
+        Err(e) => {
+            CallToolResult::error(vec![Content::text(format!("Failed to save config: {}", e))])
+        }
+    }
+}
+
+/// Connect with `config` and run whoami, describing a successful login for the setup message
+async fn test_connection(
+    mut config: crate::config::Config,
+) -> std::result::Result<String, SshMcpError> {
+    // One attempt is enough to tell whether the settings work
+    config.connect_retries = Some(1);
+    let expected_user = config.user.clone();
+    let mut client = SshClient::new(config);
+    let outcome = client.execute_command("whoami", 10).await;
+    client.disconnect().await;
+
+    let result = outcome?;
+    Ok(match result.exit_code {
+        Some(0) => {
+            let whoami = result.stdout.trim();
+            if whoami.is_empty() || whoami == expected_user {
+                "✓ Connection test passed: logged in and ran whoami\n\n".to_string()
+            } else {
+                format!(
+                    "✓ Connection test passed: logged in as '{}', which whoami reports as '{}'\n\n",
+                    expected_user, whoami
+                )
+            }
+        }
+        _ => format!(
+            "✓ Connection test logged in, but whoami exited with code {}: {}\n\n",
+            result.exit_code_text(),
+            result.stderr.trim()
+        ),
+    })
+}
+
+/// Flag settings saved by setup as untested, since a failed test doesn't stop the save
+fn connection_test_failed(error: &SshMcpError) -> String {
+    format!(
+        "⚠ Connection test failed ({}), so these settings are saved but untested:\n{}\n\n\
+         Fix them and run setup again, or pass test_connection=false to skip the test.\n\n",
+        error.code(),
+        error
+    )
+}
+
+/// A command started in the background by start_job
+#[derive(Debug, Clone)]
+pub(crate) struct BackgroundJob {
+    pub command: String,
+    pub pid: u32,
+    pub log_path: String,
+    pub started: Instant,
+    /// Set once the job has been seen exited, so its pid is never signalled again
+    pub exited: bool,
+}
+
+/// A command the stream tool is running, which send_signal can reach
+pub(crate) struct RunningStream {
+    pub command: String,
+    /// Delivers signals to the stream loop, which owns the channel; closed once it ends
+    pub signals: tokio::sync::mpsc::UnboundedSender<JobSignal>,
+}
+
+/// What the probe_commands check found, keyed like available_commands
+#[derive(Debug, Default)]
+pub(crate) struct CommandProbe {
+    probed: HashSet<String>,
+    missing: HashSet<String>,
+}
+
+/// A lazily connected client, None until a config has been loaded
+type SharedClient = Arc<Mutex<Option<SshClient>>>;
+
+#[derive(Clone)]
+pub struct AndroidSshService {
+    pub(crate) ssh_client: SharedClient,
+    /// Clients for profiles other than the default, created on first use
+    pub(crate) profile_clients: Arc<Mutex<HashMap<String, SharedClient>>>,
+    /// Loaded configuration, for building profile clients; replaced by reload_config
+    config: Arc<Mutex<Option<crate::config::Config>>>,
+    pub(crate) jobs: Arc<Mutex<HashMap<u32, BackgroundJob>>>,
+    next_job_id: Arc<AtomicU32>,
+    /// Local port forwards opened by forward_port, closed when removed
+    forwards: Arc<Mutex<BTreeMap<u32, PortForward>>>,
+    next_forward_id: Arc<AtomicU32>,
+    /// Commands running under the stream tool, by the id announced when they start
+    streams: Arc<Mutex<BTreeMap<u32, RunningStream>>>,
+    next_stream_id: Arc<AtomicU32>,
+    /// Shells opened by shell_send, kept until closed or exited
+    shells: Arc<Mutex<BTreeMap<u32, Arc<Mutex<ShellSession>>>>>,
+    next_shell_id: Arc<AtomicU32>,
+    pub(crate) history: Arc<Mutex<CommandHistory>>,
+    /// Commands confirmed installed by `command -v`, as "host:port/command"
+    /// Only hits are cached, so a command installed later is picked up on the next probe
+    pub(crate) available_commands: Arc<Mutex<HashSet<String>>>,
+    /// Whitelisted commands from probe_commands, checked once per device
+    probe_commands: Arc<Vec<String>>,
+    /// Devices probed so far ("host:port") and the commands found missing ("host:port/command")
+    command_probe: Arc<Mutex<CommandProbe>>,
+    /// Server instructions overriding the built-in ones (from `[instructions]`)
+    pub(crate) server_instructions: Option<String>,
+    /// disable_execute removed the command and write tools, which the instructions have to say
+    pub(crate) execute_disabled: bool,
+    /// Directory set by set_working_directory, applied to execute and execute_read
+    pub(crate) cwd: Arc<Mutex<Option<String>>>,
+    /// Commands execute_read allows, after applying `[whitelist]`
+    pub(crate) read_only_commands: Arc<HashSet<String>>,
+    /// Whether execute_read accepts pipelines of whitelisted commands
+    pub(crate) allow_pipes_in_read: bool,
+    /// Commands execute refuses, from `[blocklist]`
+    pub(crate) blocklist: Arc<Blocklist>,
+    /// Whether commands matching the blocklist are kept out of the history file
+    history_skip_blocked: bool,
+    /// Limit from max_commands_per_minute, shared by every device
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    /// Command and connection counters for the stats tool, shared by every client
+    stats: Arc<ConnectionStats>,
+    /// Full output of truncated execute/execute_read results, served as resources
+    pub(crate) outputs: Arc<Mutex<OutputStore>>,
+    pub tool_router: ToolRouter<Self>,
+}
+
+impl AndroidSshService {
+    pub fn new(config: Option<crate::config::Config>) -> Self {
+        let mut tool_router = Self::tool_router();
+        let mut server_instructions = None;
+
+        // Apply per-deployment instructions and tool descriptions
+        if let Some(ref cfg) = config {
+            server_instructions = cfg.server_instructions().unwrap_or_else(|e| {
+                tracing::warn!("Using built-in instructions: {}", e);
+                None
+            });
+
+            if let Some(ref instructions) = cfg.instructions {
+                for (name, description) in &instructions.tools {
+                    match tool_router.map.get_mut(name.as_str()) {
+                        Some(route) => route.attr.description = Some(description.clone().into()),
+                        None => tracing::warn!(
+                            "Ignoring description override for unknown tool: {}",
+                            name
+                        ),
+                    }
+                }
+            }
+        }
+
+        let execute_disabled = config.as_ref().is_some_and(|c| c.disable_execute);
+        if execute_disabled {
+            for name in EXECUTE_DISABLED_TOOLS {
+                tool_router.remove_route(name);
+            }
+        }
+
+        let read_only_commands =
+            read_only_commands(config.as_ref().and_then(|c| c.whitelist.as_ref()));
+        let allow_pipes_in_read = config.as_ref().is_some_and(|c| c.allow_pipes_in_read);
+        let probe_commands: Vec<String> = config
+            .as_ref()
+            .map(|c| c.probe_commands.as_slice())
+            .unwrap_or_default()
+            .iter()
+            .filter(|name| {
+                let whitelisted = read_only_commands.contains(*name);
+                if !whitelisted {
+                    tracing::warn!("Not probing '{}', which execute_read doesn't allow", name);
+                }
+                whitelisted
+            })
+            .cloned()
+            .collect();
+        let blocklist = Blocklist::new(config.as_ref().and_then(|c| c.blocklist.as_ref()));
+        let history = match config.as_ref().filter(|c| c.persist_history) {
+            Some(cfg) => match cfg.history_path() {
+                Ok(path) => CommandHistory::persisted(path),
+                Err(e) => {
+                    tracing::warn!("Not persisting command history: {}", e);
+                    CommandHistory::default()
+                }
+            },
+            None => CommandHistory::default(),
+        };
+        let history_skip_blocked = config.as_ref().is_some_and(|c| c.history_skip_blocked);
+        let rate_limiter = config
+            .as_ref()
+            .and_then(|c| c.max_commands_per_minute)
+            .map(|n| Arc::new(Mutex::new(RateLimiter::per_minute(n))));
+
+        // Profiles were validated when the config was loaded
+        let stats = Arc::new(ConnectionStats::default());
+        let ssh_client = config.as_ref().map(|cfg| {
+            SshClient::with_stats(
+                cfg.for_profile(None).unwrap_or_else(|_| cfg.clone()),
+                stats.clone(),
+            )
+        });
+        Self {
+            ssh_client: Arc::new(Mutex::new(ssh_client)),
+            profile_clients: Arc::new(Mutex::new(HashMap::new())),
+            config: Arc::new(Mutex::new(config)),
+            jobs: Arc::new(Mutex::new(HashMap::new())),
+            next_job_id: Arc::new(AtomicU32::new(1)),
+            forwards: Arc::new(Mutex::new(BTreeMap::new())),
+            next_forward_id: Arc::new(AtomicU32::new(1)),
+            streams: Arc::new(Mutex::new(BTreeMap::new())),
+            next_stream_id: Arc::new(AtomicU32::new(1)),
+            shells: Arc::new(Mutex::new(BTreeMap::new())),
+            next_shell_id: Arc::new(AtomicU32::new(1)),
+            history: Arc::new(Mutex::new(history)),
+            available_commands: Arc::new(Mutex::new(HashSet::new())),
+            probe_commands: Arc::new(probe_commands),
+            command_probe: Arc::new(Mutex::new(CommandProbe::default())),
+            server_instructions,
+            execute_disabled,
+            cwd: Arc::new(Mutex::new(None)),
+            read_only_commands: Arc::new(read_only_commands),
+            allow_pipes_in_read,
+            blocklist: Arc::new(blocklist),
+            history_skip_blocked,
+            rate_limiter,
+            stats,
+            outputs: Arc::new(Mutex::new(OutputStore::default())),
+            tool_router,
+        }
+    }
+
+    /// Disconnect every open SSH session, default and profile clients alike
+    pub async fn disconnect_all(&self) {
+        if let Some(client) = self.ssh_client.lock().await.as_mut() {
+            client.disconnect().await;
+        }
+        let profile_clients: Vec<SharedClient> = self
+            .profile_clients
+            .lock()
+            .await
+            .values()
+            .cloned()
+            .collect();
+        for handle in profile_clients {
+            if let Some(client) = handle.lock().await.as_mut() {
+                client.disconnect().await;
+            }
+        }
+    }
+
+    /// Get the client for a profile, creating it on first use
+    /// None, or the default profile, selects the default client
+    async fn client_for_profile(
+        &self,
+        profile: Option<&str>,
+    ) -> std::result::Result<SharedClient, CallToolResult> {
+        // Without a config every tool reports the first-run message via the default client
+        let config = self.config.lock().await;
+        let (Some(name), Some(config)) = (profile, config.as_ref()) else {
+            return Ok(self.ssh_client.clone());
+        };
+        if config.default_profile.as_deref() == Some(name) {
+            return Ok(self.ssh_client.clone());
+        }
+
+        let mut clients = self.profile_clients.lock().await;
+        if let Some(client) = clients.get(name) {
+            return Ok(client.clone());
+        }
+        let profile_config = config
+            .for_profile(Some(name))
+            .map_err(|e| CallToolResult::error(vec![Content::text(e.to_string())]))?;
+        let client = Arc::new(Mutex::new(Some(SshClient::with_stats(
+            profile_config,
+            self.stats.clone(),
+        ))));
+        clients.insert(name.to_string(), client.clone());
+        Ok(client)
+    }
+
+    /// Spend `count` commands from the max_commands_per_minute budget
+    /// When over the limit, returns the error telling the client how long to wait
+    async fn check_rate_limit(&self, count: u32) -> std::result::Result<(), CallToolResult> {
+        let Some(ref limiter) = self.rate_limiter else {
+            return Ok(());
+        };
+        limiter.lock().await.try_acquire(count).map_err(|wait| {
+            CallToolResult::error(vec![Content::text(format!(
+                "Rate limited: max_commands_per_minute reached. Retry after {}s.",
+                wait.as_secs_f64().ceil() as u64
+            ))])
+        })
+    }
+
+    /// Prefix a command with `cd` into the stored working directory, if one is set
+    /// Also returns the directory so it can be reported alongside the output
+    async fn with_cwd(&self, command: &str) -> (String, Option<String>) {
+        match self.cwd.lock().await.clone() {
+            Some(dir) => (
+                format!("cd {} && {}", shell_quote(&dir), command),
+                Some(dir),
+            ),
+            None => (command.to_string(), None),
+        }
+    }
+
+    /// Execute a command and record it in the session history
+    async fn execute_recorded(
+        &self,
+        client: &mut SshClient,
+        command: &str,
+        timeout: u64,
+    ) -> crate::error::Result<CommandResult> {
+        self.execute_recorded_with(client, command, ExecOptions::default(), timeout)
+            .await
+    }
+
+    /// Execute a command with extra options and record it in the session history
+    async fn execute_recorded_with(
+        &self,
+        client: &mut SshClient,
+        command: &str,
+        options: ExecOptions<'_>,
+        timeout: u64,
+    ) -> crate::error::Result<CommandResult> {
+        let started = SystemTime::now();
+        let timer = Instant::now();
+        let result = client.execute_command_with(command, options, timeout).await;
+        self.record_history(
+            client,
+            command,
+            started,
+            timer,
+            result.as_ref().ok().and_then(|r| r.exit_code),
+        )
+        .await;
+        result
+    }
+
+    /// Hash a remote file on the device with md5sum/sha*sum, returning the lowercase hex digest
+    async fn device_checksum(
+        &self,
+        client: &mut SshClient,
+        path: &str,
+        algorithm: ChecksumAlgorithm,
+        timeout: u64,
+    ) -> std::result::Result<String, String> {
+        let command = format!("{} -- {}", algorithm.command(), quote_remote_path(path));
+        let result = self
+            .execute_recorded(client, &command, timeout)
+            .await
+            .map_err(|e| format!("Command execution failed: {}", e))?;
+        if !result.success() {
+            return Err(format_command_result(&result));
+        }
+        result
+            .stdout
+            .split_whitespace()
+            .next()
+            .filter(|d| d.chars().all(|c| c.is_ascii_hexdigit()))
+            .map(|d| d.to_ascii_lowercase())
+            .ok_or_else(|| {
+                format!(
+                    "Unexpected {} output: {}",
+                    algorithm.command(),
+                    result.stdout
+                )
+            })
+    }
+
+    /// Add a command to the session history, masking configured passwords and key passphrases
+    async fn record_history(
+        &self,
+        client: &SshClient,
+        command: &str,
+        started: SystemTime,
+        timer: Instant,
+        exit_code: Option<i32>,
+    ) {
+        let config = client.config();
+        let mut command = command.to_string();
+        let jump = config.jump_host.as_ref();
+        let secrets = [
+            config.password.as_ref(),
+            config.key_passphrase.as_ref(),
+            jump.and_then(|j| j.password.as_ref()),
+            jump.and_then(|j| j.key_passphrase.as_ref()),
+        ];
+        for secret in secrets.into_iter().flatten() {
+            if !secret.expose().is_empty() {
+                command = command.replace(secret.expose(), "***");
+            }
+        }
+        let persist = !(self.history_skip_blocked && self.blocklist.check(&command).is_err());
+        self.history
+            .lock()
+            .await
+            .record(command, started, exit_code, timer.elapsed(), persist);
+    }
+
+    /// Check whether a command is installed on the device, using the cached probe results
+    async fn is_command_available(
+        &self,
+        client: &mut SshClient,
+        name: &str,
+    ) -> crate::error::Result<bool> {
+        // Keyed by device so profiles don't share results
+        let key = format!("{}:{}/{}", client.config().host, client.config().port, name);
+        if self.available_commands.lock().await.contains(&key) {
+            return Ok(true);
+        }
+
+        let probe = format!("command -v {} >/dev/null 2>&1", shell_quote(name));
+        let available = client
+            .execute_command(&probe, default_timeout())
+            .await?
+            .success();
+        if available {
+            self.available_commands.lock().await.insert(key);
+        }
+        Ok(available)
+    }
+
+    /// Check `name` against the probe_commands results, probing the device on first use
+    /// Returns the not-installed message if the command is still missing; a command
+    /// found missing is checked again, so one installed since the probe is picked up
+    async fn check_probed(&self, client: &mut SshClient, name: &str) -> Option<String> {
+        if self.probe_commands.is_empty() {
+            return None;
+        }
+        let device = format!("{}:{}", client.config().host, client.config().port);
+        let key = format!("{}/{}", device, name);
+
+        if !self.command_probe.lock().await.probed.contains(&device) {
+            // A failed probe is left for the command itself to report
+            let result = client
+                .execute_command(&probe_command(&self.probe_commands), default_timeout())
+                .await
+                .ok()?;
+            let (found, missing) = parse_probe(&self.probe_commands, &result.stdout);
+            let mut available = self.available_commands.lock().await;
+            available.extend(found.iter().map(|c| format!("{}/{}", device, c)));
+            let mut probe = self.command_probe.lock().await;
+            probe
+                .missing
+                .extend(missing.iter().map(|c| format!("{}/{}", device, c)));
+            probe.probed.insert(device);
+        }
+
+        if !self.command_probe.lock().await.missing.contains(&key) {
+            return None;
+        }
+        match self.is_command_available(client, name).await {
+            Ok(false) => Some(not_installed_message(name)),
+            Ok(true) => {
+                self.command_probe.lock().await.missing.remove(&key);
+                None
+            }
+            Err(_) => None,
+        }
+    }
+
+    /// Run a command for a tool that builds its own command line
+    /// On failure, returns the error result to hand back to the client
+    async fn run_command(
+        &self,
+        command: &str,
+        timeout: u64,
+    ) -> std::result::Result<CommandResult, CallToolResult> {
+        self.run_command_with(command, ExecOptions::default(), timeout)
+            .await
+    }
+
+    /// Like run_command, with extra options such as input for the command's stdin
+    async fn run_command_with(
+        &self,
+        command: &str,
+        options: ExecOptions<'_>,
+        timeout: u64,
+    ) -> std::result::Result<CommandResult, CallToolResult> {
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Err(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        if timeout == 0 || timeout > 300 {
+            return Err(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+
+        self.execute_recorded_with(client, command, options, timeout)
+            .await
+            .map_err(|e| ssh_error_result("Command execution failed", &e))
+    }
+
+    /// Keep the full stdout as a resource when the first page of it leaves some out,
+    /// returning its URI
+    /// Later pages (offset > 0) come from a re-run, so they aren't stored again
+    async fn store_truncated_output(
+        &self,
+        command: &str,
+        stdout: &str,
+        encoding: &str,
+        page: &OutputPage,
+    ) -> Option<String> {
+        if page.start > 0 || page.end >= page.total_bytes {
+            return None;
+        }
+        self.outputs
+            .lock()
+            .await
+            .insert(command, stdout, encoding == "base64")
+    }
+
+    /// Shared body of copy_path and move_path
+    async fn transfer_path(
+        &self,
+        op: TransferOp,
+        request: TransferPathRequest,
+    ) -> Result<CallToolResult, McpError> {
+        for (name, path) in [("source", &request.source), ("dest", &request.dest)] {
+            if path.trim().is_empty() {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "{} must not be empty",
+                    name
+                ))]));
+            }
+            if path.contains(['\n', '\0']) {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "{} must not contain newlines or NUL bytes",
+                    name
+                ))]));
+            }
+        }
+
+        let command = transfer_command(op, &request.source, &request.dest, request.overwrite);
+        let result = match self.run_command(&command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+
+        let (outcome, target) = parse_transfer(&result.stdout);
+        let target = target.unwrap_or(&request.dest);
+        let source = &request.source;
+        match outcome {
+            TransferOutcome::Done => {
+                let done = match op {
+                    TransferOp::Copy => "Copied",
+                    TransferOp::Move => "Moved",
+                };
+                Ok(CallToolResult::success(vec![Content::text(format!(
+                    "✓ {} {} to {}",
+                    done, source, target
+                ))]))
+            }
+            TransferOutcome::SourceMissing => Ok(CallToolResult::error(vec![Content::text(
+                format!("Cannot {} {}: it does not exist", op.verb(), source),
+            )])),
+            TransferOutcome::Exists => Ok(CallToolResult::error(vec![Content::text(format!(
+                "Not {}: {} already exists. Pass overwrite=true to replace it, or pick another dest.",
+                match op {
+                    TransferOp::Copy => "copied",
+                    TransferOp::Move => "moved",
+                },
+                target
+            ))])),
+            TransferOutcome::TargetIsDir => {
+                Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Cannot {} {} to {}: that is an existing directory, which is never replaced, \
+                     even with overwrite=true. Remove it first or pick another dest.",
+                    op.verb(),
+                    source,
+                    target
+                ))]))
+            }
+            TransferOutcome::Failed => Ok(CallToolResult::error(vec![Content::text(format!(
+                "Failed to {} {} to {}:\n\n{}",
+                op.verb(),
+                source,
+                target,
+                format_command_result(&result)
+            ))])),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ExecuteRequest {
+    /// The shell command to execute
+    pub command: String,
+    /// Command timeout in seconds (default: default_read_timeout_secs or default_exec_timeout_secs config setting, 30; max: 300)
+    pub timeout: Option<u64>,
+    /// Also return stdout, stderr, exit_code, success, duration_ms and cwd as structured JSON (default: structured_output config setting)
+    pub structured: Option<bool>,
+    /// Report the command as failed if it writes to stderr, even with exit code 0; exit_code itself is unchanged (default: fail_on_stderr config setting)
+    pub fail_on_stderr: Option<bool>,
+    /// Most stdout bytes to return (default: max_output_bytes config setting, 64 KiB)
+    pub max_output_bytes: Option<usize>,
+    /// Byte offset into stdout to start from, for paging through long output (default: 0)
+    #[serde(default)]
+    pub offset: usize,
+    /// Device profile to run on (default: default_profile, or the top-level settings)
+    pub profile: Option<String>,
+    /// Run under a PTY so stderr is interleaved into stdout in the order it was produced (default: false)
+    #[serde(default)]
+    pub merge_streams: bool,
+    /// Environment variables to set for the command, e.g. {"LC_ALL": "C"}; values are quoted for you
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// How to return stdout: "utf8", "base64", or "auto" to base64 only output that isn't valid UTF-8 (default: auto)
+    #[serde(default)]
+    pub encoding: OutputEncoding,
+    /// Show the command line that would be sent, and whether blocklist and whitelist allow it, without running it (execute only, default: false)
+    #[serde(default)]
+    pub dry_run: bool,
+    /// Only return stdout lines matching this regex, filtered here rather than on the device (e.g. "error|warn")
+    pub grep: Option<String>,
+    /// Return the stdout lines that don't match grep instead (default: false)
+    #[serde(default)]
+    pub grep_invert: bool,
+    /// Run the command as this user through su, or sudo if su is missing, e.g. "root" on a rooted device (execute only)
+    pub run_as: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ExecuteReadRequest {
+    #[serde(flatten)]
+    pub execute: ExecuteRequest,
+    /// Check the command is installed before running it (default: false)
+    #[serde(default)]
+    pub verify_available: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct BatchRequest {
+    /// Shell commands to run in order, each in its own shell
+    pub commands: Vec<String>,
+    /// Stop at the first command that fails and skip the rest (default: false)
+    #[serde(default)]
+    pub stop_on_error: bool,
+    /// Timeout in seconds for the whole batch (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+    /// Device profile to run on (default: default_profile, or the top-level settings)
+    pub profile: Option<String>,
+    /// Count a command that writes to stderr as failed, even with exit code 0 (default: fail_on_stderr config setting)
+    pub fail_on_stderr: Option<bool>,
+}
+
+/// What happened to one command in a batch
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum BatchStatus {
+    /// Exited with code 0
+    Ok,
+    /// Exited with a non-zero code, or wrote to stderr with fail_on_stderr set
+    Failed,
+    /// Couldn't be run, e.g. it timed out or the connection dropped
+    Error,
+    /// Not run, because an earlier command failed or the batch ran out of time
+    Skipped,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchEntry {
+    pub command: String,
+    pub status: BatchStatus,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stdout: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub stderr: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub duration_ms: Option<u64>,
+    /// Why the command errored or was skipped
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reason: Option<String>,
+}
+
+impl BatchEntry {
+    fn skipped(command: &str, reason: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            status: BatchStatus::Skipped,
+            stdout: None,
+            stderr: None,
+            exit_code: None,
+            duration_ms: None,
+            reason: Some(reason.to_string()),
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct StreamRequest {
+    /// The shell command to run, e.g. `logcat` or `tail -f file`
+    pub command: String,
+    /// Stop after this many seconds (default: 10, max: 300)
+    #[serde(default = "default_stream_duration")]
+    pub duration_secs: u64,
+    /// Stop as soon as the output matches this regex
+    pub stop_pattern: Option<String>,
+    /// Milliseconds between progress notifications carrying new output (default: stream_flush_interval_ms config setting, 500)
+    pub flush_interval_ms: Option<u64>,
+    /// Device profile to run on (default: default_profile, or the top-level settings)
+    pub profile: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SetupRequest {
+    /// Android device address: IPv4 (192.168.1.100), IPv6 (fe80::1 or [fe80::1]) or hostname (phone.local)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub host: Option<String>,
+    /// SSH port (default: 8022 for Termux)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub port: Option<u16>,
+    /// Termux username (run 'whoami' in Termux)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub user: Option<String>,
+    /// Path to SSH private key (recommended, e.g., ~/.ssh/id_ed25519)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub key_path: Option<String>,
+    /// SSH password (alternative to key_path, not recommended)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub password: Option<Redacted>,
+    /// Authenticate with keys held by ssh-agent (tried before key_path and password)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub use_agent: Option<bool>,
+    /// Save these settings as a named device profile instead of the top-level connection
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub profile: Option<String>,
+    /// Connect and run whoami with the merged settings before saving (default: true)
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub test_connection: Option<bool>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ProvisionKeyRequest {
+    /// Where to write the new private key; the public key goes next to it with .pub
+    /// (default: ~/.ssh/mcp_android_ed25519). Existing files are never overwritten
+    pub key_path: Option<String>,
+    /// Remove the stored password (and password_command/password_file) once the new key
+    /// is confirmed to work (default: false)
+    #[serde(default)]
+    pub clear_password: bool,
+    /// Timeout in seconds for installing the key (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// Where provision_key writes the key pair unless told otherwise
+const DEFAULT_PROVISIONED_KEY_PATH: &str = "~/.ssh/mcp_android_ed25519";
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct RunScriptRequest {
+    /// Path to a local script file to run on the device (e.g., ~/scripts/setup.sh); give this or script
+    pub path: Option<String>,
+    /// Script text to run, with real newlines instead of `;` chains; give this or path
+    pub script: Option<String>,
+    /// Program that runs the script, e.g. "bash" or "python3" (default: the script's #! line, or sh)
+    pub interpreter: Option<String>,
+    /// Arguments passed to the script ($1, $2, ...); each is shell-quoted
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Command timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// The interpreter named by a script's `#!` line, resolved through PATH on the device
+/// Termux has no /usr/bin/env or /bin/bash, so `#!/usr/bin/env python3` becomes
+/// `python3` and `#!/bin/bash` becomes `bash`
+fn shebang_interpreter(script: &[u8]) -> Option<String> {
+    let line = script.strip_prefix(b"#!")?.split(|&b| b == b'\n').next()?;
+    let line = std::str::from_utf8(line).ok()?.trim();
+    let (program, args) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let args = args.trim();
+    if program == "/usr/bin/env" {
+        return (!args.is_empty()).then(|| args.to_string());
+    }
+    let program = match program.rsplit_once('/') {
+        Some((dir, name)) if dir == "/bin" || dir == "/usr/bin" => name,
+        _ => program,
+    };
+    if program.is_empty() {
+        return None;
+    }
+    Some(if args.is_empty() {
+        program.to_string()
+    } else {
+        format!("{} {}", program, args)
+    })
+}
+
+/// Command that runs the uploaded script at `remote_path` and removes it, keeping the
+/// script's exit code
+fn run_script_command(interpreter: &str, remote_path: &str, args: &[String]) -> String {
+    let args: String = args
+        .iter()
+        .map(|a| format!(" {}", shell_quote(a)))
+        .collect();
+    format!(
+        "f={}; {} \"$f\"{}; status=$?; rm -f \"$f\"; exit $status",
+        quote_remote_path(remote_path),
+        interpreter,
+        args
+    )
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum PackageSource {
+    /// Termux packages installed with pkg/apt
+    #[default]
+    Termux,
+    /// Android apps installed on the device
+    Android,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ListPackagesRequest {
+    /// Which packages to list: "termux" (pkg/apt) or "android" (installed apps). Default: termux
+    #[serde(default)]
+    pub source: PackageSource,
+    /// Command timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct AndroidInfoRequest {
+    /// Timeout in seconds for each underlying command (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct GetPropertyRequest {
+    /// Property name, e.g. "ro.build.version.release" or "persist.sys.timezone"
+    pub key: String,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ListPropertiesRequest {
+    /// Only return properties whose name starts with this, e.g. "ro.product." (default: all)
+    #[serde(default)]
+    pub prefix: String,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct DiskUsageRequest {
+    /// Directory to summarize (default: /sdcard)
+    #[serde(default = "default_disk_usage_path")]
+    pub path: String,
+    /// Number of largest subdirectories to return (default: 10, max: 100)
+    #[serde(default = "default_disk_usage_top")]
+    pub top: usize,
+    /// Timeout in seconds for each underlying command (default: 30, max: 300); du on a large directory can be slow
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_disk_usage_path() -> String {
+    "/sdcard".to_string()
+}
+
+fn default_disk_usage_top() -> usize {
+    10
+}
+
+const MAX_DISK_USAGE_TOP: usize = 100;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct StorageStatusRequest {
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ClipboardGetRequest {
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ClipboardSetRequest {
+    /// Text to put on the device's clipboard, exactly as given (newlines and quotes included)
+    pub text: String,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum NotificationPriority {
+    Min,
+    Low,
+    #[default]
+    Default,
+    High,
+    Max,
+}
+
+impl NotificationPriority {
+    fn name(self) -> &'static str {
+        match self {
+            NotificationPriority::Min => "min",
+            NotificationPriority::Low => "low",
+            NotificationPriority::Default => "default",
+            NotificationPriority::High => "high",
+            NotificationPriority::Max => "max",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct NotifyRequest {
+    /// Notification title
+    pub title: String,
+    /// Notification text, sent on stdin so quotes and newlines arrive unchanged
+    pub content: String,
+    /// min, low, default, high (shown as a heads-up on most devices) or max
+    #[serde(default)]
+    pub priority: NotificationPriority,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// Longest title and content notify accepts; Android cuts both off well before this
+const MAX_NOTIFICATION_BYTES: usize = 4096;
+
+/// Largest text clipboard_set accepts; Android passes the clipboard through a binder
+/// transaction, which is limited to 1 MiB in all
+const MAX_CLIPBOARD_BYTES: usize = 256 * 1024;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct KeepAwakeRequest {
+    /// Release the wake lock instead of taking it (default: false)
+    #[serde(default)]
+    pub release: bool,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ScreenshotRequest {
+    /// Also save the PNG to this local path (~ is expanded); overwritten if it exists
+    pub local_path: Option<String>,
+    /// Capture through su, for devices where screencap needs root (default: false)
+    #[serde(default)]
+    pub root: bool,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// Free space on the filesystem holding a directory, and its largest subdirectories
+#[derive(Debug, Serialize)]
+pub struct DiskUsage {
+    pub path: String,
+    pub filesystem: Option<android::FilesystemUsage>,
+    /// Total size of the directory, from du
+    pub total_bytes: Option<u64>,
+    pub largest: Vec<android::DirUsage>,
+    /// Subdirectories left out because of `top`
+    pub omitted: usize,
+    /// Why parts of the summary are missing or incomplete
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub notes: Vec<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct PathInfoRequest {
+    /// Remote path to inspect (absolute, or relative to the home directory)
+    pub remote_path: String,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct MakeDirRequest {
+    /// Directory to create on the device (absolute, or relative to the home directory)
+    pub remote_path: String,
+    /// Also create missing parent directories, like mkdir -p (default: false)
+    #[serde(default)]
+    pub parents: bool,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct TransferPathRequest {
+    /// File or directory to copy or move (absolute, or relative to the home directory)
+    pub source: String,
+    /// Destination path; an existing directory receives the source inside it, like cp and mv
+    pub dest: String,
+    /// Replace the destination if it already exists (default: false); an existing directory is never replaced
+    #[serde(default)]
+    pub overwrite: bool,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct DiffFilesRequest {
+    /// First file, shown as the old side of the diff (absolute, or relative to the home directory)
+    pub path_a: String,
+    /// Second file, shown as the new side
+    pub path_b: String,
+    /// Lines of unchanged context around each change (default: 3)
+    pub context_lines: Option<u32>,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SearchFilesRequest {
+    /// Directory to search (absolute, or relative to the home directory)
+    pub root: String,
+    /// Shell glob matched against each name (not the whole path), e.g. "*.log"
+    pub name_glob: Option<String>,
+    /// How many levels below root to descend; 1 searches only root's entries (default: unlimited)
+    pub max_depth: Option<u32>,
+    /// Only return "file" (regular files) or "dir" (directories) (default: both, and everything else)
+    #[serde(rename = "type")]
+    pub kind: Option<SearchType>,
+    /// Paths to return at most (default: 200, max: 10000)
+    pub max_results: Option<usize>,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// What a remote path is; type, size, mode and mtime describe the symlink target for links
+#[derive(Debug, Serialize)]
+pub struct PathInfo {
+    pub path: String,
+    pub exists: bool,
+    pub is_dir: bool,
+    pub is_file: bool,
+    pub is_symlink: bool,
+    pub size_bytes: Option<u64>,
+    /// Permission bits, e.g. "0644"
+    pub mode_octal: Option<String>,
+    /// Last modification time (Unix seconds)
+    pub mtime: Option<u64>,
+}
+
+impl PathInfo {
+    fn missing(path: &str) -> Self {
+        Self {
+            path: path.to_string(),
+            exists: false,
+            is_dir: false,
+            is_file: false,
+            is_symlink: false,
+            size_bytes: None,
+            mode_octal: None,
+            mtime: None,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct PackageEntry {
+    pub name: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub version: Option<String>,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct StartJobRequest {
+    /// The shell command to run in the background
+    pub command: String,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct JobStatusRequest {
+    /// Job id returned by start_job (omit to list all jobs)
+    #[serde(default)]
+    pub job_id: Option<u32>,
+    /// Number of log lines to return (default: 50)
+    #[serde(default = "default_job_lines")]
+    pub lines: u32,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "UPPERCASE")]
+pub enum JobSignal {
+    #[default]
+    Term,
+    Int,
+    Hup,
+    Kill,
+}
+
+impl JobSignal {
+    fn name(self) -> &'static str {
+        match self {
+            JobSignal::Term => "TERM",
+            JobSignal::Int => "INT",
+            JobSignal::Hup => "HUP",
+            JobSignal::Kill => "KILL",
+        }
+    }
+
+    fn sig(self) -> russh::Sig {
+        match self {
+            JobSignal::Term => russh::Sig::TERM,
+            JobSignal::Int => russh::Sig::INT,
+            JobSignal::Hup => russh::Sig::HUP,
+            JobSignal::Kill => russh::Sig::KILL,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SendSignalRequest {
+    /// Stream id announced when the stream tool started (default: the only running stream)
+    pub stream_id: Option<u32>,
+    /// Signal to send: INT (default, like Ctrl-C), TERM, HUP, or KILL
+    #[serde(default = "default_stream_signal")]
+    pub signal: JobSignal,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ShellSendRequest {
+    /// Command to run; `cd`, `export` and shell functions stay in effect for later commands in the same shell
+    pub command: String,
+    /// Shell to run it in, as returned by an earlier shell_send (default: open a new shell)
+    pub session_id: Option<u32>,
+    /// Timeout in seconds (default: 30, max: 300); a command still running then is interrupted with Ctrl-C
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ShellCloseRequest {
+    /// Shell to close, as returned by shell_send
+    pub session_id: u32,
+}
+
+fn default_stream_signal() -> JobSignal {
+    JobSignal::Int
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct KillJobRequest {
+    /// Job id returned by start_job
+    pub job_id: u32,
+    /// Signal to send: TERM (default), INT, HUP, or KILL
+    #[serde(default)]
+    pub signal: JobSignal,
+    /// Delete the job's log file afterwards (default: false)
+    #[serde(default)]
+    pub remove_log: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ProcessListRequest {
+    /// Only list processes whose command line contains this text (case-insensitive)
+    pub filter: Option<String>,
+    /// Most processes to return (default: 200)
+    #[serde(default = "default_process_limit")]
+    pub limit: usize,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_process_limit() -> usize {
+    200
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ProcessKillRequest {
+    /// Process id, as reported by process_list
+    pub pid: u32,
+    /// Signal to send: TERM (default), INT, HUP, or KILL
+    #[serde(default)]
+    pub signal: JobSignal,
+    /// Must be true to send KILL, which gives the process no chance to clean up
+    #[serde(default)]
+    pub confirm: bool,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct TailLinesRequest {
+    /// Remote file path (absolute, or relative to the home directory)
+    pub path: String,
+    /// Number of lines to return from the end of the file (default: 100, max: 10000)
+    #[serde(default = "default_tail_lines")]
+    pub lines: usize,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// Lowest logcat priority to include
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+pub enum LogcatPriority {
+    /// Verbose
+    V,
+    /// Debug
+    D,
+    /// Info
+    I,
+    /// Warning
+    W,
+    /// Error
+    E,
+    /// Fatal
+    F,
+}
+
+impl LogcatPriority {
+    fn letter(self) -> char {
+        match self {
+            Self::V => 'V',
+            Self::D => 'D',
+            Self::I => 'I',
+            Self::W => 'W',
+            Self::E => 'E',
+            Self::F => 'F',
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct LogcatRequest {
+    /// Number of most recent log lines to return (default: 200, max: 5000)
+    #[serde(default = "default_logcat_lines")]
+    pub lines: usize,
+    /// Only include messages at this priority or above: V, D, I, W, E or F (default: all)
+    pub priority: Option<LogcatPriority>,
+    /// Only include messages with this tag, e.g. ActivityManager
+    pub tag: Option<String>,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct UploadFileRequest {
+    /// Local file to upload (~ is expanded)
+    pub local_path: String,
+    /// Destination path on the device (absolute, or relative to the home directory)
+    pub remote_path: String,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct WriteFileRequest {
+    /// Destination path on the device (absolute, or relative to the home directory)
+    pub remote_path: String,
+    /// Text to write, exactly as given (quotes, $ and newlines need no escaping)
+    pub content: String,
+    /// Add to the end of the file instead of replacing it (default: false)
+    #[serde(default)]
+    pub append: bool,
+    /// Create missing parent directories (default: false)
+    #[serde(default)]
+    pub create_dirs: bool,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct DownloadFileRequest {
+    /// Remote file to download (absolute, or relative to the home directory)
+    pub remote_path: String,
+    /// Local destination path (~ is expanded); overwritten if it exists
+    pub local_path: String,
+    /// Also return the contents inline if the file is UTF-8 text under 1 MiB (default: false)
+    #[serde(default)]
+    pub inline: bool,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumAlgorithm {
+    Md5,
+    Sha1,
+    #[default]
+    Sha256,
+    Sha512,
+}
+
+impl ChecksumAlgorithm {
+    fn name(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Md5 => "md5",
+            ChecksumAlgorithm::Sha1 => "sha1",
+            ChecksumAlgorithm::Sha256 => "sha256",
+            ChecksumAlgorithm::Sha512 => "sha512",
+        }
+    }
+
+    /// The coreutils/busybox command computing this checksum on the device
+    fn command(self) -> &'static str {
+        match self {
+            ChecksumAlgorithm::Md5 => "md5sum",
+            ChecksumAlgorithm::Sha1 => "sha1sum",
+            ChecksumAlgorithm::Sha256 => "sha256sum",
+            ChecksumAlgorithm::Sha512 => "sha512sum",
+        }
+    }
+
+    /// Length of the digest in hex digits
+    fn hex_len(self) -> usize {
+        match self {
+            ChecksumAlgorithm::Md5 => 32,
+            ChecksumAlgorithm::Sha1 => 40,
+            ChecksumAlgorithm::Sha256 => 64,
+            ChecksumAlgorithm::Sha512 => 128,
+        }
+    }
+
+    fn hasher(self) -> Box<dyn DynDigest + Send> {
+        match self {
+            ChecksumAlgorithm::Md5 => Box::new(md5::Md5::default()),
+            ChecksumAlgorithm::Sha1 => Box::new(sha1::Sha1::default()),
+            ChecksumAlgorithm::Sha256 => Box::new(sha2::Sha256::default()),
+            ChecksumAlgorithm::Sha512 => Box::new(sha2::Sha512::default()),
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "lowercase")]
+pub enum ChecksumMode {
+    /// Hash on the device with md5sum/sha*sum (best when the network is slow)
+    #[default]
+    Device,
+    /// Stream the file over SFTP and hash locally (best when the device CPU is slow)
+    Stream,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ComputeChecksumRequest {
+    /// Remote file path
+    pub path: String,
+    /// Hash algorithm: md5, sha1, sha256 (default), or sha512
+    #[serde(default)]
+    pub algorithm: ChecksumAlgorithm,
+    /// Where to hash: "device" (default) or "stream" (over SFTP, reports progress)
+    #[serde(default)]
+    pub mode: ChecksumMode,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct VerifyChecksumRequest {
+    /// Remote file path
+    pub remote_path: String,
+    /// Hash algorithm: md5, sha1, sha256 (default), or sha512
+    #[serde(default)]
+    pub algorithm: ChecksumAlgorithm,
+    /// Hex digest the remote file should have (give this or local_path)
+    pub expected: Option<String>,
+    /// Local file the remote one should be identical to (give this or expected)
+    pub local_path: Option<String>,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CommandHistoryRequest {
+    /// Number of most recent commands to return (default: 20)
+    #[serde(default = "default_history_limit")]
+    pub limit: usize,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct SetWorkingDirectoryRequest {
+    /// Directory for later execute/execute_read calls (absolute, or relative to the current one)
+    pub path: Option<String>,
+    /// Clear the working directory back to the login default (default: false)
+    #[serde(default)]
+    pub reset: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct DisconnectRequest {
+    /// Device profile to disconnect (default: default_profile, or the top-level settings)
+    pub profile: Option<String>,
+    /// Disconnect every device instead of one (default: false)
+    #[serde(default)]
+    pub all: bool,
+}
+
+/// What the power tool does to the device
+#[derive(Debug, Clone, Copy, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum PowerAction {
+    /// Restart the device
+    Reboot,
+    /// Turn the device off
+    Shutdown,
+    /// Restart into the recovery partition
+    RebootRecovery,
+}
+
+impl PowerAction {
+    /// Command that performs the action as root, preferring Android's power service
+    /// over the reboot binary, which some ROMs lack
+    fn command(self) -> &'static str {
+        match self {
+            Self::Reboot => "su -c 'svc power reboot || reboot'",
+            Self::Shutdown => "su -c 'svc power shutdown || reboot -p'",
+            Self::RebootRecovery => "su -c 'svc power reboot recovery || reboot recovery'",
+        }
+    }
+
+    fn describe(self) -> &'static str {
+        match self {
+            Self::Reboot => "Reboot",
+            Self::Shutdown => "Shutdown",
+            Self::RebootRecovery => "Reboot into recovery",
+        }
+    }
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct PowerRequest {
+    /// reboot, shutdown or reboot_recovery
+    pub action: PowerAction,
+    /// Must be true to act; the device restarts or turns off and the SSH session drops
+    #[serde(default)]
+    pub confirm: bool,
+}
+
+/// Printed to stderr by the shell run_as starts, telling a command that ran from a refused su
+const RUN_AS_MARKER: &str = "mcp-android-ssh:run_as";
+/// Printed to stderr when neither su nor sudo is installed
+const RUN_AS_UNAVAILABLE: &str = "mcp-android-ssh:no_su";
+
+/// Wrap `command` to run as `user`: `su -c` for root (`su <user> -c` otherwise), or
+/// `sudo -u <user>` where there is no su
+/// The wrapped shell prints RUN_AS_MARKER first, which take_run_as_marker removes again
+fn run_as_command(command: &str, user: &str) -> String {
+    let inner = shell_quote(&format!("echo {} >&2\n{}", RUN_AS_MARKER, command));
+    let su = if user == "root" {
+        format!("su -c {}", inner)
+    } else {
+        format!("su {} -c {}", shell_quote(user), inner)
+    };
+    let user = shell_quote(user);
+    format!(
+        "if command -v su >/dev/null 2>&1; then {}; \
+         elif command -v sudo >/dev/null 2>&1; then sudo -u {} -- sh -c {}; \
+         else echo {} >&2; exit 127; fi",
+        su, user, inner, RUN_AS_UNAVAILABLE
+    )
+}
+
+/// Whether a run_as command got as far as running as the requested user
+#[derive(Debug, PartialEq)]
+enum RunAsOutcome {
+    Ran,
+    Unavailable,
+    Refused,
+}
+
+/// Find and remove the marker printed by `run_as_command`
+/// On a PTY stderr is merged, so the marker starts stdout instead
+fn take_run_as_marker(result: &mut CommandResult) -> RunAsOutcome {
+    let marker_line = format!("{}\n", RUN_AS_MARKER);
+    if let Some(start) = result
+        .stderr
+        .match_indices(&marker_line)
+        .map(|(i, _)| i)
+        .find(|&i| i == 0 || result.stderr[..i].ends_with('\n'))
+    {
+        result
+            .stderr
+            .replace_range(start..start + marker_line.len(), "");
+        return RunAsOutcome::Ran;
+    }
+    if let Some(rest) = result.stdout.strip_prefix(&marker_line) {
+        result.stdout = rest.to_string();
+        if let Some(ref mut bytes) = result.stdout_bytes {
+            bytes.drain(..marker_line.len().min(bytes.len()));
+        }
+        return RunAsOutcome::Ran;
+    }
+    if result.stderr.contains(RUN_AS_UNAVAILABLE) || result.stdout.contains(RUN_AS_UNAVAILABLE) {
+        return RunAsOutcome::Unavailable;
+    }
+    RunAsOutcome::Refused
+}
+
+/// How long to wait for su to answer the root check, which may wait on a root-manager prompt
+const ROOT_CHECK_TIMEOUT_SECS: u64 = 15;
+/// How long to wait for a power command before assuming the device went down mid-command
+const POWER_COMMAND_TIMEOUT_SECS: u64 = 10;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ForwardPortRequest {
+    /// Port to listen on at 127.0.0.1 on this machine (default: 0, any free port)
+    #[serde(default)]
+    pub local_port: u16,
+    /// Host to connect to, as seen from the device (default: 127.0.0.1, the device itself)
+    #[serde(default = "default_forward_host")]
+    pub remote_host: String,
+    /// Port to connect to on remote_host
+    pub remote_port: u16,
+}
+
+fn default_forward_host() -> String {
+    "127.0.0.1".to_string()
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CloseForwardRequest {
+    /// Forward id returned by forward_port
+    pub id: u32,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct BenchmarkRequest {
+    /// Megabytes of random data to send each way (default: 4, max: 100)
+    #[serde(default = "default_benchmark_size_mb")]
+    pub size_mb: u64,
+    /// Timeout in seconds for the whole benchmark (default: 60, max: 300)
+    #[serde(default = "default_benchmark_timeout")]
+    pub timeout: u64,
+}
+
+fn default_benchmark_size_mb() -> u64 {
+    4
+}
+
+fn default_benchmark_timeout() -> u64 {
+    60
+}
+
+const MAX_BENCHMARK_SIZE_MB: u64 = 100;
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct ConnectionStatusRequest {
+    /// Drop the current session and connect again before reporting (default: false)
+    #[serde(default)]
+    pub reconnect: bool,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct CaptureBannerRequest {
+    /// Longest to wait for the login shell, in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct DiscoverRequest {
+    /// IPv4 subnet to scan in CIDR notation (default: this machine's /24, max /20)
+    pub subnet: Option<String>,
+    /// Ports to probe (default: 8022 and 22)
+    pub ports: Option<Vec<u16>>,
+    /// Per-host connect and banner timeout in milliseconds (default: 500, max: 10000)
+    #[serde(default = "default_discover_timeout_ms")]
+    pub timeout_ms: u64,
+    /// Maximum simultaneous connection attempts (default: 64, max: 256)
+    #[serde(default = "default_discover_concurrency")]
+    pub concurrency: usize,
+}
+
+fn default_discover_timeout_ms() -> u64 {
+    500
+}
+
+fn default_discover_concurrency() -> usize {
+    64
+}
+
+const MAX_DISCOVER_CONCURRENCY: usize = 256;
+
+fn default_stream_duration() -> u64 {
+    10
+}
+
+fn default_timeout() -> u64 {
+    30
+}
+
+fn default_history_limit() -> usize {
+    20
+}
+
+fn default_tail_lines() -> usize {
+    100
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct TailFileRequest {
+    /// Remote file path (absolute, or relative to the home directory)
+    pub remote_path: String,
+    /// Number of lines to return from the end of the file (default: 50, max: 10000; 0 with follow_secs shows only new lines)
+    #[serde(default = "default_tail_file_lines")]
+    pub lines: usize,
+    /// Keep reading lines appended to the file for this many seconds, like tail -f (max: 300)
+    pub follow_secs: Option<u64>,
+    /// Timeout in seconds without follow_secs (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_tail_file_lines() -> usize {
+    50
+}
+
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct JournalRequest {
+    /// Number of most recent journal entries to return (default: 200, max: 5000)
+    #[serde(default = "default_logcat_lines")]
+    pub lines: usize,
+    /// Only include entries newer than this, e.g. "1 hour ago", "today" or "2024-01-01 10:00"
+    pub since: Option<String>,
+    /// Only include entries from this systemd unit, e.g. sshd.service
+    pub unit: Option<String>,
+    /// Timeout in seconds (default: 30, max: 300)
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+const MAX_TAIL_LINES: usize = 10_000;
+
+fn default_logcat_lines() -> usize {
+    200
+}
+
+const MAX_LOGCAT_LINES: usize = 5000;
+
+fn default_job_lines() -> u32 {
+    50
+}
+
+// Largest local script run_script will send to the device
+const MAX_SCRIPT_BYTES: u64 = 1024 * 1024;
+
+/// Name run_script uploads scripts under in the home directory, followed by the server's pid
+const SCRIPT_FILE_PREFIX: &str = ".mcp-android-ssh-script";
+
+// Most commands one batch call may run
+const MAX_BATCH_COMMANDS: usize = 100;
+
+// Largest downloaded file download_file will return inline
+const MAX_INLINE_BYTES: u64 = 1024 * 1024;
+
+// Largest content write_file accepts
+const MAX_WRITE_BYTES: usize = 1024 * 1024;
+
+#[tool_router]
+impl AndroidSshService {
+    #[tool(
+        description = "Execute safe read-only shell commands on Android via SSH (81 whitelisted commands)"
+    )]
+    async fn execute_read(
+        &self,
+        Parameters(ExecuteReadRequest {
+            execute: request,
+            verify_available,
+        }): Parameters<ExecuteReadRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let client_handle = match self.client_for_profile(request.profile.as_deref()).await {
+            Ok(handle) => handle,
+            Err(error_result) => return Ok(error_result),
+        };
+        let is_default_client = Arc::ptr_eq(&client_handle, &self.ssh_client);
+        let mut client_guard = client_handle.lock().await;
+        if client_guard.is_none() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        }
+
+        // Check whitelist
+        let cmd_name = match check_read_only(
+            &request.command,
+            &self.read_only_commands,
+            self.allow_pipes_in_read,
+        ) {
+            Ok(name) => name,
+            Err(msg) => return Ok(CallToolResult::error(vec![Content::text(msg)])),
+        };
+        if request.dry_run {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "dry_run is only supported by the execute tool".to_string(),
+            )]));
+        }
+        if request.run_as.is_some() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "run_as is only supported by the execute tool".to_string(),
+            )]));
+        }
+        if let Err(msg) = check_env_names(&request.env) {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+        if let Some(name) = request.env.keys().find(|name| is_dangerous_env_var(name)) {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Environment variable '{}' is not allowed in execute_read because it can change \
+                 what runs. Use execute tool instead.",
+                name
+            ))]));
+        }
+
+        let client = client_guard.as_mut().unwrap();
+
+        // Validate timeout, falling back to the configured default for this tool
+        let timeout = request
+            .timeout
+            .unwrap_or_else(|| client.config().default_read_timeout());
+        if timeout == 0 || timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+
+        // Validate command length
+        if let Err(msg) =
+            check_command_length(&request.command, client.config().max_command_length())
+        {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+
+        // A command probe_commands found missing is reported with an install hint
+        if let Some(msg) = self.check_probed(client, cmd_name).await {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+
+        // Optionally make sure the command exists before running it
+        if verify_available {
+            match self.is_command_available(client, cmd_name).await {
+                Ok(true) => {}
+                Ok(false) => {
+                    return Ok(CallToolResult::error(vec![Content::text(
+                        not_installed_message(cmd_name),
+                    )]));
+                }
+                Err(e) => {
+                    return Ok(CallToolResult::error(vec![Content::text(format!(
+                        "Failed to check whether '{}' is installed: {}",
+                        cmd_name, e
+                    ))]));
+                }
+            }
+        }
+
+        if let Err(error_result) = self.check_rate_limit(1).await {
+            return Ok(error_result);
+        }
+
+        // Execute command in the stored working directory
+        let structured = request
+            .structured
+            .unwrap_or(client.config().structured_output);
+        let fail_on_stderr = request
+            .fail_on_stderr
+            .unwrap_or(client.config().fail_on_stderr);
+        let max_output_bytes = request
+            .max_output_bytes
+            .unwrap_or(client.config().max_output_bytes());
+        if max_output_bytes == 0 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "max_output_bytes must be at least 1".to_string(),
+            )]));
+        }
+        let grep = match compile_grep(request.grep.as_deref()) {
+            Ok(grep) => grep,
+            Err(error_result) => return Ok(error_result),
+        };
+        // The working directory belongs to the default device
+        let (command, cwd) = if is_default_client {
+            self.with_cwd(&request.command).await
+        } else {
+            (request.command.clone(), None)
+        };
+        // Read-only commands have no side effects, so rerunning one after a dropped session is safe
+        let options = ExecOptions {
+            merge_streams: request.merge_streams,
+            env: Some(&request.env),
+            retry_on_disconnect: client.config().auto_retry_read_commands,
+            ..Default::default()
+        };
+        match self
+            .execute_recorded_with(client, &command, options, timeout)
+            .await
+        {
+            Ok(mut result) => {
+                let filter = grep
+                    .as_ref()
+                    .map(|pattern| filter_stdout(&mut result, pattern, request.grep_invert));
+                let encoding = encode_stdout(&mut result, request.encoding);
+                let page = OutputPage::new(&result.stdout, request.offset, max_output_bytes);
+                let resource_uri = self
+                    .store_truncated_output(&request.command, &result.stdout, encoding.0, &page)
+                    .await;
+                Ok(command_tool_result(
+                    result,
+                    page,
+                    cwd,
+                    ResultOptions {
+                        structured,
+                        fail_on_stderr,
+                    },
+                    encoding,
+                    filter,
+                    resource_uri,
+                ))
+            }
+            Err(e) => Ok(ssh_error_result("Command execution failed", &e)),
+        }
+    }
+
+    #[tool(
+        description = "List the commands execute_read allows, grouped by category and including [whitelist] config changes"
+    )]
+    async fn list_read_only_commands(&self) -> Result<CallToolResult, McpError> {
+        let categories = categorize_read_only_commands(&self.read_only_commands);
+        Ok(CallToolResult::structured(serde_json::json!({
+            "count": self.read_only_commands.len(),
+            "allow_pipes": self.allow_pipes_in_read,
+            "categories": categories,
+        })))
+    }
+
+    #[tool(
+        description = "Execute any shell command on Android via SSH, including write/modify/delete operations"
+    )]
+    async fn execute(
+        &self,
+        Parameters(request): Parameters<ExecuteRequest>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let client_handle = match self.client_for_profile(request.profile.as_deref()).await {
+            Ok(handle) => handle,
+            Err(error_result) => return Ok(error_result),
+        };
+        let is_default_client = Arc::ptr_eq(&client_handle, &self.ssh_client);
+        let mut client_guard = client_handle.lock().await;
+        if client_guard.is_none() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        }
+
+        let client = client_guard.as_mut().unwrap();
+
+        // Validate timeout, falling back to the configured default for this tool
+        let timeout = request
+            .timeout
+            .unwrap_or_else(|| client.config().default_exec_timeout());
+        if timeout == 0 || timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+
+        // Validate command length
+        if let Err(msg) =
+            check_command_length(&request.command, client.config().max_command_length())
+        {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+
+        let blocked = self.blocklist.check(&request.command);
+        if let Err(msg) = blocked.clone()
+            && !request.dry_run
+        {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+        if let Err(msg) = check_env_names(&request.env) {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+        if let Some(ref user) = request.run_as
+            && (user.is_empty()
+                || !user
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || "_-.".contains(c)))
+        {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "run_as must be a user name or uid, e.g. \"root\" (got '{}')",
+                user
+            ))]));
+        }
+
+        if request.dry_run {
+            let (command, cwd) = if is_default_client {
+                self.with_cwd(&request.command).await
+            } else {
+                (request.command.clone(), None)
+            };
+            let command = match request.run_as {
+                Some(ref user) => run_as_command(&command, user),
+                None => command,
+            };
+            let options = ExecOptions {
+                merge_streams: request.merge_streams,
+                env: Some(&request.env),
+                ..Default::default()
+            };
+            let command_line = match client.command_line(&command, &options) {
+                Ok(line) => line,
+                Err(e) => return Ok(CallToolResult::error(vec![Content::text(e.to_string())])),
+            };
+            let read_only = if request.run_as.is_some() {
+                Err("run_as is only supported by the execute tool".to_string())
+            } else {
+                check_read_only(
+                    &request.command,
+                    &self.read_only_commands,
+                    self.allow_pipes_in_read,
+                )
+            };
+            return Ok(CallToolResult::structured(serde_json::json!({
+                "dry_run": true,
+                "command_line": command_line,
+                "cwd": cwd,
+                "pty": request.merge_streams,
+                "run_as": request.run_as,
+                "blocked": blocked.is_err(),
+                "blocked_reason": blocked.err(),
+                "read_only": read_only.is_ok(),
+                "read_only_reason": read_only.err(),
+            })));
+        }
+
+        if let Err(error_result) = self.check_rate_limit(1).await {
+            return Ok(error_result);
+        }
+
+        // Execute command in the stored working directory
+        let structured = request
+            .structured
+            .unwrap_or(client.config().structured_output);
+        let fail_on_stderr = request
+            .fail_on_stderr
+            .unwrap_or(client.config().fail_on_stderr);
+        let max_output_bytes = request
+            .max_output_bytes
+            .unwrap_or(client.config().max_output_bytes());
+        if max_output_bytes == 0 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "max_output_bytes must be at least 1".to_string(),
+            )]));
+        }
+        let grep = match compile_grep(request.grep.as_deref()) {
+            Ok(grep) => grep,
+            Err(error_result) => return Ok(error_result),
+        };
+        // The working directory belongs to the default device
+        let (command, cwd) = if is_default_client {
+            self.with_cwd(&request.command).await
+        } else {
+            (request.command.clone(), None)
+        };
+        // cd runs inside the wrapped shell, since su may not keep the working directory
+        let command = match request.run_as {
+            Some(ref user) => run_as_command(&command, user),
+            None => command,
+        };
+        // Output is passed on as it arrives when the client asked for progress
+        let progress_token = context.meta.get_progress_token();
+        let (output_tx, output_rx) = tokio::sync::mpsc::unbounded_channel();
+        let options = ExecOptions {
+            merge_streams: request.merge_streams,
+            env: Some(&request.env),
+            output: progress_token.is_some().then_some(&output_tx),
+            ..Default::default()
+        };
+        let run = self.execute_recorded_with(client, &command, options, timeout);
+        let outcome = match progress_token {
+            Some(token) => with_output_progress(run, output_rx, token, &context.peer).await,
+            None => run.await,
+        };
+        match outcome {
+            Ok(mut result) => {
+                if let Some(ref user) = request.run_as {
+                    match take_run_as_marker(&mut result) {
+                        RunAsOutcome::Ran => {}
+                        RunAsOutcome::Unavailable => {
+                            return Ok(CallToolResult::error(vec![Content::text(
+                                "run_as needs su (a rooted device, e.g. with Magisk) or sudo \
+                                 (pkg install tsu), and neither is installed on the device."
+                                    .to_string(),
+                            )]));
+                        }
+                        RunAsOutcome::Refused => {
+                            return Ok(CallToolResult::error(vec![Content::text(format!(
+                                "su/sudo refused to run the command as '{}':\n\n{}\n\n\
+                                 On a rooted device, allow Termux in the root manager \
+                                 (e.g. Magisk) and try again.",
+                                user,
+                                format_command_result(&result)
+                            ))]));
+                        }
+                    }
+                }
+                let filter = grep
+                    .as_ref()
+                    .map(|pattern| filter_stdout(&mut result, pattern, request.grep_invert));
+                let encoding = encode_stdout(&mut result, request.encoding);
+                let page = OutputPage::new(&result.stdout, request.offset, max_output_bytes);
+                let resource_uri = self
+                    .store_truncated_output(&request.command, &result.stdout, encoding.0, &page)
+                    .await;
+                Ok(command_tool_result(
+                    result,
+                    page,
+                    cwd,
+                    ResultOptions {
+                        structured,
+                        fail_on_stderr,
+                    },
+                    encoding,
+                    filter,
+                    resource_uri,
+                ))
+            }
+            Err(e) => Ok(ssh_error_result("Command execution failed", &e)),
+        }
+    }
+
+    #[tool(
+        description = "Run several shell commands in order over one SSH session and return each command's stdout, stderr and exit code (can write/modify/delete)"
+    )]
+    async fn batch(
+        &self,
+        Parameters(request): Parameters<BatchRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let client_handle = match self.client_for_profile(request.profile.as_deref()).await {
+            Ok(handle) => handle,
+            Err(error_result) => return Ok(error_result),
+        };
+        let is_default_client = Arc::ptr_eq(&client_handle, &self.ssh_client);
+        let mut client_guard = client_handle.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        // Validate timeout
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+
+        let fail_on_stderr = request
+            .fail_on_stderr
+            .unwrap_or(client.config().fail_on_stderr);
+
+        if request.commands.is_empty() || request.commands.len() > MAX_BATCH_COMMANDS {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "commands must list between 1 and {} commands",
+                MAX_BATCH_COMMANDS
+            ))]));
+        }
+
+        // Refuse the whole batch up front rather than stopping halfway through it
+        for (index, command) in request.commands.iter().enumerate() {
+            let check = check_command_length(command, client.config().max_command_length())
+                .and_then(|_| self.blocklist.check(command));
+            if let Err(msg) = check {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Command {} ({}): {}",
+                    index + 1,
+                    command,
+                    msg
+                ))]));
+            }
+        }
+
+        if let Err(error_result) = self.check_rate_limit(request.commands.len() as u32).await {
+            return Ok(error_result);
+        }
+
+        let deadline = Instant::now() + std::time::Duration::from_secs(request.timeout);
+        let mut entries = Vec::with_capacity(request.commands.len());
+        let mut skip_reason = None;
+        for command in &request.commands {
+            if let Some(reason) = skip_reason {
+                entries.push(BatchEntry::skipped(command, reason));
+                continue;
+            }
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                skip_reason = Some("batch timeout reached");
+                entries.push(BatchEntry::skipped(command, "batch timeout reached"));
+                continue;
+            }
+
+            // The working directory belongs to the default device
+            let (full_command, _) = if is_default_client {
+                self.with_cwd(command).await
+            } else {
+                (command.clone(), None)
+            };
+            // Whole seconds, so the last command may overrun the deadline by under a second
+            let timeout = remaining.as_secs_f64().ceil() as u64;
+            let entry = match self.execute_recorded(client, &full_command, timeout).await {
+                Ok(result) => BatchEntry {
+                    command: command.clone(),
+                    status: if result.success() && !(fail_on_stderr && result.fails_on_stderr()) {
+                        BatchStatus::Ok
+                    } else {
+                        BatchStatus::Failed
+                    },
+                    stdout: Some(result.stdout),
+                    stderr: Some(result.stderr),
+                    exit_code: result.exit_code,
+                    duration_ms: Some(result.duration_ms),
+                    reason: None,
+                },
+                // A connection failure before anything ran is reported like execute does
+                Err(e) if e.is_connection_failure() && entries.is_empty() => {
+                    return Ok(ssh_error_result("Command execution failed", &e));
+                }
+                Err(e) => {
+                    // Nothing later can run once the time is up or the connection is gone
+                    skip_reason = match e {
+                        SshMcpError::Timeout(_) => Some("batch timeout reached"),
+                        ref e if e.is_connection_failure() => Some("connection failed"),
+                        _ => None,
+                    };
+                    BatchEntry {
+                        command: command.clone(),
+                        status: BatchStatus::Error,
+                        stdout: None,
+                        stderr: None,
+                        exit_code: None,
+                        duration_ms: None,
+                        reason: Some(e.to_string()),
+                    }
+                }
+            };
+            if request.stop_on_error && entry.status != BatchStatus::Ok && skip_reason.is_none() {
+                skip_reason = Some("an earlier command failed (stop_on_error)");
+            }
+            entries.push(entry);
+        }
+
+        let count = |status| entries.iter().filter(|e| e.status == status).count();
+        let summary = serde_json::json!({
+            "succeeded": count(BatchStatus::Ok),
+            "failed": count(BatchStatus::Failed) + count(BatchStatus::Error),
+            "skipped": count(BatchStatus::Skipped),
+        });
+        Ok(CallToolResult::structured(serde_json::json!({
+            "summary": summary,
+            "cwd": if is_default_client { self.cwd.lock().await.clone() } else { None },
+            "results": entries,
+        })))
+    }
+
+    #[tool(
+        description = "Run a long-running command (logcat, top, tail -f) on a PTY, streaming output as progress notifications until duration_secs elapses, stop_pattern matches or send_signal stops it, then send Ctrl-C (can write/modify/delete)"
+    )]
+    async fn stream(
+        &self,
+        Parameters(request): Parameters<StreamRequest>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let client_handle = match self.client_for_profile(request.profile.as_deref()).await {
+            Ok(handle) => handle,
+            Err(error_result) => return Ok(error_result),
+        };
+        let is_default_client = Arc::ptr_eq(&client_handle, &self.ssh_client);
+        let mut client_guard = client_handle.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        if request.duration_secs == 0 || request.duration_secs > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "duration_secs must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+        let flush_interval = match request.flush_interval_ms {
+            Some(0) => {
+                return Ok(CallToolResult::error(vec![Content::text(
+                    "flush_interval_ms must be at least 1".to_string(),
+                )]));
+            }
+            Some(ms) => std::time::Duration::from_millis(ms),
+            None => client.config().stream_flush_interval(),
+        };
+        let stop_pattern = match request.stop_pattern.as_deref().map(Regex::new).transpose() {
+            Ok(pattern) => pattern,
+            Err(e) => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Invalid stop_pattern: {}",
+                    e
+                ))]));
+            }
+        };
+        if let Err(msg) =
+            check_command_length(&request.command, client.config().max_command_length())
+        {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+        if let Err(msg) = self.blocklist.check(&request.command) {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+        if let Err(error_result) = self.check_rate_limit(1).await {
+            return Ok(error_result);
+        }
+
+        // The working directory belongs to the default device
+        let (command, cwd) = if is_default_client {
+            self.with_cwd(&request.command).await
+        } else {
+            (request.command.clone(), None)
+        };
+
+        let started = SystemTime::now();
+        let timer = Instant::now();
+        let mut stream = match client.stream_command(&command).await {
+            Ok(stream) => stream,
+            Err(e) => {
+                return Ok(ssh_error_result("Command execution failed", &e));
+            }
+        };
+
+        let stream_id = self.next_stream_id.fetch_add(1, Ordering::Relaxed);
+        let (signal_tx, mut signals) = tokio::sync::mpsc::unbounded_channel();
+        {
+            let mut streams = self.streams.lock().await;
+            // Drop streams whose call ended without removing itself, e.g. when it was aborted
+            streams.retain(|_, running| !running.signals.is_closed());
+            streams.insert(
+                stream_id,
+                RunningStream {
+                    command: request.command.clone(),
+                    signals: signal_tx,
+                },
+            );
+        }
+
+        let progress_token = context.meta.get_progress_token();
+        let peer: &Peer<RoleServer> = &context.peer;
+        let notify = |text: &str| {
+            let token = progress_token.clone();
+            let message = text.to_string();
+            async move {
+                if let Some(token) = token {
+                    let _ = peer
+                        .notify_progress(ProgressNotificationParam {
+                            progress_token: token,
+                            progress: timer.elapsed().as_secs_f64(),
+                            total: Some(request.duration_secs as f64),
+                            message: Some(message),
+                        })
+                        .await;
+                }
+            }
+        };
+
+        let deadline = tokio::time::sleep(std::time::Duration::from_secs(request.duration_secs));
+        tokio::pin!(deadline);
+        let mut flush = tokio::time::interval(flush_interval);
+        let mut output = String::new();
+        // Output up to `flushed` has been sent; lines before `scanned` didn't match stop_pattern
+        let mut flushed = 0;
+        let mut scanned = 0;
+        let mut exited = false;
+        let mut signalled = Vec::new();
+        notify(&format!(
+            "[stream {} started; send_signal(stream_id={}) interrupts it]\n",
+            stream_id, stream_id
+        ))
+        .await;
+        let stopped_by = loop {
+            tokio::select! {
+                _ = &mut deadline => {
+                    break format!("duration of {}s elapsed", request.duration_secs);
+                }
+                _ = context.ct.cancelled() => break "cancelled".to_string(),
+                _ = flush.tick() => {
+                    if flushed < output.len() {
+                        notify(&output[flushed..]).await;
+                        flushed = output.len();
+                    }
+                }
+                Some(signal) = signals.recv() => {
+                    match stream.signal(signal.sig()).await {
+                        Ok(()) => signalled.push(signal.name()),
+                        Err(e) => tracing::warn!("Stream {}: {}", stream_id, e),
+                    }
+                }
+                chunk = stream.next_output() => {
+                    let Some(chunk) = chunk else {
+                        exited = true;
+                        break if signalled.is_empty() {
+                            "command exited".to_string()
+                        } else {
+                            format!("command exited after SIG{}", signalled.join(", SIG"))
+                        };
+                    };
+                    output.push_str(&chunk);
+                    if let Some(ref pattern) = stop_pattern {
+                        if let Some(found) = pattern.find(&output[scanned..]) {
+                            break format!("stop_pattern matched '{}'", found.as_str());
+                        }
+                        // Rescan the unfinished last line, since a match may span chunks
+                        scanned = output.rfind('\n').map_or(scanned, |i| i + 1);
+                    }
+                }
+            }
+        };
+        self.streams.lock().await.remove(&stream_id);
+        let exit_code = stream.exit_code();
+        if !exited {
+            stream.interrupt().await;
+        }
+        if flushed < output.len() {
+            notify(&output[flushed..]).await;
+        }
+        self.record_history(client, &command, started, timer, exit_code)
+            .await;
+
+        // Keep the most recent output, which is usually what a log tail is for
+        let mut text = tail_of_output(&output, client.config().max_output_bytes());
+        if !text.is_empty() && !text.ends_with('\n') {
+            text.push('\n');
+        }
+        if !text.is_empty() {
+            text.push('\n');
+        }
+        let seconds = timer.elapsed().as_secs_f64();
+        if exited {
+            text.push_str(&format!(
+                "Stopped: {} (exit code: {}, {:.2}s)",
+                stopped_by,
+                exit_code.map_or_else(|| "unknown".to_string(), |code| code.to_string()),
+                seconds
+            ));
+        } else {
+            text.push_str(&format!(
+                "Stopped: {}, sent Ctrl-C ({:.2}s)",
+                stopped_by, seconds
+            ));
+        }
+        if let Some(cwd) = cwd {
+            text.push_str(&format!("\nWorking directory: {}", cwd));
+        }
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    #[tool(
+        description = "Send a signal (INT, TERM, HUP or KILL) to a command the stream tool is still running, e.g. to stop a slow find /"
+    )]
+    async fn send_signal(
+        &self,
+        Parameters(request): Parameters<SendSignalRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let mut streams = self.streams.lock().await;
+        streams.retain(|_, running| !running.signals.is_closed());
+
+        let stream_id = match (request.stream_id, streams.keys().next()) {
+            (Some(id), _) => id,
+            (None, Some(&id)) if streams.len() == 1 => id,
+            (None, _) => {
+                let msg = if streams.is_empty() {
+                    "No streamed command is running.".to_string()
+                } else {
+                    let running: Vec<String> = streams
+                        .iter()
+                        .map(|(id, running)| format!("  {}: {}", id, running.command))
+                        .collect();
+                    format!(
+                        "Several streamed commands are running; pass stream_id:\n{}",
+                        running.join("\n")
+                    )
+                };
+                return Ok(CallToolResult::error(vec![Content::text(msg)]));
+            }
+        };
+        let Some(running) = streams.get(&stream_id) else {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "No running stream with id {}. It may already have finished.",
+                stream_id
+            ))]));
+        };
+
+        let signal = request.signal.name();
+        if running.signals.send(request.signal).is_err() {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Stream {} has already finished.",
+                stream_id
+            ))]));
+        }
+        // The stream call returns once the command exits, with its output up to that point
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "✓ Sent SIG{} to stream {} ({})",
+            signal, stream_id, running.command
+        ))]))
+    }
+
+    #[tool(
+        description = "Run a command in a persistent shell on Android, where cd, export and shell functions carry over to later calls with the same session_id; omit session_id to open a new shell (can write/modify/delete)"
+    )]
+    async fn shell_send(
+        &self,
+        Parameters(request): Parameters<ShellSendRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+        if request.command.trim().is_empty() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "command must not be empty".to_string(),
+            )]));
+        }
+        if let Err(msg) = self.blocklist.check(&request.command) {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+
+        let max_output_bytes = {
+            let client_guard = self.ssh_client.lock().await;
+            let Some(client) = client_guard.as_ref() else {
+                return Ok(CallToolResult::error(vec![Content::text(
+                    crate::config::Config::first_run_message(),
+                )]));
+            };
+            if let Err(msg) =
+                check_command_length(&request.command, client.config().max_command_length())
+            {
+                return Ok(CallToolResult::error(vec![Content::text(msg)]));
+            }
+            client.config().max_output_bytes()
+        };
+        if let Err(error_result) = self.check_rate_limit(1).await {
+            return Ok(error_result);
+        }
+
+        let (session_id, shell) = match request.session_id {
+            Some(id) => match self.shells.lock().await.get(&id) {
+                Some(shell) => (id, shell.clone()),
+                None => {
+                    return Ok(CallToolResult::error(vec![Content::text(format!(
+                        "No open shell with id {}. It may have exited or been closed; \
+                         omit session_id to open a new one (variables and cd from the old shell are gone).",
+                        id
+                    ))]));
+                }
+            },
+            None => {
+                let open: Vec<u32> = self.shells.lock().await.keys().copied().collect();
+                if open.len() >= MAX_SHELL_SESSIONS {
+                    return Ok(CallToolResult::error(vec![Content::text(format!(
+                        "{} shells are already open (ids: {}). Pass one as session_id, \
+                         or close one with shell_close.",
+                        open.len(),
+                        open.iter()
+                            .map(u32::to_string)
+                            .collect::<Vec<_>>()
+                            .join(", ")
+                    ))]));
+                }
+                let mut client_guard = self.ssh_client.lock().await;
+                let Some(client) = client_guard.as_mut() else {
+                    return Ok(CallToolResult::error(vec![Content::text(
+                        crate::config::Config::first_run_message(),
+                    )]));
+                };
+                let shell = match client.open_shell().await {
+                    Ok(shell) => Arc::new(Mutex::new(shell)),
+                    Err(e) => return Ok(ssh_error_result("Failed to open a shell", &e)),
+                };
+                let id = self.next_shell_id.fetch_add(1, Ordering::Relaxed);
+                self.shells.lock().await.insert(id, shell.clone());
+                (id, shell)
+            }
+        };
+
+        let started = SystemTime::now();
+        let timer = Instant::now();
+        let mut shell = shell.lock().await;
+        let outcome = shell
+            .send(
+                &request.command,
+                std::time::Duration::from_secs(request.timeout),
+            )
+            .await;
+        let closed = shell.is_closed();
+        drop(shell);
+        if closed {
+            self.shells.lock().await.remove(&session_id);
+        }
+        if let Some(client) = self.ssh_client.lock().await.as_ref() {
+            let exit_code = outcome.as_ref().ok().and_then(|output| output.exit_code);
+            self.record_history(client, &request.command, started, timer, exit_code)
+                .await;
+        }
+
+        let session_note = if closed {
+            format!(
+                "Shell {} has exited; omit session_id to open a new one.",
+                session_id
+            )
+        } else {
+            format!(
+                "Shell {}: pass session_id={} to keep using it, and shell_close when done.",
+                session_id, session_id
+            )
+        };
+        match outcome {
+            Ok(output) => {
+                let result = CommandResult {
+                    stdout: tail_of_output(&output.output, max_output_bytes),
+                    stdout_bytes: None,
+                    stderr: String::new(),
+                    exit_code: output.exit_code,
+                    duration_ms: timer.elapsed().as_millis() as u64,
+                };
+                Ok(CallToolResult::success(vec![Content::text(format!(
+                    "{}\n{}",
+                    format_command_result(&result),
+                    session_note
+                ))]))
+            }
+            Err(e) => Ok(CallToolResult::error(vec![Content::text(format!(
+                "Command execution failed: {}\n\n{}",
+                e, session_note
+            ))])),
+        }
+    }
+
+    #[tool(description = "Close a persistent shell opened by shell_send")]
+    async fn shell_close(
+        &self,
+        Parameters(request): Parameters<ShellCloseRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let Some(shell) = self.shells.lock().await.remove(&request.session_id) else {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "No open shell with id {}. It may already have exited.",
+                request.session_id
+            ))]));
+        };
+        let mut shell = shell.lock().await;
+        let commands = shell.commands_run;
+        shell.close().await;
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "✓ Closed shell {} after {} command{}",
+            request.session_id,
+            commands,
+            if commands == 1 { "" } else { "s" }
+        ))]))
+    }
+
+    #[tool(
+        description = "Run a multi-line script on Android, given as text or a local file, with sh or another interpreter and optional arguments (can write/modify/delete)"
+    )]
+    async fn run_script(
+        &self,
+        Parameters(request): Parameters<RunScriptRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let mut client_guard = self.ssh_client.lock().await;
+        if client_guard.is_none() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        }
+
+        // Validate timeout
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+
+        let (script, label) = match (&request.path, &request.script) {
+            (Some(path), None) => {
+                // Validate local script
+                let script_path = std::path::PathBuf::from(shellexpand::tilde(path).to_string());
+                let metadata = match std::fs::metadata(&script_path) {
+                    Ok(m) if m.is_file() => m,
+                    Ok(_) => {
+                        return Ok(CallToolResult::error(vec![Content::text(format!(
+                            "Script path is not a file: {}",
+                            script_path.display()
+                        ))]));
+                    }
+                    Err(e) => {
+                        return Ok(CallToolResult::error(vec![Content::text(format!(
+                            "Script file not found: {} ({})",
+                            script_path.display(),
+                            e
+                        ))]));
+                    }
+                };
+                if metadata.len() > MAX_SCRIPT_BYTES {
+                    return Ok(CallToolResult::error(vec![Content::text(format!(
+                        "Script is too large ({} bytes, max {} bytes)",
+                        metadata.len(),
+                        MAX_SCRIPT_BYTES
+                    ))]));
+                }
+                match std::fs::read(&script_path) {
+                    Ok(s) => (s, script_path.display().to_string()),
+                    Err(e) => {
+                        return Ok(CallToolResult::error(vec![Content::text(format!(
+                            "Failed to read script {}: {}",
+                            script_path.display(),
+                            e
+                        ))]));
+                    }
+                }
+            }
+            (None, Some(script)) => {
+                if script.len() as u64 > MAX_SCRIPT_BYTES {
+                    return Ok(CallToolResult::error(vec![Content::text(format!(
+                        "Script is too large ({} bytes, max {} bytes)",
+                        script.len(),
+                        MAX_SCRIPT_BYTES
+                    ))]));
+                }
+                (script.clone().into_bytes(), "<inline script>".to_string())
+            }
+            _ => {
+                return Ok(CallToolResult::error(vec![Content::text(
+                    "Pass either path (a local script file) or script (the script text), not both"
+                        .to_string(),
+                )]));
+            }
+        };
+
+        let interpreter = match request.interpreter {
+            Some(ref interpreter)
+                if interpreter.trim().is_empty() || interpreter.contains('\n') =>
+            {
+                return Ok(CallToolResult::error(vec![Content::text(
+                    "interpreter must be a command such as \"bash\" or \"python3\"".to_string(),
+                )]));
+            }
+            Some(ref interpreter) => interpreter.trim().to_string(),
+            None => shebang_interpreter(&script).unwrap_or_else(|| "sh".to_string()),
+        };
+
+        // Upload the script, then run it as a file so $0, $@ and the script's own stdin behave
+        // as they would for a script on the device
+        let client = client_guard.as_mut().unwrap();
+        let remote_path = format!("~/{}-{}.tmp", SCRIPT_FILE_PREFIX, std::process::id());
+        // The script goes over SFTP, but its arguments are on the command line
+        let command = run_script_command(&interpreter, &remote_path, &request.args);
+        if let Err(msg) = check_command_length(&command, client.config().max_command_length()) {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+        // Every line of the script is checked, as it would be if sent to execute
+        if let Err(msg) = self.blocklist.check(&format!(
+            "{}\n{}",
+            command,
+            String::from_utf8_lossy(&script)
+        )) {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+        if let Err(error_result) = self.check_rate_limit(1).await {
+            return Ok(error_result);
+        }
+        if let Err(e) = client
+            .write_file(&remote_path, &script, false, false, request.timeout)
+            .await
+        {
+            return Ok(ssh_error_result("Failed to upload script", &e));
+        }
+
+        let started = SystemTime::now();
+        let timer = Instant::now();
+        let result = client.execute_command(&command, request.timeout).await;
+        let args: String = request
+            .args
+            .iter()
+            .map(|a| format!(" {}", shell_quote(a)))
+            .collect();
+        self.record_history(
+            client,
+            &format!("{} {}{}", interpreter, label, args),
+            started,
+            timer,
+            result.as_ref().ok().and_then(|r| r.exit_code),
+        )
+        .await;
+        match result {
+            Ok(result) => Ok(CallToolResult::success(vec![Content::text(
+                format_command_result(&result),
+            )])),
+            Err(e) => {
+                // The script didn't get to remove itself
+                let cleanup = format!("rm -f {}", quote_remote_path(&remote_path));
+                if let Err(cleanup_error) = client.execute_command(&cleanup, 10).await {
+                    tracing::warn!("Could not remove {}: {}", remote_path, cleanup_error);
+                }
+                Ok(ssh_error_result("Script execution failed", &e))
+            }
+        }
+    }
+
+    #[tool(
+        description = "List installed packages on Android: Termux packages (pkg/apt) or Android apps (pm)"
+    )]
+    async fn list_packages(
+        &self,
+        Parameters(request): Parameters<ListPackagesRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // dpkg-query gives a stable, tab-separated format; `pkg list-installed` and
+        // `dpkg -l` are meant for humans and vary with terminal width
+        let command = match request.source {
+            PackageSource::Termux => "dpkg-query -W -f='${Package}\\t${Version}\\n'",
+            PackageSource::Android => "pm list packages --show-versioncode",
+        };
+
+        let result = match self.run_command(command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+
+        if !result.success() {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Failed to list packages:\n\n{}",
+                format_command_result(&result)
+            ))]));
+        }
+
+        let packages = match request.source {
+            PackageSource::Termux => parse_dpkg_packages(&result.stdout),
+            PackageSource::Android => parse_pm_packages(&result.stdout),
+        };
+
+        Ok(CallToolResult::structured(serde_json::json!({
+            "count": packages.len(),
+            "packages": packages,
+        })))
+    }
+
+    #[tool(
+        description = "Check whether a remote path exists and whether it is a file, directory or symlink, with its size, permissions and modification time"
+    )]
+    async fn path_info(
+        &self,
+        Parameters(request): Parameters<PathInfoRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if request.remote_path.is_empty() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "remote_path must not be empty".to_string(),
+            )]));
+        }
+
+        let command = path_stat_command(&request.remote_path);
+        let result = match self.run_command(&command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+
+        match parse_path_stat(&request.remote_path, &result.stdout) {
+            Some(info) => Ok(CallToolResult::structured(
+                serde_json::to_value(&info).unwrap_or_default(),
+            )),
+            None => Ok(CallToolResult::error(vec![Content::text(format!(
+                "Unexpected stat output for {}:\n\n{}",
+                request.remote_path,
+                format_command_result(&result)
+            ))])),
+        }
+    }
+
+    #[tool(
+        description = "Create a directory on the device (optionally with missing parents, like mkdir -p) and confirm it exists (can write)"
+    )]
+    async fn make_dir(
+        &self,
+        Parameters(request): Parameters<MakeDirRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if request.remote_path.trim().is_empty() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "remote_path must not be empty".to_string(),
+            )]));
+        }
+        if request.remote_path.contains(['\n', '\0']) {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "remote_path must not contain newlines or NUL bytes".to_string(),
+            )]));
+        }
+
+        let command = make_dir_command(&request.remote_path, request.parents);
+        let result = match self.run_command(&command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+
+        let path = &request.remote_path;
+        match parse_make_dir(&result.stdout) {
+            MakeDirOutcome::Created => Ok(CallToolResult::success(vec![Content::text(format!(
+                "✓ Created directory {}",
+                path
+            ))])),
+            MakeDirOutcome::AlreadyExists => Ok(CallToolResult::success(vec![Content::text(
+                format!("Directory {} already exists", path),
+            )])),
+            MakeDirOutcome::NotADirectory => {
+                Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Cannot create directory {}: a file (or other non-directory) is already \
+                     at that path. Use path_info to see what it is.",
+                    path
+                ))]))
+            }
+            MakeDirOutcome::Failed => {
+                let hint = if request.parents {
+                    ""
+                } else {
+                    "\n\nIf a parent directory is missing, pass parents=true to create it too."
+                };
+                Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Failed to create directory {}:\n\n{}{}",
+                    path,
+                    format_command_result(&result),
+                    hint
+                ))]))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Copy a file or directory on the device, refusing to replace an existing destination unless overwrite is true, and confirm the copy exists (can write)"
+    )]
+    async fn copy_path(
+        &self,
+        Parameters(request): Parameters<TransferPathRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        self.transfer_path(TransferOp::Copy, request).await
+    }
+
+    #[tool(
+        description = "Move or rename a file or directory on the device, refusing to replace an existing destination unless overwrite is true, and confirm the move (can write)"
+    )]
+    async fn move_path(
+        &self,
+        Parameters(request): Parameters<TransferPathRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        self.transfer_path(TransferOp::Move, request).await
+    }
+
+    #[tool(
+        description = "Compare two files on the device, returning a unified diff and whether they are identical; binary files are only compared byte for byte"
+    )]
+    async fn diff_files(
+        &self,
+        Parameters(request): Parameters<DiffFilesRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        for (name, path) in [("path_a", &request.path_a), ("path_b", &request.path_b)] {
+            if path.trim().is_empty() {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "{} must not be empty",
+                    name
+                ))]));
+            }
+            if path.contains(['\n', '\0']) {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "{} must not contain newlines or NUL bytes",
+                    name
+                ))]));
+            }
+        }
+
+        let context_lines = request.context_lines.unwrap_or(DEFAULT_DIFF_CONTEXT);
+        let command = diff_command(&request.path_a, &request.path_b, context_lines);
+        let result = match self.run_command(&command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+
+        let (identical, binary, diff) = match parse_diff(&result.stdout) {
+            DiffOutcome::Identical => (true, false, ""),
+            DiffOutcome::Differ(diff) => (false, false, diff),
+            DiffOutcome::BinaryDiffer => (false, true, ""),
+            DiffOutcome::Missing(path) => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Cannot compare: {} does not exist",
+                    path
+                ))]));
+            }
+            DiffOutcome::IsDir(path) => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Cannot compare: {} is a directory; diff_files compares two files",
+                    path
+                ))]));
+            }
+            DiffOutcome::Unreadable(path) => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Cannot compare: {} is not readable (permission denied)",
+                    path
+                ))]));
+            }
+            DiffOutcome::MissingTool(name) => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "diff_files needs '{}', which is not installed on this device. \
+                     Install it with: pkg install {}",
+                    name,
+                    termux_package(name)
+                ))]));
+            }
+            DiffOutcome::Failed => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Failed to compare {} and {}:\n\n{}",
+                    request.path_a,
+                    request.path_b,
+                    format_command_result(&result)
+                ))]));
+            }
+        };
+
+        Ok(CallToolResult::structured(serde_json::json!({
+            "path_a": request.path_a,
+            "path_b": request.path_b,
+            "identical": identical,
+            "binary": binary,
+            "diff": diff,
+        })))
+    }
+
+    #[tool(
+        description = "Find files under a directory by name glob, depth and type, returning a list of paths; capped at max_results and reports when truncated"
+    )]
+    async fn search_files(
+        &self,
+        Parameters(request): Parameters<SearchFilesRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if request.root.trim().is_empty() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "root must not be empty".to_string(),
+            )]));
+        }
+        let fields = [
+            ("root", Some(&request.root)),
+            ("name_glob", request.name_glob.as_ref()),
+        ];
+        for (name, value) in fields {
+            if value.is_some_and(|v| v.contains(['\n', '\0'])) {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "{} must not contain newlines or NUL bytes",
+                    name
+                ))]));
+            }
+        }
+        if let Some(glob) = &request.name_glob
+            && glob.contains('/')
+        {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "name_glob is matched against names, not paths, so '{}' can never match. \
+                 Put the directory in root instead.",
+                glob
+            ))]));
+        }
+        let max_results = request.max_results.unwrap_or(DEFAULT_SEARCH_RESULTS);
+        if max_results == 0 || max_results > MAX_SEARCH_RESULTS {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "max_results must be between 1 and {}",
+                MAX_SEARCH_RESULTS
+            ))]));
+        }
+
+        let command = search_command(
+            &request.root,
+            request.name_glob.as_deref(),
+            request.max_depth,
+            request.kind,
+            max_results,
+        );
+        let result = match self.run_command(&command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+
+        let (paths, truncated) = match parse_search(&result.stdout, max_results) {
+            SearchOutcome::Found(paths, truncated) => (paths, truncated),
+            SearchOutcome::Missing => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Cannot search: {} does not exist",
+                    request.root
+                ))]));
+            }
+            SearchOutcome::NotDir => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Cannot search: {} is not a directory",
+                    request.root
+                ))]));
+            }
+            SearchOutcome::MissingTool => {
+                return Ok(CallToolResult::error(vec![Content::text(
+                    not_installed_message("find"),
+                )]));
+            }
+            SearchOutcome::Failed => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Failed to search {}:\n\n{}",
+                    request.root,
+                    format_command_result(&result)
+                ))]));
+            }
+        };
+
+        // Unreadable directories are skipped with an error each; the search still returns
+        let errors: Vec<&str> = result
+            .stderr
+            .lines()
+            .filter(|l| !l.trim().is_empty())
+            .take(MAX_SEARCH_ERRORS)
+            .collect();
+        Ok(CallToolResult::structured(serde_json::json!({
+            "root": request.root,
+            "count": paths.len(),
+            "truncated": truncated,
+            "paths": paths,
+            "errors": errors,
+        })))
+    }
+
+    #[tool(
+        description = "Summarize the Android device: model, Android version, battery, Wi-Fi SSID and /data storage, from getprop, dumpsys and df"
+    )]
+    async fn android_info(
+        &self,
+        Parameters(request): Parameters<AndroidInfoRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        // Validate timeout
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+
+        let mut info = AndroidInfo::default();
+        let commands = [
+            android::GETPROP_COMMAND,
+            android::BATTERY_COMMAND,
+            android::WIFI_COMMAND,
+            android::STORAGE_COMMAND,
+        ];
+        let mut outputs: [Option<String>; 4] = Default::default();
+        for (command, output) in commands.into_iter().zip(outputs.iter_mut()) {
+            // A connection failure affects every command, so report it instead of four nulls
+            let result = match self
+                .execute_recorded(client, command, request.timeout)
+                .await
+            {
+                Ok(result) => result,
+                Err(e) if e.is_connection_failure() => {
+                    return Ok(ssh_error_result("Command execution failed", &e));
+                }
+                Err(e) => {
+                    info.unavailable.push(format!("{}: {}", command, e));
+                    continue;
+                }
+            };
+            if !result.success() {
+                info.unavailable.push(format!(
+                    "{}: exit code {} ({})",
+                    command,
+                    result.exit_code_text(),
+                    first_output_line(&result)
+                ));
+                continue;
+            }
+            *output = Some(result.stdout);
+        }
+
+        let [getprop, battery, wifi, storage] = outputs;
+        if let Some(ref output) = getprop {
+            let mut props = android::parse_getprop(output);
+            info.manufacturer = props.remove("ro.product.manufacturer");
+            info.model = props.remove("ro.product.model");
+            info.android_version = props.remove("ro.build.version.release");
+            info.sdk = props
+                .get("ro.build.version.sdk")
+                .and_then(|sdk| sdk.parse().ok());
+        }
+        if let Some(ref output) = battery {
+            info.battery = android::parse_battery(output);
+            if info.battery.is_none() {
+                // Non-root shells are often refused with a "Permission Denial" message
+                info.unavailable.push(format!(
+                    "{}: no battery level in output ({})",
+                    android::BATTERY_COMMAND,
+                    output.lines().next().unwrap_or("empty").trim()
+                ));
+            }
+        }
+        if let Some(ref output) = wifi {
+            info.wifi_ssid = android::parse_wifi_ssid(output);
+        }
+        if let Some(ref output) = storage {
+            info.storage = android::parse_df(output);
+        }
+
+        Ok(CallToolResult::structured(
+            serde_json::to_value(&info).unwrap_or_default(),
+        ))
+    }
+
+    #[tool(
+        description = "Read one Android system property with getprop, e.g. ro.build.version.release; reports when the property is not set"
+    )]
+    async fn get_property(
+        &self,
+        Parameters(request): Parameters<GetPropertyRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if !android::is_property_key(&request.key) {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "'{}' is not a property name; use letters, digits and . _ - : @, e.g. ro.product.model",
+                request.key
+            ))]));
+        }
+
+        let command = format!("getprop {}", request.key);
+        let result = match self.run_command(&command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+        if !result.success() {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Failed to read property {}:\n\n{}",
+                request.key,
+                format_command_result(&result)
+            ))]));
+        }
+
+        // getprop prints an empty line for a property that isn't set
+        let value = result.stdout.trim();
+        if value.is_empty() {
+            return Ok(CallToolResult::structured(serde_json::json!({
+                "key": request.key,
+                "value": null,
+                "note": format!(
+                    "Property {} is not set on this device; list_properties shows the ones that are",
+                    request.key
+                ),
+            })));
+        }
+        Ok(CallToolResult::structured(serde_json::json!({
+            "key": request.key,
+            "value": value,
+        })))
+    }
+
+    #[tool(
+        description = "List Android system properties from getprop as name/value pairs, optionally only those starting with a prefix such as ro.product."
+    )]
+    async fn list_properties(
+        &self,
+        Parameters(request): Parameters<ListPropertiesRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if !request.prefix.is_empty() && !android::is_property_key(&request.prefix) {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "'{}' is not a property name prefix; use letters, digits and . _ - : @",
+                request.prefix
+            ))]));
+        }
+
+        let result = match self
+            .run_command(android::GETPROP_COMMAND, request.timeout)
+            .await
+        {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+        if !result.success() {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Failed to list properties:\n\n{}",
+                format_command_result(&result)
+            ))]));
+        }
+
+        let properties: BTreeMap<String, String> = android::parse_getprop(&result.stdout)
+            .into_iter()
+            .filter(|(key, _)| key.starts_with(&request.prefix))
+            .collect();
+        Ok(CallToolResult::structured(serde_json::json!({
+            "prefix": request.prefix,
+            "count": properties.len(),
+            "properties": properties,
+        })))
+    }
+
+    #[tool(
+        description = "Show free storage on the filesystem holding a directory (default /sdcard) and its largest subdirectories, from df and du, sorted by size"
+    )]
+    async fn disk_usage(
+        &self,
+        Parameters(request): Parameters<DiskUsageRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if request.path.trim().is_empty() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "path must not be empty".to_string(),
+            )]));
+        }
+        if request.top == 0 || request.top > MAX_DISK_USAGE_TOP {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "top must be between 1 and {}",
+                MAX_DISK_USAGE_TOP
+            ))]));
+        }
+
+        let (df_command, du_command) = disk_usage_commands(&request.path);
+        let df = match self.run_command(&df_command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+        let du = match self.run_command(&du_command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+
+        let mut notes = Vec::new();
+        let filesystem = android::parse_df_usage(&df.stdout, 1024);
+        if filesystem.is_none() {
+            notes.push(format!(
+                "df: no usage in output (exit code {}: {})",
+                df.exit_code_text(),
+                first_output_line(&df)
+            ));
+        }
+        // du still prints what it could read when some directories are unreadable
+        let (total_bytes, mut largest) = android::parse_du(&du.stdout, 1024);
+        if total_bytes.is_none() {
+            notes.push(format!(
+                "du: no sizes in output (exit code {}: {})",
+                du.exit_code_text(),
+                first_output_line(&du)
+            ));
+        } else if !du.success() {
+            let unreadable = du.stderr.lines().filter(|l| !l.trim().is_empty()).count();
+            notes.push(format!(
+                "du could not read {} entries (e.g. {}); sizes may be low",
+                unreadable,
+                first_output_line(&du)
+            ));
+        }
+        if filesystem.is_none() && total_bytes.is_none() {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Failed to read disk usage for {}:\n\n{}\n\n{}",
+                request.path,
+                format_command_result(&df),
+                format_command_result(&du)
+            ))]));
+        }
+
+        let omitted = largest.len().saturating_sub(request.top);
+        largest.truncate(request.top);
+        let usage = DiskUsage {
+            path: request.path,
+            filesystem,
+            total_bytes,
+            largest,
+            omitted,
+            notes,
+        };
+        Ok(CallToolResult::structured(
+            serde_json::to_value(&usage).unwrap_or_default(),
+        ))
+    }
+
+    #[tool(
+        description = "Check whether Termux can access shared storage (/sdcard and ~/storage), with what to do if not; call it before working in /sdcard or after a permission denied there"
+    )]
+    async fn storage_status(
+        &self,
+        Parameters(request): Parameters<StorageStatusRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let result = match self
+            .run_command(android::STORAGE_STATUS_COMMAND, request.timeout)
+            .await
+        {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+
+        let status = android::parse_storage_status(&result.stdout);
+        Ok(CallToolResult::structured(
+            serde_json::to_value(&status).unwrap_or_default(),
+        ))
+    }
+
+    #[tool(
+        description = "Take (or with release=true, release) the Termux wake lock, so Doze doesn't suspend Termux and drop the session during long operations"
+    )]
+    async fn keep_awake(
+        &self,
+        Parameters(request): Parameters<KeepAwakeRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let command = android::wake_lock_command(!request.release);
+        let result = match self.run_command(&command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+
+        match android::parse_wake_lock(&result.stdout) {
+            android::WakeLockOutcome::Done if request.release => {
+                Ok(CallToolResult::success(vec![Content::text(
+                    "✓ Released the Termux wake lock; Android may suspend Termux again when the screen is off"
+                        .to_string(),
+                )]))
+            }
+            android::WakeLockOutcome::Done => Ok(CallToolResult::success(vec![Content::text(
+                "✓ Took the Termux wake lock; Termux keeps running while the screen is off. \
+                 Termux's notification shows it is held. Release it with keep_awake(release=true) \
+                 when done, as it uses more battery."
+                    .to_string(),
+            )])),
+            android::WakeLockOutcome::MissingTool => Ok(CallToolResult::error(vec![
+                Content::text(android::WAKE_LOCK_MISSING.to_string()),
+            ])),
+            android::WakeLockOutcome::Failed(_) => {
+                Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Failed to {} the Termux wake lock:\n\n{}",
+                    if request.release { "release" } else { "take" },
+                    format_command_result(&result)
+                ))]))
+            }
+        }
+    }
+
+    #[tool(
+        description = "Read the text on the device's clipboard with termux-clipboard-get (needs Termux:API)"
+    )]
+    async fn clipboard_get(
+        &self,
+        Parameters(request): Parameters<ClipboardGetRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let command = android::termux_api_command("termux-clipboard-get", "");
+        let result = match self.run_command(&command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+        if android::termux_api_missing(&result.stderr) {
+            return Ok(CallToolResult::error(vec![Content::text(
+                android::termux_api_guidance("termux-clipboard-get"),
+            )]));
+        }
+        if !result.success() {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Failed to read the clipboard:\n\n{}",
+                format_command_result(&result)
+            ))]));
+        }
+
+        Ok(CallToolResult::structured(serde_json::json!({
+            "text": result.stdout,
+            "empty": result.stdout.is_empty(),
+        })))
+    }
+
+    #[tool(
+        description = "Put text on the device's clipboard with termux-clipboard-set (needs Termux:API); the text is sent on stdin, so it arrives unchanged"
+    )]
+    async fn clipboard_set(
+        &self,
+        Parameters(request): Parameters<ClipboardSetRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if request.text.len() > MAX_CLIPBOARD_BYTES {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "text is {} bytes; clipboard_set takes at most {} KiB",
+                request.text.len(),
+                MAX_CLIPBOARD_BYTES / 1024
+            ))]));
+        }
+
+        // Given no arguments, termux-clipboard-set reads the text from stdin, so it is
+        // never parsed by a shell
+        let command = android::termux_api_command("termux-clipboard-set", "");
+        let options = ExecOptions {
+            stdin: Some(request.text.as_bytes()),
+            ..Default::default()
+        };
+        let result = match self
+            .run_command_with(&command, options, request.timeout)
+            .await
+        {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+        if android::termux_api_missing(&result.stderr) {
+            return Ok(CallToolResult::error(vec![Content::text(
+                android::termux_api_guidance("termux-clipboard-set"),
+            )]));
+        }
+        if !result.success() {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Failed to set the clipboard:\n\n{}",
+                format_command_result(&result)
+            ))]));
+        }
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "✓ Copied {} characters to the device's clipboard",
+            request.text.chars().count()
+        ))]))
+    }
+
+    #[tool(
+        description = "Show a notification on the device with termux-notification (needs Termux:API), e.g. to say a long task finished"
+    )]
+    async fn notify(
+        &self,
+        Parameters(request): Parameters<NotifyRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        for (name, value) in [("title", &request.title), ("content", &request.content)] {
+            if value.len() > MAX_NOTIFICATION_BYTES {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "{} is {} bytes; notify takes at most {}",
+                    name,
+                    value.len(),
+                    MAX_NOTIFICATION_BYTES
+                ))]));
+            }
+        }
+        if request.title.trim().is_empty() && request.content.trim().is_empty() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Give a title or content for the notification".to_string(),
+            )]));
+        }
+
+        // Without -c, termux-notification reads the content from stdin
+        let args = format!(
+            " -t {} --priority {}",
+            shell_quote(&request.title),
+            request.priority.name()
+        );
+        let command = android::termux_api_command("termux-notification", &args);
+        let options = ExecOptions {
+            stdin: Some(request.content.as_bytes()),
+            ..Default::default()
+        };
+        let result = match self
+            .run_command_with(&command, options, request.timeout)
+            .await
+        {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+        if android::termux_api_missing(&result.stderr) {
+            return Ok(CallToolResult::error(vec![Content::text(
+                android::termux_api_guidance("termux-notification"),
+            )]));
+        }
+        if !result.success() {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Failed to show the notification:\n\n{}",
+                format_command_result(&result)
+            ))]));
+        }
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "✓ Notification \"{}\" sent to the device",
+            request.title
+        ))]))
+    }
+
+    #[tool(
+        description = "Capture the device screen with screencap and return it as a PNG image, optionally saving it locally"
+    )]
+    async fn screenshot(
+        &self,
+        Parameters(request): Parameters<ScreenshotRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let command = if request.root {
+            run_as_command(android::SCREENCAP_COMMAND, "root")
+        } else {
+            android::SCREENCAP_COMMAND.to_string()
+        };
+        let mut result = match self.run_command(&command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+        if request.root {
+            match take_run_as_marker(&mut result) {
+                RunAsOutcome::Ran => {}
+                RunAsOutcome::Unavailable => {
+                    return Ok(CallToolResult::error(vec![Content::text(
+                        "root=true needs su (a rooted device, e.g. with Magisk) or sudo \
+                         (pkg install tsu), and neither is installed on the device."
+                            .to_string(),
+                    )]));
+                }
+                RunAsOutcome::Refused => {
+                    return Ok(CallToolResult::error(vec![Content::text(format!(
+                        "su refused to run screencap as root:\n\n{}\n\n\
+                         Allow Termux in the root manager (e.g. Magisk) and try again.",
+                        format_command_result(&result)
+                    ))]));
+                }
+            }
+        }
+
+        // A PNG is never valid UTF-8, so the exact bytes are in stdout_bytes
+        let png = result
+            .stdout_bytes
+            .take()
+            .unwrap_or_else(|| std::mem::take(&mut result.stdout).into_bytes());
+        let Some((width, height)) = android::png_dimensions(&png) else {
+            let hint = if result.exit_code == Some(127) {
+                "screencap isn't available on this device. It ships with Android in /system/bin; \
+                 check that /system/bin is on PATH."
+            } else if request.root {
+                "screencap ran but didn't produce a PNG. The screen may be off or protected (FLAG_SECURE)."
+            } else {
+                "On many devices screencap needs a privileged user: retry with root=true on a \
+                 rooted device, or capture over adb instead."
+            };
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "screencap did not return a PNG ({} bytes of output, exit code {}{}).\n\n{}",
+                png.len(),
+                result.exit_code_text(),
+                if result.stderr.trim().is_empty() {
+                    String::new()
+                } else {
+                    format!(": {}", result.stderr.trim())
+                },
+                hint
+            ))]));
+        };
+
+        let mut summary = format!("Screenshot: {}x{}, {} bytes", width, height, png.len());
+        if let Some(ref local_path) = request.local_path {
+            let local_path = std::path::PathBuf::from(shellexpand::tilde(local_path).to_string());
+            if let Err(e) = std::fs::write(&local_path, &png) {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Captured the screen but could not save it to {}: {}",
+                    local_path.display(),
+                    e
+                ))]));
+            }
+            summary.push_str(&format!(", saved to {}", local_path.display()));
+        }
+
+        use base64::Engine;
+        Ok(CallToolResult::success(vec![
+            Content::image(
+                base64::engine::general_purpose::STANDARD.encode(&png),
+                "image/png",
+            ),
+            Content::text(summary),
+        ]))
+    }
+
+    #[tool(
+        description = "Start a shell command in the background on Android; returns a job id for job_status and kill_job"
+    )]
+    async fn start_job(
+        &self,
+        Parameters(request): Parameters<StartJobRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Without a config, run_command reports the first-run message
+        if let Some(client) = self.ssh_client.lock().await.as_ref()
+            && let Err(msg) =
+                check_command_length(&request.command, client.config().max_command_length())
+        {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+        if let Err(msg) = self.blocklist.check(&request.command) {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+        if let Err(error_result) = self.check_rate_limit(1).await {
+            return Ok(error_result);
+        }
+
+        let job_id = self.next_job_id.fetch_add(1, Ordering::Relaxed);
+
+        // Detach from the channel so the job outlives this call, and report
+        // the pid and resolved log path ($TMPDIR on Termux, /tmp elsewhere)
+        let command = format!(
+            "log=\"${{TMPDIR:-/tmp}}/mcp-android-ssh-job-{}.log\"; \
+             nohup sh -c {} > \"$log\" 2>&1 < /dev/null & echo \"$! $log\"",
+            job_id,
+            shell_quote(&request.command)
+        );
+
+        let result = match self.run_command(&command, default_timeout()).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+
+        let started = result
+            .stdout
+            .trim()
+            .split_once(' ')
+            .and_then(|(pid, log)| Some((pid.parse::<u32>().ok()?, log.to_string())));
+        let Some((pid, log_path)) = started.filter(|_| result.success()) else {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Failed to start background job:\n\n{}",
+                format_command_result(&result)
+            ))]));
+        };
+
+        self.jobs.lock().await.insert(
+            job_id,
+            BackgroundJob {
+                command: request.command,
+                pid,
+                log_path: log_path.clone(),
+                started: Instant::now(),
+                exited: false,
+            },
+        );
+
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "✓ Started job {} (pid {})\nOutput: {}\n\n\
+             Use job_status to check progress and kill_job to stop it.",
+            job_id, pid, log_path
+        ))]))
+    }
+
+    #[tool(description = "Check whether background jobs are running and show their recent output")]
+    async fn job_status(
+        &self,
+        Parameters(request): Parameters<JobStatusRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let Some(job_id) = request.job_id else {
+            let jobs = self.jobs.lock().await;
+            if jobs.is_empty() {
+                return Ok(CallToolResult::success(vec![Content::text(
+                    "No background jobs started this session".to_string(),
+                )]));
+            }
+            let mut ids: Vec<_> = jobs.keys().copied().collect();
+            ids.sort_unstable();
+            let mut msg = String::from("Background jobs:\n");
+            for id in ids {
+                let job = &jobs[&id];
+                msg.push_str(&format!(
+                    "• {} (pid {}, {}): {}\n",
+                    id,
+                    job.pid,
+                    if job.exited { "exited" } else { "started" },
+                    job.command
+                ));
+            }
+            return Ok(CallToolResult::success(vec![Content::text(msg)]));
+        };
+
+        let Some(job) = self.jobs.lock().await.get(&job_id).cloned() else {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Unknown job id {}. Only jobs started with start_job this session are tracked.",
+                job_id
+            ))]));
+        };
+
+        let command = format!(
+            "if {}; then echo running; else echo exited; fi; tail -n {} {} 2>/dev/null",
+            if job.exited {
+                "false".to_string()
+            } else {
+                format!("kill -0 {} 2>/dev/null", job.pid)
+            },
+            request.lines,
+            shell_quote(&job.log_path)
+        );
+
+        let result = match self.run_command(&command, default_timeout()).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+
+        let (state, log) = result
+            .stdout
+            .split_once('\n')
+            .unwrap_or((result.stdout.trim(), ""));
+        let running = state == "running";
+        if !running && let Some(tracked) = self.jobs.lock().await.get_mut(&job_id) {
+            tracked.exited = true;
+        }
+
+        let mut msg = format!(
+            "Job {} (pid {}): {}\nCommand: {}\nStarted: {}s ago\n\nLast {} lines of {}:\n{}",
+            job_id,
+            job.pid,
+            if running { "running" } else { "exited" },
+            job.command,
+            job.started.elapsed().as_secs(),
+            request.lines,
+            job.log_path,
+            log
+        );
+        if !msg.ends_with('\n') {
+            msg.push('\n');
+        }
+
+        Ok(CallToolResult::success(vec![Content::text(msg)]))
+    }
+
+    #[tool(
+        description = "Stop a background job started with start_job by sending it a signal (only tracked jobs can be killed)"
+    )]
+    async fn kill_job(
+        &self,
+        Parameters(request): Parameters<KillJobRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Only pids recorded by start_job are ever signalled
+        let Some(job) = self.jobs.lock().await.get(&request.job_id).cloned() else {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Unknown job id {}. Only jobs started with start_job this session can be killed.",
+                request.job_id
+            ))]));
+        };
+
+        let signal = request.signal.name();
+        let mut command = if job.exited {
+            // The pid may have been reused by an unrelated process
+            "echo exited".to_string()
+        } else {
+            format!(
+                "if kill -0 {pid} 2>/dev/null; then kill -{signal} {pid} && echo running; \
+                 else echo exited; fi",
+                pid = job.pid,
+                signal = signal
+            )
+        };
+        if request.remove_log {
+            command.push_str(&format!("; rm -f {}", shell_quote(&job.log_path)));
+        }
+
+        let result = match self.run_command(&command, default_timeout()).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+        let was_running = result.stdout.trim() == "running";
+
+        {
+            let mut jobs = self.jobs.lock().await;
+            if request.remove_log {
+                jobs.remove(&request.job_id);
+            } else if let Some(tracked) = jobs.get_mut(&request.job_id) {
+                // A job that wasn't running can't be signalled again safely
+                tracked.exited |= !was_running;
+            }
+        }
+
+        let mut msg = if was_running {
+            format!(
+                "✓ Sent SIG{} to job {} (pid {})",
+                signal, request.job_id, job.pid
+            )
+        } else {
+            format!("Job {} (pid {}) was not running", request.job_id, job.pid)
+        };
+        if request.remove_log {
+            msg.push_str(&format!("\nRemoved log file: {}", job.log_path));
+        }
+
+        Ok(CallToolResult::success(vec![Content::text(msg)]))
+    }
+
+    #[tool(
+        description = "List processes as structured rows (pid, user, cpu, mem, command), optionally filtered by command line"
+    )]
+    async fn process_list(
+        &self,
+        Parameters(request): Parameters<ProcessListRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if request.limit == 0 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "limit must be at least 1".to_string(),
+            )]));
+        }
+        let result = match self.run_command(android::PS_COMMAND, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+        let Some(mut processes) = android::parse_ps(&result.stdout) else {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Couldn't read the process list from ps:\n\n{}",
+                format_command_result(&result)
+            ))]));
+        };
+
+        if let Some(ref filter) = request.filter {
+            let filter = filter.to_lowercase();
+            processes.retain(|p| p.command.to_lowercase().contains(&filter));
+        }
+        let total = processes.len();
+        processes.truncate(request.limit);
+        Ok(CallToolResult::structured(serde_json::json!({
+            "total": total,
+            "truncated": total > processes.len(),
+            "processes": processes,
+        })))
+    }
+
+    #[tool(
+        description = "Send a signal (TERM by default) to a process by pid and report whether it exited; KILL needs confirm=true (can write)"
+    )]
+    async fn process_kill(
+        &self,
+        Parameters(request): Parameters<ProcessKillRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if request.pid <= 1 {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "pid must be a process id above 1 (got {})",
+                request.pid
+            ))]));
+        }
+        let signal = request.signal.name();
+        if matches!(request.signal, JobSignal::Kill) && !request.confirm {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "KILL ends pid {} at once, without letting it save its work or clean up. \
+                 Try TERM first; to send KILL anyway, call process_kill again with confirm=true.",
+                request.pid
+            ))]));
+        }
+
+        // A moment's wait lets a process that handles the signal finish exiting; a zombie
+        // waiting for its parent to reap it has exited too
+        let command = format!(
+            "[ -d /proc/{pid} ] || {{ echo no_process; exit 0; }}; \
+             kill -{signal} {pid} || {{ echo failed; exit 0; }}; sleep 1; \
+             if [ -d /proc/{pid} ] && ! grep -q '^State:[[:space:]]*Z' /proc/{pid}/status 2>/dev/null; \
+             then echo running; else echo exited; fi",
+            pid = request.pid,
+            signal = signal
+        );
+        if let Err(msg) = self.blocklist.check(&command) {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+        let result = match self.run_command(&command, request.timeout).await {
+            Ok(result) => result,
+            Err(error) => return Ok(error),
+        };
+
+        match result
+            .stdout
+            .lines()
+            .map(str::trim)
+            .rfind(|l| !l.is_empty())
+        {
+            Some("exited") => Ok(CallToolResult::success(vec![Content::text(format!(
+                "✓ Sent SIG{} to pid {}; it has exited",
+                signal, request.pid
+            ))])),
+            Some("running") => Ok(CallToolResult::success(vec![Content::text(format!(
+                "Sent SIG{} to pid {}, but it is still running a second later. \
+                 Check again with process_list; if it ignores the signal, KILL (with confirm=true) ends it.",
+                signal, request.pid
+            ))])),
+            Some("no_process") => Ok(CallToolResult::error(vec![Content::text(format!(
+                "No process with pid {}. It may have exited already, or belong to another app \
+                 whose processes Termux can't see.",
+                request.pid
+            ))])),
+            _ => Ok(CallToolResult::error(vec![Content::text(format!(
+                "Failed to send SIG{} to pid {}; a process of another user needs root:\n\n{}",
+                signal,
+                request.pid,
+                format_command_result(&result)
+            ))])),
+        }
+    }
+
+    #[tool(
+        description = "Dump recent Android logcat entries (logcat -d, so it never hangs), filtered by priority and tag"
+    )]
+    async fn logcat(
+        &self,
+        Parameters(request): Parameters<LogcatRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        // Validate parameters
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+        if request.lines == 0 || request.lines > MAX_LOGCAT_LINES {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Lines must be between 1 and {}",
+                MAX_LOGCAT_LINES
+            ))]));
+        }
+        let command = match logcat_command(request.lines, request.priority, request.tag.as_deref())
+        {
+            Ok(command) => command,
+            Err(msg) => return Ok(CallToolResult::error(vec![Content::text(msg)])),
+        };
+
+        match self.is_command_available(client, "logcat").await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Ok(CallToolResult::error(vec![Content::text(
+                    "logcat is not available on this device. It ships with Android, so the \
+                     server is probably connected to a non-Android host."
+                        .to_string(),
+                )]));
+            }
+            Err(e) => {
+                return Ok(ssh_error_result("Command execution failed", &e));
+            }
+        }
+
+        let result = match self
+            .execute_recorded(client, &command, request.timeout)
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => {
+                return Ok(ssh_error_result("Command execution failed", &e));
+            }
+        };
+        if !result.success() {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "logcat failed:\n\n{}",
+                format_command_result(&result)
+            ))]));
+        }
+
+        let mut output = result.stdout;
+        if output.trim().is_empty() {
+            output = "No log entries matched.".to_string();
+        } else if !output.ends_with('\n') {
+            output.push('\n');
+        }
+        output.push_str(&format!("\n(command: {})", command));
+        Ok(CallToolResult::success(vec![Content::text(output)]))
+    }
+
+    #[tool(
+        description = "Print recent systemd journal entries (journalctl -n, so it never hangs), filtered by time and unit"
+    )]
+    async fn journal(
+        &self,
+        Parameters(request): Parameters<JournalRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        // Validate parameters
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+        if request.lines == 0 || request.lines > MAX_LOGCAT_LINES {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Lines must be between 1 and {}",
+                MAX_LOGCAT_LINES
+            ))]));
+        }
+        let command = match journal_command(
+            request.lines,
+            request.since.as_deref(),
+            request.unit.as_deref(),
+        ) {
+            Ok(command) => command,
+            Err(msg) => return Ok(CallToolResult::error(vec![Content::text(msg)])),
+        };
+
+        match self.is_command_available(client, "journalctl").await {
+            Ok(true) => {}
+            Ok(false) => {
+                return Ok(CallToolResult::error(vec![Content::text(
+                    "journalctl is not available on this device. Android doesn't use systemd, \
+                     so use the logcat tool for Android logs."
+                        .to_string(),
+                )]));
+            }
+            Err(e) => {
+                return Ok(ssh_error_result("Command execution failed", &e));
+            }
+        }
+
+        let result = match self
+            .execute_recorded(client, &command, request.timeout)
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => {
+                return Ok(ssh_error_result("Command execution failed", &e));
+            }
+        };
+        if !result.success() {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "journalctl failed:\n\n{}",
+                format_command_result(&result)
+            ))]));
+        }
+
+        let mut output = result.stdout;
+        if output.trim().is_empty() || output.trim() == "-- No entries --" {
+            output = "No journal entries matched.".to_string();
+        } else if !output.ends_with('\n') {
+            output.push('\n');
+        }
+        output.push_str(&format!("\n(command: {})", command));
+        Ok(CallToolResult::success(vec![Content::text(output)]))
+    }
+
+    #[tool(
+        description = "Read the last N lines of a remote file efficiently over SFTP, without transferring the whole file"
+    )]
+    async fn tail_lines(
+        &self,
+        Parameters(request): Parameters<TailLinesRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        // Validate parameters
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+        if request.lines == 0 || request.lines > MAX_TAIL_LINES {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Lines must be between 1 and {}",
+                MAX_TAIL_LINES
+            ))]));
+        }
+
+        match client
+            .read_tail(&request.path, request.lines, request.timeout)
+            .await
+        {
+            Ok(tail) => {
+                let mut output = tail.text;
+                if !output.is_empty() {
+                    output.push_str("\n\n");
+                }
+                output.push_str(&format!(
+                    "(last {} of requested {} lines, file size: {} bytes)",
+                    tail.lines_returned, request.lines, tail.total_size
+                ));
+                if !tail.valid_utf8 {
+                    output.push_str(
+                        "\nNote: file is not valid UTF-8; invalid bytes were replaced with �",
+                    );
+                }
+                Ok(CallToolResult::success(vec![Content::text(output)]))
+            }
+            Err(e) => Ok(ssh_error_result("Failed to read file", &e)),
+        }
+    }
+
+    #[tool(
+        description = "Show the last lines of a remote file with tail -n, optionally following new lines (tail -f) for follow_secs and then returning"
+    )]
+    async fn tail_file(
+        &self,
+        Parameters(request): Parameters<TailFileRequest>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        if request.remote_path.trim().is_empty() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "remote_path must not be empty".to_string(),
+            )]));
+        }
+        if request
+            .follow_secs
+            .is_some_and(|secs| secs == 0 || secs > 300)
+        {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "follow_secs must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+        let min_lines = usize::from(request.follow_secs.is_none());
+        if request.lines < min_lines || request.lines > MAX_TAIL_LINES {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Lines must be between {} and {}",
+                min_lines, MAX_TAIL_LINES
+            ))]));
+        }
+
+        let path = quote_remote_path(&request.remote_path);
+        let Some(follow_secs) = request.follow_secs else {
+            let command = format!("tail -n {} {}", request.lines, path);
+            return match self.run_command(&command, request.timeout).await {
+                Ok(result) if result.success() => {
+                    Ok(CallToolResult::success(vec![Content::text(result.stdout)]))
+                }
+                Ok(result) => Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Failed to read {}:\n\n{}",
+                    request.remote_path,
+                    format_command_result(&result)
+                ))])),
+                Err(error) => Ok(error),
+            };
+        };
+
+        // Following is a stream that never exits on its own, stopped after follow_secs
+        let stream = StreamRequest {
+            command: format!("tail -n {} -f {}", request.lines, path),
+            duration_secs: follow_secs,
+            stop_pattern: None,
+            flush_interval_ms: None,
+            profile: None,
+        };
+        self.stream(Parameters(stream), context).await
+    }
+
+    #[tool(description = "Upload a local file to the device over SFTP")]
+    async fn upload_file(
+        &self,
+        Parameters(request): Parameters<UploadFileRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        // Validate timeout
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+
+        let local_path =
+            std::path::PathBuf::from(shellexpand::tilde(&request.local_path).to_string());
+        match client
+            .upload(&local_path, &request.remote_path, request.timeout)
+            .await
+        {
+            Ok(bytes) => Ok(CallToolResult::success(vec![Content::text(format!(
+                "✓ Uploaded {} to {} ({} bytes)",
+                local_path.display(),
+                request.remote_path,
+                bytes
+            ))])),
+            Err(e) => Ok(ssh_error_result("Failed to upload file", &e)),
+        }
+    }
+
+    #[tool(
+        description = "Write text to a remote file exactly as given, without shell quoting; replaces or appends (can write/modify)"
+    )]
+    async fn write_file(
+        &self,
+        Parameters(request): Parameters<WriteFileRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        // Validate parameters
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+        if request.remote_path.is_empty() || request.remote_path.ends_with('/') {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "remote_path must name a file".to_string(),
+            )]));
+        }
+        if request.content.len() > MAX_WRITE_BYTES {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Content is too large ({} bytes, max {} bytes). Use upload_file for large files.",
+                request.content.len(),
+                MAX_WRITE_BYTES
+            ))]));
+        }
+
+        match client
+            .write_file(
+                &request.remote_path,
+                request.content.as_bytes(),
+                request.append,
+                request.create_dirs,
+                request.timeout,
+            )
+            .await
+        {
+            Ok(bytes) => Ok(CallToolResult::success(vec![Content::text(format!(
+                "✓ {} {} bytes to {}",
+                if request.append { "Appended" } else { "Wrote" },
+                bytes,
+                request.remote_path
+            ))])),
+            Err(e) => Ok(ssh_error_result("Failed to write file", &e)),
+        }
+    }
+
+    #[tool(
+        description = "Download a remote file over SFTP to a local path, optionally returning small text files inline"
+    )]
+    async fn download_file(
+        &self,
+        Parameters(request): Parameters<DownloadFileRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        // Validate timeout
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+
+        let local_path =
+            std::path::PathBuf::from(shellexpand::tilde(&request.local_path).to_string());
+        let bytes = match client
+            .download(&request.remote_path, &local_path, request.timeout)
+            .await
+        {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                return Ok(ssh_error_result("Failed to download file", &e));
+            }
+        };
+
+        let mut output = format!(
+            "✓ Downloaded {} to {} ({} bytes)",
+            request.remote_path,
+            local_path.display(),
+            bytes
+        );
+        if request.inline {
+            if bytes > MAX_INLINE_BYTES {
+                output.push_str(&format!(
+                    "\nNot shown inline: file is larger than {} bytes",
+                    MAX_INLINE_BYTES
+                ));
+            } else {
+                match std::fs::read(&local_path).map(String::from_utf8) {
+                    Ok(Ok(text)) => {
+                        output.push_str("\n\n");
+                        output.push_str(&text);
+                    }
+                    Ok(Err(_)) => output.push_str("\nNot shown inline: file is not UTF-8 text"),
+                    Err(e) => output.push_str(&format!("\nNot shown inline: {}", e)),
+                }
+            }
+        }
+
+        Ok(CallToolResult::success(vec![Content::text(output)]))
+    }
+
+    #[tool(
+        description = "Compute a file checksum (md5/sha1/sha256/sha512) on the device or by streaming it over SFTP; cancellable, reports progress when streaming"
+    )]
+    async fn compute_checksum(
+        &self,
+        Parameters(request): Parameters<ComputeChecksumRequest>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        // Validate timeout
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+
+        let algorithm = request.algorithm;
+        let work = async {
+            match request.mode {
+                ChecksumMode::Device => {
+                    let digest = self
+                        .device_checksum(client, &request.path, algorithm, request.timeout)
+                        .await?;
+                    Ok::<_, String>((digest, None))
+                }
+                ChecksumMode::Stream => {
+                    let path = client
+                        .resolve_remote_path(&request.path)
+                        .await
+                        .map_err(|e| e.to_string())?;
+                    let sftp = client.sftp().await.map_err(|e| e.to_string())?;
+                    let streamed = tokio::time::timeout(
+                        std::time::Duration::from_secs(request.timeout),
+                        stream_checksum(&sftp, &path, algorithm, &context),
+                    )
+                    .await
+                    .map_err(|_| {
+                        format!("Checksum timed out after {} seconds", request.timeout)
+                    })??;
+                    Ok((streamed.0, Some(streamed.1)))
+                }
+            }
+        };
+
+        let outcome = tokio::select! {
+            _ = context.ct.cancelled() => {
+                return Ok(CallToolResult::error(vec![Content::text(
+                    "Checksum cancelled".to_string(),
+                )]));
+            }
+            outcome = work => outcome,
+        };
+
+        match outcome {
+            Ok((digest, bytes)) => {
+                let mut msg = format!("{}  {}  {}\n", algorithm.name(), digest, request.path);
+                match bytes {
+                    Some(bytes) => {
+                        msg.push_str(&format!("(streamed over SFTP, {} bytes hashed)", bytes))
+                    }
+                    None => msg.push_str(&format!(
+                        "(computed on device with {})",
+                        algorithm.command()
+                    )),
+                }
+                Ok(CallToolResult::success(vec![Content::text(msg)]))
+            }
+            Err(e) => Ok(CallToolResult::error(vec![Content::text(format!(
+                "Failed to compute checksum: {}",
+                e
+            ))])),
+        }
+    }
+
+    #[tool(
+        description = "Check a remote file against an expected hash or a local file; returns matches plus both digests"
+    )]
+    async fn verify_checksum(
+        &self,
+        Parameters(request): Parameters<VerifyChecksumRequest>,
+        context: RequestContext<RoleServer>,
+    ) -> Result<CallToolResult, McpError> {
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+
+        let algorithm = request.algorithm;
+        let expected = match (request.expected.as_deref(), request.local_path.as_deref()) {
+            (Some(_), Some(_)) | (None, None) => {
+                return Ok(CallToolResult::error(vec![Content::text(
+                    "Give either expected (a hex digest) or local_path (a file on this machine). \
+                     To just hash a remote file, use compute_checksum."
+                        .to_string(),
+                )]));
+            }
+            (Some(expected), None) => {
+                let expected = expected.trim().to_ascii_lowercase();
+                if expected.len() != algorithm.hex_len()
+                    || !expected.chars().all(|c| c.is_ascii_hexdigit())
+                {
+                    return Ok(CallToolResult::error(vec![Content::text(format!(
+                        "expected must be a {} digest: {} hex digits, got '{}'",
+                        algorithm.name(),
+                        algorithm.hex_len(),
+                        expected
+                    ))]));
+                }
+                Some(expected)
+            }
+            (None, Some(_)) => None,
+        };
+        let local_path = request
+            .local_path
+            .as_ref()
+            .map(|p| std::path::PathBuf::from(shellexpand::tilde(p).to_string()));
+
+        let work = async {
+            let remote = self
+                .device_checksum(client, &request.remote_path, algorithm, request.timeout)
+                .await?;
+            let local = match local_path {
+                Some(ref path) => Some(local_checksum(path, algorithm).await?),
+                None => None,
+            };
+            Ok::<_, String>((remote, local))
+        };
+
+        let outcome = tokio::select! {
+            _ = context.ct.cancelled() => {
+                return Ok(CallToolResult::error(vec![Content::text(
+                    "Checksum cancelled".to_string(),
+                )]));
+            }
+            outcome = work => outcome,
+        };
+
+        let (remote, local) = match outcome {
+            Ok(digests) => digests,
+            Err(e) => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Failed to verify checksum: {}",
+                    e
+                ))]));
+            }
+        };
+        let matches = expected.as_ref().or(local.as_ref()) == Some(&remote);
+        Ok(CallToolResult::structured(serde_json::json!({
+            "matches": matches,
+            "algorithm": algorithm.name(),
+            "remote_path": request.remote_path,
+            "remote_digest": remote,
+            "expected": expected,
+            "local_path": local_path.map(|p| p.display().to_string()),
+            "local_digest": local,
+        })))
+    }
+
+    #[tool(
+        description = "Configure Android SSH connection - provide credentials to connect to your Android device"
+    )]
+    async fn setup(
+        &self,
+        Parameters(request): Parameters<SetupRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if let Some(name) = request.profile.clone() {
+            return Ok(setup_profile(&name, request).await);
+        }
+
+        // Try to load existing config, or create empty one
+        let existing_config = crate::config::Config::load_existing().ok();
+
+        // Merge with provided values
+        let host = request
+            .host
+            .or_else(|| existing_config.as_ref().map(|c| c.host.clone()));
+        let port = request
+            .port
+            .or_else(|| existing_config.as_ref().map(|c| c.port));
+        let user = request
+            .user
+            .or_else(|| existing_config.as_ref().map(|c| c.user.clone()));
+        let key_path = request
+            .key_path
+            .or_else(|| existing_config.as_ref().and_then(|c| c.key_path.clone()));
+        // A password given here replaces password_command/password_file
+        let password_helper = request.password.is_none()
+            && existing_config
+                .as_ref()
+                .is_some_and(|c| c.password_command.is_some() || c.password_file.is_some());
+        let password = request
+            .password
+            .or_else(|| existing_config.as_ref().and_then(|c| c.password.clone()));
+        let use_agent = request
+            .use_agent
+            .or_else(|| existing_config.as_ref().map(|c| c.use_agent))
+            .unwrap_or(false);
+        let test = request.test_connection.unwrap_or(true);
+
+        // Check what's missing
+        let mut missing = Vec::new();
+        if host.is_none() {
+            missing.push("host");
+        }
+        if user.is_none() {
+            missing.push("user");
+        }
+        if key_path.is_none() && password.is_none() && !password_helper && !use_agent {
+            missing.push("key_path or password");
+        }
+
+        // If anything is missing, return helpful message
+        if !missing.is_empty() {
+            let mut msg = String::from("Setup incomplete. Missing:\n\n");
+
+            if missing.contains(&"host") {
+                msg.push_str("• host - Your Android device IP (IPv4 or IPv6) or hostname\n");
+                msg.push_str("  Find it: Run 'ifconfig wlan0' in Termux\n\n");
+            }
+
+            if missing.contains(&"user") {
+                msg.push_str("• user - Your Termux username\n");
+                msg.push_str("  Find it: Run 'whoami' in Termux\n\n");
+            }
+
+            if missing.contains(&"key_path or password") {
+                msg.push_str("• Authentication - Choose one:\n");
+                msg.push_str("  SSH key (recommended):\n");
+                msg.push_str("    Generate: ssh-keygen -t ed25519 -f ~/.ssh/id_ed25519\n");
+                msg.push_str(
+                    "    Copy to device: ssh-copy-id -p 8022 -i ~/.ssh/id_ed25519.pub USER@HOST\n",
+                );
+                msg.push_str("    Then provide: key_path = \"~/.ssh/id_ed25519\"\n\n");
+                msg.push_str("  OR a key already loaded in ssh-agent:\n");
+                msg.push_str("    Check: ssh-add -l\n");
+                msg.push_str("    Then provide: use_agent = true\n\n");
+                msg.push_str("  OR password (less secure):\n");
+                msg.push_str("    Set Termux password: Run 'passwd' in Termux\n");
+                msg.push_str("    Then provide: password = \"your_password\"\n\n");
+            }
+
+            if let Some(ref h) = host {
+                msg.push_str(&format!("Current: host = \"{}\"\n", h));
+            }
+            if let Some(ref u) = user {
+                msg.push_str(&format!("Current: user = \"{}\"\n", u));
+            }
+            if let Some(ref k) = key_path {
+                msg.push_str(&format!("Current: key_path = \"{}\"\n", k));
+            }
+            if password.is_some() {
+                msg.push_str("Current: password = \"***\"\n");
+            }
+
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+
+        // All required fields present - create config
+        let mut config = crate::config::Config {
+            host: host.unwrap(),
+            port: port.unwrap_or(8022),
+            user: user.unwrap(),
+            password,
+            key_path,
+            use_agent,
+            ..existing_config.unwrap_or_default()
+        };
+        if !password_helper {
+            config.password_command = None;
+            config.password_file = None;
+        }
+
+        // Notes appended to the success message
+        let mut notes = Vec::new();
+        let auth_methods: Vec<&str> = [
+            (config.use_agent, "ssh-agent"),
+            (config.key_path.is_some(), "SSH key"),
+            (config.has_password(), "password"),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, method)| enabled.then_some(method))
+        .collect();
+        if auth_methods.len() > 1 {
+            notes.push(format!(
+                "Several auth methods are set. They are tried in order: {}; \
+                 each later one is only used if the earlier ones fail.",
+                auth_methods.join(", then ")
+            ));
+        }
+        if let Some(expanded) = config.expanded_key_path()
+            && !expanded.is_file()
+        {
+            notes.push(format!(
+                "⚠ SSH key file not found: {}\n  \
+                 The server will fail to start until the key exists or key_path is fixed.",
+                expanded.display()
+            ));
+        }
+        if let Some(expanded) = config.expanded_key_path()
+            && expanded.is_file()
+        {
+            match config.check_key_permissions(&expanded) {
+                Ok(Some(problem)) => notes.push(format!("⚠ {}", problem)),
+                Ok(None) => {}
+                Err(e) => notes.push(format!(
+                    "⚠ {}\n  The server won't connect until this is fixed.",
+                    e
+                )),
+            }
+        }
+
+        let tested = if test {
+            test_connection(config.clone())
+                .await
+                .unwrap_or_else(|e| connection_test_failed(&e))
+        } else {
+            String::new()
+        };
+
+        // Save config
+        match crate::config::Config::save(&config) {
+            Ok(path) => {
+                let mut msg = format!(
                     "✓ Configuration saved to: {}\n\n\
                      Connection details:\n\
                      • Host: {}:{}\n\
                      • User: {}\n\
                      • Auth: {}\n\n\
-                     To activate, restart the MCP server:\n\
-                     1. Type /mcp\n\
-                     2. Find mcp-android-ssh in the list\n\
-                     3. Click restart\n\n\
-                     Then try: \"list files in /sdcard\"",
+                     {}",
                     path.display(),
                     config.host,
                     config.port,
                     config.user,
-                    if config.key_path.is_some() {
-                        "SSH key"
-                    } else {
-                        "Password"
+                    auth_methods.join(", falling back to "),
+                    tested
+                );
+                for note in &notes {
+                    msg.push_str(note);
+                    msg.push_str("\n\n");
+                }
+                msg.push_str(
+                    "To activate, call the reload_config tool (or restart the MCP server: \
+                     type /mcp, find mcp-android-ssh in the list and click restart).\n\n\
+                     Then try: \"list files in /sdcard\"",
+                );
+                Ok(CallToolResult::success(vec![Content::text(msg)]))
+            }
+            Err(e) => Ok(ssh_error_result("Failed to save config", &e)),
+        }
+    }
+
+    #[tool(
+        description = "Generate an ed25519 key pair on this machine, add the public key to the device's ~/.ssh/authorized_keys over the current session, and switch config.toml to the key (can write/modify)"
+    )]
+    async fn provision_key(
+        &self,
+        Parameters(request): Parameters<ProvisionKeyRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+        let Ok(mut saved) = crate::config::Config::load_existing() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "provision_key updates config.toml, which doesn't exist yet.\n\
+                 Run setup with host, user and password first."
+                    .to_string(),
+            )]));
+        };
+
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        let key_path_setting = request
+            .key_path
+            .unwrap_or_else(|| DEFAULT_PROVISIONED_KEY_PATH.to_string());
+        let key_path = std::path::PathBuf::from(shellexpand::tilde(&key_path_setting).to_string());
+        let public_key = match keygen::generate_ed25519(&key_path) {
+            Ok(public_key) => public_key,
+            Err(e) => return Ok(ssh_error_result("Failed to create key", &e)),
+        };
+        let remove_key = || {
+            let _ = std::fs::remove_file(&key_path);
+            let _ = std::fs::remove_file(keygen::public_key_path(&key_path));
+        };
+
+        // The key is sent over stdin, so it never appears in a command line
+        let line = format!("{}\n", public_key);
+        let options = ExecOptions {
+            stdin: Some(line.as_bytes()),
+            ..Default::default()
+        };
+        match client
+            .execute_command_with(
+                keygen::authorized_keys_append_command(),
+                options,
+                request.timeout,
+            )
+            .await
+        {
+            Ok(result) if result.success() => {}
+            Ok(result) => {
+                remove_key();
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Failed to add the key to ~/.ssh/authorized_keys on the device, so it was \
+                     removed again:\n\n{}",
+                    format_command_result(&result)
+                ))]));
+            }
+            Err(e) => {
+                remove_key();
+                return Ok(ssh_error_result("Failed to install the key", &e));
+            }
+        }
+
+        // Log in with the new key alone, so success shows the password is no longer needed
+        let mut key_only = client.config().clone();
+        drop(client_guard);
+        key_only.key_path = Some(key_path_setting.clone());
+        key_only.key_passphrase = None;
+        key_only.use_agent = false;
+        key_only.password = None;
+        key_only.password_command = None;
+        key_only.password_file = None;
+        let tested = test_connection(key_only).await;
+
+        saved.key_path = Some(key_path_setting);
+        saved.key_passphrase = None;
+        let mut notes = Vec::new();
+        match &tested {
+            Ok(_) if request.clear_password && saved.has_password() => {
+                saved.password = None;
+                saved.password_command = None;
+                saved.password_file = None;
+                notes.push("The stored password was removed from the config.".to_string());
+            }
+            Ok(_) => {}
+            Err(e) => notes.push(format!(
+                "⚠ Logging in with the new key failed ({}), so any stored password was kept:\n{}\n\n\
+                 Check that sshd allows public key authentication and that ~/.ssh on the \
+                 device isn't writable by other users.",
+                e.code(),
+                e
+            )),
+        }
+
+        match crate::config::Config::save(&saved) {
+            Ok(path) => {
+                let mut msg = format!(
+                    "✓ Generated {} and added its public key to ~/.ssh/authorized_keys on the device\n\n\
+                     Public key: {}\n\
+                     Config updated: {}\n\n",
+                    key_path.display(),
+                    public_key,
+                    path.display()
+                );
+                if let Ok(passed) = &tested {
+                    msg.push_str(passed);
+                }
+                for note in &notes {
+                    msg.push_str(note);
+                    msg.push_str("\n\n");
+                }
+                msg.push_str("Call reload_config to connect with the key.");
+                Ok(CallToolResult::success(vec![Content::text(msg)]))
+            }
+            Err(e) => Ok(ssh_error_result(
+                "The key was installed on the device, but saving the config failed",
+                &e,
+            )),
+        }
+    }
+
+    #[tool(
+        description = "Report whether the SSH session is open, which device and host key it uses, and its round-trip latency; optionally force a reconnect"
+    )]
+    async fn connection_status(
+        &self,
+        Parameters(request): Parameters<ConnectionStatusRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        if request.reconnect
+            && let Err(e) = client.reconnect().await
+        {
+            return Ok(ssh_error_result("Reconnect failed", &e));
+        }
+
+        let status = client.status().await;
+        Ok(CallToolResult::structured(
+            serde_json::to_value(status).unwrap_or_default(),
+        ))
+    }
+
+    #[tool(
+        description = "Show the device's login banner: the SSH pre-auth banner and the motd a login shell prints, which commands never see"
+    )]
+    async fn capture_banner(
+        &self,
+        Parameters(request): Parameters<CaptureBannerRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+
+        match client
+            .capture_banner(std::time::Duration::from_secs(request.timeout))
+            .await
+        {
+            Ok(banner) => Ok(CallToolResult::structured(
+                serde_json::to_value(banner).unwrap_or_default(),
+            )),
+            Err(e) => Ok(ssh_error_result("Failed to capture the banner", &e)),
+        }
+    }
+
+    #[tool(
+        description = "Measure the link to the device: round-trip latency, and SFTP upload and download throughput in MB/s using a temporary file that is removed afterwards"
+    )]
+    async fn benchmark(
+        &self,
+        Parameters(request): Parameters<BenchmarkRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Check if client exists (config was loaded)
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        // Validate timeout
+        if request.timeout == 0 || request.timeout > 300 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Timeout must be between 1 and 300 seconds".to_string(),
+            )]));
+        }
+        if request.size_mb == 0 || request.size_mb > MAX_BENCHMARK_SIZE_MB {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "size_mb must be between 1 and {}",
+                MAX_BENCHMARK_SIZE_MB
+            ))]));
+        }
+
+        let size_bytes = (request.size_mb * 1_000_000) as usize;
+        match client.benchmark(size_bytes, request.timeout).await {
+            Ok(benchmark) => Ok(CallToolResult::structured(
+                serde_json::to_value(benchmark).unwrap_or_default(),
+            )),
+            Err(e) => Ok(ssh_error_result("Benchmark failed", &e)),
+        }
+    }
+
+    #[tool(
+        description = "Report command and connection counters since the server started: commands, failures, timeouts, reconnects, bytes sent and received, and uptime"
+    )]
+    async fn stats(&self) -> Result<CallToolResult, McpError> {
+        Ok(CallToolResult::structured(
+            serde_json::to_value(self.stats.snapshot()).unwrap_or_default(),
+        ))
+    }
+
+    #[tool(
+        description = "Re-read config.toml after setup or a manual edit and reconnect with the new connection settings, without restarting the server"
+    )]
+    async fn reload_config(&self) -> Result<CallToolResult, McpError> {
+        let new_config = match crate::config::Config::load() {
+            Ok(Some(cfg)) => cfg,
+            Ok(None) => {
+                return Ok(CallToolResult::error(vec![Content::text(
+                    crate::config::Config::first_run_message(),
+                )]));
+            }
+            Err(e) => {
+                return Ok(ssh_error_result(
+                    "Config not reloaded, the current settings stay in effect",
+                    &e,
+                ));
+            }
+        };
+        // Validated by load, including every profile
+        let default_config = new_config
+            .for_profile(None)
+            .unwrap_or_else(|_| new_config.clone());
+        let target = format!(
+            "{}@{}:{}",
+            default_config.user, default_config.host, default_config.port
+        );
+
+        let mut config = self.config.lock().await;
+        let mut client_guard = self.ssh_client.lock().await;
+        let same_device = client_guard.as_ref().is_some_and(|c| {
+            c.config().host == default_config.host && c.config().port == default_config.port
+        });
+        if let Some(client) = client_guard.as_mut() {
+            client.disconnect().await;
+        }
+        *client_guard = Some(SshClient::with_stats(default_config, self.stats.clone()));
+        drop(client_guard);
+
+        let profile_clients: Vec<SharedClient> = self
+            .profile_clients
+            .lock()
+            .await
+            .drain()
+            .map(|(_, c)| c)
+            .collect();
+        for handle in profile_clients {
+            if let Some(client) = handle.lock().await.as_mut() {
+                client.disconnect().await;
+            }
+        }
+        // The working directory was on the old device
+        if !same_device {
+            *self.cwd.lock().await = None;
+        }
+
+        let mut msg = format!(
+            "✓ Reloaded {}\n\nConnection: {}\n",
+            crate::config::Config::config_file_path()
+                .map(|p| p.display().to_string())
+                .unwrap_or_default(),
+            target
+        );
+        if !new_config.profiles.is_empty() {
+            let mut names: Vec<&String> = new_config.profiles.keys().collect();
+            names.sort();
+            msg.push_str(&format!(
+                "Profiles: {}\n",
+                names
+                    .iter()
+                    .map(|n| n.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
+        msg.push_str("\nThe next command connects with the new settings.");
+        // These are applied when the service is built, so a reload can't switch them
+        let policy = |cfg: Option<&crate::config::Config>| {
+            let cfg = cfg.cloned().unwrap_or_default();
+            [
+                ("[whitelist]", serde_json::to_value(&cfg.whitelist)),
+                ("[blocklist]", serde_json::to_value(&cfg.blocklist)),
+                ("[instructions]", serde_json::to_value(&cfg.instructions)),
+                (
+                    "allow_pipes_in_read",
+                    serde_json::to_value(cfg.allow_pipes_in_read),
+                ),
+                ("disable_execute", serde_json::to_value(cfg.disable_execute)),
+                ("probe_commands", serde_json::to_value(&cfg.probe_commands)),
+                (
+                    "max_commands_per_minute",
+                    serde_json::to_value(cfg.max_commands_per_minute),
+                ),
+                ("persist_history", serde_json::to_value(cfg.persist_history)),
+                ("history_path", serde_json::to_value(&cfg.history_path)),
+                (
+                    "history_skip_blocked",
+                    serde_json::to_value(cfg.history_skip_blocked),
+                ),
+            ]
+            .map(|(name, value)| (name, value.ok()))
+        };
+        let changed: Vec<&str> = policy(config.as_ref())
+            .into_iter()
+            .zip(policy(Some(&new_config)))
+            .filter(|(old, new)| old.1 != new.1)
+            .map(|(old, _)| old.0)
+            .collect();
+        if !changed.is_empty() {
+            msg.push_str(&format!(
+                "\n\n⚠ Changes to {} are not applied until the server is restarted \
+                 (type /mcp, find mcp-android-ssh and click restart).",
+                changed.join(", ")
+            ));
+        }
+        *config = Some(new_config);
+        Ok(CallToolResult::success(vec![Content::text(msg)]))
+    }
+
+    #[tool(
+        description = "Close the SSH session to the device, e.g. to force re-authentication after changing keys or free the sshd connection; the next command reconnects"
+    )]
+    async fn disconnect(
+        &self,
+        Parameters(request): Parameters<DisconnectRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if self.config.lock().await.is_none() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        }
+
+        if request.all {
+            self.disconnect_all().await;
+            return Ok(CallToolResult::success(vec![Content::text(
+                "✓ Disconnected from all devices. The next command will reconnect.".to_string(),
+            )]));
+        }
+
+        let client_handle = match self.client_for_profile(request.profile.as_deref()).await {
+            Ok(handle) => handle,
+            Err(error_result) => return Ok(error_result),
+        };
+        let mut client_guard = client_handle.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        let target = format!(
+            "{}@{}:{}",
+            client.config().user,
+            client.config().host,
+            client.config().port
+        );
+        let text = if client.disconnect().await {
+            format!(
+                "✓ Disconnected from {}. The next command will reconnect.",
+                target
+            )
+        } else {
+            format!("Not connected to {}; nothing to do.", target)
+        };
+        Ok(CallToolResult::success(vec![Content::text(text)]))
+    }
+
+    #[tool(
+        description = "Reboot, shut down or reboot the device into recovery (needs root and confirm=true; the SSH session drops)"
+    )]
+    async fn power(
+        &self,
+        Parameters(request): Parameters<PowerRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let action = request.action;
+        if !request.confirm {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "{} drops the SSH session, and the device is unreachable until it is back up. \
+                 Call power again with confirm=true to go ahead.",
+                action.describe()
+            ))]));
+        }
+        let command = action.command();
+        if let Err(msg) = self.blocklist.check(command) {
+            return Ok(CallToolResult::error(vec![Content::text(msg)]));
+        }
+
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        let root = match self
+            .execute_recorded(client, "su -c 'id -u' 2>&1", ROOT_CHECK_TIMEOUT_SECS)
+            .await
+        {
+            Ok(result) => result,
+            Err(e) => return Ok(ssh_error_result("Root check failed", &e)),
+        };
+        if !root.success() || root.stdout.trim() != "0" {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "{} needs root, and su didn't grant it:\n\n{}\n\n\
+                 On a rooted device, allow Termux in the root manager (e.g. Magisk) and try again. \
+                 Without root, use the device's power button.",
+                action.describe(),
+                format_command_result(&root)
+            ))]));
+        }
+
+        // The device going down usually cuts the command off, which is the expected outcome,
+        // so only an exit code the device actually reported counts as failure
+        match self
+            .execute_recorded(client, command, POWER_COMMAND_TIMEOUT_SECS)
+            .await
+        {
+            Ok(result) if result.exit_code.is_some_and(|code| code != 0) => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "{} failed:\n\n{}",
+                    action.describe(),
+                    format_command_result(&result)
+                ))]));
+            }
+            Ok(_) => {}
+            Err(e) => tracing::info!("Connection ended after power command: {}", e),
+        }
+        client.disconnect().await;
+
+        let next = match action {
+            PowerAction::Shutdown => {
+                "The device is turning off; it can't be reached until it is switched on again."
+            }
+            PowerAction::RebootRecovery => {
+                "Recovery doesn't run Termux, so the device can't be reached until it boots \
+                 normally again."
+            }
+            PowerAction::Reboot => {
+                "The device usually takes a minute or two to come back. Start sshd in Termux \
+                 afterwards unless it starts on boot; the next command reconnects."
+            }
+        };
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "✓ {} requested. The SSH session has been closed.\n\n{}",
+            action.describe(),
+            next
+        ))]))
+    }
+
+    #[tool(
+        description = "Forward a local port on this machine (127.0.0.1) through the SSH session to a host:port reachable from the device, e.g. a web server running in Termux"
+    )]
+    async fn forward_port(
+        &self,
+        Parameters(request): Parameters<ForwardPortRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if request.remote_port == 0 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "remote_port must be between 1 and 65535".to_string(),
+            )]));
+        }
+        if request.remote_host.trim().is_empty() {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "remote_host cannot be empty".to_string(),
+            )]));
+        }
+
+        let mut client_guard = self.ssh_client.lock().await;
+        let Some(client) = client_guard.as_mut() else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                crate::config::Config::first_run_message(),
+            )]));
+        };
+
+        let forward = match client
+            .forward_port(
+                request.local_port,
+                request.remote_host.trim(),
+                request.remote_port,
+            )
+            .await
+        {
+            Ok(forward) => forward,
+            Err(e) => return Ok(ssh_error_result("Failed to forward port", &e)),
+        };
+        drop(client_guard);
+
+        let id = self.next_forward_id.fetch_add(1, Ordering::Relaxed);
+        let msg = format!(
+            "✓ Forward {}: {} → {}:{} (on the device)\n\n\
+             Connections to {} on this machine reach the device. The forward stays open until \
+             close_forward is called or the SSH session ends.",
+            id, forward.local_addr, forward.remote_host, forward.remote_port, forward.local_addr
+        );
+        let structured = serde_json::json!({
+            "id": id,
+            "local_address": forward.local_addr.to_string(),
+            "remote_host": forward.remote_host,
+            "remote_port": forward.remote_port,
+        });
+        self.forwards.lock().await.insert(id, forward);
+
+        let mut result = CallToolResult::success(vec![Content::text(msg)]);
+        result.structured_content = Some(structured);
+        Ok(result)
+    }
+
+    #[tool(
+        description = "List port forwards opened with forward_port and whether each is still active"
+    )]
+    async fn list_forwards(&self) -> Result<CallToolResult, McpError> {
+        let forwards = self.forwards.lock().await;
+        if forwards.is_empty() {
+            return Ok(CallToolResult::success(vec![Content::text(
+                "No port forwards. Open one with forward_port.".to_string(),
+            )]));
+        }
+
+        let mut msg = String::from("Port forwards:\n");
+        for (id, forward) in forwards.iter() {
+            msg.push_str(&format!(
+                "  {}: {} → {}:{} ({})\n",
+                id,
+                forward.local_addr,
+                forward.remote_host,
+                forward.remote_port,
+                if forward.is_active() {
+                    "active"
+                } else {
+                    "closed - the SSH session ended; close it and forward again"
+                }
+            ));
+        }
+        Ok(CallToolResult::success(vec![Content::text(msg)]))
+    }
+
+    #[tool(description = "Close a port forward opened with forward_port, releasing its local port")]
+    async fn close_forward(
+        &self,
+        Parameters(request): Parameters<CloseForwardRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Dropping the forward stops its listener
+        let Some(forward) = self.forwards.lock().await.remove(&request.id) else {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Unknown forward id {}. Use list_forwards to see open forwards.",
+                request.id
+            ))]));
+        };
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "✓ Closed forward {}: {} → {}:{}",
+            request.id, forward.local_addr, forward.remote_host, forward.remote_port
+        ))]))
+    }
+
+    #[tool(
+        description = "Scan a local subnet for devices presenting an SSH banner on the Termux SSH ports, to find the device IP for setup"
+    )]
+    async fn discover(
+        &self,
+        Parameters(request): Parameters<DiscoverRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        // Validate parameters
+        if request.timeout_ms == 0 || request.timeout_ms > 10_000 {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "timeout_ms must be between 1 and 10000".to_string(),
+            )]));
+        }
+        if request.concurrency == 0 || request.concurrency > MAX_DISCOVER_CONCURRENCY {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "concurrency must be between 1 and {}",
+                MAX_DISCOVER_CONCURRENCY
+            ))]));
+        }
+        let ports = request
+            .ports
+            .unwrap_or_else(|| discover::DEFAULT_PORTS.to_vec());
+        if ports.is_empty() || ports.contains(&0) {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "ports must be a non-empty list of ports between 1 and 65535".to_string(),
+            )]));
+        }
+
+        let subnet = match request.subnet.as_deref() {
+            Some(cidr) => discover::Subnet::parse(cidr),
+            None => discover::Subnet::local(),
+        };
+        let subnet = match subnet {
+            Ok(subnet) => subnet,
+            Err(e) => return Ok(CallToolResult::error(vec![Content::text(e.to_string())])),
+        };
+        if subnet.size() > discover::MAX_HOSTS as u64 {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Subnet {} is too large to scan (max {} addresses, i.e. a /20)",
+                subnet,
+                discover::MAX_HOSTS
+            ))]));
+        }
+
+        let hosts = discover::scan(
+            subnet,
+            &ports,
+            std::time::Duration::from_millis(request.timeout_ms),
+            request.concurrency,
+        )
+        .await;
+
+        Ok(CallToolResult::structured(serde_json::json!({
+            "subnet": subnet.to_string(),
+            "ports": ports,
+            "hosts": hosts,
+        })))
+    }
+
+    #[tool(
+        description = "Show the effective configuration (config file merged with environment overrides) and where each value came from"
+    )]
+    async fn effective_config(&self) -> Result<CallToolResult, McpError> {
+        let config_path = crate::config::Config::config_file_path()
+            .map(|p| p.display().to_string())
+            .unwrap_or_else(|_| "~/.config/mcp-android-ssh/config.toml".to_string());
+
+        let (config, sources) = match crate::config::Config::load_with_sources() {
+            Ok(loaded) => loaded,
+            Err(e) => {
+                return Ok(CallToolResult::error(vec![Content::text(format!(
+                    "Failed to load config from {}: {}\n\nUse setup to create or fix it.",
+                    config_path, e
+                ))]));
+            }
+        };
+
+        let mut msg = format!("Effective configuration ({})\n\n", config_path);
+        for (field, value) in config.display_fields() {
+            let source = sources
+                .iter()
+                .find(|(f, _)| *f == field)
+                .map(|(_, s)| s.to_string())
+                .unwrap_or_else(|| "default".to_string());
+            match value {
+                Some(v) => msg.push_str(&format!("{} = {}  [{}]\n", field, v, source)),
+                None => msg.push_str(&format!("{} = (unset)\n", field)),
+            }
+        }
+        if !config.profiles.is_empty() {
+            let mut names: Vec<&str> = config.profiles.keys().map(String::as_str).collect();
+            names.sort_unstable();
+            msg.push_str(&format!("\nProfiles: {}\n", names.join(", ")));
+        }
+        msg.push_str("\nPrecedence: environment variables override the config file, which overrides defaults.");
+
+        Ok(CallToolResult::success(vec![Content::text(msg)]))
+    }
+
+    #[tool(
+        description = "Set the working directory used by later execute and execute_read calls, or reset it to the login default"
+    )]
+    async fn set_working_directory(
+        &self,
+        Parameters(request): Parameters<SetWorkingDirectoryRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        if request.reset {
+            *self.cwd.lock().await = None;
+            return Ok(CallToolResult::success(vec![Content::text(
+                "✓ Working directory reset to the login default".to_string(),
+            )]));
+        }
+
+        let Some(path) = request.path else {
+            return Ok(CallToolResult::error(vec![Content::text(
+                "Provide a path, or reset=true to clear the working directory".to_string(),
+            )]));
+        };
+
+        // Resolve relative paths against the current working directory and store
+        // the absolute result, so the stored directory doesn't depend on later changes
+        let quoted = quote_remote_path(&path);
+        let (command, _) = self
+            .with_cwd(&format!("test -d {} && cd {} && pwd", quoted, quoted))
+            .await;
+        let result = match self.run_command(&command, default_timeout()).await {
+            Ok(result) => result,
+            Err(error_result) => return Ok(error_result),
+        };
+        if !result.success() {
+            return Ok(CallToolResult::error(vec![Content::text(format!(
+                "Not a directory on the device: {}",
+                path
+            ))]));
+        }
+
+        let dir = result.stdout.trim().to_string();
+        *self.cwd.lock().await = Some(dir.clone());
+        Ok(CallToolResult::success(vec![Content::text(format!(
+            "✓ Working directory set to {}",
+            dir
+        ))]))
+    }
+
+    #[tool(
+        description = "List commands run on the device this session with timestamp, exit code, and duration"
+    )]
+    async fn command_history(
+        &self,
+        Parameters(request): Parameters<CommandHistoryRequest>,
+    ) -> Result<CallToolResult, McpError> {
+        let history = self.history.lock().await;
+        let entries = history.recent(request.limit);
+
+        Ok(CallToolResult::structured(serde_json::json!({
+            "total_recorded": history.len(),
+            "commands": entries,
+        })))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+    use crate::test_support::mcp::TestClient;
+    use crate::test_support::sshd::TestSshd;
+    use crate::test_support::{ENV_LOCK, TempDir};
+
+    /// A service for a device that is never connected to, for checks made before any command runs
+    fn offline_service(config: Config) -> AndroidSshService {
+        AndroidSshService::new(Some(Config {
+            host: "127.0.0.1".to_string(),
+            user: "tester".to_string(),
+            ..config
+        }))
+    }
+
+    /// All the text content of a tool result
+    fn text(result: &CallToolResult) -> String {
+        result
+            .content
+            .iter()
+            .filter_map(|content| content.as_text())
+            .map(|content| content.text.as_str())
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    /// Tool parameters from JSON, with every field not given left at its default
+    fn params<T: serde::de::DeserializeOwned>(json: serde_json::Value) -> Parameters<T> {
+        Parameters(serde_json::from_value(json).unwrap())
+    }
+
+    fn check_read(command: &str) -> std::result::Result<&str, String> {
+        check_read_only(command, &read_only_commands(None), false)
+    }
+
+    #[test]
+    fn read_only_skips_benign_leading_assignments() {
+        assert_eq!(check_read("FOO=bar ls -la"), Ok("ls"));
+        assert_eq!(check_read("LANG=C TZ=UTC date"), Ok("date"));
+        assert!(check_read("FOO=bar rm file").is_err());
+    }
+
+    #[test]
+    fn read_only_rejects_dangerous_leading_assignments() {
+        for command in [
+            "LD_PRELOAD=/tmp/x ls",
+            "LD_LIBRARY_PATH=/tmp ls",
+            "PATH=/tmp:$PATH ls",
+            "FOO=bar LD_PRELOAD=/tmp/x cat file",
+        ] {
+            let err = check_read(command).unwrap_err();
+            assert!(
+                err.contains("is not allowed in execute_read"),
+                "{command}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn read_only_keeps_quoted_assignment_values_whole() {
+        let err = check_read("X=\" ls \" rm -rf ~").unwrap_err();
+        assert!(err.contains("Command 'rm' is not whitelisted"), "{err}");
+        let err = check_read("X=' cat ' touch /tmp/pwned").unwrap_err();
+        assert!(err.contains("Command 'touch' is not whitelisted"), "{err}");
+        assert_eq!(check_read("LANG='en US' ls"), Ok("ls"));
+    }
+
+    #[test]
+    fn read_only_allows_env_to_print_the_environment() {
+        assert_eq!(check_read("env"), Ok("env"));
+        assert_eq!(check_read("env -0"), Ok("env"));
+        assert_eq!(check_read("env -u HOME FOO=bar"), Ok("env"));
+    }
+
+    #[test]
+    fn read_only_rejects_env_running_a_command() {
+        for command in [
+            "env sh -c 'rm -rf ~'",
+            "env ls",
+            "env -i FOO=bar rm file",
+            "env -S sh",
+        ] {
+            let err = check_read(command).unwrap_err();
+            assert!(
+                err.contains("env can only print the environment"),
+                "{command}: {err}"
+            );
+        }
+        let err = check_read("env LD_PRELOAD=/tmp/x ls").unwrap_err();
+        assert!(err.contains("'LD_PRELOAD' is not allowed"), "{err}");
+    }
+
+    #[tokio::test]
+    async fn start_job_enforces_max_command_length() {
+        let service = offline_service(Config {
+            max_command_length: Some(16),
+            ..Default::default()
+        });
+        let result = service
+            .start_job(Parameters(StartJobRequest {
+                command: "x".repeat(17),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(text(&result).contains("Command is too long (17 bytes, max 16 bytes)"));
+        assert!(service.jobs.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn start_job_applies_the_blocklist() {
+        let service = offline_service(Config {
+            blocklist: Some(BlocklistConfig {
+                prefixes: vec!["rm -rf /".to_string()],
+                ..Default::default()
+            }),
+            ..Default::default()
+        });
+        let result = service
+            .start_job(Parameters(StartJobRequest {
+                command: "rm -rf /".to_string(),
+            }))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(text(&result).contains("blocklist"));
+        assert!(service.jobs.lock().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn start_job_applies_the_rate_limit() {
+        // Nothing listens on port 1, so the first job fails to connect after using the budget
+        let service = offline_service(Config {
+            port: 1,
+            max_commands_per_minute: Some(1),
+            ..Default::default()
+        });
+        let start = || {
+            service.start_job(Parameters(StartJobRequest {
+                command: "sleep 60".to_string(),
+            }))
+        };
+        start().await.unwrap();
+        let result = start().await.unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(text(&result).contains("Rate limited"));
+    }
+
+    #[tokio::test]
+    async fn stream_and_run_script_share_the_rate_limit() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(Config {
+            max_commands_per_minute: Some(1),
+            ..sshd.config()
+        }));
+        let client = TestClient::start();
+
+        let result = service
+            .run_script(params(serde_json::json!({"script": "echo hi\n"})))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+
+        let result = service
+            .stream(
+                params(serde_json::json!({"command": "echo hi", "duration_secs": 1})),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        assert!(text(&result).contains("Rate limited"), "{}", text(&result));
+        let result = service
+            .run_script(params(serde_json::json!({"script": "echo hi\n"})))
+            .await
+            .unwrap();
+        assert!(text(&result).contains("Rate limited"), "{}", text(&result));
+    }
+
+    #[tokio::test]
+    async fn run_script_enforces_max_command_length_on_arguments() {
+        let service = offline_service(Config {
+            max_command_length: Some(64),
+            ..Default::default()
+        });
+        let result = service
+            .run_script(Parameters(RunScriptRequest {
+                path: None,
+                script: Some("echo \"$1\"\n".to_string()),
+                interpreter: None,
+                args: vec!["x".repeat(64)],
+                timeout: 5,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(text(&result).contains("Command is too long"));
+    }
+
+    #[tokio::test]
+    async fn upload_and_download_round_trip_bytes_exactly() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let local = TempDir::new();
+        let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 251) as u8).collect();
+        std::fs::write(local.join("original.bin"), &contents).unwrap();
+
+        let uploaded = service
+            .upload_file(Parameters(UploadFileRequest {
+                local_path: local.join("original.bin").display().to_string(),
+                remote_path: "copy.bin".to_string(),
+                timeout: 30,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(uploaded.is_error, Some(false), "{}", text(&uploaded));
+        assert_eq!(std::fs::read(sshd.home.join("copy.bin")).unwrap(), contents);
+
+        let downloaded = service
+            .download_file(Parameters(DownloadFileRequest {
+                remote_path: "copy.bin".to_string(),
+                local_path: local.join("downloaded.bin").display().to_string(),
+                inline: false,
+                timeout: 30,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(downloaded.is_error, Some(false), "{}", text(&downloaded));
+        assert!(text(&downloaded).contains("(300000 bytes)"));
+        assert_eq!(
+            std::fs::read(local.join("downloaded.bin")).unwrap(),
+            contents
+        );
+    }
+
+    #[tokio::test]
+    async fn download_returns_small_text_inline_and_refuses_directories() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let local = TempDir::new();
+        std::fs::write(sshd.home.join("notes.txt"), "line one\nline 'two' $HOME\n").unwrap();
+        std::fs::create_dir(sshd.home.join("folder")).unwrap();
+
+        let result = service
+            .download_file(Parameters(DownloadFileRequest {
+                remote_path: "notes.txt".to_string(),
+                local_path: local.join("notes.txt").display().to_string(),
+                inline: true,
+                timeout: 30,
+            }))
+            .await
+            .unwrap();
+        assert!(text(&result).ends_with("\n\nline one\nline 'two' $HOME\n"));
+
+        let result = service
+            .download_file(Parameters(DownloadFileRequest {
+                remote_path: "folder".to_string(),
+                local_path: local.join("folder").display().to_string(),
+                inline: false,
+                timeout: 30,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(text(&result).contains("directory"), "{}", text(&result));
+        assert!(!local.join("folder").exists());
+    }
+
+    #[tokio::test]
+    async fn working_directory_applies_to_later_commands() {
+        let sshd = TestSshd::start().await;
+        std::fs::create_dir_all(sshd.home.join("projects/app")).unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        let result = service
+            .set_working_directory(params(serde_json::json!({"path": "projects"})))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        // Relative paths resolve against the stored directory
+        service
+            .set_working_directory(params(serde_json::json!({"path": "app"})))
+            .await
+            .unwrap();
+
+        let dir = sshd
+            .home
+            .path()
+            .canonicalize()
+            .unwrap()
+            .join("projects/app");
+        let result = service
+            .execute_read(params(serde_json::json!({"command": "pwd"})))
+            .await
+            .unwrap();
+        let output = text(&result);
+        assert!(output.contains(&format!("{}\n", dir.display())), "{output}");
+        assert!(output.contains(&format!("Working directory: {}", dir.display())));
+
+        service
+            .set_working_directory(params(serde_json::json!({"reset": true})))
+            .await
+            .unwrap();
+        let result = service
+            .execute_read(params(serde_json::json!({"command": "pwd"})))
+            .await
+            .unwrap();
+        assert!(!text(&result).contains("Working directory"));
+    }
+
+    #[tokio::test]
+    async fn working_directory_must_exist() {
+        let sshd = TestSshd::start().await;
+        std::fs::write(sshd.home.join("file"), "").unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        for path in ["missing", "file"] {
+            let result = service
+                .set_working_directory(params(serde_json::json!({"path": path})))
+                .await
+                .unwrap();
+            assert_eq!(result.is_error, Some(true));
+            assert!(text(&result).contains("Not a directory on the device"));
+        }
+        assert!(service.cwd.lock().await.is_none());
+    }
+
+    #[test]
+    fn whitelist_config_adds_and_removes_commands() {
+        let config: Config = toml::from_str(
+            r#"
+            host = "127.0.0.1"
+            user = "tester"
+
+            [whitelist]
+            additional = ["foo"]
+            removed = ["cat"]
+            "#,
+        )
+        .unwrap();
+        let service = AndroidSshService::new(Some(config));
+        let check = |command| check_read_only(command, &service.read_only_commands, false);
+
+        assert_eq!(check("foo --bar"), Ok("foo"));
+        assert!(
+            check("cat file")
+                .unwrap_err()
+                .contains("'cat' is not whitelisted")
+        );
+        assert_eq!(check("head file"), Ok("head"));
+        assert_eq!(
+            service.read_only_commands.len(),
+            read_only_commands(None).len()
+        );
+    }
+
+    fn operator_error(command: &str) -> String {
+        check_read(command).unwrap_err()
+    }
+
+    #[test]
+    fn read_only_rejects_chained_commands() {
+        for (command, op) in [
+            ("cat x; rm -rf ~", "';'"),
+            ("ls && curl evil", "'&&'"),
+            ("ls || rm file", "'||'"),
+            ("sleep 1 & rm file", "'&'"),
+            ("ls\nrm file", "'newline'"),
+        ] {
+            let err = operator_error(command);
+            assert!(
+                err.contains(&format!("Shell operator {op}")),
+                "{command}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn read_only_rejects_redirects() {
+        for (command, op) in [
+            ("echo x > file", "'>'"),
+            ("echo x >> file", "'>>'"),
+            ("cat < /etc/passwd", "'<'"),
+        ] {
+            let err = operator_error(command);
+            assert!(
+                err.contains(&format!("Shell operator {op}")),
+                "{command}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn read_only_rejects_command_substitution() {
+        for (command, op) in [
+            ("echo $(rm -rf ~)", "'$('"),
+            ("echo `rm -rf ~`", "'`'"),
+            ("echo \"$(rm -rf ~)\"", "'$('"),
+        ] {
+            let err = operator_error(command);
+            assert!(
+                err.contains(&format!("Shell operator {op}")),
+                "{command}: {err}"
+            );
+        }
+    }
+
+    #[test]
+    fn read_only_ignores_quoted_operators() {
+        assert_eq!(check_read("echo 'a; b && c > d | e'"), Ok("echo"));
+        assert_eq!(check_read("grep \"x|y\" file"), Ok("grep"));
+        assert_eq!(check_read("echo '$(not run)'"), Ok("echo"));
+        assert_eq!(check_read("echo a\\;b"), Ok("echo"));
+    }
+
+    #[test]
+    fn read_only_pipes_need_allow_pipes_in_read() {
+        let allowed = read_only_commands(None);
+        let err = check_read_only("grep x file | wc -l", &allowed, false).unwrap_err();
+        assert!(err.contains("allow_pipes_in_read"), "{err}");
+
+        assert_eq!(
+            check_read_only("grep x file | wc -l", &allowed, true),
+            Ok("grep")
+        );
+        let err = check_read_only("ls | sh", &allowed, true).unwrap_err();
+        assert!(err.contains("'sh' is not whitelisted"), "{err}");
+        assert!(check_read_only("ls | wc -l; rm x", &allowed, true).is_err());
+    }
+
+    #[test]
+    fn read_only_rejects_find_actions() {
+        for command in [
+            r"find . -exec rm {} \;",
+            "find / -execdir sh -c id {} +",
+            "find . -ok rm {} +",
+            "find . -name '*.tmp' -delete",
+            "find . '-exec' rm {} +",
+            "find . -fprint /sdcard/list",
+            "find . $'\\x2dexec' rm {} +",
+        ] {
+            let err = check_read(command).unwrap_err();
+            assert!(
+                err.contains("runs commands or changes files"),
+                "{command}: {err}"
+            );
+        }
+        assert_eq!(check_read("find . -name '*.log' -type f"), Ok("find"));
+    }
+
+    #[test]
+    fn read_only_rejects_options_that_run_or_write() {
+        for command in [
+            "fd -x rm",
+            "fd . -X rm",
+            "fd -Hx rm",
+            "fd --exec rm",
+            "fd --exec-batch=rm",
+            "rg --pre sh x",
+            "rg --pre=sh x",
+            "rg --pre-glob '*' --pre sh x",
+            "sort -o /tmp/x /etc/passwd",
+            "sort -o/tmp/x /etc/passwd",
+            "sort -uo /tmp/x /etc/passwd",
+            "sort --output=/tmp/x /etc/passwd",
+            "sort --out /tmp/x /etc/passwd",
+            "sort --compress-program=sh /etc/passwd",
+        ] {
+            let err = check_read(command).unwrap_err();
+            assert!(
+                err.contains("runs commands or changes files"),
+                "{command}: {err}"
+            );
+        }
+        assert_eq!(check_read("fd -e rs src"), Ok("fd"));
+        assert_eq!(check_read("rg --pretty TODO"), Ok("rg"));
+        assert_eq!(check_read("sort -u -k2 /etc/passwd"), Ok("sort"));
+    }
+
+    #[test]
+    fn read_only_rejects_env_exec_form() {
+        assert!(check_read("env sh -c 'rm -rf ~'").is_err());
+        assert!(check_read("env FOO=1 find . -delete").is_err());
+        assert_eq!(check_read("env"), Ok("env"));
+    }
+
+    #[tokio::test]
+    async fn structured_results_carry_the_command_fields() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        let result = service
+            .execute_read(params(serde_json::json!({
+                "command": "echo hello",
+                "structured": true,
+            })))
+            .await
+            .unwrap();
+        let json = result.structured_content.unwrap();
+        assert_eq!(json["stdout"], "hello\n");
+        assert_eq!(json["stderr"], "");
+        assert_eq!(json["exit_code"], 0);
+        assert_eq!(json["success"], true);
+        assert!(json["duration_ms"].is_u64());
+
+        let result = service
+            .execute_read(params(serde_json::json!({
+                "command": "ls missing-file",
+                "structured": true,
+            })))
+            .await
+            .unwrap();
+        let json = result.structured_content.unwrap();
+        assert_eq!(json["stdout"], "");
+        assert!(json["stderr"].as_str().unwrap().contains("missing-file"));
+        assert_ne!(json["exit_code"], 0);
+        assert!(json["exit_code"].is_i64());
+        assert_eq!(json["success"], false);
+        assert!(json["duration_ms"].is_u64());
+
+        // Without the flag only the text is returned
+        let result = service
+            .execute_read(params(serde_json::json!({"command": "echo hello"})))
+            .await
+            .unwrap();
+        assert!(result.structured_content.is_none());
+        assert!(text(&result).contains("hello"));
+    }
+
+    #[test]
+    fn output_pages_split_on_char_boundaries_and_cover_everything() {
+        let text = "é".repeat(50_000) + "end";
+        let mut pages = Vec::new();
+        let mut offset = 0;
+        loop {
+            let page = OutputPage::new(&text, offset, 4001);
+            assert!(text.is_char_boundary(page.start) && text.is_char_boundary(page.end));
+            pages.push(&text[page.start..page.end]);
+            if page.end == page.total_bytes {
+                assert!(page.marker().unwrap().starts_with("[showing bytes"));
+                break;
+            }
+            let marker = page.marker().unwrap();
+            assert!(
+                marker.contains(&format!("re-run with offset={}", page.end)),
+                "{marker}"
+            );
+            offset = page.end;
+        }
+        assert_eq!(pages.concat(), text);
+        assert!(OutputPage::new("short", 0, 4001).marker().is_none());
+    }
+
+    #[tokio::test]
+    async fn large_output_is_truncated_and_paged_by_offset() {
+        let sshd = TestSshd::start().await;
+        let contents: String = (0..20_000).map(|n| format!("line {n}\n")).collect();
+        std::fs::write(sshd.home.join("big.log"), &contents).unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        let first = service
+            .execute_read(params(serde_json::json!({
+                "command": "cat big.log",
+                "max_output_bytes": 100_000,
+                "structured": true,
+            })))
+            .await
+            .unwrap();
+        let output = text(&first);
+        let omitted = contents.len() - 100_000;
+        assert!(
+            output.contains(&format!(
+                "{omitted} bytes omitted; re-run with offset=100000"
+            )),
+            "{}",
+            &output[output.len() - 300..]
+        );
+        let json = first.structured_content.unwrap();
+        assert_eq!(json["next_offset"], 100_000);
+        assert_eq!(json["stdout_total_bytes"], contents.len());
+
+        let rest = service
+            .execute_read(params(serde_json::json!({
+                "command": "cat big.log",
+                "offset": 100_000,
+                "max_output_bytes": contents.len(),
+                "structured": true,
+            })))
+            .await
+            .unwrap();
+        let json = rest.structured_content.unwrap();
+        assert!(json["next_offset"].is_null());
+        assert!(
+            json["stdout"]
+                .as_str()
+                .unwrap()
+                .starts_with(&contents[100_000..])
+        );
+    }
+
+    #[tokio::test]
+    async fn profile_parameter_picks_the_device() {
+        let phone = TestSshd::start().await;
+        let tablet = TestSshd::start().await;
+        let mut config = phone.config();
+        config.profiles.insert(
+            "tablet".to_string(),
+            toml::from_str(&format!("port = {}", tablet.port)).unwrap(),
+        );
+        let service = AndroidSshService::new(Some(config));
+
+        let home = |json: serde_json::Value| {
+            let service = &service;
+            async move {
+                let result = service.execute_read(params(json)).await.unwrap();
+                result.structured_content.unwrap()["stdout"]
+                    .as_str()
+                    .unwrap()
+                    .trim()
+                    .to_string()
+            }
+        };
+        let phone_home = phone.home.path().display().to_string();
+        let tablet_home = tablet.home.path().display().to_string();
+        assert_eq!(
+            home(serde_json::json!({"command": "echo $HOME", "structured": true})).await,
+            phone_home
+        );
+        assert_eq!(
+            home(serde_json::json!({
+                "command": "echo $HOME",
+                "structured": true,
+                "profile": "tablet",
+            }))
+            .await,
+            tablet_home
+        );
+
+        let result = service
+            .execute_read(params(serde_json::json!({
+                "command": "echo $HOME",
+                "profile": "laptop",
+            })))
+            .await
+            .unwrap();
+        assert!(
+            text(&result).contains("Unknown profile 'laptop' (available profiles: tablet)"),
+            "{}",
+            text(&result)
+        );
+    }
+
+    /// Prints a numbered line every second until stopped
+    const TICKER: &str = "i=0; while true; do i=$((i+1)); echo tick $i; sleep 1; done";
+
+    #[tokio::test]
+    async fn stream_stops_when_the_duration_elapses() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let mut client = TestClient::start();
+
+        let started = Instant::now();
+        let result = service
+            .stream(
+                params(serde_json::json!({
+                    "command": TICKER,
+                    "duration_secs": 3,
+                    "flush_interval_ms": 100,
+                })),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        let elapsed = started.elapsed();
+        let output = text(&result);
+        assert!(
+            output.contains("Stopped: duration of 3s elapsed, sent Ctrl-C"),
+            "{output}"
+        );
+        assert!(output.contains("tick 1\n") && output.contains("tick 3\n"));
+        assert!(!output.contains("tick 5"), "{output}");
+        assert!((3..5).contains(&elapsed.as_secs()), "{elapsed:?}");
+
+        let progress = client.progress_messages().await;
+        assert!(progress[0].starts_with("[stream 1 started"), "{progress:?}");
+        // Output arrives over several notifications, not all at the end
+        let with_ticks = progress.iter().filter(|m| m.contains("tick")).count();
+        assert!(with_ticks >= 3, "{progress:?}");
+    }
+
+    #[tokio::test]
+    async fn stream_stops_at_the_stop_pattern() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let client = TestClient::start();
+
+        let result = service
+            .stream(
+                params(serde_json::json!({
+                    "command": TICKER,
+                    "duration_secs": 30,
+                    "stop_pattern": "tick [2]",
+                })),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        let output = text(&result);
+        assert!(
+            output.contains("Stopped: stop_pattern matched 'tick 2', sent Ctrl-C"),
+            "{output}"
+        );
+        assert!(!output.contains("tick 3"));
+    }
+
+    fn blocklist(prefixes: &[&str], patterns: &[&str], case_insensitive: bool) -> Blocklist {
+        Blocklist::new(Some(&BlocklistConfig {
+            prefixes: prefixes.iter().map(|p| p.to_string()).collect(),
+            patterns: patterns.iter().map(|p| p.to_string()).collect(),
+            case_insensitive,
+        }))
+    }
+
+    #[test]
+    fn blocklist_refuses_rm_rf_root_wherever_it_starts_a_command() {
+        let blocklist = blocklist(&["rm -rf /"], &[], false);
+        for command in [
+            "rm -rf /",
+            "ls; rm -rf /",
+            "true && rm -rf /",
+            "echo $(rm -rf /)",
+            "echo `rm -rf /`",
+            "(rm -rf /)",
+            "{ rm -rf /; }",
+            "! rm -rf /",
+            "sudo rm -rf /",
+            "sudo -E env FOO=1 nohup rm -rf /",
+            "command rm -rf /",
+            "LANG=C rm -rf /",
+            "rm  -rf /",
+            "rm\t-rf /",
+            "/bin/rm -rf /",
+            "X=\" a\" rm -rf /",
+        ] {
+            let err = blocklist.check(command).unwrap_err();
+            assert_eq!(
+                err, "Command blocked by [blocklist] prefix 'rm -rf /'",
+                "{command}"
+            );
+        }
+    }
+
+    #[test]
+    fn blocklist_allows_commands_no_rule_matches() {
+        let blocklist = blocklist(&["rm -rf /"], &[], false);
+        for command in ["rm tmpfile", "rm -rf ./build", "echo rm -rf /", "ls -la /"] {
+            assert_eq!(blocklist.check(command), Ok(()), "{command}");
+        }
+        assert_eq!(Blocklist::new(None).check("rm -rf /"), Ok(()));
+    }
+
+    #[test]
+    fn blocklist_regex_rules_match_anywhere_and_fold_case() {
+        let blocklist = blocklist(&["REBOOT"], &[r"\bdd\s+.*of=/dev/"], true);
+        let err = blocklist
+            .check("cat image | dd bs=4M of=/dev/block/sda")
+            .unwrap_err();
+        assert_eq!(
+            err,
+            r"Command blocked by [blocklist] pattern '\bdd\s+.*of=/dev/'"
+        );
+        assert!(blocklist.check("reboot now").is_err());
+        assert_eq!(blocklist.check("dd if=/dev/zero of=out.img"), Ok(()));
+    }
+
+    #[test]
+    fn logcat_arguments_follow_the_filters() {
+        assert_eq!(
+            logcat_command(200, None, None).unwrap(),
+            "logcat -d -v threadtime -t 200"
+        );
+        assert_eq!(
+            logcat_command(50, Some(LogcatPriority::W), None).unwrap(),
+            "logcat -d -v threadtime -t 50 '*:W'"
+        );
+        assert_eq!(
+            logcat_command(50, None, Some("ActivityManager")).unwrap(),
+            "logcat -d -v threadtime -t 50 'ActivityManager:V' '*:S'"
+        );
+        assert_eq!(
+            logcat_command(10, Some(LogcatPriority::E), Some("my.app")).unwrap(),
+            "logcat -d -v threadtime -t 10 'my.app:E' '*:S'"
+        );
+        // Quoted, so a tag can't add shell syntax
+        assert_eq!(
+            logcat_command(10, None, Some("it's")).unwrap(),
+            "logcat -d -v threadtime -t 10 'it'\\''s:V' '*:S'"
+        );
+        for tag in ["", "a:b", "two words"] {
+            assert!(logcat_command(10, None, Some(tag)).is_err(), "{tag:?}");
+        }
+    }
+
+    #[test]
+    fn journalctl_follow_flags_are_detected() {
+        for command in [
+            "journalctl -f",
+            "journalctl --follow",
+            "journalctl -kf",
+            "journalctl -n 50 -f",
+            "journalctl -b -f",
+        ] {
+            assert!(journalctl_follows(command), "{command}");
+            assert!(
+                check_read(command).unwrap_err().contains("journal tool"),
+                "{command}"
+            );
+        }
+        for command in [
+            "journalctl",
+            "journalctl -n 50",
+            "journalctl -uf",
+            "journalctl -u foo.service",
+            "journalctl --since today -- -f",
+            "journalctl --flush",
+        ] {
+            assert!(!journalctl_follows(command), "{command}");
+        }
+        assert_eq!(check_read("journalctl -n 50"), Ok("journalctl"));
+    }
+
+    #[test]
+    fn journal_arguments_become_journalctl_flags() {
+        assert_eq!(
+            journal_command(200, None, None).unwrap(),
+            "journalctl --no-pager -n 200"
+        );
+        assert_eq!(
+            journal_command(20, Some(" 1 hour ago "), Some("sshd.service")).unwrap(),
+            "journalctl --no-pager -n 20 --since '1 hour ago' -u 'sshd.service'"
+        );
+        assert_eq!(
+            journal_command(20, Some("it's"), None).unwrap(),
+            "journalctl --no-pager -n 20 --since 'it'\\''s'"
+        );
+        assert!(journal_command(20, Some("  "), None).is_err());
+        for unit in ["", "two words"] {
+            assert!(journal_command(20, None, Some(unit)).is_err(), "{unit:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn disconnect_all_closes_every_session() {
+        let phone = TestSshd::start().await;
+        let tablet = TestSshd::start().await;
+        let mut config = phone.config();
+        config.profiles.insert(
+            "tablet".to_string(),
+            toml::from_str(&format!("port = {}", tablet.port)).unwrap(),
+        );
+        let service = AndroidSshService::new(Some(config));
+        for profile in [None, Some("tablet")] {
+            service
+                .execute_read(params(
+                    serde_json::json!({"command": "true", "profile": profile}),
+                ))
+                .await
+                .unwrap();
+        }
+        let tablet_client = service.profile_clients.lock().await["tablet"].clone();
+        for client in [&service.ssh_client, &tablet_client] {
+            assert!(
+                client
+                    .lock()
+                    .await
+                    .as_ref()
+                    .unwrap()
+                    .status()
+                    .await
+                    .connected
+            );
+        }
+
+        service.disconnect_all().await;
+        for client in [&service.ssh_client, &tablet_client] {
+            let mut guard = client.lock().await;
+            let client = guard.as_mut().unwrap();
+            assert!(!client.status().await.connected);
+            // Already taken, so there is nothing left to close
+            assert!(!client.disconnect().await);
+        }
+    }
+
+    #[test]
+    fn every_base_command_is_listed_in_exactly_one_category() {
+        let allowed = read_only_commands(None);
+        // Every built-in command, across all categories
+        assert_eq!(allowed.len(), 101);
+
+        let categories = categorize_read_only_commands(&allowed);
+        assert_eq!(categories.len(), READ_ONLY_COMMANDS.len());
+        let listed: Vec<&str> = categories
+            .iter()
+            .flat_map(|category| category.commands.iter().map(String::as_str))
+            .collect();
+        assert_eq!(listed.len(), allowed.len());
+        let unique: HashSet<&str> = listed.iter().copied().collect();
+        assert_eq!(unique.len(), listed.len(), "a command is in two categories");
+        assert!(allowed.iter().all(|name| unique.contains(name.as_str())));
+
+        let by_name = |name: &str| {
+            categories
+                .iter()
+                .find(|category| category.commands.iter().any(|c| c == name))
+                .map(|category| category.category.as_str())
+        };
+        assert_eq!(by_name("ls"), Some("File viewing"));
+        assert_eq!(by_name("sha256sum"), Some("Checksums"));
+        assert_eq!(by_name("false"), Some("Test/null commands"));
+    }
+
+    #[test]
+    fn whitelist_changes_show_in_the_categories() {
+        let allowed = read_only_commands(Some(&WhitelistConfig {
+            additional: vec!["termux-battery-status".to_string()],
+            removed: vec!["jq".to_string(), "yq".to_string(), "xmllint".to_string()],
+        }));
+        let categories = categorize_read_only_commands(&allowed);
+        assert!(!categories.iter().any(|c| c.category == "Data parsers"));
+        let last = categories.last().unwrap();
+        assert_eq!(last.category, "Added by [whitelist] config");
+        assert_eq!(last.commands, ["termux-battery-status"]);
+    }
+
+    #[tokio::test]
+    async fn batch_stop_on_error_skips_the_rest() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let commands = ["echo one", "exit 4", "echo three > three.txt", "echo four"];
+
+        let result = service
+            .batch(params(serde_json::json!({
+                "commands": commands,
+                "stop_on_error": true,
+            })))
+            .await
+            .unwrap();
+        let json = result.structured_content.unwrap();
+        let results = json["results"].as_array().unwrap();
+        let statuses: Vec<&str> = results
+            .iter()
+            .map(|r| r["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(statuses, ["ok", "failed", "skipped", "skipped"]);
+        assert_eq!(results[0]["stdout"], "one\n");
+        assert_eq!(results[1]["exit_code"], 4);
+        for skipped in &results[2..] {
+            assert_eq!(
+                skipped["reason"],
+                "an earlier command failed (stop_on_error)"
+            );
+            assert!(skipped["exit_code"].is_null() && skipped["stdout"].is_null());
+        }
+        assert_eq!(
+            json["summary"],
+            serde_json::json!({"succeeded": 1, "failed": 1, "skipped": 2})
+        );
+        assert!(!sshd.home.join("three.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn batch_without_stop_on_error_runs_everything() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let commands = ["echo one", "exit 4", "echo three > three.txt", "echo four"];
+
+        let result = service
+            .batch(params(serde_json::json!({"commands": commands})))
+            .await
+            .unwrap();
+        let json = result.structured_content.unwrap();
+        let results = json["results"].as_array().unwrap();
+        let statuses: Vec<&str> = results
+            .iter()
+            .map(|r| r["status"].as_str().unwrap())
+            .collect();
+        assert_eq!(statuses, ["ok", "failed", "ok", "ok"]);
+        assert_eq!(results[3]["stdout"], "four\n");
+        assert_eq!(
+            json["summary"],
+            serde_json::json!({"succeeded": 3, "failed": 1, "skipped": 0})
+        );
+        assert!(sshd.home.join("three.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn batch_timeout_covers_the_whole_batch() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        let result = service
+            .batch(params(serde_json::json!({
+                "commands": ["sleep 3", "echo never"],
+                "timeout": 1,
+            })))
+            .await
+            .unwrap();
+        let json = result.structured_content.unwrap();
+        let results = json["results"].as_array().unwrap();
+        assert_eq!(results[1]["status"], "skipped");
+        assert_eq!(results[1]["reason"], "batch timeout reached");
+    }
+
+    #[test]
+    fn path_stat_parses_gnu_stat_output() {
+        // GNU coreutils, as Termux ships it: a 0644 file, then a 0755 directory
+        let file = parse_path_stat("notes.txt", "81a4\n81a4 1234 1700000000\n").unwrap();
+        assert!(file.exists && file.is_file && !file.is_dir && !file.is_symlink);
+        assert_eq!(file.size_bytes, Some(1234));
+        assert_eq!(file.mode_octal.as_deref(), Some("0644"));
+        assert_eq!(file.mtime, Some(1700000000));
+
+        let dir = parse_path_stat("bin", "41ed\n41ed 4096 1699999999\n").unwrap();
+        assert!(dir.is_dir && !dir.is_file);
+        assert_eq!(dir.mode_octal.as_deref(), Some("0755"));
+    }
+
+    #[test]
+    fn path_stat_parses_busybox_stat_output() {
+        // busybox, as on stock Android shells: /sdcard is a symlink to a directory
+        let link = parse_path_stat("/sdcard", "a1ff \n41f9 3452 1698000000 \n").unwrap();
+        assert!(link.exists && link.is_symlink && link.is_dir);
+        assert_eq!(link.mode_octal.as_deref(), Some("0771"));
+        assert_eq!(link.size_bytes, Some(3452));
+
+        let setuid = parse_path_stat("su", "89ed\n89ED 8 1600000000\n").unwrap();
+        assert_eq!(setuid.mode_octal.as_deref(), Some("4755"));
+
+        let dangling = parse_path_stat("broken", "a1ff\n").unwrap();
+        assert!(dangling.exists && dangling.is_symlink && !dangling.is_file);
+        assert_eq!(dangling.size_bytes, None);
+
+        let missing = parse_path_stat("nope", "").unwrap();
+        assert!(!missing.exists);
+        assert!(parse_path_stat("x", "stat: applet not found\n").is_none());
+    }
+
+    #[tokio::test]
+    async fn path_info_reports_what_is_at_a_path() {
+        let sshd = TestSshd::start().await;
+        std::fs::write(sshd.home.join("file.txt"), "12345").unwrap();
+        std::fs::create_dir(sshd.home.join("dir")).unwrap();
+        std::os::unix::fs::symlink("dir", sshd.home.join("link")).unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        let info = |path: &str| {
+            let service = &service;
+            let path = path.to_string();
+            async move {
+                service
+                    .path_info(params(serde_json::json!({"remote_path": path})))
+                    .await
+                    .unwrap()
+                    .structured_content
+                    .unwrap()
+            }
+        };
+        let file = info("file.txt").await;
+        assert_eq!(file["is_file"], true);
+        assert_eq!(file["size_bytes"], 5);
+        let link = info("link").await;
+        assert_eq!(link["is_symlink"], true);
+        assert_eq!(link["is_dir"], true);
+        assert_eq!(info("missing").await["exists"], false);
+    }
+
+    #[tokio::test]
+    async fn dry_run_returns_the_command_without_running_it() {
+        let sshd = TestSshd::start().await;
+        std::fs::create_dir(sshd.home.join("work")).unwrap();
+        let service = AndroidSshService::new(Some(Config {
+            blocklist: Some(BlocklistConfig {
+                prefixes: vec!["rm -rf /".to_string()],
+                ..Default::default()
+            }),
+            ..sshd.config()
+        }));
+        service
+            .set_working_directory(params(serde_json::json!({"path": "work"})))
+            .await
+            .unwrap();
+        let channels = sshd.channels_opened();
+        let client = TestClient::start();
+        let cwd = sshd.home.path().canonicalize().unwrap().join("work");
+
+        let result = service
+            .execute(
+                params(serde_json::json!({
+                    "command": "ls -la",
+                    "env": {"LANG": "C"},
+                    "dry_run": true,
+                })),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        let json = result.structured_content.unwrap();
+        assert_eq!(
+            json["command_line"],
+            format!("export LANG='C'; cd '{}' && ls -la", cwd.display())
+        );
+        assert_eq!(json["cwd"], cwd.display().to_string());
+        assert_eq!(json["blocked"], false);
+        assert_eq!(json["read_only"], true);
+
+        let result = service
+            .execute(
+                params(
+                    serde_json::json!({"command": "rm -rf / --no-preserve-root", "dry_run": true}),
+                ),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        let json = result.structured_content.unwrap();
+        assert_eq!(json["blocked"], true);
+        assert_eq!(
+            json["blocked_reason"],
+            "Command blocked by [blocklist] prefix 'rm -rf /'"
+        );
+        assert_eq!(json["read_only"], false);
+
+        assert_eq!(sshd.channels_opened(), channels);
+    }
+
+    #[tokio::test]
+    async fn write_file_keeps_quotes_dollars_and_newlines_verbatim() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let content = "echo 'single' \"double\" $HOME `id` \\n\nsecond line\n\n";
+
+        let result = service
+            .write_file(params(serde_json::json!({
+                "remote_path": "notes.sh",
+                "content": content,
+            })))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        assert_eq!(
+            std::fs::read_to_string(sshd.home.join("notes.sh")).unwrap(),
+            content
+        );
+
+        let result = service
+            .write_file(params(serde_json::json!({
+                "remote_path": "notes.sh",
+                "content": "$((1 + 1)) 'tail'\n",
+                "append": true,
+            })))
+            .await
+            .unwrap();
+        assert!(text(&result).starts_with("✓ Appended"), "{}", text(&result));
+        assert_eq!(
+            std::fs::read_to_string(sshd.home.join("notes.sh")).unwrap(),
+            format!("{content}$((1 + 1)) 'tail'\n")
+        );
+    }
+
+    #[tokio::test]
+    async fn sftp_tools_expand_a_leading_tilde() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let local = TempDir::new();
+
+        let result = service
+            .write_file(params(serde_json::json!({
+                "remote_path": "~/from-tilde.txt",
+                "content": "home",
+            })))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        assert_eq!(
+            std::fs::read_to_string(sshd.home.join("from-tilde.txt")).unwrap(),
+            "home"
+        );
+
+        let result = service
+            .download_file(Parameters(DownloadFileRequest {
+                remote_path: "~/from-tilde.txt".to_string(),
+                local_path: local.join("copy.txt").display().to_string(),
+                inline: false,
+                timeout: 30,
+            }))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        assert_eq!(
+            std::fs::read_to_string(local.join("copy.txt")).unwrap(),
+            "home"
+        );
+    }
+
+    #[tokio::test]
+    async fn write_file_creates_parent_directories_only_when_asked() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        let result = service
+            .write_file(params(serde_json::json!({
+                "remote_path": "a/b/c.txt",
+                "content": "nested",
+            })))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(!sshd.home.join("a").exists());
+
+        let result = service
+            .write_file(params(serde_json::json!({
+                "remote_path": "a/b/c.txt",
+                "content": "nested",
+                "create_dirs": true,
+            })))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        assert_eq!(
+            std::fs::read_to_string(sshd.home.join("a/b/c.txt")).unwrap(),
+            "nested"
+        );
+    }
+
+    #[tokio::test]
+    async fn binary_stdout_is_base64_encoded_with_a_note() {
+        use base64::Engine;
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let client = TestClient::start();
+        let bytes = [0x89, b'P', b'N', b'G', 0x00, 0xff, 0xfe, b'\n'];
+        std::fs::write(sshd.home.join("image.png"), bytes).unwrap();
+
+        let run = |encoding: &'static str| {
+            service.execute(
+                params(serde_json::json!({
+                    "command": "cat image.png",
+                    "encoding": encoding,
+                    "structured": true,
+                })),
+                client.context(),
+            )
+        };
+
+        let result = run("auto").await.unwrap();
+        let json = result.structured_content.clone().unwrap();
+        assert_eq!(json["stdout_encoding"], "base64");
+        assert_eq!(
+            base64::engine::general_purpose::STANDARD
+                .decode(json["stdout"].as_str().unwrap())
+                .unwrap(),
+            bytes
+        );
+        assert!(
+            text(&result).contains("Note: stdout is binary (not valid UTF-8)"),
+            "{}",
+            text(&result)
+        );
+
+        let result = run("utf8").await.unwrap();
+        let json = result.structured_content.clone().unwrap();
+        assert_eq!(json["stdout_encoding"], "utf8");
+        assert!(json["stdout"].as_str().unwrap().contains('\u{fffd}'));
+        assert!(text(&result).contains("Re-run with encoding=\"base64\""));
+
+        let result = run("base64").await.unwrap();
+        assert_eq!(
+            result.structured_content.unwrap()["stdout_encoding"],
+            "base64"
+        );
+    }
+
+    #[tokio::test]
+    async fn text_stdout_stays_text_under_auto() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let client = TestClient::start();
+
+        let result = service
+            .execute(
+                params(serde_json::json!({"command": "echo héllo", "structured": true})),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        let json = result.structured_content.clone().unwrap();
+        assert_eq!(json["stdout_encoding"], "utf8");
+        assert_eq!(json["stdout"], "héllo\n");
+        assert!(!text(&result).contains("Note:"));
+    }
+
+    #[tokio::test]
+    async fn disconnect_drops_the_session_and_the_next_command_reconnects() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        service
+            .execute_read(params(serde_json::json!({"command": "true"})))
+            .await
+            .unwrap();
+        assert_eq!(sshd.connections(), 1);
+
+        let result = service
+            .disconnect(params(serde_json::json!({})))
+            .await
+            .unwrap();
+        assert!(
+            text(&result).starts_with("✓ Disconnected from"),
+            "{}",
+            text(&result)
+        );
+        {
+            let mut guard = service.ssh_client.lock().await;
+            let client = guard.as_mut().unwrap();
+            assert!(!client.status().await.connected);
+            // The session was already taken, so there is nothing left to close
+            assert!(!client.disconnect().await);
+        }
+
+        let result = service
+            .execute_read(params(serde_json::json!({"command": "echo again"})))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        assert!(text(&result).contains("again"));
+        assert_eq!(sshd.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn reload_config_switches_to_the_new_host_and_user() {
+        let _env = ENV_LOCK.lock().await;
+        let phone = TestSshd::start().await;
+        let tablet = TestSshd::start().await;
+        std::fs::write(tablet.home.join("device"), "tablet").unwrap();
+        let config_dir = TempDir::new();
+        unsafe {
+            std::env::set_var(
+                crate::config::CONFIG_PATH_ENV,
+                config_dir.join("config.toml"),
+            )
+        };
+        let service = AndroidSshService::new(Some(phone.config()));
+        let cat_device =
+            || service.execute_read(params(serde_json::json!({"command": "cat device"})));
+        let result = cat_device().await.unwrap();
+        assert!(text(&result).contains("No such file"), "{}", text(&result));
+
+        Config::save(&Config {
+            host: "localhost".to_string(),
+            ..tablet.config()
+        })
+        .unwrap();
+        let result = service.reload_config().await.unwrap();
+        assert!(
+            text(&result).contains(&format!("Connection: tester@localhost:{}", tablet.port)),
+            "{}",
+            text(&result)
+        );
+        let result = cat_device().await.unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        assert!(text(&result).contains("tablet"));
+        assert_eq!(phone.connections(), 1);
+
+        Config::save(&Config {
+            user: "stranger".to_string(),
+            ..tablet.config()
+        })
+        .unwrap();
+        service.reload_config().await.unwrap();
+        assert_eq!(
+            service
+                .ssh_client
+                .lock()
+                .await
+                .as_ref()
+                .unwrap()
+                .config()
+                .user,
+            "stranger"
+        );
+        let result = cat_device().await.unwrap();
+        assert!(
+            text(&result).contains("Authentication failed"),
+            "{}",
+            text(&result)
+        );
+        unsafe { std::env::remove_var(crate::config::CONFIG_PATH_ENV) };
+    }
+
+    #[test]
+    fn ssh_error_results_carry_the_error_code() {
+        let result = ssh_error_result(
+            "Failed to execute command",
+            &SshMcpError::Timeout("Command timed out after 5 seconds".to_string()),
+        );
+        assert_eq!(result.is_error, Some(true));
+        assert_eq!(
+            text(&result),
+            "Failed to execute command: Timeout error: Command timed out after 5 seconds"
+        );
+        let json = result.structured_content.unwrap();
+        assert_eq!(json["error"]["code"], "timeout");
+        assert_eq!(
+            json["error"]["message"],
+            "Timeout error: Command timed out after 5 seconds"
+        );
+    }
+
+    #[tokio::test]
+    async fn setup_tests_the_connection_before_saving() {
+        let _env = ENV_LOCK.lock().await;
+        let sshd = TestSshd::start().await;
+        let config_dir = TempDir::new();
+        unsafe {
+            std::env::set_var(
+                crate::config::CONFIG_PATH_ENV,
+                config_dir.join("config.toml"),
+            )
+        };
+        // Keeps known_hosts_path pointing at the test server's file
+        Config::save(&sshd.config()).unwrap();
+        let service = AndroidSshService::new(None);
+        let setup = |password: &str| {
+            service.setup(params(serde_json::json!({
+                "host": "127.0.0.1",
+                "port": sshd.port,
+                "user": crate::test_support::sshd::USER,
+                "password": password,
+            })))
+        };
+
+        let result = setup(crate::test_support::sshd::PASSWORD).await.unwrap();
+        assert!(
+            text(&result).contains("✓ Connection test passed"),
+            "{}",
+            text(&result)
+        );
+        assert_eq!(sshd.connections(), 1);
+
+        let result = setup("wrong").await.unwrap();
+        assert!(
+            text(&result).contains("⚠ Connection test failed (auth_failed)"),
+            "{}",
+            text(&result)
+        );
+        assert!(text(&result).contains("saved but untested"));
+        let saved = Config::load_existing().unwrap();
+        assert_eq!(saved.password.unwrap().expose(), "wrong");
+
+        let connections = sshd.connections();
+        let result = service
+            .setup(params(serde_json::json!({"test_connection": false})))
+            .await
+            .unwrap();
+        assert!(
+            !text(&result).contains("Connection test"),
+            "{}",
+            text(&result)
+        );
+        assert_eq!(sshd.connections(), connections);
+        unsafe { std::env::remove_var(crate::config::CONFIG_PATH_ENV) };
+    }
+
+    #[tokio::test]
+    async fn provision_key_installs_a_key_that_logs_in() {
+        let _env = ENV_LOCK.lock().await;
+        let sshd = TestSshd::start().await;
+        let local = TempDir::new();
+        unsafe { std::env::set_var(crate::config::CONFIG_PATH_ENV, local.join("config.toml")) };
+        Config::save(&sshd.config()).unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let key_path = local.join("id_device");
+
+        let result = service
+            .provision_key(params(serde_json::json!({
+                "key_path": key_path.display().to_string(),
+                "clear_password": true,
+            })))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        assert!(
+            text(&result).contains("✓ Connection test passed"),
+            "{}",
+            text(&result)
+        );
+        assert!(text(&result).contains("The stored password was removed"));
+
+        let public = std::fs::read_to_string(keygen::public_key_path(&key_path)).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(sshd.home.join(".ssh/authorized_keys")).unwrap(),
+            public
+        );
+        let saved = Config::load_existing().unwrap();
+        assert_eq!(saved.key_path, Some(key_path.display().to_string()));
+        assert!(!saved.has_password());
+
+        // The key is never overwritten by a second run
+        let result = service
+            .provision_key(params(serde_json::json!({
+                "key_path": key_path.display().to_string(),
+            })))
+            .await
+            .unwrap();
+        assert!(
+            text(&result).contains("already exists"),
+            "{}",
+            text(&result)
+        );
+        unsafe { std::env::remove_var(crate::config::CONFIG_PATH_ENV) };
+    }
+
+    #[tokio::test]
+    async fn make_dir_creates_nested_directories_with_parents() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let make_dir = |parents: bool| {
+            service.make_dir(params(serde_json::json!({
+                "remote_path": "projects/it's $new/src",
+                "parents": parents,
+            })))
+        };
+
+        let result = make_dir(false).await.unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            text(&result).contains("pass parents=true"),
+            "{}",
+            text(&result)
+        );
+
+        let result = make_dir(true).await.unwrap();
+        assert_eq!(text(&result), "✓ Created directory projects/it's $new/src");
+        assert!(sshd.home.join("projects/it's $new/src").is_dir());
+
+        let result = make_dir(true).await.unwrap();
+        assert_eq!(result.is_error, Some(false));
+        assert!(
+            text(&result).contains("already exists"),
+            "{}",
+            text(&result)
+        );
+    }
+
+    #[tokio::test]
+    async fn make_dir_reports_a_file_in_the_way() {
+        let sshd = TestSshd::start().await;
+        std::fs::write(sshd.home.join("notes"), "not a directory").unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        for parents in [false, true] {
+            let result = service
+                .make_dir(params(serde_json::json!({
+                    "remote_path": "notes",
+                    "parents": parents,
+                })))
+                .await
+                .unwrap();
+            assert_eq!(result.is_error, Some(true));
+            assert!(
+                text(&result).contains("a file (or other non-directory) is already at that path"),
+                "{}",
+                text(&result)
+            );
+        }
+        assert!(sshd.home.join("notes").is_file());
+    }
+
+    fn stdout_result(stdout: &str) -> CommandResult {
+        CommandResult {
+            stdout: stdout.to_string(),
+            stdout_bytes: None,
+            stderr: String::new(),
+            exit_code: Some(0),
+            duration_ms: 0,
+        }
+    }
+
+    #[test]
+    fn grep_keeps_matching_lines_and_counts_them() {
+        let pattern = Regex::new(r"^E/|FATAL").unwrap();
+        let log = "I/boot: ok\nE/app: crash\nW/net: slow\nFATAL EXCEPTION\r\nI/tail: done";
+
+        let mut result = stdout_result(log);
+        let filter = filter_stdout(&mut result, &pattern, false);
+        assert_eq!(result.stdout, "E/app: crash\nFATAL EXCEPTION\r\n");
+        assert_eq!((filter.matched_lines, filter.total_lines), (2, 5));
+
+        let mut result = stdout_result(log);
+        let filter = filter_stdout(&mut result, &pattern, true);
+        assert_eq!(result.stdout, "I/boot: ok\nW/net: slow\nI/tail: done");
+        assert_eq!((filter.matched_lines, filter.total_lines), (3, 5));
+        assert!(filter.invert);
+    }
+
+    #[tokio::test]
+    async fn grep_filters_execute_read_output_and_rejects_bad_patterns() {
+        let sshd = TestSshd::start().await;
+        std::fs::write(
+            sshd.home.join("app.log"),
+            "start\nerror: disk\nok\nerror: net\n",
+        )
+        .unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        let result = service
+            .execute_read(params(serde_json::json!({
+                "command": "cat app.log",
+                "grep": "^error",
+                "structured": true,
+            })))
+            .await
+            .unwrap();
+        assert!(text(&result).contains("grep '^error': 2 of 4 stdout lines kept"));
+        let json = result.structured_content.unwrap();
+        assert_eq!(json["stdout"], "error: disk\nerror: net\n");
+        assert_eq!(json["grep"]["matched_lines"], 2);
+
+        let result = service
+            .execute_read(params(serde_json::json!({
+                "command": "cat app.log",
+                "grep": "^error",
+                "grep_invert": true,
+            })))
+            .await
+            .unwrap();
+        assert!(text(&result).contains("grep (inverted) '^error': 2 of 4 stdout lines kept"));
+
+        let channels = sshd.channels_opened();
+        let result = service
+            .execute_read(params(
+                serde_json::json!({"command": "cat app.log", "grep": "(unclosed"}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            text(&result).starts_with("Invalid grep pattern:"),
+            "{}",
+            text(&result)
+        );
+        assert_eq!(sshd.channels_opened(), channels);
+    }
+
+    #[test]
+    fn power_actions_map_to_root_commands() {
+        assert_eq!(
+            PowerAction::Reboot.command(),
+            "su -c 'svc power reboot || reboot'"
+        );
+        assert_eq!(
+            PowerAction::Shutdown.command(),
+            "su -c 'svc power shutdown || reboot -p'"
+        );
+        assert_eq!(
+            PowerAction::RebootRecovery.command(),
+            "su -c 'svc power reboot recovery || reboot recovery'"
+        );
+    }
+
+    #[tokio::test]
+    async fn power_needs_confirm_and_root() {
+        let sshd = TestSshd::start().await;
+        sshd.fake_command("su", "echo 'su: permission denied' >&2; exit 1");
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let power = |confirm: bool| {
+            service.power(params(
+                serde_json::json!({"action": "reboot", "confirm": confirm}),
+            ))
+        };
+
+        let result = power(false).await.unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(text(&result).contains("confirm=true"), "{}", text(&result));
+        assert_eq!(sshd.connections(), 0);
+
+        let result = power(true).await.unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            text(&result).contains("Reboot needs root"),
+            "{}",
+            text(&result)
+        );
+        assert!(text(&result).contains("su: permission denied"));
+    }
+
+    #[tokio::test]
+    async fn power_runs_the_action_as_root_and_closes_the_session() {
+        let sshd = TestSshd::start().await;
+        sshd.fake_command(
+            "su",
+            r#"case "$2" in 'id -u') echo 0 ;; *) echo "$2" >> "$HOME/su.log" ;; esac"#,
+        );
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        let result = service
+            .power(params(
+                serde_json::json!({"action": "shutdown", "confirm": true}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        assert!(text(&result).starts_with("✓ Shutdown requested"));
+        assert_eq!(
+            std::fs::read_to_string(sshd.home.join("su.log")).unwrap(),
+            "svc power shutdown || reboot -p\n"
+        );
+        let guard = service.ssh_client.lock().await;
+        assert!(!guard.as_ref().unwrap().status().await.connected);
+    }
+
+    /// A TCP server on loopback that sends back everything it receives, with "echo: " first
+    async fn echo_server() -> u16 {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        tokio::spawn(async move {
+            while let Ok((mut stream, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = [0u8; 1024];
+                    while let Ok(n @ 1..) = stream.read(&mut buf).await {
+                        let mut reply = b"echo: ".to_vec();
+                        reply.extend_from_slice(&buf[..n]);
+                        if stream.write_all(&reply).await.is_err() {
+                            break;
+                        }
                     }
-                );
-                Ok(CallToolResult::success(vec![Content::text(msg)]))
+                });
             }
-            Err(e) => Ok(CallToolResult::error(vec![Content::text(format!(
-                "Failed to save config: {}",
-                e
-            ))])),
+        });
+        port
+    }
+
+    #[tokio::test]
+    async fn forward_port_carries_bytes_through_the_session() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+        let sshd = TestSshd::start().await;
+        let echo_port = echo_server().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        let result = service
+            .forward_port(params(serde_json::json!({"remote_port": echo_port})))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        let json = result.structured_content.unwrap();
+        let local_address = json["local_address"].as_str().unwrap().to_string();
+        let id = json["id"].as_u64().unwrap();
+
+        let mut stream = tokio::net::TcpStream::connect(&local_address)
+            .await
+            .unwrap();
+        stream.write_all(b"ping\n").await.unwrap();
+        let mut reply = vec![0u8; b"echo: ping\n".len()];
+        stream.read_exact(&mut reply).await.unwrap();
+        assert_eq!(reply, b"echo: ping\n");
+        drop(stream);
+
+        let listed = service.list_forwards().await.unwrap();
+        assert!(
+            text(&listed).contains(&format!(
+                "{id}: {local_address} → 127.0.0.1:{echo_port} (active)"
+            )),
+            "{}",
+            text(&listed)
+        );
+        let result = service
+            .close_forward(params(serde_json::json!({"id": id})))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        // The listener is gone once the accept loop is aborted
+        tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+        assert!(
+            tokio::net::TcpStream::connect(&local_address)
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn forward_port_reports_a_local_port_in_use() {
+        let sshd = TestSshd::start().await;
+        let taken = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        let result = service
+            .forward_port(params(serde_json::json!({
+                "local_port": taken.local_addr().unwrap().port(),
+                "remote_port": 80,
+            })))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            text(&result).contains("already listening there"),
+            "{}",
+            text(&result)
+        );
+        assert!(text(&service.list_forwards().await.unwrap()).starts_with("No port forwards"));
+    }
+
+    #[tokio::test]
+    async fn each_command_tool_uses_its_configured_default_timeout() {
+        let sshd = TestSshd::start().await;
+        // A whitelisted command that takes a while
+        sshd.fake_command("uptime", "sleep 2; echo up");
+        let service = AndroidSshService::new(Some(Config {
+            default_read_timeout_secs: Some(1),
+            default_exec_timeout_secs: Some(5),
+            ..sshd.config()
+        }));
+        let client = TestClient::start();
+
+        let result = service
+            .execute_read(params(serde_json::json!({"command": "uptime"})))
+            .await
+            .unwrap();
+        assert!(
+            text(&result).contains("timed out after 1 seconds"),
+            "{}",
+            text(&result)
+        );
+
+        let result = service
+            .execute(
+                params(serde_json::json!({"command": "uptime"})),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+
+        // An explicit timeout still wins
+        let result = service
+            .execute_read(params(
+                serde_json::json!({"command": "uptime", "timeout": 5}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+    }
+
+    #[tokio::test]
+    async fn disk_usage_lists_the_largest_subdirectories() {
+        let sshd = TestSshd::start().await;
+        for (dir, size) in [("big", 300_000), ("medium", 100_000), ("small", 10)] {
+            std::fs::create_dir_all(sshd.home.join(&format!("media/{dir}"))).unwrap();
+            std::fs::write(
+                sshd.home.join(&format!("media/{dir}/data")),
+                vec![1u8; size],
+            )
+            .unwrap();
+        }
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        let result = service
+            .disk_usage(params(serde_json::json!({"path": "media", "top": 2})))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        let json = result.structured_content.unwrap();
+        let largest: Vec<&str> = json["largest"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|dir| dir["path"].as_str().unwrap())
+            .collect();
+        assert_eq!(largest, ["media/big", "media/medium"]);
+        assert_eq!(json["omitted"], 1);
+        assert!(json["total_bytes"].as_u64().unwrap() >= 400_000);
+        assert!(json["filesystem"]["size_bytes"].as_u64().unwrap() > 0);
+    }
+
+    #[tokio::test]
+    async fn send_signal_interrupts_a_streamed_command() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let client = TestClient::start();
+
+        let result = service
+            .send_signal(params(serde_json::json!({})))
+            .await
+            .unwrap();
+        assert_eq!(text(&result), "No streamed command is running.");
+
+        let started = Instant::now();
+        let stream = service.stream(
+            params(serde_json::json!({"command": "sleep 100", "duration_secs": 120})),
+            client.context(),
+        );
+        let interrupt = async {
+            while service.streams.lock().await.is_empty() {
+                tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+            }
+            service
+                .send_signal(params(serde_json::json!({"signal": "INT"})))
+                .await
+                .unwrap()
+        };
+        let (streamed, sent) = tokio::join!(stream, interrupt);
+        assert_eq!(text(&sent), "✓ Sent SIGINT to stream 1 (sleep 100)");
+        assert!(started.elapsed().as_secs() < 10, "{:?}", started.elapsed());
+        assert!(!text(&streamed.unwrap()).contains("duration of 120s elapsed"));
+
+        let result = service
+            .send_signal(params(serde_json::json!({"stream_id": 1})))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+    }
+
+    #[tokio::test]
+    async fn tail_file_returns_the_last_lines() {
+        let sshd = TestSshd::start().await;
+        let log: String = (1..=100).map(|i| format!("line {i}\n")).collect();
+        std::fs::write(sshd.home.join("app's.log"), log).unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let client = TestClient::start();
+
+        let result = service
+            .tail_file(
+                params(serde_json::json!({"remote_path": "app's.log", "lines": 3})),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(text(&result), "line 98\nline 99\nline 100\n");
+
+        let result = service
+            .tail_file(
+                params(serde_json::json!({"remote_path": "missing.log"})),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(text(&result).starts_with("Failed to read missing.log"));
+    }
+
+    #[tokio::test]
+    async fn tail_file_follows_new_lines_until_follow_secs() {
+        let sshd = TestSshd::start().await;
+        let path = sshd.home.join("app.log");
+        std::fs::write(&path, "old 1\nold 2\n").unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let client = TestClient::start();
+
+        let started = Instant::now();
+        let follow = service.tail_file(
+            params(serde_json::json!({"remote_path": "app.log", "lines": 1, "follow_secs": 3})),
+            client.context(),
+        );
+        let append = async {
+            tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&path)
+                .unwrap();
+            std::io::Write::write_all(&mut file, b"new 3\n").unwrap();
+        };
+        let (result, ()) = tokio::join!(follow, append);
+        let output = text(&result.unwrap());
+        let elapsed = started.elapsed();
+        assert!(
+            output.contains("old 2\n") && output.contains("new 3\n"),
+            "{output}"
+        );
+        assert!(!output.contains("old 1"), "{output}");
+        assert!(output.contains("duration of 3s elapsed"), "{output}");
+        assert!((3..6).contains(&elapsed.as_secs()), "{elapsed:?}");
+    }
+
+    #[test]
+    fn probe_output_splits_names_into_installed_and_missing() {
+        let names: Vec<String> = ["rg", "jq", "ls"].map(String::from).to_vec();
+        assert_eq!(
+            probe_command(&names),
+            "for c in 'rg' 'jq' 'ls'; do command -v \"$c\" >/dev/null 2>&1 && echo \"$c\"; done"
+        );
+        let (found, missing) = parse_probe(&names, "rg\nls\n");
+        assert_eq!(found, ["rg", "ls"]);
+        assert_eq!(missing, ["jq"]);
+        assert_eq!(
+            not_installed_message("rg"),
+            "Command 'rg' is whitelisted but not installed on this device. \
+             Install it with: pkg install ripgrep"
+        );
+    }
+
+    #[tokio::test]
+    async fn probed_commands_that_are_missing_get_an_install_hint() {
+        let sshd = TestSshd::start().await;
+        sshd.fake_command("mcp-probe-present", "echo present");
+        let service = AndroidSshService::new(Some(Config {
+            whitelist: Some(WhitelistConfig {
+                additional: vec![
+                    "mcp-probe-present".to_string(),
+                    "mcp-probe-absent".to_string(),
+                ],
+                removed: Vec::new(),
+            }),
+            probe_commands: vec![
+                "mcp-probe-present".to_string(),
+                "mcp-probe-absent".to_string(),
+            ],
+            ..sshd.config()
+        }));
+        let run = |command: &'static str| {
+            service.execute_read(params(serde_json::json!({"command": command})))
+        };
+
+        let result = run("mcp-probe-absent --version").await.unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert_eq!(
+            text(&result),
+            "Command 'mcp-probe-absent' is whitelisted but not installed on this device. \
+             Install it with: pkg install mcp-probe-absent"
+        );
+
+        // The probe ran once for the device, so a present command goes straight through
+        let channels = sshd.channels_opened();
+        let result = run("mcp-probe-present").await.unwrap();
+        assert!(text(&result).contains("present"), "{}", text(&result));
+        assert_eq!(sshd.channels_opened(), channels + 1);
+
+        // Installed since the probe, so it is checked again and runs
+        sshd.fake_command("mcp-probe-absent", "echo installed now");
+        let result = run("mcp-probe-absent").await.unwrap();
+        assert!(text(&result).contains("installed now"), "{}", text(&result));
+    }
+
+    /// Stands in for su: runs `su -c CMD` and `su USER -c CMD` in sh, reporting the user
+    const FAKE_SU: &str = r#"[ "$1" = -c ] || { echo "as $1" >&2; shift; }
+exec sh -c "$2""#;
+
+    /// Run the run_as wrapper in sh locally, with `path` as PATH
+    fn run_as_locally(command: &str, user: &str, path: &std::path::Path) -> CommandResult {
+        let output = std::process::Command::new("/bin/sh")
+            .arg("-c")
+            .arg(run_as_command(command, user))
+            .env("PATH", path)
+            .output()
+            .unwrap();
+        CommandResult {
+            stdout: String::from_utf8(output.stdout).unwrap(),
+            stdout_bytes: None,
+            stderr: String::from_utf8(output.stderr).unwrap(),
+            exit_code: output.status.code(),
+            duration_ms: 0,
+        }
+    }
+
+    #[test]
+    fn run_as_quotes_the_command_and_strips_its_marker() {
+        use std::os::unix::fs::PermissionsExt;
+        let bin = TempDir::new();
+        let su = bin.join("su");
+        std::fs::write(&su, format!("#!/bin/sh\n{FAKE_SU}\n")).unwrap();
+        std::fs::set_permissions(&su, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let path = format!("{}:/bin:/usr/bin", bin.path().display());
+
+        let command = r#"printf '%s\n' "it's" '$HOME' "a;b""#;
+        let mut result = run_as_locally(command, "root", path.as_ref());
+        assert_eq!(take_run_as_marker(&mut result), RunAsOutcome::Ran);
+        assert_eq!(result.stdout, "it's\n$HOME\na;b\n");
+        assert_eq!(result.stderr, "");
+
+        let mut result = run_as_locally("echo hi", "shell", path.as_ref());
+        assert_eq!(take_run_as_marker(&mut result), RunAsOutcome::Ran);
+        assert_eq!(result.stderr, "as shell\n");
+    }
+
+    #[test]
+    fn run_as_reports_a_missing_or_refusing_su() {
+        let empty = TempDir::new();
+        let mut result = run_as_locally("id", "root", empty.path());
+        assert_eq!(result.exit_code, Some(127));
+        assert_eq!(take_run_as_marker(&mut result), RunAsOutcome::Unavailable);
+
+        let mut refused = stdout_result("");
+        refused.stderr = "su: permission denied\n".to_string();
+        refused.exit_code = Some(1);
+        assert_eq!(take_run_as_marker(&mut refused), RunAsOutcome::Refused);
+
+        // With a PTY the marker arrives at the start of stdout
+        let mut merged = stdout_result(&format!("{RUN_AS_MARKER}\nuid=0\n"));
+        assert_eq!(take_run_as_marker(&mut merged), RunAsOutcome::Ran);
+        assert_eq!(merged.stdout, "uid=0\n");
+    }
+
+    #[tokio::test]
+    async fn execute_run_as_goes_through_su() {
+        let sshd = TestSshd::start().await;
+        sshd.fake_command("su", FAKE_SU);
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let client = TestClient::start();
+
+        let result = service
+            .execute(
+                params(serde_json::json!({"command": "echo \"root's $0\"", "run_as": "root"})),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        assert!(text(&result).contains("root's sh"), "{}", text(&result));
+        assert!(!text(&result).contains(RUN_AS_MARKER));
+
+        sshd.fake_command("su", "echo 'su: permission denied' >&2; exit 1");
+        let result = service
+            .execute(
+                params(serde_json::json!({"command": "id", "run_as": "root"})),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            text(&result).starts_with("su/sudo refused to run the command as 'root'"),
+            "{}",
+            text(&result)
+        );
+    }
+
+    /// The start of a 1080x2400 PNG, with bytes that aren't valid UTF-8 after the header
+    fn png_fixture() -> Vec<u8> {
+        let mut png = b"\x89PNG\r\n\x1a\n\x00\x00\x00\x0dIHDR".to_vec();
+        png.extend_from_slice(&1080u32.to_be_bytes());
+        png.extend_from_slice(&2400u32.to_be_bytes());
+        png.extend_from_slice(&[
+            8, 6, 0, 0, 0, 0xde, 0xad, 0xbe, 0xef, 0xff, 0x00, 0x0a, 0x0d,
+        ]);
+        png
+    }
+
+    #[tokio::test]
+    async fn screenshot_returns_the_png_bytes_exactly() {
+        use base64::Engine;
+        let sshd = TestSshd::start().await;
+        std::fs::write(sshd.home.join("screen.png"), png_fixture()).unwrap();
+        sshd.fake_command("screencap", r#"[ "$1" = -p ] && cat "$HOME/screen.png""#);
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let local = TempDir::new();
+
+        let result = service
+            .screenshot(params(serde_json::json!({
+                "local_path": local.join("shot.png").display().to_string(),
+            })))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        let image = result.content[0].as_image().unwrap();
+        assert_eq!(image.mime_type, "image/png");
+        assert_eq!(
+            base64::engine::general_purpose::STANDARD
+                .decode(&image.data)
+                .unwrap(),
+            png_fixture()
+        );
+        assert!(
+            text(&result).starts_with("Screenshot: 1080x2400, 37 bytes, saved to"),
+            "{}",
+            text(&result)
+        );
+        assert_eq!(
+            std::fs::read(local.join("shot.png")).unwrap(),
+            png_fixture()
+        );
+    }
+
+    #[tokio::test]
+    async fn screenshot_explains_output_that_is_not_a_png() {
+        let sshd = TestSshd::start().await;
+        sshd.fake_command("screencap", "echo 'Permission Denial' >&2; exit 1");
+        let service = AndroidSshService::new(Some(sshd.config()));
+
+        let result = service
+            .screenshot(params(serde_json::json!({})))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            text(&result).starts_with(
+                "screencap did not return a PNG (0 bytes of output, exit code 1: Permission Denial)"
+            ),
+            "{}",
+            text(&result)
+        );
+        assert!(text(&result).contains("retry with root=true"));
+
+        sshd.fake_command("screencap", "exit 127");
+        let result = service
+            .screenshot(params(serde_json::json!({})))
+            .await
+            .unwrap();
+        assert!(
+            text(&result).contains("screencap isn't available"),
+            "{}",
+            text(&result)
+        );
+    }
+
+    #[tokio::test]
+    async fn copy_path_refuses_an_existing_dest_without_overwrite() {
+        let sshd = TestSshd::start().await;
+        std::fs::write(sshd.home.join("new's.txt"), "new").unwrap();
+        std::fs::write(sshd.home.join("old.txt"), "old").unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let copy = |overwrite: bool| {
+            service.copy_path(params(serde_json::json!({
+                "source": "new's.txt",
+                "dest": "old.txt",
+                "overwrite": overwrite,
+            })))
+        };
+
+        let result = copy(false).await.unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            text(&result).starts_with("Not copied: old.txt already exists. Pass overwrite=true"),
+            "{}",
+            text(&result)
+        );
+        assert_eq!(
+            std::fs::read_to_string(sshd.home.join("old.txt")).unwrap(),
+            "old"
+        );
+
+        let result = copy(true).await.unwrap();
+        assert_eq!(text(&result), "✓ Copied new's.txt to old.txt");
+        assert_eq!(
+            std::fs::read_to_string(sshd.home.join("old.txt")).unwrap(),
+            "new"
+        );
+        assert!(sshd.home.join("new's.txt").exists());
+    }
+
+    #[tokio::test]
+    async fn move_path_moves_into_a_directory_and_reports_a_missing_source() {
+        let sshd = TestSshd::start().await;
+        std::fs::write(sshd.home.join("report.txt"), "data").unwrap();
+        std::fs::create_dir(sshd.home.join("archive")).unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let move_to = |source: &str, dest: &str| {
+            service.move_path(params(serde_json::json!({"source": source, "dest": dest})))
+        };
+
+        let result = move_to("report.txt", "archive").await.unwrap();
+        assert_eq!(result.is_error, Some(false), "{}", text(&result));
+        assert!(text(&result).starts_with("✓ Moved report.txt to archive"));
+        assert!(!sshd.home.join("report.txt").exists());
+        assert_eq!(
+            std::fs::read_to_string(sshd.home.join("archive/report.txt")).unwrap(),
+            "data"
+        );
+
+        let result = move_to("report.txt", "elsewhere.txt").await.unwrap();
+        assert_eq!(text(&result), "Cannot move report.txt: it does not exist");
+    }
+
+    #[tokio::test]
+    async fn a_missing_exit_status_is_reported_as_unknown() {
+        let sshd = TestSshd::start().await;
+        sshd.withhold_exit_status();
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let client = TestClient::start();
+        let result = service
+            .execute(
+                params(serde_json::json!({"command": "echo partial; exit 3", "structured": true})),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        let output = text(&result);
+        assert!(output.contains("partial"), "{output}");
+        assert!(
+            output.contains("? Unknown result (exit code: unknown"),
+            "{output}"
+        );
+        assert!(!output.contains("Success"), "{output}");
+        assert!(result.structured_content.unwrap()["exit_code"].is_null());
+    }
+
+    fn leftover_scripts(sshd: &TestSshd) -> Vec<String> {
+        std::fs::read_dir(sshd.home.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with(SCRIPT_FILE_PREFIX))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn run_script_runs_a_multi_line_script_with_variables_and_args() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let script = "greeting=\"hello $1\"\n\
+                      count=0\n\
+                      for word in $greeting; do\n\
+                      \x20 count=$((count + 1))\n\
+                      done\n\
+                      echo \"$greeting has $count words, last arg: $2\"\n\
+                      exit 4\n";
+        let result = service
+            .run_script(params(serde_json::json!({
+                "script": script,
+                "args": ["big world", "it's $HOME"],
+            })))
+            .await
+            .unwrap();
+        let output = text(&result);
+        assert!(
+            output.contains("hello big world has 3 words, last arg: it's $HOME"),
+            "{output}"
+        );
+        assert!(output.contains("exit code: 4"), "{output}");
+        assert!(leftover_scripts(&sshd).is_empty());
+    }
+
+    #[tokio::test]
+    async fn run_script_picks_the_interpreter_from_the_request_or_shebang() {
+        let sshd = TestSshd::start().await;
+        sshd.fake_command("fakeinterp", "echo \"fakeinterp ran $# args: $*\"");
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let run = |request: serde_json::Value| service.run_script(params(request));
+
+        let result = run(serde_json::json!({
+            "script": "echo from sh\n",
+            "interpreter": "fakeinterp",
+            "args": ["a"],
+        }))
+        .await
+        .unwrap();
+        assert!(
+            text(&result).contains("fakeinterp ran 2 args"),
+            "{}",
+            text(&result)
+        );
+        assert!(!text(&result).contains("from sh"), "{}", text(&result));
+
+        let result =
+            run(serde_json::json!({"script": "#!/usr/bin/env fakeinterp\necho from sh\n"}))
+                .await
+                .unwrap();
+        assert!(
+            text(&result).contains("fakeinterp ran 1 args"),
+            "{}",
+            text(&result)
+        );
+
+        let result = run(serde_json::json!({"script": "echo plain sh\n"}))
+            .await
+            .unwrap();
+        assert!(text(&result).contains("plain sh"), "{}", text(&result));
+
+        let result = run(serde_json::json!({"script": "true\n", "interpreter": "  "}))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(leftover_scripts(&sshd).is_empty());
+    }
+
+    #[test]
+    fn shebang_lines_resolve_through_path() {
+        assert_eq!(
+            shebang_interpreter(b"#!/bin/bash\necho").as_deref(),
+            Some("bash")
+        );
+        assert_eq!(
+            shebang_interpreter(b"#!/usr/bin/env python3 -u\n").as_deref(),
+            Some("python3 -u")
+        );
+        assert_eq!(
+            shebang_interpreter(b"#!/data/data/com.termux/files/usr/bin/sh").as_deref(),
+            Some("/data/data/com.termux/files/usr/bin/sh")
+        );
+        assert_eq!(shebang_interpreter(b"#!/usr/bin/env\n"), None);
+        assert_eq!(shebang_interpreter(b"echo no shebang"), None);
+    }
+
+    #[tokio::test]
+    async fn truncated_output_is_kept_as_a_readable_resource() {
+        use rmcp::ServerHandler;
+        use rmcp::model::{ReadResourceRequestParam, ResourceContents};
+
+        let sshd = TestSshd::start().await;
+        let full: String = (1..=20_000).map(|n| format!("{n}\n")).collect();
+        std::fs::write(sshd.home.join("numbers.txt"), &full).unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let client = TestClient::start();
+
+        let result = service
+            .execute(
+                params(serde_json::json!({
+                    "command": "cat numbers.txt",
+                    "max_output_bytes": 1000,
+                    "structured": true,
+                })),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        let json = result.structured_content.clone().unwrap();
+        let uri = json["resource_uri"].as_str().unwrap().to_string();
+        assert!(uri.starts_with("android-ssh://output/"), "{uri}");
+        assert_eq!(json["stdout_total_bytes"], full.len());
+        assert!(json["stdout"].as_str().unwrap().len() < full.len() / 10);
+        assert!(
+            result
+                .content
+                .iter()
+                .any(|content| content.as_resource_link().is_some())
+        );
+        assert!(
+            text(&result).contains(&format!("Full output: {uri}")),
+            "{}",
+            text(&result)
+        );
+
+        let listed = service
+            .list_resources(None, client.context())
+            .await
+            .unwrap();
+        assert!(listed.resources.iter().any(|resource| resource.uri == uri));
+        let read = service
+            .read_resource(
+                ReadResourceRequestParam { uri: uri.clone() },
+                client.context(),
+            )
+            .await
+            .unwrap();
+        match &read.contents[..] {
+            [ResourceContents::TextResourceContents { text, .. }] => assert_eq!(*text, full),
+            other => panic!("unexpected contents: {other:?}"),
+        }
+
+        // Output that fits is returned inline and not stored
+        let result = service
+            .execute(
+                params(serde_json::json!({"command": "echo short", "structured": true})),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        assert!(result.structured_content.unwrap()["resource_uri"].is_null());
+        let missing = ReadResourceRequestParam {
+            uri: "android-ssh://output/999".to_string(),
+        };
+        assert!(
+            service
+                .read_resource(missing, client.context())
+                .await
+                .is_err()
+        );
+    }
+
+    #[tokio::test]
+    async fn a_read_command_cut_off_by_a_dropped_session_is_retried_once() {
+        let sshd = TestSshd::start().await;
+        std::fs::write(sshd.home.join("notes.txt"), "still here\n").unwrap();
+        let service = AndroidSshService::new(Some(Config {
+            auto_retry_read_commands: true,
+            ..sshd.config()
+        }));
+        let client = TestClient::start();
+        let read = || service.execute_read(params(serde_json::json!({"command": "cat notes.txt"})));
+
+        let result = read().await.unwrap();
+        assert!(text(&result).contains("still here"), "{}", text(&result));
+        sshd.drop_next_command();
+        let result = read().await.unwrap();
+        assert!(text(&result).contains("still here"), "{}", text(&result));
+        assert!(text(&result).contains("✓ Success"), "{}", text(&result));
+        assert_eq!(sshd.connections(), 2);
+
+        // execute can have side effects, so it is never rerun
+        sshd.drop_next_command();
+        let result = service
+            .execute(
+                params(serde_json::json!({"command": "cat notes.txt"})),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        assert!(!text(&result).contains("still here"), "{}", text(&result));
+        assert_eq!(sshd.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn read_commands_are_not_retried_unless_configured() {
+        let sshd = TestSshd::start().await;
+        std::fs::write(sshd.home.join("notes.txt"), "still here\n").unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let read = || service.execute_read(params(serde_json::json!({"command": "cat notes.txt"})));
+        read().await.unwrap();
+        sshd.drop_next_command();
+        let result = read().await.unwrap();
+        assert!(!text(&result).contains("still here"), "{}", text(&result));
+        assert_eq!(sshd.connections(), 1);
+    }
+
+    #[tokio::test]
+    async fn diff_files_reports_identical_text_and_binary_files() {
+        let sshd = TestSshd::start().await;
+        let write =
+            |name: &str, contents: &[u8]| std::fs::write(sshd.home.join(name), contents).unwrap();
+        write("a.txt", b"one\ntwo\nthree\n");
+        write("same.txt", b"one\ntwo\nthree\n");
+        write("b.txt", b"one\n2\nthree\n");
+        write("a.bin", b"\x00\x01binary\xff");
+        write("b.bin", b"\x00\x02binary\xff");
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let diff = |path_a: &str, path_b: &str| {
+            service.diff_files(params(serde_json::json!({
+                "path_a": path_a,
+                "path_b": path_b,
+                "context_lines": 0,
+            })))
+        };
+
+        let json = diff("a.txt", "same.txt")
+            .await
+            .unwrap()
+            .structured_content
+            .unwrap();
+        assert_eq!(json["identical"], true);
+        assert_eq!(json["diff"], "");
+
+        let json = diff("a.txt", "b.txt")
+            .await
+            .unwrap()
+            .structured_content
+            .unwrap();
+        assert_eq!(json["identical"], false);
+        assert_eq!(json["binary"], false);
+        let unified = json["diff"].as_str().unwrap();
+        assert!(unified.contains("--- a.txt"), "{unified}");
+        assert!(unified.contains("@@ -2 +2 @@\n-two\n+2"), "{unified}");
+        assert!(!unified.contains("one"), "{unified}");
+
+        let json = diff("a.bin", "b.bin")
+            .await
+            .unwrap()
+            .structured_content
+            .unwrap();
+        assert_eq!(json["identical"], false);
+        assert_eq!(json["binary"], true);
+        assert_eq!(json["diff"], "");
+
+        let result = diff("a.txt", "nowhere.txt").await.unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            text(&result).contains("nowhere.txt does not exist"),
+            "{}",
+            text(&result)
+        );
+    }
+
+    #[tokio::test]
+    async fn profiles_keep_their_own_host_keys_and_sessions() {
+        let phone = TestSshd::start().await;
+        let tablet = TestSshd::start().await;
+        let profile = |port: u16, strict: bool| crate::config::ProfileConfig {
+            host: Some("127.0.0.1".to_string()),
+            port: Some(port),
+            strict_host_key_checking: Some(strict),
+            ..Default::default()
+        };
+        let config = Config {
+            profiles: HashMap::from([
+                ("tablet".to_string(), profile(tablet.port, false)),
+                // The phone again, but under a profile that has never trusted its key
+                ("strict".to_string(), profile(phone.port, true)),
+            ]),
+            ..phone.config()
+        };
+        let known_hosts = config.known_hosts_path().unwrap();
+        let service = AndroidSshService::new(Some(config));
+        let client = TestClient::start();
+        let run = |profile: Option<&str>| {
+            service.execute(
+                params(serde_json::json!({"command": "echo ok", "profile": profile})),
+                client.context(),
+            )
+        };
+
+        for profile in [None, Some("tablet"), None, Some("tablet")] {
+            let result = run(profile).await.unwrap();
+            assert!(text(&result).contains("✓ Success"), "{}", text(&result));
+        }
+        // One session per device, reused across calls
+        assert_eq!((phone.connections(), tablet.connections()), (1, 1));
+        let profiles: Vec<String> = service
+            .profile_clients
+            .lock()
+            .await
+            .keys()
+            .cloned()
+            .collect();
+        assert_eq!(profiles, ["tablet"]);
+
+        let read = |path: &std::path::Path| std::fs::read_to_string(path).unwrap_or_default();
+        let phone_entry = format!("[127.0.0.1]:{}", phone.port);
+        let tablet_entry = format!("[127.0.0.1]:{}", tablet.port);
+        let phone_hosts = read(&known_hosts);
+        let tablet_hosts = read(std::path::Path::new(&format!(
+            "{}.tablet",
+            known_hosts.display()
+        )));
+        assert!(phone_hosts.contains(&phone_entry), "{phone_hosts}");
+        assert!(!phone_hosts.contains(&tablet_entry), "{phone_hosts}");
+        assert!(tablet_hosts.contains(&tablet_entry), "{tablet_hosts}");
+        assert!(!tablet_hosts.contains(&phone_entry), "{tablet_hosts}");
+
+        // Trusting the phone's key for the default profile doesn't vouch for it elsewhere
+        let result = run(Some("strict")).await.unwrap();
+        assert_eq!(result.is_error, Some(true), "{}", text(&result));
+        assert_eq!(phone.connections(), 2);
+    }
+
+    #[tokio::test]
+    async fn disable_execute_removes_command_and_write_tools() {
+        use rmcp::ServerHandler;
+        use rmcp::model::CallToolRequestParam;
+
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(Config {
+            disable_execute: true,
+            ..sshd.config()
+        }));
+        let client = TestClient::start();
+        let call = |name: &'static str, command: &str| {
+            let arguments = serde_json::json!({"command": command, "script": command});
+            service.call_tool(
+                CallToolRequestParam {
+                    name: name.into(),
+                    arguments: arguments.as_object().cloned(),
+                },
+                client.context(),
+            )
+        };
+
+        for name in EXECUTE_DISABLED_TOOLS {
+            assert!(
+                !service.tool_router.has_route(name),
+                "{name} is still routed"
+            );
+            assert!(call(name, "echo hi").await.is_err(), "{name} ran");
+        }
+        let tools = service
+            .list_tools(None, client.context())
+            .await
+            .unwrap()
+            .tools;
+        assert!(
+            tools
+                .iter()
+                .all(|tool| !EXECUTE_DISABLED_TOOLS.contains(&&*tool.name))
+        );
+        assert!(tools.iter().any(|tool| tool.name == "execute_read"));
+        assert!(tools.iter().any(|tool| tool.name == "download_file"));
+        assert!(
+            service
+                .get_info()
+                .instructions
+                .unwrap()
+                .contains("policy disables execute")
+        );
+
+        let result = call("execute_read", "uptime").await.unwrap();
+        assert_ne!(result.is_error, Some(true), "{}", text(&result));
+        assert_eq!(sshd.channels_opened(), 1);
+    }
+
+    /// getprop with a few properties: the whole list without arguments, one value with a key
+    const FAKE_GETPROP: &str = r#"
+case "$1" in
+  "") printf '%s\n' '[ro.build.version.release]: [14]' '[ro.build.version.sdk]: [34]' \
+        '[ro.product.model]: [Pixel 7]' '[persist.sys.timezone]: [Europe/Berlin]' ;;
+  ro.build.version.release) echo 14 ;;
+  ro.product.model) echo 'Pixel 7' ;;
+  *) echo ;;
+esac"#;
+
+    #[tokio::test]
+    async fn get_property_reads_one_value_or_says_it_is_unset() {
+        let sshd = TestSshd::start().await;
+        sshd.fake_command("getprop", FAKE_GETPROP);
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let get = |key: &str| service.get_property(params(serde_json::json!({"key": key})));
+
+        let json = get("ro.product.model")
+            .await
+            .unwrap()
+            .structured_content
+            .unwrap();
+        assert_eq!(json["value"], "Pixel 7");
+        assert!(json.get("note").is_none());
+
+        let json = get("ro.not.a.real.key")
+            .await
+            .unwrap()
+            .structured_content
+            .unwrap();
+        assert!(json["value"].is_null());
+        assert!(
+            json["note"]
+                .as_str()
+                .unwrap()
+                .contains("is not set on this device")
+        );
+
+        let result = get("ro.model; reboot").await.unwrap();
+        assert_eq!(result.is_error, Some(true));
+    }
+
+    #[tokio::test]
+    async fn list_properties_filters_by_prefix() {
+        let sshd = TestSshd::start().await;
+        sshd.fake_command("getprop", FAKE_GETPROP);
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let list =
+            |prefix: &str| service.list_properties(params(serde_json::json!({"prefix": prefix})));
+
+        let json = list("ro.build.").await.unwrap().structured_content.unwrap();
+        assert_eq!(json["count"], 2);
+        assert_eq!(
+            json["properties"],
+            serde_json::json!({"ro.build.version.release": "14", "ro.build.version.sdk": "34"})
+        );
+        let json = list("").await.unwrap().structured_content.unwrap();
+        assert_eq!(json["count"], 4);
+        let json = list("vendor.").await.unwrap().structured_content.unwrap();
+        assert_eq!(json["count"], 0);
+    }
+
+    #[tokio::test]
+    async fn shell_send_keeps_the_environment_between_calls() {
+        let sshd = TestSshd::start().await;
+        std::fs::create_dir(sshd.home.join("work")).unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let send = |command: &str, session_id: Option<u32>| {
+            service.shell_send(params(serde_json::json!({
+                "command": command,
+                "session_id": session_id,
+                "timeout": 10,
+            })))
+        };
+
+        let result = send(
+            "export GREETING='hello there'; cd work; shout() { echo \"$1!\"; }",
+            None,
+        )
+        .await
+        .unwrap();
+        let output = text(&result);
+        assert!(output.contains("✓ Success"), "{output}");
+        assert!(output.contains("Shell 1: pass session_id=1"), "{output}");
+
+        let result = send("shout \"$GREETING from $(basename \"$PWD\")\"", Some(1))
+            .await
+            .unwrap();
+        let output = text(&result);
+        assert!(output.contains("hello there from work!"), "{output}");
+        let result = send("false", Some(1)).await.unwrap();
+        assert!(text(&result).contains("exit code: 1"), "{}", text(&result));
+        // One channel for the whole shell
+        assert_eq!(sshd.channels_opened(), 1);
+
+        // A new shell starts fresh
+        let result = send("echo \"[$GREETING]\"", None).await.unwrap();
+        assert!(text(&result).contains("[]"), "{}", text(&result));
+
+        let result = service
+            .shell_close(params(serde_json::json!({"session_id": 1})))
+            .await
+            .unwrap();
+        assert!(
+            text(&result).contains("Closed shell 1 after 3 commands"),
+            "{}",
+            text(&result)
+        );
+        let result = send("echo gone", Some(1)).await.unwrap();
+        assert_eq!(result.is_error, Some(true));
+    }
+
+    #[tokio::test]
+    async fn verify_checksum_compares_against_a_digest_or_a_local_file() {
+        const HELLO_SHA256: &str =
+            "5891b5b522d5df086d0ff0b110fbd9d21bb4fc7163af34d08286a2e846f6be03";
+        let sshd = TestSshd::start().await;
+        std::fs::write(sshd.home.join("hello.txt"), "hello\n").unwrap();
+        let local = TempDir::new();
+        std::fs::write(local.join("same.txt"), "hello\n").unwrap();
+        std::fs::write(local.join("other.txt"), "hello!\n").unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let client = TestClient::start();
+        let verify = |request: serde_json::Value| async {
+            let mut request = request;
+            request["remote_path"] = "hello.txt".into();
+            service
+                .verify_checksum(params(request), client.context())
+                .await
+                .unwrap()
+                .structured_content
+                .unwrap()
+        };
+
+        let json = verify(serde_json::json!({"expected": HELLO_SHA256.to_uppercase()})).await;
+        assert_eq!(json["matches"], true);
+        assert_eq!(json["remote_digest"], HELLO_SHA256);
+        let json = verify(serde_json::json!({"expected": "0".repeat(64)})).await;
+        assert_eq!(json["matches"], false);
+        assert_eq!(json["remote_digest"], HELLO_SHA256);
+
+        let json = verify(serde_json::json!({
+            "algorithm": "md5",
+            "expected": "b1946ac92492d2347c6235b4d2611184",
+        }))
+        .await;
+        assert_eq!(json["matches"], true);
+
+        let same = local.join("same.txt").display().to_string();
+        let json = verify(serde_json::json!({"local_path": same})).await;
+        assert_eq!(json["matches"], true);
+        assert_eq!(json["local_digest"], HELLO_SHA256);
+        let other = local.join("other.txt").display().to_string();
+        let json = verify(serde_json::json!({"local_path": other})).await;
+        assert_eq!(json["matches"], false);
+        assert_ne!(json["local_digest"], json["remote_digest"]);
+    }
+
+    #[test]
+    fn search_command_puts_options_before_tests_and_quotes_the_glob() {
+        let command = search_command("~/src", Some("*.rs"), Some(2), Some(SearchType::File), 50);
+        assert!(
+            command.ends_with("find \"$r\" -maxdepth 2 -type f -name '*.rs' -print | head -n 51"),
+            "{command}"
+        );
+        assert!(command.contains("r=\"$HOME\"'/src';"), "{command}");
+        let command = search_command("/sdcard", Some("it's *"), None, Some(SearchType::Dir), 5);
+        assert!(
+            command.ends_with("find \"$r\" -type d -name 'it'\\''s *' -print | head -n 6"),
+            "{command}"
+        );
+        let command = search_command("/", None, None, None, 1);
+        assert!(
+            command.ends_with("find \"$r\" -print | head -n 2"),
+            "{command}"
+        );
+    }
+
+    #[tokio::test]
+    async fn search_files_finds_matches_and_caps_the_results() {
+        let sshd = TestSshd::start().await;
+        for path in ["src/a.rs", "src/b.rs", "src/nested/c.rs", "src/notes.txt"] {
+            let path = sshd.home.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, "").unwrap();
+        }
+        std::fs::create_dir(sshd.home.join("src/empty.rs")).unwrap();
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let search = |request: serde_json::Value| async {
+            service
+                .search_files(params(request))
+                .await
+                .unwrap()
+                .structured_content
+                .unwrap()
+        };
+
+        let json =
+            search(serde_json::json!({"root": "src", "name_glob": "*.rs", "type": "file"})).await;
+        let mut paths: Vec<&str> = json["paths"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|path| path.as_str().unwrap())
+            .collect();
+        paths.sort();
+        assert_eq!(paths, ["src/a.rs", "src/b.rs", "src/nested/c.rs"]);
+        assert_eq!(json["truncated"], false);
+
+        let json =
+            search(serde_json::json!({"root": "src", "name_glob": "*.rs", "max_depth": 1})).await;
+        assert_eq!(json["count"], 3, "{json}");
+
+        let json =
+            search(serde_json::json!({"root": "src", "type": "file", "max_results": 2})).await;
+        assert_eq!(json["count"], 2);
+        assert_eq!(json["truncated"], true);
+
+        let result = service
+            .search_files(params(serde_json::json!({"root": "nowhere"})))
+            .await
+            .unwrap();
+        assert!(
+            text(&result).contains("nowhere does not exist"),
+            "{}",
+            text(&result)
+        );
+    }
+
+    #[tokio::test]
+    async fn keep_awake_runs_the_wake_lock_scripts_or_says_they_are_missing() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let keep_awake =
+            |release: bool| service.keep_awake(params(serde_json::json!({"release": release})));
+
+        let result = keep_awake(false).await.unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(
+            text(&result).contains(android::WAKE_LOCK_MISSING),
+            "{}",
+            text(&result)
+        );
+
+        sshd.fake_command("termux-wake-lock", "echo held > \"$HOME/wake_lock\"");
+        sshd.fake_command("termux-wake-unlock", "rm \"$HOME/wake_lock\"");
+        let result = keep_awake(false).await.unwrap();
+        assert!(
+            text(&result).contains("✓ Took the Termux wake lock"),
+            "{}",
+            text(&result)
+        );
+        assert!(sshd.home.join("wake_lock").exists());
+        let result = keep_awake(true).await.unwrap();
+        assert!(text(&result).contains("✓ Released"), "{}", text(&result));
+        assert!(!sshd.home.join("wake_lock").exists());
+    }
+
+    #[tokio::test]
+    async fn acquire_wake_lock_on_connect_takes_it_once_per_connection() {
+        let sshd = TestSshd::start().await;
+        sshd.fake_command("termux-wake-lock", "echo x >> \"$HOME/wake_locks\"");
+        let service = AndroidSshService::new(Some(Config {
+            acquire_wake_lock_on_connect: true,
+            ..sshd.config()
+        }));
+        let read = || service.execute_read(params(serde_json::json!({"command": "uptime"})));
+        read().await.unwrap();
+        read().await.unwrap();
+        let taken = std::fs::read_to_string(sshd.home.join("wake_locks")).unwrap();
+        assert_eq!(taken, "x\n");
+    }
+
+    #[tokio::test]
+    async fn execute_sends_staggered_output_as_progress_notifications() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let mut client = TestClient::start();
+        let result = service
+            .execute(
+                params(serde_json::json!({
+                    "command": "head -c 5000 /dev/zero | tr '\\0' a; echo; \
+                                sleep 1.5; echo second; sleep 1.5; echo third",
+                })),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        let output = text(&result);
+        assert!(output.contains("second\nthird\n"), "{output}");
+
+        let progress = client.progress_messages().await;
+        assert!(progress.len() >= 2, "{progress:?}");
+        // Each part shows up on its own, in order, including the output arriving as the
+        // command finishes
+        let position = |needle: &str| progress.iter().position(|m| m.contains(needle));
+        let (first, second) = (position("aaaa"), position("second"));
+        assert!(first.is_some() && first < second, "{progress:?}");
+        assert!(second < position("third"), "{progress:?}");
+        assert!(progress.iter().all(|m| !m.is_empty()), "{progress:?}");
+        assert_eq!(progress.concat().matches('a').count(), 5000);
+    }
+
+    #[tokio::test]
+    async fn execute_progress_waits_for_a_whole_character() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let mut client = TestClient::start();
+        // The euro sign's first byte arrives alone and sits through a few flush ticks
+        let result = service
+            .execute(
+                params(serde_json::json!({
+                    "command": r"printf '\342'; sleep 2.5; printf '\202\254\n'",
+                })),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        assert!(text(&result).contains("€"), "{}", text(&result));
+
+        let progress = client.progress_messages().await;
+        assert!(progress.iter().all(|m| !m.is_empty()), "{progress:?}");
+        assert_eq!(progress.concat(), "€\n");
+    }
+
+    async fn run_warning(
+        service: &AndroidSshService,
+        client: &TestClient,
+        fail_on_stderr: Option<bool>,
+    ) -> (String, serde_json::Value) {
+        let request = params(serde_json::json!({
+            "command": "echo out; echo 'warning: disk almost full' >&2",
+            "fail_on_stderr": fail_on_stderr,
+            "structured": true,
+        }));
+        let result = service.execute(request, client.context()).await.unwrap();
+        (text(&result), result.structured_content.unwrap())
+    }
+
+    #[tokio::test]
+    async fn fail_on_stderr_turns_a_warning_into_a_failure_but_keeps_the_exit_code() {
+        let sshd = TestSshd::start().await;
+        let client = TestClient::start();
+
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let (output, json) = run_warning(&service, &client, None).await;
+        assert!(output.contains("✓ Success"), "{output}");
+        assert_eq!(
+            (&json["success"], &json["exit_code"]),
+            (&true.into(), &0.into())
+        );
+
+        let (output, json) = run_warning(&service, &client, Some(true)).await;
+        assert!(
+            output.contains("✗ Failed (wrote to stderr, exit code: 0"),
+            "{output}"
+        );
+        assert_eq!(
+            (&json["success"], &json["exit_code"]),
+            (&false.into(), &0.into())
+        );
+        assert_eq!(json["stderr"], "warning: disk almost full\n");
+
+        // The config sets the default, and a request can still turn it off
+        let service = AndroidSshService::new(Some(Config {
+            fail_on_stderr: true,
+            ..sshd.config()
+        }));
+        assert_eq!(
+            run_warning(&service, &client, None).await.1["success"],
+            false
+        );
+        assert_eq!(
+            run_warning(&service, &client, Some(false)).await.1["success"],
+            true
+        );
+    }
+
+    #[tokio::test]
+    async fn clipboard_set_sends_the_text_on_stdin_and_get_reads_it_back() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let clipboard = "line one\n  'quoted' \"double\" $(touch pwned) `touch pwned` \\n\n\nlast";
+
+        let result = service
+            .clipboard_set(params(serde_json::json!({"text": clipboard})))
+            .await
+            .unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(text(&result).contains("Termux:API"), "{}", text(&result));
+
+        sshd.fake_command(
+            "termux-clipboard-set",
+            "[ $# -eq 0 ] && cat > \"$HOME/clipboard\"",
+        );
+        sshd.fake_command("termux-clipboard-get", "cat \"$HOME/clipboard\"");
+        let result = service
+            .clipboard_set(params(serde_json::json!({"text": clipboard})))
+            .await
+            .unwrap();
+        assert!(text(&result).contains("✓ Copied"), "{}", text(&result));
+        assert_eq!(
+            std::fs::read_to_string(sshd.home.join("clipboard")).unwrap(),
+            clipboard
+        );
+        assert!(!sshd.home.join("pwned").exists());
+
+        let json = service
+            .clipboard_get(params(serde_json::json!({})))
+            .await
+            .unwrap()
+            .structured_content
+            .unwrap();
+        assert_eq!(json["text"], clipboard);
+        assert_eq!(json["empty"], false);
+
+        std::fs::write(sshd.home.join("clipboard"), "").unwrap();
+        let json = service
+            .clipboard_get(params(serde_json::json!({})))
+            .await
+            .unwrap()
+            .structured_content
+            .unwrap();
+        assert_eq!((&json["text"], &json["empty"]), (&"".into(), &true.into()));
+    }
+
+    #[tokio::test]
+    async fn notify_passes_the_title_as_an_argument_and_the_content_on_stdin() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let notify = |request: serde_json::Value| service.notify(params(request));
+        let request = serde_json::json!({
+            "title": "Build 'done' $(touch pwned)",
+            "content": "All 42 tests passed\n\"quotes\" and `backticks` stay",
+            "priority": "high",
+        });
+
+        let result = notify(request.clone()).await.unwrap();
+        assert_eq!(result.is_error, Some(true));
+        assert!(text(&result).contains("termux-notification is not installed"));
+
+        sshd.fake_command(
+            "termux-notification",
+            "printf '%s\\n' \"$@\" > \"$HOME/args\"; cat > \"$HOME/content\"",
+        );
+        let result = notify(request).await.unwrap();
+        assert_ne!(result.is_error, Some(true), "{}", text(&result));
+        assert_eq!(
+            std::fs::read_to_string(sshd.home.join("args")).unwrap(),
+            "-t\nBuild 'done' $(touch pwned)\n--priority\nhigh\n"
+        );
+        assert_eq!(
+            std::fs::read_to_string(sshd.home.join("content")).unwrap(),
+            "All 42 tests passed\n\"quotes\" and `backticks` stay"
+        );
+        assert!(!sshd.home.join("pwned").exists());
+
+        // The priority defaults to Android's default
+        notify(serde_json::json!({"title": "t", "content": ""}))
+            .await
+            .unwrap();
+        let args = std::fs::read_to_string(sshd.home.join("args")).unwrap();
+        assert!(args.ends_with("--priority\ndefault\n"), "{args}");
+    }
+
+    fn exited(exit_code: i32, stderr: &str) -> CommandResult {
+        CommandResult {
+            stdout: String::new(),
+            stdout_bytes: None,
+            stderr: stderr.to_string(),
+            exit_code: Some(exit_code),
+            duration_ms: 0,
         }
     }
+
+    #[test]
+    fn missing_programs_are_recognized_from_each_shells_message() {
+        let missing = |stderr: &str| missing_command(&exited(127, stderr));
+        let expected = |name: &str, package: &str| {
+            Some(MissingCommand {
+                name: name.to_string(),
+                package: package.to_string(),
+            })
+        };
+        for stderr in [
+            "bash: rg: command not found",
+            "sh: 1: rg: not found",
+            "sh: rg: not found",
+            "zsh: command not found: rg",
+            "fish: Unknown command: rg",
+            "/system/bin/sh: rg: inaccessible or not found",
+        ] {
+            assert_eq!(missing(stderr), expected("rg", "ripgrep"), "{stderr}");
+        }
+        // Termux's handler names the package itself
+        assert_eq!(
+            missing(
+                "The program htop is not installed. Install it by executing:\n pkg install htop\n"
+            ),
+            expected("htop", "htop")
+        );
+        assert_eq!(
+            missing("No command pgrep found, did you mean:\n Command pgrep in package procps\n"),
+            expected("pgrep", "procps")
+        );
+
+        // A missing script path, or another failure, isn't a package to install
+        assert_eq!(missing("sh: 1: ./build.sh: not found"), None);
+        assert_eq!(
+            missing_command(&exited(1, "bash: rg: command not found")),
+            None
+        );
+        assert_eq!(missing("segmentation fault"), None);
+    }
+
+    #[tokio::test]
+    async fn execute_adds_an_install_hint_for_a_missing_program() {
+        let sshd = TestSshd::start().await;
+        let service = AndroidSshService::new(Some(sshd.config()));
+        let client = TestClient::start();
+        let result = service
+            .execute(
+                params(serde_json::json!({"command": "nosuchtool --version", "structured": true})),
+                client.context(),
+            )
+            .await
+            .unwrap();
+        let output = text(&result);
+        assert!(output.contains("exit code: 127"), "{output}");
+        assert!(
+            output.contains(
+                "Hint: 'nosuchtool' is not installed on the device. Install it with: pkg install nosuchtool"
+            ),
+            "{output}"
+        );
+        assert_eq!(
+            result.structured_content.unwrap()["missing_command"],
+            serde_json::json!({"name": "nosuchtool", "package": "nosuchtool"})
+        );
+    }
 }